@@ -5,15 +5,27 @@ use serde::Deserialize;
 #[derive(Deserialize)]
 struct RawConfig {
     device_id: String,
+    diagnostics_interval_seconds: Option<u16>,
     location: String,
+    mqtt_availability_topic: Option<String>,
+    mqtt_command_topic: Option<String>,
+    mqtt_diagnostics_topic: Option<String>,
     measurement_interval_seconds: u16,
     mqtt_hostname: String,
     mqtt_password: String,
     mqtt_port: u16,
     mqtt_topic: String,
     mqtt_username: String,
+    ntp_hostname: Option<String>,
+    ota_channel: Option<String>,
+    ota_health_timeout_seconds: Option<u16>,
     ota_hostname: Option<String>,
     ota_port: Option<u16>,
+    ota_public_key: Option<String>,
+    ota_registry_password: Option<String>,
+    ota_registry_repository: Option<String>,
+    ota_registry_username: Option<String>,
+    provisioning_psk: Option<String>,
     tls_ca: Option<String>,
     tls_cert: Option<String>,
     tls_key: Option<String>,
@@ -29,22 +41,58 @@ fn main() -> Result<(), Box<dyn Error>> {
     let toml_str = fs::read_to_string("cfg.toml")?;
     let raw: RawConfig = toml::from_str(&toml_str)?;
 
+    let diagnostics_interval_seconds = raw.diagnostics_interval_seconds.unwrap_or(300);
+
+    // A new firmware has 5 minutes to prove it works
+    let ota_health_timeout_seconds = raw.ota_health_timeout_seconds.unwrap_or(300);
+
+    // Devices only install releases unless asked for pre-releases
+    let ota_channel = match raw.ota_channel.as_deref().unwrap_or("stable") {
+        "dev" => "Dev",
+        "beta" => "Beta",
+        "stable" => "Stable",
+        other => return Err(format!("unknown ota_channel {other:?}").into()),
+    };
+
+    // The portal hands out the broker settings, it's never an open network
+    if let Some(psk) = &raw.provisioning_psk {
+        if !(8..=63).contains(&psk.len()) {
+            return Err("provisioning_psk must be 8 to 63 characters long".into());
+        }
+    }
+
+    let ntp_hostname = raw
+        .ntp_hostname
+        .unwrap_or_else(|| "pool.ntp.org".to_string());
+
     // Generate Rust code
     let out_dir = env::var("OUT_DIR")?;
     let dest_path = Path::new(&out_dir).join("config.rs");
     let code = format!(
         r"
-        pub const CONFIG: Config = Config {{
+        pub const DEFAULT_CONFIG: Config = Config {{
             device_id: {id:?},
+            diagnostics_interval_seconds: {dint},
             location: {loc:?},
             measurement_interval_seconds: {intv},
+            mqtt_availability_topic: {mat:?},
+            mqtt_command_topic: {mct:?},
+            mqtt_diagnostics_topic: {mdt:?},
             mqtt_hostname: {mh:?},
             mqtt_password: {mpw:?},
             mqtt_port: {mp},
             mqtt_topic: {mt:?},
             mqtt_username: {mu:?},
+            ntp_hostname: {ntp:?},
+            ota_channel: Channel::{och},
+            ota_health_timeout_seconds: {oht},
             ota_hostname: {oh:?},
             ota_port: {op:?},
+            ota_public_key: {opk:?},
+            ota_registry_password: {orp:?},
+            ota_registry_repository: {orr:?},
+            ota_registry_username: {oru:?},
+            provisioning_psk: {ppsk:?},
             tls_ca: {ca:?},
             tls_cert: {cert:?},
             tls_key: {key:?},
@@ -54,17 +102,29 @@ fn main() -> Result<(), Box<dyn Error>> {
     ",
         ca = raw.tls_ca,
         cert = raw.tls_cert,
+        dint = diagnostics_interval_seconds,
         id = raw.device_id,
         intv = raw.measurement_interval_seconds,
         key = raw.tls_key,
         loc = raw.location,
+        mat = raw.mqtt_availability_topic,
+        mct = raw.mqtt_command_topic,
+        mdt = raw.mqtt_diagnostics_topic,
         mh = raw.mqtt_hostname,
         mp = raw.mqtt_port,
         mpw = raw.mqtt_password,
         mt = raw.mqtt_topic,
         mu = raw.mqtt_username,
+        ntp = ntp_hostname,
+        och = ota_channel,
         oh = raw.ota_hostname,
+        oht = ota_health_timeout_seconds,
         op = raw.ota_port,
+        opk = raw.ota_public_key,
+        orp = raw.ota_registry_password,
+        orr = raw.ota_registry_repository,
+        oru = raw.ota_registry_username,
+        ppsk = raw.provisioning_psk,
         psk = raw.wifi_psk,
         ssid = raw.wifi_ssid,
     );
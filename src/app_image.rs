@@ -0,0 +1,50 @@
+//! ESP-IDF application image header.
+//!
+//! The image starts with its header and the header of its first segment,
+//! followed by the application descriptor (`esp_app_desc_t`) written by
+//! `esp_app_desc!()`, which holds the firmware version:
+//!
+//! ```text
+//! 0   image header (24 bytes), starts with 0xE9
+//! 24  first segment header (8 bytes)
+//! 32  magic_word      u32  0xABCD5432
+//! 36  secure_version  u32
+//! 40  reserved        u32 * 2
+//! 48  version         char[32], NUL padded
+//! ```
+//!
+//! The descriptor is covered by the image signature, unlike the manifest.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+/// First byte of an application image
+const IMAGE_MAGIC: u8 = 0xE9;
+
+/// Offset of the application descriptor
+const DESC_OFFSET: usize = 24 + 8;
+
+/// Marks the application descriptor
+const DESC_MAGIC: u32 = 0xABCD_5432;
+
+/// Offset of the version in the application descriptor
+const VERSION_OFFSET: usize = DESC_OFFSET + 16;
+
+/// Longest version the descriptor holds
+const VERSION_LEN: usize = 32;
+
+/// Bytes at the start of the image needed to read its version
+pub const HEADER_LEN: usize = VERSION_OFFSET + VERSION_LEN;
+
+/// Firmware version of the image starting with `header`, `None` when it has
+/// no application descriptor or the version isn't valid UTF-8.
+pub fn version(header: &[u8]) -> Option<&str> {
+    let header = header.get(..HEADER_LEN)?;
+    let magic = u32::from_le_bytes(header[DESC_OFFSET..DESC_OFFSET + 4].try_into().ok()?);
+    if header[0] != IMAGE_MAGIC || magic != DESC_MAGIC {
+        return None;
+    }
+    let version = &header[VERSION_OFFSET..];
+    let len = version.iter().position(|&b| b == 0).unwrap_or(VERSION_LEN);
+    core::str::from_utf8(&version[..len]).ok()
+}
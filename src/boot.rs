@@ -0,0 +1,156 @@
+//! Boot counter, reset cause and boot loop detection.
+//!
+//! The boot record is kept in RTC memory, which survives resets and deep sleep
+//! but not a power loss, see `boot_loop`.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{peripherals::TIMG0, ram, rtc_cntl::SocResetReason, timer::timg::Wdt};
+
+use crate::boot_loop::{self, BootRecord, Outcome};
+use crate::constants::*;
+use crate::diagnostics_format::ResetReason;
+
+/// Record of the running boot, read back by the next one
+#[ram(unstable(rtc_fast, persistent))]
+static mut RTC_BOOT: [u32; boot_loop::WORDS] = [0; boot_loop::WORDS];
+
+static RECORD: Mutex<CriticalSectionRawMutex, Cell<BootRecord>> =
+    Mutex::new(Cell::new(BootRecord {
+        boot_count: 0,
+        failed_boots: 0,
+        safe_mode: false,
+        outcome: Outcome::Running,
+    }));
+
+/// Count this boot and decide whether to start in safe mode. Must run once
+/// at startup, before anything else uses this module.
+pub fn init() {
+    let reset_reason = reset_reason();
+    // SAFETY: only accessed from `save`, RECORD serialises the writes
+    let words = unsafe { core::ptr::addr_of!(RTC_BOOT).read_volatile() };
+    let previous = match reset_reason {
+        ResetReason::PowerOn => None,
+        _ => BootRecord::from_words(&words),
+    };
+    let record = BootRecord::next(previous, BOOT_LOOP_THRESHOLD);
+    RECORD.lock(|cell| cell.set(record));
+    save(record);
+
+    log::info!("Boot #{} ({})", record.boot_count, reset_reason.as_str());
+    if record.safe_mode {
+        log::warn!(
+            "{} failed boots in a row, starting in safe mode for {}s",
+            record.failed_boots,
+            retry_delay_secs()
+        );
+    } else if record.failed_boots > 0 {
+        log::warn!("{} failed boots in a row", record.failed_boots);
+    }
+}
+
+/// Boots since power on, including wake-ups from deep sleep
+pub fn count() -> u32 {
+    record().boot_count
+}
+
+/// Failed boots in a row, up to this one
+pub fn failed_boots() -> u32 {
+    record().failed_boots
+}
+
+/// Whether the device started in safe mode after a boot loop: failing sensors
+/// are skipped and failures which would reboot the device are tolerated, so
+/// the network and firmware updates stay available.
+pub fn safe_mode() -> bool {
+    record().safe_mode
+}
+
+/// Time safe mode runs before the normal mode is tried again, and failures
+/// wait before rebooting in safe mode
+pub fn retry_delay_secs() -> u64 {
+    record().retry_delay_secs(
+        BOOT_LOOP_THRESHOLD,
+        SAFE_MODE_RETRY_MIN_SECS,
+        SAFE_MODE_RETRY_MAX_SECS,
+    )
+}
+
+/// Whether this boot ran long enough for a later reset not to be part of a
+/// boot loop
+pub fn is_stable() -> bool {
+    record().outcome == Outcome::Stable
+}
+
+/// Mark this boot as stable: the failed boots are cleared at the next boot.
+/// Also called before deep sleep, a completed cycle being a successful boot.
+pub fn mark_stable() {
+    set_outcome(Outcome::Stable);
+}
+
+/// Mark the coming reboot as planned: it neither counts as a failure nor
+/// clears the failed boots, and the next boot runs in normal mode.
+pub fn mark_planned() {
+    set_outcome(Outcome::Planned);
+}
+
+/// Mark the coming reboot as a switch to another firmware, updated or rolled
+/// back: the next boot starts with no failed boots.
+pub fn mark_switched() {
+    set_outcome(Outcome::Switched);
+}
+
+/// Reboot after a failure, which counts as a failed boot. In safe mode the
+/// reboot is delayed by `retry_delay_secs`, feeding the watchdog meanwhile.
+pub async fn fail(wdt: &mut Wdt<TIMG0<'static>>) -> ! {
+    if safe_mode() {
+        let delay = retry_delay_secs();
+        log::error!("Failure in safe mode, rebooting in {}s", delay);
+        let reboot_at = Instant::now() + Duration::from_secs(delay);
+        while Instant::now() < reboot_at {
+            wdt.feed();
+            Timer::after(Duration::from_secs(1)).await;
+        }
+    }
+    esp_hal::system::software_reset();
+}
+
+pub fn reset_reason() -> ResetReason {
+    match esp_hal::system::reset_reason() {
+        Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+        Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleep,
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::CpuMwdt0
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt,
+        ) => ResetReason::Watchdog,
+        Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+        _ => ResetReason::Unknown,
+    }
+}
+
+fn record() -> BootRecord {
+    RECORD.lock(|cell| cell.get())
+}
+
+fn set_outcome(outcome: Outcome) {
+    RECORD.lock(|cell| {
+        let record = BootRecord {
+            outcome,
+            ..cell.get()
+        };
+        cell.set(record);
+        save(record);
+    });
+}
+
+fn save(record: BootRecord) {
+    // SAFETY: see `init`
+    unsafe { core::ptr::addr_of_mut!(RTC_BOOT).write_volatile(record.to_words()) };
+}
@@ -0,0 +1,123 @@
+//! Boot loop detection.
+//!
+//! Every boot leaves a record in RTC memory, which survives resets but not a
+//! power loss. A boot which resets before becoming stable, without a planned
+//! reboot, is a failed boot. After `threshold` failed boots in a row the
+//! device starts in safe mode, and a planned reboot gives the normal mode
+//! another try:
+//!
+//! ```text
+//! previous boot ended   failed boots   this boot
+//! power on              0              normal
+//! stable                0              normal
+//! planned reboot        unchanged      normal
+//! firmware switch       0              normal
+//! failure               +1             safe mode from `threshold`
+//! ```
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use crate::rtc_record;
+
+/// Marks an initialised record ("BOOT")
+const MAGIC: u32 = 0x424F_4F54;
+
+/// RTC memory taken by the record, see `rtc_record`
+pub const WORDS: usize = rtc_record::len(4);
+
+/// How a boot ended, as far as it got to record it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Still running, or reset by a failure or a crash
+    Running,
+    /// Ran long enough, or completed a deep sleep cycle
+    Stable,
+    /// Rebooted on purpose: a command or a safe mode retry
+    Planned,
+    /// Rebooted into another firmware, updated or rolled back, which starts
+    /// with a clean record
+    Switched,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootRecord {
+    /// Boots since power on, including wake-ups from deep sleep
+    pub boot_count: u32,
+    /// Failed boots in a row, up to this one
+    pub failed_boots: u32,
+    pub safe_mode: bool,
+    pub outcome: Outcome,
+}
+
+impl BootRecord {
+    /// Record of the boot starting now, from the record left by the previous
+    /// boot. `previous` is `None` after a power loss.
+    pub fn next(previous: Option<BootRecord>, threshold: u32) -> Self {
+        let Some(previous) = previous else {
+            return Self {
+                boot_count: 1,
+                failed_boots: 0,
+                safe_mode: false,
+                outcome: Outcome::Running,
+            };
+        };
+
+        let (failed_boots, safe_mode) = match previous.outcome {
+            Outcome::Stable | Outcome::Switched => (0, false),
+            Outcome::Planned => (previous.failed_boots, false),
+            Outcome::Running => {
+                let failed_boots = previous.failed_boots.saturating_add(1);
+                (failed_boots, failed_boots >= threshold)
+            }
+        };
+        Self {
+            boot_count: previous.boot_count.wrapping_add(1),
+            failed_boots,
+            safe_mode,
+            outcome: Outcome::Running,
+        }
+    }
+
+    /// Time before rebooting, doubled for each failed boot past the safe mode
+    /// threshold: how long safe mode runs before the normal mode is tried
+    /// again, and how long a failure in safe mode waits before rebooting.
+    pub fn retry_delay_secs(&self, threshold: u32, min_secs: u64, max_secs: u64) -> u64 {
+        let doublings = self.failed_boots.saturating_sub(threshold).min(32);
+        min_secs.saturating_mul(1 << doublings).min(max_secs)
+    }
+
+    pub fn to_words(self) -> [u32; WORDS] {
+        let outcome = match self.outcome {
+            Outcome::Running => 0,
+            Outcome::Stable => 1,
+            Outcome::Planned => 2,
+            Outcome::Switched => 3,
+        };
+        let fields = [
+            self.boot_count,
+            self.failed_boots,
+            self.safe_mode as u32,
+            outcome,
+        ];
+        rtc_record::encode(MAGIC, &fields)
+    }
+
+    /// Decode a record, `None` when the words hold no valid record (power on).
+    pub fn from_words(words: &[u32; WORDS]) -> Option<Self> {
+        let fields = rtc_record::decode(MAGIC, words)?;
+        let outcome = match fields[3] {
+            0 => Outcome::Running,
+            1 => Outcome::Stable,
+            2 => Outcome::Planned,
+            3 => Outcome::Switched,
+            _ => return None,
+        };
+        Some(Self {
+            boot_count: fields[0],
+            failed_boots: fields[1],
+            safe_mode: fields[2] != 0,
+            outcome,
+        })
+    }
+}
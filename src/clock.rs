@@ -0,0 +1,146 @@
+//! UTC clock synchronised over SNTP.
+//!
+//! The time is only known once a server answered: until then `now` returns
+//! `None` and readings are published without timestamp. After a sync, the
+//! time is tracked from the monotonic `Instant` of the sync point.
+
+use core::cell::Cell;
+
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+
+use crate::config::CONFIG;
+use crate::constants::*;
+use crate::sntp;
+
+/// Unix time in microseconds at a given instant
+#[derive(Clone, Copy)]
+struct SyncPoint {
+    instant: Instant,
+    unix_micros: u64,
+}
+
+static SYNC_POINT: Mutex<CriticalSectionRawMutex, Cell<Option<SyncPoint>>> =
+    Mutex::new(Cell::new(None));
+
+#[derive(Debug)]
+pub enum Error {
+    Dns,
+    Socket,
+    Timeout,
+    Sntp(sntp::Error),
+}
+
+/// Current time in microseconds since the Unix epoch, `None` until the
+/// first sync.
+pub fn now() -> Option<u64> {
+    SYNC_POINT
+        .lock(|point| point.get())
+        .map(|point| point.unix_micros + Instant::now().duration_since(point.instant).as_micros())
+}
+
+/// Wait for the first sync, returns at once when already synced. Polls the
+/// clock, so any number of tasks can wait.
+pub async fn synced() {
+    while now().is_none() {
+        Timer::after(Duration::from_millis(SNTP_SYNCED_POLL_MS)).await;
+    }
+}
+
+/// Keep the clock synchronised with `CONFIG.ntp_hostname`.
+#[embassy_executor::task]
+pub async fn sntp_task(stack: Stack<'static>, rng: Rng) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; sntp::PACKET_LEN];
+    let mut tx_buffer = [0u8; sntp::PACKET_LEN];
+
+    loop {
+        stack.wait_config_up().await;
+
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let delay = match sync(stack, &mut socket, rng).await {
+            Ok(()) => SNTP_SYNC_INTERVAL_SECS,
+            Err(e) => {
+                log::warn!("SNTP sync with {} failed: {:?}", CONFIG.ntp_hostname, e);
+                SNTP_RETRY_INTERVAL_SECS
+            }
+        };
+        drop(socket);
+
+        Timer::after(Duration::from_secs(delay)).await;
+    }
+}
+
+async fn sync(stack: Stack<'_>, socket: &mut UdpSocket<'_>, rng: Rng) -> Result<(), Error> {
+    let addr = stack
+        .dns_query(CONFIG.ntp_hostname, DnsQueryType::A)
+        .await
+        .map_err(|_| Error::Dns)?
+        .first()
+        .copied()
+        .ok_or(Error::Dns)?;
+    // Any free local port
+    socket.bind(0).map_err(|_| Error::Socket)?;
+
+    let transmit = (rng.random() as u64) << 32 | rng.random() as u64;
+    let mut packet = [0u8; sntp::PACKET_LEN];
+    sntp::write_request(transmit, &mut packet);
+
+    let sent = Instant::now();
+    socket
+        .send_to(&packet, (addr, sntp::PORT))
+        .await
+        .map_err(|_| Error::Socket)?;
+
+    // Replies to earlier requests or from other hosts are skipped
+    let response = with_timeout(Duration::from_secs(SNTP_TIMEOUT_SECS), async {
+        loop {
+            let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+                continue;
+            };
+            if meta.endpoint.addr != addr {
+                continue;
+            }
+            match sntp::parse_response(&packet[..len], transmit) {
+                Err(sntp::Error::OriginMismatch) => continue,
+                result => return result,
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::Timeout)?
+    .map_err(Error::Sntp)?;
+
+    let arrival = Instant::now();
+    let unix_micros = response.time_at_arrival(arrival.duration_since(sent).as_micros());
+    let first = now().is_none();
+    SYNC_POINT.lock(|point| {
+        point.set(Some(SyncPoint {
+            instant: arrival,
+            unix_micros,
+        }))
+    });
+
+    if first {
+        log::info!(
+            "Clock synchronised with {} (stratum {}), Unix time {}s",
+            CONFIG.ntp_hostname,
+            response.stratum,
+            unix_micros / 1_000_000
+        );
+    }
+    Ok(())
+}
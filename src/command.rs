@@ -0,0 +1,204 @@
+//! Commands received on the device command topic.
+//!
+//! A command is a JSON object naming the command in `cmd`, with its argument
+//! in `value` when it takes one. The result is published on the `result`
+//! subtopic of the command topic:
+//!
+//! ```text
+//! {"id":"42","cmd":"set_interval","value":300}
+//! {"id":"42","cmd":"set_interval","status":"ok"}
+//! {"id":"43","status":"error","error":"unknown_command"}
+//! ```
+//!
+//! `id` is an optional correlation ID, echoed in the result. The MQTT v5
+//! response topic and correlation data properties aren't used: rust-mqtt 0.4
+//! parses them in received PUBLISH packets but leaves them out of
+//! `event::Publish`, and `PublicationOptions` can't set them (only the Will
+//! has them). rust-mqtt 0.6 exposes both, until then the correlation ID is
+//! carried in the payload and results go to the fixed `result` subtopic.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use core::fmt::{self, Write};
+
+use crate::json::{parse_object, Value};
+
+/// Maximum length of a correlation ID
+pub const ID_MAX_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "off" => LogLevel::Off,
+            "error" => LogLevel::Error,
+            "warn" => LogLevel::Warn,
+            "info" => LogLevel::Info,
+            "debug" => LogLevel::Debug,
+            "trace" => LogLevel::Trace,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Planned reboot
+    Reboot,
+    /// Check for a firmware update now
+    OtaCheck,
+    /// Take a measurement now
+    MeasureNow,
+    /// Measurement interval in seconds
+    SetInterval(u16),
+    SetLogLevel(LogLevel),
+    /// Blink the LED to find the device
+    Identify,
+}
+
+impl Command {
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::Reboot => "reboot",
+            Command::OtaCheck => "ota_check",
+            Command::MeasureNow => "measure_now",
+            Command::SetInterval(_) => "set_interval",
+            Command::SetLogLevel(_) => "set_log_level",
+            Command::Identify => "identify",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not a JSON object of strings, numbers and literals
+    Syntax,
+    /// `id` isn't a string or is longer than `ID_MAX_LEN`
+    InvalidId,
+    MissingCommand,
+    UnknownCommand,
+    /// `value` is missing or out of range
+    InvalidValue,
+    /// The firmware was built without the feature the command needs
+    Unsupported,
+    /// Too many commands are waiting to run
+    Busy,
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::Syntax => "syntax",
+            Error::InvalidId => "invalid_id",
+            Error::MissingCommand => "missing_command",
+            Error::UnknownCommand => "unknown_command",
+            Error::InvalidValue => "invalid_value",
+            Error::Unsupported => "unsupported",
+            Error::Busy => "busy",
+        }
+    }
+}
+
+/// A parsed command payload
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// Correlation ID, still JSON escaped so it is echoed as received
+    pub id: Option<&'a str>,
+    pub command: Result<Command, Error>,
+}
+
+/// Parse a command payload. Unknown members are ignored.
+pub fn parse(payload: &[u8]) -> Request<'_> {
+    let mut id = None;
+    let mut cmd = None;
+    let mut value = None;
+
+    // Commands are flat objects
+    let mut nested = false;
+    let object = core::str::from_utf8(payload).ok().and_then(|s| {
+        parse_object(s, |key, v| {
+            nested |= matches!(v, Value::Object(_) | Value::Array(_));
+            match key {
+                "id" => id = Some(v),
+                "cmd" => cmd = Some(v),
+                "value" => value = Some(v),
+                _ => {}
+            }
+        })
+    });
+    if object.is_none() || nested {
+        return Request {
+            id: None,
+            command: Err(Error::Syntax),
+        };
+    }
+
+    let id = match id {
+        None => None,
+        Some(Value::Str(id)) if id.len() <= ID_MAX_LEN => Some(id),
+        Some(_) => {
+            return Request {
+                id: None,
+                command: Err(Error::InvalidId),
+            }
+        }
+    };
+
+    let command = match cmd {
+        None => Err(Error::MissingCommand),
+        Some(Value::Str("reboot")) => Ok(Command::Reboot),
+        Some(Value::Str("ota_check")) => Ok(Command::OtaCheck),
+        Some(Value::Str("measure_now")) => Ok(Command::MeasureNow),
+        Some(Value::Str("set_interval")) => match value {
+            Some(Value::Number(n)) => n
+                .parse::<u16>()
+                .ok()
+                .filter(|secs| *secs > 0)
+                .map(Command::SetInterval)
+                .ok_or(Error::InvalidValue),
+            _ => Err(Error::InvalidValue),
+        },
+        Some(Value::Str("set_log_level")) => match value {
+            Some(Value::Str(level)) => LogLevel::from_name(level)
+                .map(Command::SetLogLevel)
+                .ok_or(Error::InvalidValue),
+            _ => Err(Error::InvalidValue),
+        },
+        Some(Value::Str("identify")) => Ok(Command::Identify),
+        Some(_) => Err(Error::UnknownCommand),
+    };
+
+    Request { id, command }
+}
+
+/// Write the result of a command as a JSON object. `id` is written as is, it
+/// must be JSON escaped already as returned by `parse`.
+pub fn write_result<W: Write>(
+    w: &mut W,
+    id: Option<&str>,
+    command: Option<Command>,
+    result: Result<(), Error>,
+) -> fmt::Result {
+    w.write_char('{')?;
+    if let Some(id) = id {
+        write!(w, "\"id\":\"{}\",", id)?;
+    }
+    if let Some(command) = command {
+        write!(w, "\"cmd\":\"{}\",", command.name())?;
+    }
+    match result {
+        Ok(()) => w.write_str("\"status\":\"ok\"")?,
+        Err(e) => write!(w, "\"status\":\"error\",\"error\":\"{}\"", e.as_str())?,
+    }
+    w.write_char('}')
+}
@@ -1,13 +1,43 @@
+use core::ops::Deref;
+
+use embassy_sync::once_lock::OnceLock;
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, FlashRegion, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_storage::FlashStorage;
+use heapless::String;
+
+use crate::config_store::{self, Key, Record, Value};
+use crate::constants::MQTT_TOPIC_MAX_LEN;
+use crate::semver::Channel;
+use crate::topic;
+
+#[derive(Clone)]
 pub struct Config {
     // Device ID (used as DHCP hostname and passed to the OTA for firmware identification)
     pub device_id: &'static str,
 
+    // Interval in seconds between diagnostics publications, 0 to disable them
+    pub diagnostics_interval_seconds: u16,
+
     // Location identifier (used in MQTT payloads)
     pub location: &'static str,
 
     // Measurement interval in seconds
     pub measurement_interval_seconds: u16,
 
+    // MQTT topic for the retained online/offline availability status. Derived
+    // from the device ID when not set, see `availability_topic`
+    pub mqtt_availability_topic: Option<&'static str>,
+
+    // MQTT topic the device receives commands on, results go to its `result`
+    // subtopic. Derived from the device ID when not set, see `command_topic`
+    pub mqtt_command_topic: Option<&'static str>,
+
+    // MQTT topic for the device diagnostics. Derived from the device ID when
+    // not set, see `diagnostics_topic`
+    pub mqtt_diagnostics_topic: Option<&'static str>,
+
     // MQTT broker hostname or IP address
     pub mqtt_hostname: &'static str,
 
@@ -23,12 +53,39 @@ pub struct Config {
     // MQTT username for authentication
     pub mqtt_username: &'static str,
 
+    // NTP server used to timestamp the readings
+    pub ntp_hostname: &'static str,
+
+    // OTA release channel, pre-releases are only installed on the dev and beta ones
+    pub ota_channel: Channel,
+
+    // Seconds a new firmware has to connect to the broker before it is rolled
+    // back, 0 to accept it at boot
+    pub ota_health_timeout_seconds: u16,
+
     // OTA server hostname (optional)
     pub ota_hostname: Option<&'static str>,
 
     // OTA server port
     pub ota_port: Option<u16>,
 
+    // OTA firmware signing public key, P-256 SPKI in PEM format (optional)
+    pub ota_public_key: Option<&'static str>,
+
+    // OCI registry repository holding the firmware of each device, pulled from
+    // ota_hostname instead of an OTA server (optional)
+    pub ota_registry_repository: Option<&'static str>,
+
+    // OCI registry account, anonymous pulls without it (optional)
+    pub ota_registry_username: Option<&'static str>,
+
+    // OCI registry account password or robot secret (optional)
+    pub ota_registry_password: Option<&'static str>,
+
+    // WPA2 passphrase of the provisioning access point, the portal is only
+    // started when set (optional)
+    pub provisioning_psk: Option<&'static str>,
+
     // TLS CA certificate (optional)
     pub tls_ca: Option<&'static str>,
 
@@ -45,5 +102,183 @@ pub struct Config {
     pub wifi_ssid: &'static str,
 }
 
-// config values are generated at compile time
+// default config values are generated at compile time
 include!(concat!(env!("OUT_DIR"), "/config.rs"));
+
+/// Active configuration: the compiled defaults with the overrides stored in
+/// the `nvs` partition applied once `load` ran.
+pub static CONFIG: ActiveConfig = ActiveConfig(OnceLock::new());
+
+/// Overrides loaded from flash at boot
+static OVERRIDES: OnceLock<Record<'static>> = OnceLock::new();
+
+pub struct ActiveConfig(OnceLock<Config>);
+
+impl Deref for ActiveConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        self.0.try_get().unwrap_or(&DEFAULT_CONFIG)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    PartitionTable,
+    NoPartition,
+    Store(config_store::Error),
+}
+
+/// MQTT topic derived from the configuration
+pub type Topic = String<MQTT_TOPIC_MAX_LEN>;
+
+impl Config {
+    /// Topic of the retained availability status,
+    /// `<mqtt_topic>/<device_id>/status` unless `mqtt_availability_topic` is
+    /// set. `None` when too long.
+    pub fn availability_topic(&self) -> Option<Topic> {
+        topic::device_topic(
+            self.mqtt_availability_topic,
+            self.mqtt_topic,
+            self.device_id,
+            "status",
+        )
+    }
+
+    /// Topic the device receives commands on, `<mqtt_topic>/<device_id>/cmd`
+    /// unless `mqtt_command_topic` is set. `None` when too long.
+    pub fn command_topic(&self) -> Option<Topic> {
+        topic::device_topic(
+            self.mqtt_command_topic,
+            self.mqtt_topic,
+            self.device_id,
+            "cmd",
+        )
+    }
+
+    /// Topic of the device health, `<mqtt_topic>/<device_id>/diagnostics`
+    /// unless `mqtt_diagnostics_topic` is set. `None` when too long.
+    pub fn diagnostics_topic(&self) -> Option<Topic> {
+        topic::device_topic(
+            self.mqtt_diagnostics_topic,
+            self.mqtt_topic,
+            self.device_id,
+            "diagnostics",
+        )
+    }
+
+    fn with_overrides(&self, record: &Record<'static>) -> Config {
+        let mut config = self.clone();
+        for (key, value) in record.iter() {
+            match (*key, *value) {
+                (Key::DeviceId, Value::Str(s)) => config.device_id = s,
+                (Key::Location, Value::Str(s)) => config.location = s,
+                (Key::DiagnosticsIntervalSeconds, Value::U16(v)) => {
+                    config.diagnostics_interval_seconds = v
+                }
+                // A zero interval would make the main loop spin
+                (Key::MeasurementIntervalSeconds, Value::U16(v)) if v > 0 => {
+                    config.measurement_interval_seconds = v
+                }
+                (Key::MqttHostname, Value::Str(s)) => config.mqtt_hostname = s,
+                (Key::MqttPassword, Value::Str(s)) => config.mqtt_password = s,
+                (Key::MqttPort, Value::U16(v)) => config.mqtt_port = v,
+                (Key::MqttTopic, Value::Str(s)) => config.mqtt_topic = s,
+                (Key::MqttUsername, Value::Str(s)) => config.mqtt_username = s,
+                (Key::MqttAvailabilityTopic, Value::Str(s)) => {
+                    config.mqtt_availability_topic = Some(s)
+                }
+                (Key::MqttCommandTopic, Value::Str(s)) => config.mqtt_command_topic = Some(s),
+                (Key::MqttDiagnosticsTopic, Value::Str(s)) => {
+                    config.mqtt_diagnostics_topic = Some(s)
+                }
+                (Key::NtpHostname, Value::Str(s)) => config.ntp_hostname = s,
+                (Key::OtaHostname, Value::Str(s)) => config.ota_hostname = Some(s),
+                (Key::OtaPort, Value::U16(v)) => config.ota_port = Some(v),
+                (Key::OtaChannel, Value::Str(s)) => match Channel::from_name(s) {
+                    Some(channel) => config.ota_channel = channel,
+                    None => log::warn!("Ignoring unknown OTA channel {:?}", s),
+                },
+                (Key::OtaRegistryRepository, Value::Str(s)) => {
+                    config.ota_registry_repository = Some(s)
+                }
+                (Key::OtaRegistryUsername, Value::Str(s)) => config.ota_registry_username = Some(s),
+                (Key::OtaRegistryPassword, Value::Str(s)) => config.ota_registry_password = Some(s),
+                (Key::OtaHealthTimeoutSeconds, Value::U16(v)) => {
+                    config.ota_health_timeout_seconds = v
+                }
+                (Key::TlsCa, Value::Str(s)) => config.tls_ca = Some(s),
+                (Key::TlsCert, Value::Str(s)) => config.tls_cert = Some(s),
+                (Key::TlsPrivateKey, Value::Str(s)) => config.tls_key = Some(s),
+                (Key::WifiPsk, Value::Str(s)) => config.wifi_psk = s,
+                (Key::WifiSsid, Value::Str(s)) => config.wifi_ssid = s,
+                (key, value) => {
+                    log::warn!("Ignoring invalid config override {:?}={:?}", key, value)
+                }
+            }
+        }
+        config
+    }
+}
+
+/// Apply the overrides stored in flash on top of the compiled defaults. Must
+/// run once at boot, before anything reads `CONFIG`. On error, including an
+/// empty or corrupt store, the compiled defaults stay in use.
+pub fn load(flash: &mut FlashStorage<'_>) -> Result<(), Error> {
+    let record = with_store(flash, |store| {
+        let Some(slot) = config_store::find(store)? else {
+            return Ok(Record::new());
+        };
+        // Values are borrowed for the lifetime of the program
+        let buf = alloc::vec![0u8; slot.payload_len()].leak();
+        config_store::read(store, &slot, buf)?;
+        Record::decode(buf)
+    })?;
+
+    if record.is_empty() {
+        log::info!("No stored configuration, using compiled defaults");
+    } else {
+        log::info!(
+            "Applying {} stored configuration overrides",
+            record.iter().count()
+        );
+    }
+
+    let _ = CONFIG.0.init(DEFAULT_CONFIG.with_overrides(&record));
+    let _ = OVERRIDES.init(record);
+    Ok(())
+}
+
+/// Overrides currently applied, empty when running on compiled defaults.
+pub fn overrides() -> Record<'static> {
+    OVERRIDES.try_get().cloned().unwrap_or_default()
+}
+
+/// Persist `record` as the new set of overrides. Takes effect on next boot.
+pub fn save(flash: &mut FlashStorage<'_>, record: &Record<'_>) -> Result<(), Error> {
+    let mut scratch = alloc::vec![0u8; config_store::SLOT_SIZE];
+    with_store(flash, |store| {
+        config_store::save(store, record, &mut scratch)
+    })
+}
+
+/// Run `f` on the part of the `nvs` partition holding the config store.
+fn with_store<'d, R>(
+    flash: &mut FlashStorage<'d>,
+    f: impl FnOnce(&mut FlashRegion<'_, FlashStorage<'d>>) -> Result<R, config_store::Error>,
+) -> Result<R, Error> {
+    let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+    let partition_table =
+        partitions::read_partition_table(flash, &mut table).map_err(|_| Error::PartitionTable)?;
+    let nvs = partition_table
+        .find_partition(PartitionType::Data(DataPartitionSubType::Nvs))
+        .map_err(|_| Error::PartitionTable)?
+        .ok_or(Error::NoPartition)?;
+    if (nvs.len() as usize) < config_store::STORE_SIZE {
+        log::error!("nvs partition too small for the config store");
+        return Err(Error::NoPartition);
+    }
+
+    let mut region = nvs.as_embedded_storage(flash);
+    f(&mut region).map_err(Error::Store)
+}
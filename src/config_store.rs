@@ -0,0 +1,374 @@
+//! Persistent runtime configuration record.
+//!
+//! The record overrides the values compiled in from `cfg.toml`, so a single
+//! firmware image can be flashed on every device. It is stored in the `nvs`
+//! partition in two slots written alternately, so a power loss while saving
+//! leaves the previous record intact:
+//!
+//! ```text
+//! slot header (20 bytes, little endian)
+//!   magic     u32  "CNFG"
+//!   version   u16  layout version, currently 1
+//!   reserved  u16
+//!   sequence  u32  incremented on each save, the highest valid slot wins
+//!   length    u32  payload length
+//!   crc32     u32  CRC32 of the first 16 header bytes and the payload
+//! payload: a list of entries
+//!   key       u8
+//!   length    u16
+//!   value     strings as UTF-8, numbers as u16
+//! ```
+//!
+//! Unknown keys are skipped so that older firmware can read records written by
+//! newer versions. Only depends on `core` and `embedded-storage` so it can be
+//! unit tested on the host, see `tools/firmware-core`.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::Vec;
+
+use crate::crc32::Crc32;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"CNFG");
+const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 20;
+
+/// Size of each of the two slots, a multiple of the flash erase size
+pub const SLOT_SIZE: usize = 0x2000;
+/// Largest payload which fits in a slot
+pub const MAX_PAYLOAD_SIZE: usize = SLOT_SIZE - HEADER_SIZE;
+/// Space needed in the partition to hold both slots
+pub const STORE_SIZE: usize = 2 * SLOT_SIZE;
+
+/// Maximum number of entries in a record (one per key, with room for new keys)
+const MAX_ENTRIES: usize = 32;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Flash,
+    /// The record doesn't fit in a slot
+    TooLarge,
+    /// A known key holds a value of the wrong type or invalid UTF-8
+    InvalidValue(Key),
+    /// The payload ends in the middle of an entry
+    Truncated,
+}
+
+/// Configuration keys. Values are persisted, never reuse or renumber them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Key {
+    DeviceId = 1,
+    Location = 2,
+    MeasurementIntervalSeconds = 3,
+    MqttHostname = 4,
+    MqttPassword = 5,
+    MqttPort = 6,
+    MqttTopic = 7,
+    MqttUsername = 8,
+    MqttAvailabilityTopic = 9,
+    OtaHostname = 10,
+    OtaPort = 11,
+    TlsCa = 12,
+    TlsCert = 13,
+    TlsPrivateKey = 14,
+    WifiPsk = 15,
+    WifiSsid = 16,
+    NtpHostname = 17,
+    MqttCommandTopic = 18,
+    DiagnosticsIntervalSeconds = 19,
+    MqttDiagnosticsTopic = 20,
+    OtaHealthTimeoutSeconds = 21,
+    OtaChannel = 22,
+    OtaRegistryRepository = 23,
+    OtaRegistryUsername = 24,
+    OtaRegistryPassword = 25,
+}
+
+impl Key {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Key::DeviceId,
+            2 => Key::Location,
+            3 => Key::MeasurementIntervalSeconds,
+            4 => Key::MqttHostname,
+            5 => Key::MqttPassword,
+            6 => Key::MqttPort,
+            7 => Key::MqttTopic,
+            8 => Key::MqttUsername,
+            9 => Key::MqttAvailabilityTopic,
+            10 => Key::OtaHostname,
+            11 => Key::OtaPort,
+            12 => Key::TlsCa,
+            13 => Key::TlsCert,
+            14 => Key::TlsPrivateKey,
+            15 => Key::WifiPsk,
+            16 => Key::WifiSsid,
+            17 => Key::NtpHostname,
+            18 => Key::MqttCommandTopic,
+            19 => Key::DiagnosticsIntervalSeconds,
+            20 => Key::MqttDiagnosticsTopic,
+            21 => Key::OtaHealthTimeoutSeconds,
+            22 => Key::OtaChannel,
+            23 => Key::OtaRegistryRepository,
+            24 => Key::OtaRegistryUsername,
+            25 => Key::OtaRegistryPassword,
+            _ => return None,
+        })
+    }
+
+    fn is_number(self) -> bool {
+        matches!(
+            self,
+            Key::MeasurementIntervalSeconds
+                | Key::MqttPort
+                | Key::OtaPort
+                | Key::DiagnosticsIntervalSeconds
+                | Key::OtaHealthTimeoutSeconds
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    Str(&'a str),
+    U16(u16),
+}
+
+/// A set of configuration overrides
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Record<'a> {
+    entries: Vec<(Key, Value<'a>), MAX_ENTRIES>,
+}
+
+impl<'a> Record<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set or replace the value of `key`.
+    pub fn set(&mut self, key: Key, value: Value<'a>) -> Result<(), Error> {
+        if key.is_number() != matches!(value, Value::U16(_)) {
+            return Err(Error::InvalidValue(key));
+        }
+        if let Some(entry) = self.entries.iter_mut().find(|(k, _)| *k == key) {
+            entry.1 = value;
+            return Ok(());
+        }
+        // One entry per key, so this can't overflow
+        self.entries.push((key, value)).map_err(|_| Error::TooLarge)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Key, Value<'a>)> {
+        self.entries.iter()
+    }
+
+    /// Parse a record payload.
+    pub fn decode(mut payload: &'a [u8]) -> Result<Self, Error> {
+        let mut record = Record::new();
+
+        while !payload.is_empty() {
+            if payload.len() < 3 {
+                return Err(Error::Truncated);
+            }
+            let raw_key = payload[0];
+            let len = u16::from_le_bytes([payload[1], payload[2]]) as usize;
+            let value = payload.get(3..3 + len).ok_or(Error::Truncated)?;
+            payload = &payload[3 + len..];
+
+            let Some(key) = Key::from_u8(raw_key) else {
+                continue;
+            };
+            let value = if key.is_number() {
+                let bytes: [u8; 2] = value.try_into().map_err(|_| Error::InvalidValue(key))?;
+                Value::U16(u16::from_le_bytes(bytes))
+            } else {
+                Value::Str(core::str::from_utf8(value).map_err(|_| Error::InvalidValue(key))?)
+            };
+            record.set(key, value)?;
+        }
+
+        Ok(record)
+    }
+
+    /// Serialize the record payload into `out`, returning its length.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        let mut len = 0;
+        for (key, value) in self.entries.iter() {
+            let number;
+            let bytes = match value {
+                Value::Str(s) => s.as_bytes(),
+                Value::U16(v) => {
+                    number = v.to_le_bytes();
+                    &number[..]
+                }
+            };
+            let value_len = u16::try_from(bytes.len()).map_err(|_| Error::TooLarge)?;
+            let entry = out
+                .get_mut(len..len + 3 + bytes.len())
+                .ok_or(Error::TooLarge)?;
+            entry[0] = *key as u8;
+            entry[1..3].copy_from_slice(&value_len.to_le_bytes());
+            entry[3..].copy_from_slice(bytes);
+            len += entry.len();
+        }
+        Ok(len)
+    }
+}
+
+/// Location of the current record in the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    offset: u32,
+    sequence: u32,
+    len: usize,
+}
+
+impl Slot {
+    /// Length of the payload, the size of the buffer to pass to `read`
+    pub fn payload_len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Find the most recent valid record. Returns `None` when the store is empty
+/// or both slots are corrupt.
+pub fn find<F: ReadNorFlash>(flash: &mut F) -> Result<Option<Slot>, Error> {
+    let mut latest: Option<Slot> = None;
+
+    for offset in [0, SLOT_SIZE as u32] {
+        let mut header = [0u8; HEADER_SIZE];
+        flash.read(offset, &mut header).map_err(|_| Error::Flash)?;
+
+        let Some(slot) = parse_header(offset, &header) else {
+            continue;
+        };
+        if !check_crc(flash, &slot, &header)? {
+            continue;
+        }
+        if latest.is_none_or(|latest| slot.sequence > latest.sequence) {
+            latest = Some(slot);
+        }
+    }
+
+    Ok(latest)
+}
+
+/// Read the payload of `slot` into `buf`, which must be `slot.payload_len()` long.
+pub fn read<F: ReadNorFlash>(flash: &mut F, slot: &Slot, buf: &mut [u8]) -> Result<(), Error> {
+    read_unaligned(flash, slot.offset + HEADER_SIZE as u32, buf)
+}
+
+/// Save `record` in the slot not holding the current record. `scratch` must
+/// be at least `SLOT_SIZE` long.
+pub fn save<F: NorFlash>(flash: &mut F, record: &Record, scratch: &mut [u8]) -> Result<(), Error> {
+    let scratch = scratch.get_mut(..SLOT_SIZE).ok_or(Error::TooLarge)?;
+    let len = record.encode(&mut scratch[HEADER_SIZE..])?;
+
+    let (offset, sequence) = match find(flash)? {
+        Some(current) if current.offset == 0 => {
+            (SLOT_SIZE as u32, current.sequence.wrapping_add(1))
+        }
+        Some(current) => (0, current.sequence.wrapping_add(1)),
+        None => (0, 1),
+    };
+
+    scratch[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    scratch[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    scratch[6..8].copy_from_slice(&0u16.to_le_bytes());
+    scratch[8..12].copy_from_slice(&sequence.to_le_bytes());
+    scratch[12..16].copy_from_slice(&(len as u32).to_le_bytes());
+
+    let mut crc = Crc32::new();
+    crc.update(&scratch[..16]);
+    crc.update(&scratch[HEADER_SIZE..HEADER_SIZE + len]);
+    scratch[16..20].copy_from_slice(&crc.finalize().to_le_bytes());
+
+    // Pad to the flash write size with the erased value
+    let total = (HEADER_SIZE + len).next_multiple_of(F::WRITE_SIZE);
+    scratch[HEADER_SIZE + len..total].fill(0xFF);
+
+    flash
+        .erase(offset, offset + SLOT_SIZE as u32)
+        .map_err(|_| Error::Flash)?;
+    flash
+        .write(offset, &scratch[..total])
+        .map_err(|_| Error::Flash)?;
+
+    Ok(())
+}
+
+fn parse_header(offset: u32, header: &[u8; HEADER_SIZE]) -> Option<Slot> {
+    let word =
+        |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+
+    if word(0) != MAGIC {
+        return None;
+    }
+    // Layout changes need a new version, which this firmware can't read
+    if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return None;
+    }
+    let len = word(12) as usize;
+    if len > MAX_PAYLOAD_SIZE {
+        return None;
+    }
+
+    Some(Slot {
+        offset,
+        sequence: word(8),
+        len,
+    })
+}
+
+fn check_crc<F: ReadNorFlash>(
+    flash: &mut F,
+    slot: &Slot,
+    header: &[u8; HEADER_SIZE],
+) -> Result<bool, Error> {
+    let mut crc = Crc32::new();
+    crc.update(&header[..16]);
+
+    let mut buf = [0u8; 256];
+    let mut offset = 0;
+    while offset < slot.len {
+        let chunk = core::cmp::min(buf.len(), slot.len - offset);
+        read_unaligned(
+            flash,
+            slot.offset + (HEADER_SIZE + offset) as u32,
+            &mut buf[..chunk],
+        )?;
+        crc.update(&buf[..chunk]);
+        offset += chunk;
+    }
+
+    let expected = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+    Ok(crc.finalize() == expected)
+}
+
+/// Read at a word aligned `offset` into a buffer of any length, the flash
+/// only supports reads of whole words.
+fn read_unaligned<F: ReadNorFlash>(
+    flash: &mut F,
+    offset: u32,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    let aligned = buf.len() - buf.len() % F::READ_SIZE;
+    flash
+        .read(offset, &mut buf[..aligned])
+        .map_err(|_| Error::Flash)?;
+
+    if aligned < buf.len() {
+        let mut word = [0u8; 16];
+        let word = &mut word[..F::READ_SIZE];
+        flash
+            .read(offset + aligned as u32, word)
+            .map_err(|_| Error::Flash)?;
+        let rest = buf.len() - aligned;
+        buf[aligned..].copy_from_slice(&word[..rest]);
+    }
+    Ok(())
+}
@@ -11,8 +11,32 @@ pub const TX_BUFFER_SIZE: usize = 3072;
 /// (16384 bytes for TLS record + 256 bytes overhead)
 pub const TLS_BUFFER_MAX: usize = 16640;
 
+/// Maximum size of the server certificate message kept for verifying the
+/// CertificateVerify signature (leaf + intermediates, DER encoded)
+pub const TLS_CERT_MAX_SIZE: usize = 4096;
+
 /// Buffer size for OTA firmware update chunks
 pub const OTA_CHUNK_BUFFER_SIZE: usize = 2048;
+/// Largest firmware manifest read from the OTA server
+pub const OTA_MANIFEST_MAX_SIZE: usize = 2048;
+/// Connections tried by a single firmware check when the download is
+/// interrupted, each one resuming where the previous one stopped
+pub const OTA_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Number of consecutive measurement failures after which a sensor is
+/// re-initialised
+pub const SENSOR_MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// Shortest deep sleep, when a cycle took longer than the measurement interval
+pub const DEEP_SLEEP_MIN_DURATION_MS: u64 = 10_000;
+
+/// Time the LED blinks after an `identify` command
+pub const IDENTIFY_DURATION_SECS: u64 = 10;
+/// LED blink period while identifying
+pub const IDENTIFY_BLINK_PERIOD_MS: u64 = 500;
+
+/// Time the SDS011 fan runs after waking up before its readings are accurate
+pub const SDS011_WARMUP_SECS: u64 = 30;
 
 /// Buffer size for UART read operations (for SDS011 sensor)
 pub const UART_READ_BUFFER_SIZE: usize = 64;
@@ -28,22 +52,116 @@ pub const FIRMWARE_CHECK_INTERVAL: u64 = 3600;
 /// - Network operations during poor connectivity
 pub const WATCHDOG_TIMEOUT_SECS: u64 = 60;
 
+/// Failed boots in a row after which the device starts in safe mode. A boot
+/// fails when the device resets before `BOOT_STABLE_SECS` without a planned
+/// reboot.
+pub const BOOT_LOOP_THRESHOLD: u32 = 3;
+/// Uptime after which a reset is no longer part of a boot loop
+pub const BOOT_STABLE_SECS: u64 = 300;
+/// Time safe mode runs before the normal mode is tried again, doubled for each
+/// failed boot past `BOOT_LOOP_THRESHOLD`
+pub const SAFE_MODE_RETRY_MIN_SECS: u64 = 600;
+/// Upper bound for the safe mode retry delay
+pub const SAFE_MODE_RETRY_MAX_SECS: u64 = 21_600;
+
 /// Timeout for WiFi connection attempts during reconnection
 pub const WIFI_CONNECT_TIMEOUT_SECS: u64 = 10;
 
 /// Delay between WiFi reconnection attempts
 pub const WIFI_RECONNECT_DELAY_MS: u64 = 5000;
 
+/// Interval between samples of the signal strength, reported in the
+/// diagnostics
+pub const WIFI_RSSI_INTERVAL_SECS: u64 = 30;
+
 /// Timeout for initial WiFi connection at boot (longer than reconnect to allow
 /// for slower network initialization)
 pub const WIFI_INITIAL_CONNECT_TIMEOUT_SECS: u64 = 20;
 
+/// Time the provisioning portal stays up before rebooting to retry the
+/// configured network, in case it was only temporarily unreachable
+pub const PROVISIONING_TIMEOUT_SECS: u64 = 600;
+/// Address of the device on the provisioning access point network (/24)
+pub const PORTAL_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+/// Maximum size of an HTTP request to the provisioning portal
+pub const PORTAL_REQUEST_MAX_LEN: usize = 1024;
+/// Maximum size of a provisioning portal page
+pub const PORTAL_PAGE_MAX_LEN: usize = 3072;
+
+/// Interval between SNTP clock syncs, to correct the drift of the crystal
+pub const SNTP_SYNC_INTERVAL_SECS: u64 = 3600;
+/// Delay before retrying a failed SNTP sync
+pub const SNTP_RETRY_INTERVAL_SECS: u64 = 30;
+/// Time to wait for the reply of the NTP server
+pub const SNTP_TIMEOUT_SECS: u64 = 5;
+/// Time the first measurement waits for the clock to be synchronised, so it
+/// gets a timestamp
+pub const SNTP_INITIAL_SYNC_TIMEOUT_SECS: u64 = 10;
+/// Interval at which tasks waiting for the first clock sync check for it
+pub const SNTP_SYNCED_POLL_MS: u64 = 100;
+
 /// TCP socket timeout for network operations (reads/writes)
 pub const TCP_SOCKET_TIMEOUT_SECS: u64 = 30;
 
 /// Delay after MQTT disconnect to allow socket cleanup before next connection
 pub const MQTT_DISCONNECT_CLEANUP_DELAY_MS: u64 = 100;
 
+/// MQTT keep alive requested from the broker. The client pings at half this
+/// interval when idle.
+pub const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+
+/// Time to wait for the broker to acknowledge a QoS 1 publication
+pub const MQTT_ACK_TIMEOUT_SECS: u64 = 10;
+
+/// Initial delay before reconnecting to the broker, doubled after each failure
+pub const MQTT_RECONNECT_BACKOFF_MIN_SECS: u64 = 2;
+/// Upper bound for the reconnect delay
+pub const MQTT_RECONNECT_BACKOFF_MAX_SECS: u64 = 300;
+
+/// With `deep_sleep`, measurement intervals the broker waits for the device to
+/// come back before publishing its "offline" Last Will
+#[cfg(feature = "deep_sleep")]
+pub const MQTT_WILL_DELAY_INTERVALS: u32 = 2;
+
+/// Time given to the MQTT task to release the network buffers before the OTA
+/// check is skipped. Covers a publication waiting for its acknowledgment or a
+/// connection being set up.
+pub const MQTT_PAUSE_TIMEOUT_SECS: u64 = 40;
+
+/// Time given to the MQTT task to announce a planned reboot before giving up.
+/// Includes a reconnection when the session is paused, so it is kept below
+/// the watchdog timeout.
+pub const MQTT_SHUTDOWN_TIMEOUT_SECS: u64 = 40;
+
+/// Time a shutdown leaves for publishing the messages stored in the outbox,
+/// the rest is published after the next boot. Kept well below
+/// `MQTT_SHUTDOWN_TIMEOUT_SECS` to still disconnect cleanly.
+pub const MQTT_SHUTDOWN_REPLAY_SECS: u64 = 20;
+
+/// Number of consecutive failed broker connections before rebooting to
+/// recover the network stack
+pub const MQTT_MAX_CONNECT_FAILURES: u32 = 10;
+
+/// Number of messages which can be queued for the MQTT task
+pub const MQTT_PUBLISH_QUEUE_SIZE: usize = 4;
+/// Maximum length of a topic queued for publication
+pub const MQTT_TOPIC_MAX_LEN: usize = 128;
+/// Maximum size of a payload queued for publication
+pub const MQTT_PAYLOAD_MAX_LEN: usize = 512;
+
+/// Number of received commands waiting for the main task
+pub const MQTT_COMMAND_QUEUE_SIZE: usize = 2;
+/// Maximum size of a command result message
+pub const MQTT_COMMAND_RESULT_MAX_LEN: usize = 160;
+/// Number of command results waiting to be published
+pub const MQTT_COMMAND_RESULT_QUEUE_SIZE: usize = 4;
+
+/// Maximum number of reading keys announced to Home Assistant (same as the
+/// capacity of `SensorData`)
+pub const HA_MAX_SENSOR_KEYS: usize = 16;
+/// Maximum size of a Home Assistant discovery config message
+pub const HA_PAYLOAD_MAX_LEN: usize = 768;
+
 /// Maximum number of MQTT topic subscriptions the client can manage
 pub const MQTT_MAX_SUBSCRIBES: usize = 5;
 /// Maximum number of QoS 1/2 messages the client can receive concurrently
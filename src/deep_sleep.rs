@@ -0,0 +1,135 @@
+//! Deep sleep battery mode.
+//!
+//! The device boots for every measurement: it measures, publishes and powers
+//! down for the rest of `measurement_interval_seconds`. Only the RTC memory is
+//! kept during deep sleep, it holds the counters which must survive between
+//! cycles.
+
+use embassy_time::Instant;
+use esp_hal::{
+    peripherals::LPWR,
+    ram,
+    rtc_cntl::{sleep::TimerWakeupSource, Rtc},
+    system::SleepSource,
+};
+
+use crate::config::CONFIG;
+use crate::constants::*;
+use crate::rtc_record;
+use crate::sensors::Failures;
+
+/// Marks initialised RTC memory ("SLEP")
+const MAGIC: u32 = 0x534C_4550;
+
+/// RTC memory taken by the `State` record, see `rtc_record`
+const WORDS: usize = rtc_record::len(6);
+
+/// State saved before deep sleep, restored on wake-up
+#[ram(unstable(rtc_fast, persistent))]
+static mut RTC_STATE: [u32; WORDS] = [0; WORDS];
+
+/// Counters carried across deep sleep cycles
+#[derive(Debug, Clone, Copy)]
+pub struct State {
+    /// Measurement cycles since the last firmware update check. Saturated at
+    /// power on so the firmware is checked at the first boot.
+    pub ota_counter: u32,
+    /// Measurement cycles since the last diagnostics publication, saturated
+    /// at power on as `ota_counter`
+    pub diagnostics_counter: u32,
+    /// Consecutive measurement failures of each sensor
+    pub sensor_failures: Failures,
+    /// Interval set by the set_interval command, 0 for the configured one
+    pub measurement_interval_seconds: u16,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            ota_counter: u32::MAX,
+            diagnostics_counter: u32::MAX,
+            sensor_failures: Failures::default(),
+            measurement_interval_seconds: 0,
+        }
+    }
+}
+
+impl State {
+    fn to_words(self) -> [u32; WORDS] {
+        let fields = [
+            self.ota_counter,
+            self.diagnostics_counter,
+            self.sensor_failures.bme280,
+            self.sensor_failures.scd30,
+            self.sensor_failures.sds011,
+            self.measurement_interval_seconds as u32,
+        ];
+        rtc_record::encode(MAGIC, &fields)
+    }
+
+    fn from_words(words: &[u32; WORDS]) -> Option<Self> {
+        let fields = rtc_record::decode(MAGIC, words)?;
+        Some(Self {
+            ota_counter: fields[0],
+            diagnostics_counter: fields[1],
+            sensor_failures: Failures {
+                bme280: fields[2],
+                scd30: fields[3],
+                sds011: fields[4],
+            },
+            measurement_interval_seconds: fields[5] as u16,
+        })
+    }
+}
+
+pub struct DeepSleep {
+    rtc: Rtc<'static>,
+    /// Woken up by the deep sleep timer, rather than powered on or reset
+    woke_up: bool,
+    pub state: State,
+}
+
+impl DeepSleep {
+    /// Restore the counters kept in RTC memory, starting over when it holds
+    /// no valid state (power on).
+    pub fn new(lpwr: LPWR<'static>) -> Self {
+        // SAFETY: only accessed here and in `enter`, from the main task
+        let words = unsafe { core::ptr::addr_of!(RTC_STATE).read_volatile() };
+        let state = State::from_words(&words).unwrap_or_default();
+        let woke_up = matches!(esp_hal::system::wakeup_cause(), SleepSource::Timer);
+
+        Self {
+            rtc: Rtc::new(lpwr),
+            woke_up,
+            state,
+        }
+    }
+
+    /// Whether this boot is a wake-up from deep sleep
+    pub fn woke_up(&self) -> bool {
+        self.woke_up
+    }
+
+    /// Save the state to RTC memory and power down until the next
+    /// measurement is due. Network and sensors must be left in a clean state
+    /// first, the device boots from scratch when waking up.
+    pub fn enter(mut self) -> ! {
+        // SAFETY: see `new`
+        unsafe { core::ptr::addr_of_mut!(RTC_STATE).write_volatile(self.state.to_words()) };
+
+        // Keep the measurement cadence, the time awake is part of the interval
+        let interval = match self.state.measurement_interval_seconds {
+            0 => CONFIG.measurement_interval_seconds,
+            secs => secs,
+        } as u64
+            * 1000;
+        let awake = Instant::now().as_millis();
+        let duration = interval
+            .saturating_sub(awake)
+            .max(DEEP_SLEEP_MIN_DURATION_MS);
+
+        log::info!("Entering deep sleep for {}ms", duration);
+        let timer = TimerWakeupSource::new(core::time::Duration::from_millis(duration));
+        self.rtc.sleep_deep(&[&timer])
+    }
+}
@@ -0,0 +1,168 @@
+//! Device health published on `CONFIG.diagnostics_topic()`, every
+//! `CONFIG.diagnostics_interval_seconds`.
+
+use core::cell::Cell;
+use core::fmt::Write as _;
+
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+
+use crate::config::CONFIG;
+use crate::constants::*;
+use crate::diagnostics_format::{self, Diagnostics};
+use crate::rollback::Report;
+use crate::sensors::Failures;
+use crate::serializer::Timestamp;
+use crate::{boot, clock, mqtt, ota, transport, wifi};
+
+/// Sensor failure counters after the last measurement
+static SENSOR_FAILURES: Mutex<CriticalSectionRawMutex, Cell<Failures>> =
+    Mutex::new(Cell::new(Failures {
+        bme280: 0,
+        scd30: 0,
+        sds011: 0,
+    }));
+
+/// Keep the sensor failure counters for the next publication.
+pub fn record_sensor_failures(failures: Failures) {
+    SENSOR_FAILURES.lock(|cell| cell.set(failures));
+}
+
+/// Queue the diagnostics for publication.
+pub fn publish(stack: Stack<'static>) {
+    let sensor_failures = sensor_failures();
+    let stats = esp_alloc::HEAP.stats();
+    let diagnostics = Diagnostics {
+        firmware: VERSION,
+        uptime_secs: Instant::now().as_secs(),
+        reset_reason: boot::reset_reason(),
+        boot_count: boot::count(),
+        ota_slot: ota::running_slot(),
+        wifi: wifi::link(),
+        ip: stack
+            .config_v4()
+            .map(|config| config.address.address().octets()),
+        heap_free: stats.size.saturating_sub(stats.current_usage),
+        heap_min_free: stats.size.saturating_sub(stats.max_usage),
+        mqtt_reconnects: mqtt::reconnect_count(),
+        tls_handshake_ms: transport::last_handshake_ms(),
+        sensor_failures: &sensor_failures,
+    };
+    log::debug!("Diagnostics: {:?}", diagnostics);
+
+    let timestamp = clock::now().map_or(Timestamp::Unsynced, Timestamp::Synced);
+    let mut payload: String<MQTT_PAYLOAD_MAX_LEN> = String::new();
+    if let Err(e) = diagnostics_format::write_diagnostics(&mut payload, &diagnostics, timestamp) {
+        log::error!("Failed to format diagnostics: {:?}", e);
+        return;
+    }
+    let Some(topic) = CONFIG.diagnostics_topic() else {
+        log::error!("Diagnostics topic too long");
+        return;
+    };
+    if let Err(e) = mqtt::publish(&topic, payload.as_bytes(), false) {
+        log::error!("Failed to queue diagnostics: {:?}", e);
+    }
+}
+
+/// Publish the retained fault status on the `fault` subtopic, when starting in
+/// safe mode.
+pub fn publish_fault(failed_sensors: &[&str]) {
+    let mut payload: String<MQTT_PAYLOAD_MAX_LEN> = String::new();
+    if let Err(e) = diagnostics_format::write_fault(
+        &mut payload,
+        boot::failed_boots(),
+        boot::reset_reason(),
+        boot::retry_delay_secs(),
+        failed_sensors,
+    ) {
+        log::error!("Failed to format fault status: {:?}", e);
+        return;
+    }
+    publish_fault_payload(payload.as_bytes());
+}
+
+/// Remove the retained fault status once the device recovered from a boot
+/// loop.
+pub fn clear_fault() {
+    publish_fault_payload(&[]);
+}
+
+/// Queue the report of a firmware rolled back to this one on the `rollback`
+/// subtopic, published once the broker is connected.
+pub fn publish_rollback(report: &Report) {
+    let mut payload: String<MQTT_PAYLOAD_MAX_LEN> = String::new();
+    let (from, reason) = (report.version(), report.reason.as_str());
+    let Some(topic) = diagnostics_subtopic("rollback") else {
+        log::error!("Rollback topic too long");
+        return;
+    };
+    if diagnostics_format::write_rollback(&mut payload, from, VERSION, reason).is_err() {
+        log::error!("Rollback report too large");
+        return;
+    }
+    if let Err(e) = mqtt::publish(&topic, payload.as_bytes(), false) {
+        log::error!("Failed to queue rollback report: {:?}", e);
+    }
+}
+
+fn publish_fault_payload(payload: &[u8]) {
+    let Some(topic) = diagnostics_subtopic("fault") else {
+        log::error!("Fault topic too long");
+        return;
+    };
+    if let Err(e) = mqtt::publish(&topic, payload, true) {
+        log::error!("Failed to queue fault status: {:?}", e);
+    }
+}
+
+/// `<diagnostics topic>/<leaf>`, `None` when too long
+fn diagnostics_subtopic(leaf: &str) -> Option<String<MQTT_TOPIC_MAX_LEN>> {
+    let diagnostics_topic = CONFIG.diagnostics_topic()?;
+    let mut topic = String::new();
+    write!(topic, "{}/{}", diagnostics_topic, leaf).ok()?;
+    Some(topic)
+}
+
+/// Failure counters of the sensors the firmware was built with
+#[cfg_attr(
+    not(any(feature = "bme280", feature = "scd30", feature = "sds011")),
+    allow(unused_mut, unused_variables)
+)]
+fn sensor_failures() -> Vec<(&'static str, u32), 3> {
+    let failures = SENSOR_FAILURES.lock(|cell| cell.get());
+    let mut sensor_failures = Vec::new();
+    #[cfg(feature = "bme280")]
+    let _ = sensor_failures.push(("bme280", failures.bme280));
+    #[cfg(feature = "scd30")]
+    let _ = sensor_failures.push(("scd30", failures.scd30));
+    #[cfg(feature = "sds011")]
+    let _ = sensor_failures.push(("sds011", failures.sds011));
+    sensor_failures
+}
+
+/// Publish the diagnostics at boot then every
+/// `CONFIG.diagnostics_interval_seconds`. In deep sleep mode, the main task
+/// publishes them instead, before going to sleep.
+#[embassy_executor::task]
+pub async fn diagnostics_task(stack: Stack<'static>) {
+    let interval = CONFIG.diagnostics_interval_seconds;
+    if interval == 0 {
+        log::info!("Diagnostics disabled");
+        return;
+    }
+
+    // Give the first publication a chance to be timestamped
+    let _ = embassy_time::with_timeout(
+        Duration::from_secs(SNTP_INITIAL_SYNC_TIMEOUT_SECS),
+        clock::synced(),
+    )
+    .await;
+
+    loop {
+        publish(stack);
+        Timer::after(Duration::from_secs(interval as u64)).await;
+    }
+}
@@ -0,0 +1,202 @@
+//! JSON payloads describing the device itself: the periodic diagnostics, the
+//! safe mode fault status and the firmware rollback reports.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`. The values are gathered in `diagnostics`.
+
+use core::fmt::Write;
+
+use crate::serializer::{write_json_str, Error, Timestamp};
+
+/// Cause of the last reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    /// Planned reboot, including the reboots recovering from a failure
+    Software,
+    DeepSleep,
+    Watchdog,
+    Brownout,
+    Unknown,
+}
+
+impl ResetReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::Software => "software",
+            ResetReason::DeepSleep => "deep_sleep",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+}
+
+/// Application partition the firmware runs from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppSlot {
+    Factory,
+    Ota(u8),
+}
+
+/// Access point the device is associated with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WifiLink {
+    /// Signal strength of the last beacon, in dBm
+    pub rssi: i32,
+    pub channel: u8,
+    pub bssid: [u8; 6],
+}
+
+/// Device health. Values which aren't known are written as `null`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics<'a> {
+    pub firmware: &'a str,
+    pub uptime_secs: u64,
+    pub reset_reason: ResetReason,
+    /// Boots since power on, including wake-ups from deep sleep
+    pub boot_count: u32,
+    pub ota_slot: Option<AppSlot>,
+    pub wifi: Option<WifiLink>,
+    pub ip: Option<[u8; 4]>,
+    pub heap_free: usize,
+    /// Lowest free heap since boot
+    pub heap_min_free: usize,
+    /// Broker connections lost or failed since boot
+    pub mqtt_reconnects: u32,
+    /// Duration of the last TLS handshake
+    pub tls_handshake_ms: Option<u32>,
+    /// Consecutive measurement failures of each sensor
+    pub sensor_failures: &'a [(&'a str, u32)],
+}
+
+/// Write `diagnostics` as a flat JSON object, the sensor failures nested
+/// under `sensor_failures`, with `ts` as in `serializer::write_json`:
+///
+/// ```text
+/// {"firmware":"0.1.3","uptime":3600,"reset_reason":"power_on","boot_count":1,
+///  "ota_slot":"ota_0","rssi":-67,"channel":6,"bssid":"aa:bb:cc:dd:ee:ff",
+///  "ip":"192.168.1.20","heap_free":41234,"heap_min_free":30512,
+///  "mqtt_reconnects":0,"tls_handshake_ms":4210,"sensor_failures":{"bme280":0},
+///  "ts":1700000000000}
+/// ```
+pub fn write_diagnostics<W: Write>(
+    w: &mut W,
+    diagnostics: &Diagnostics<'_>,
+    timestamp: Timestamp,
+) -> Result<(), Error> {
+    let d = diagnostics;
+    w.write_str("{\"firmware\":")?;
+    write_json_str(w, d.firmware)?;
+    write!(
+        w,
+        ",\"uptime\":{},\"reset_reason\":\"{}\",\"boot_count\":{}",
+        d.uptime_secs,
+        d.reset_reason.as_str(),
+        d.boot_count
+    )?;
+
+    w.write_str(",\"ota_slot\":")?;
+    match d.ota_slot {
+        Some(AppSlot::Factory) => w.write_str("\"factory\"")?,
+        Some(AppSlot::Ota(n)) => write!(w, "\"ota_{}\"", n)?,
+        None => w.write_str("null")?,
+    }
+
+    match d.wifi {
+        Some(link) => {
+            let b = link.bssid;
+            write!(
+                w,
+                ",\"rssi\":{},\"channel\":{},\"bssid\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\"",
+                link.rssi, link.channel, b[0], b[1], b[2], b[3], b[4], b[5]
+            )?;
+        }
+        None => w.write_str(",\"rssi\":null,\"channel\":null,\"bssid\":null")?,
+    }
+
+    match d.ip {
+        Some([a, b, c, d]) => write!(w, ",\"ip\":\"{}.{}.{}.{}\"", a, b, c, d)?,
+        None => w.write_str(",\"ip\":null")?,
+    }
+
+    write!(
+        w,
+        ",\"heap_free\":{},\"heap_min_free\":{},\"mqtt_reconnects\":{}",
+        d.heap_free, d.heap_min_free, d.mqtt_reconnects
+    )?;
+    match d.tls_handshake_ms {
+        Some(ms) => write!(w, ",\"tls_handshake_ms\":{}", ms)?,
+        None => w.write_str(",\"tls_handshake_ms\":null")?,
+    }
+
+    w.write_str(",\"sensor_failures\":{")?;
+    for (i, (sensor, failures)) in d.sensor_failures.iter().enumerate() {
+        if i > 0 {
+            w.write_char(',')?;
+        }
+        write_json_str(w, sensor)?;
+        write!(w, ":{}", failures)?;
+    }
+    w.write_char('}')?;
+
+    match timestamp {
+        Timestamp::Synced(micros) => write!(w, ",\"ts\":{}}}", micros / 1000)?,
+        Timestamp::Unsynced => w.write_str(",\"ts\":null}")?,
+    }
+    Ok(())
+}
+
+/// Write the fault status published when the device starts in safe mode:
+///
+/// ```text
+/// {"safe_mode":true,"failed_boots":4,"reset_reason":"watchdog","retry_in":1200,
+///  "failed_sensors":["scd30"]}
+/// ```
+pub fn write_fault<W: Write>(
+    w: &mut W,
+    failed_boots: u32,
+    reset_reason: ResetReason,
+    retry_in_secs: u64,
+    failed_sensors: &[&str],
+) -> Result<(), Error> {
+    write!(
+        w,
+        "{{\"safe_mode\":true,\"failed_boots\":{},\"reset_reason\":\"{}\",\"retry_in\":{}",
+        failed_boots,
+        reset_reason.as_str(),
+        retry_in_secs
+    )?;
+    w.write_str(",\"failed_sensors\":[")?;
+    for (i, sensor) in failed_sensors.iter().enumerate() {
+        if i > 0 {
+            w.write_char(',')?;
+        }
+        write_json_str(w, sensor)?;
+    }
+    w.write_str("]}")?;
+    Ok(())
+}
+
+/// Write the report of a firmware rolled back after failing its health check,
+/// from the rolled back version to the running one:
+///
+/// ```text
+/// {"from":"0.1.4","to":"0.1.3","reason":"mqtt"}
+/// ```
+pub fn write_rollback<W: Write>(
+    w: &mut W,
+    from: &str,
+    to: &str,
+    reason: &str,
+) -> Result<(), Error> {
+    w.write_str("{\"from\":")?;
+    write_json_str(w, from)?;
+    w.write_str(",\"to\":")?;
+    write_json_str(w, to)?;
+    w.write_str(",\"reason\":")?;
+    write_json_str(w, reason)?;
+    w.write_char('}')?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Resumable firmware download.
+//!
+//! The image is written to the OTA partition as it is received, each sector
+//! being erased right before its first write. The download progress is kept
+//! by the caller across connections and reboots: an interrupted download
+//! resumes with a `Range` request from the last byte written, without erasing
+//! the sectors already written again. A server which ignores the range sends
+//! the whole image, its start is then skipped.
+//!
+//! The progress may lag behind what was actually written, e.g. after a crash:
+//! the same bytes are then written again, which NOR flash allows. The image is
+//! only trusted once its checksum and signature verify over the flash content.
+//!
+//! Only depends on `core`, `heapless`, `embedded-io-async` and
+//! `embedded-storage` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use core::fmt::Write as _;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embedded_io_async::{Read, Write};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::{String, Vec};
+
+use crate::crc32::Crc32;
+use crate::http::{self, Client};
+use crate::rtc_record::{self, Version};
+
+/// Marks an initialised progress record ("DNLD")
+const MAGIC: u32 = 0x444E_4C44;
+
+/// RTC memory taken by the progress record: the CRC32, size and bytes
+/// written of the image, then its version, see `rtc_record`
+pub const WORDS: usize = rtc_record::len(3 + Version::WORDS);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Http(http::Error),
+    /// The server answered with an error status
+    Status(u16),
+    /// Partial response not starting where the download stopped, or not
+    /// ending with the image
+    Range,
+    /// Body length other than the image size
+    Length,
+    /// The image is larger than the partition
+    TooLarge,
+    Flash,
+    /// The image read back from flash does not match its CRC32
+    Checksum,
+}
+
+/// Image being downloaded and how much of it is written to flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub crc32: u32,
+    pub size: u32,
+    written: u32,
+    version: Version,
+}
+
+impl Progress {
+    /// Nothing written yet of the image `version`, `size` bytes long with the
+    /// given CRC32.
+    pub fn new(version: &str, crc32: u32, size: u32) -> Self {
+        Self {
+            crc32,
+            size,
+            written: 0,
+            version: Version::new(version),
+        }
+    }
+
+    pub fn version(&self) -> &str {
+        self.version.as_str()
+    }
+
+    /// Bytes at the start of the image written to flash
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.written == self.size
+    }
+
+    /// Whether this is the progress of the image `version`, with the given
+    /// CRC32 and size.
+    pub fn is_for(&self, version: &str, crc32: u32, size: u32) -> bool {
+        self.crc32 == crc32 && self.size == size && self.version == Version::new(version)
+    }
+
+    pub fn to_words(self) -> [u32; WORDS] {
+        let mut fields = [0; 3 + Version::WORDS];
+        fields[..3].copy_from_slice(&[self.crc32, self.size, self.written]);
+        fields[3..].copy_from_slice(&self.version.to_words());
+        rtc_record::encode(MAGIC, &fields)
+    }
+
+    /// Decode a progress record, `None` when the words hold no valid record.
+    pub fn from_words(words: &[u32; WORDS]) -> Option<Self> {
+        let fields = rtc_record::decode(MAGIC, words)?;
+        let (crc32, size, written) = (fields[0], fields[1], fields[2]);
+        if written > size {
+            return None;
+        }
+        Some(Self {
+            crc32,
+            size,
+            written,
+            version: Version::from_words(&fields[3..])?,
+        })
+    }
+}
+
+/// Download the image `progress` is for from `path` into `flash`, starting
+/// from the bytes already written. Extra `headers`, e.g. credentials, are sent
+/// with the request. `progress` is updated as the bytes are
+/// written, including when the download fails. `buf` holds the received
+/// bytes until they are written, its length must be a multiple of the flash
+/// write size.
+pub async fn download<C: Read + Write, F: NorFlash>(
+    client: &mut Client<'_, C>,
+    path: &str,
+    headers: &[(&str, &str)],
+    flash: &mut F,
+    progress: &mut Progress,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    let size = progress.size;
+    if size as usize > flash.capacity() {
+        return Err(Error::TooLarge);
+    }
+    if progress.is_complete() {
+        return Ok(());
+    }
+
+    let mut range: String<32> = String::new();
+    let _ = write!(range, "bytes={}-", progress.written);
+    let mut request_headers: Vec<(&str, &str), 4> = Vec::new();
+    request_headers
+        .extend_from_slice(headers)
+        .map_err(|_| Error::Http(http::Error::RequestTooLarge))?;
+    if progress.written > 0 {
+        request_headers
+            .push(("Range", &range))
+            .map_err(|_| Error::Http(http::Error::RequestTooLarge))?;
+    }
+    let response = client
+        .get(path, &request_headers)
+        .await
+        .map_err(Error::Http)?;
+
+    // Bytes of the body before the ones to write
+    let mut skip = match response.status {
+        206 => {
+            let range = response.content_range.ok_or(Error::Range)?;
+            let ends_with_image = range.last + 1 == size as u64
+                && range
+                    .complete_length
+                    .is_none_or(|length| length == size as u64);
+            if range.first != progress.written as u64 || !ends_with_image {
+                return Err(Error::Range);
+            }
+            0
+        }
+        200 => {
+            if response
+                .content_length
+                .is_some_and(|length| length != size as u64)
+            {
+                return Err(Error::Length);
+            }
+            progress.written
+        }
+        status => return Err(Error::Status(status)),
+    };
+    while skip > 0 {
+        let len = buf.len().min(skip as usize);
+        match client
+            .read_body(&mut buf[..len])
+            .await
+            .map_err(Error::Http)?
+        {
+            0 => return Err(Error::Http(http::Error::UnexpectedEof)),
+            n => skip -= n as u32,
+        }
+    }
+
+    // Sectors before this offset are erased, the one holding the first byte
+    // to write was erased before its first write
+    let erase_size = F::ERASE_SIZE as u32;
+    let mut erased = progress.written.next_multiple_of(erase_size);
+
+    // Received bytes not written yet, always fewer than the write size
+    let write_size = F::WRITE_SIZE;
+    let mut pending = 0;
+    while progress.written < size {
+        // Bytes of the image not received yet
+        let remaining = (size - progress.written) as usize - pending;
+        let max_read = (buf.len() - pending).min(remaining);
+        let n = match client
+            .read_body(&mut buf[pending..pending + max_read])
+            .await
+        {
+            Ok(0) => Err(Error::Http(http::Error::UnexpectedEof)),
+            Ok(n) => Ok(n),
+            Err(e) => Err(Error::Http(e)),
+        }?;
+        let received = n == remaining;
+        pending += n;
+
+        // Write the aligned part, or everything padded with the erased state
+        // once the image is received entirely
+        let len = if received {
+            let padded = pending.next_multiple_of(write_size);
+            buf[pending..padded].fill(0xFF);
+            padded
+        } else {
+            pending - pending % write_size
+        };
+        if len == 0 {
+            continue;
+        }
+
+        let end = progress.written + len as u32;
+        if end > erased {
+            let erase_end = end.next_multiple_of(erase_size);
+            flash.erase(erased, erase_end).map_err(|_| Error::Flash)?;
+            erased = erase_end;
+        }
+        flash
+            .write(progress.written, &buf[..len])
+            .map_err(|_| Error::Flash)?;
+        let data = len.min(pending);
+        progress.written += data as u32;
+        buf.copy_within(data..pending, 0);
+        pending -= data;
+    }
+    Ok(())
+}
+
+/// Read the first `len` bytes of `flash` back, in `buf` sized chunks passed
+/// to `update`, e.g. to hash them for the signature check. With `crc32`, the
+/// bytes read must match it. `buf` length must be a multiple of the flash
+/// read size.
+pub async fn read_back<F: ReadNorFlash>(
+    flash: &mut F,
+    len: usize,
+    crc32: Option<u32>,
+    buf: &mut [u8],
+    mut update: impl FnMut(&[u8]),
+) -> Result<(), Error> {
+    let mut crc = Crc32::new();
+    let mut offset = 0;
+    while offset < len {
+        let chunk = buf.len().min(len - offset);
+        // Reads stay aligned, the bytes past `len` are ignored
+        let read_len = chunk.next_multiple_of(F::READ_SIZE);
+        flash
+            .read(offset as u32, &mut buf[..read_len])
+            .map_err(|_| Error::Flash)?;
+        crc.update(&buf[..chunk]);
+        update(&buf[..chunk]);
+        offset += chunk;
+
+        // Let the other tasks run, e.g. WiFi. The caller feeds the watchdog
+        if offset % (64 * 1024) == 0 {
+            yield_now().await;
+        }
+    }
+
+    match crc32 {
+        Some(crc32) if crc.finalize() != crc32 => Err(Error::Checksum),
+        _ => Ok(()),
+    }
+}
+
+/// Give the other tasks a turn, reading the flash never awaits
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
@@ -0,0 +1,154 @@
+//! Home Assistant MQTT discovery.
+//!
+//! Every reading key produced by the connected sensors gets a retained config
+//! message on `homeassistant/sensor/<device_id>/<key>/config` so the entities
+//! show up in Home Assistant without hand written YAML. Configs are published
+//! when the broker connection is established and whenever a new key shows up.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::config::CONFIG;
+use crate::constants::*;
+use crate::mqtt::{self, Mqtt};
+use crate::sensors::SensorData;
+use crate::serializer::{write_json_escaped, write_json_str};
+
+/// Discovery topic prefix configured in Home Assistant (default)
+const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Model reported in the Home Assistant device registry
+const DEVICE_MODEL: &str = "ESP32 home sensor";
+
+/// Reading keys announced so far. Keys are only added: a sensor failing for a
+/// while keeps its entities, Home Assistant shows them unavailable/stale.
+static SENSOR_KEYS: Mutex<CriticalSectionRawMutex, RefCell<Vec<&'static str, HA_MAX_SENSOR_KEYS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+/// Raised when `SENSOR_KEYS` changed and the configs must be published again
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Home Assistant metadata of a reading
+struct Entity<'a> {
+    name: &'a str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+}
+
+fn entity(key: &str) -> Entity<'_> {
+    let (name, device_class, unit) = match key {
+        "temperature" => ("Temperature", Some("temperature"), Some("°C")),
+        "humidity" => ("Humidity", Some("humidity"), Some("%")),
+        "pressure" => ("Pressure", Some("pressure"), Some("Pa")),
+        "co2" => ("CO2", Some("carbon_dioxide"), Some("ppm")),
+        "air_quality_pm2_5" => ("PM2.5", Some("pm25"), Some("µg/m³")),
+        "air_quality_pm10" => ("PM10", Some("pm10"), Some("µg/m³")),
+        "sensor_errors" => ("Sensor errors", None, None),
+        // Unknown readings are still exposed, without unit
+        _ => (key, None, None),
+    };
+    Entity {
+        name,
+        device_class,
+        unit,
+    }
+}
+
+/// Record the keys of a sample, waking up the MQTT task when new ones appear.
+pub fn update_sensors(sensor_data: &SensorData) {
+    let changed = SENSOR_KEYS.lock(|keys| {
+        let mut keys = keys.borrow_mut();
+        let mut changed = false;
+        for key in sensor_data.data.keys() {
+            if !keys.contains(key) {
+                if keys.push(key).is_err() {
+                    log::warn!("Too many sensor keys for discovery, ignoring {}", key);
+                    continue;
+                }
+                changed = true;
+            }
+        }
+        changed
+    });
+
+    if changed {
+        CHANGED.signal(());
+    }
+}
+
+/// Wait until new reading keys must be announced.
+pub async fn changed() {
+    CHANGED.wait().await;
+}
+
+/// Publish the discovery config of every known reading key.
+pub async fn announce<T: Read + Write>(mqtt: &mut Mqtt<'_, T>) -> Result<(), mqtt::Error> {
+    CHANGED.reset();
+    let keys = SENSOR_KEYS.lock(|keys| keys.borrow().clone());
+
+    for key in keys {
+        let mut topic: String<MQTT_TOPIC_MAX_LEN> = String::new();
+        write!(
+            topic,
+            "{}/sensor/{}/{}/config",
+            DISCOVERY_PREFIX, CONFIG.device_id, key
+        )
+        .map_err(|_| mqtt::Error::MessageTooLarge)?;
+
+        let payload = config_payload(key).map_err(|_| mqtt::Error::MessageTooLarge)?;
+        mqtt.send_message(&topic, payload.as_bytes(), true).await?;
+    }
+
+    log::info!("Home Assistant discovery published");
+    Ok(())
+}
+
+fn config_payload(key: &str) -> Result<String<HA_PAYLOAD_MAX_LEN>, core::fmt::Error> {
+    let entity = entity(key);
+    let mut payload: String<HA_PAYLOAD_MAX_LEN> = String::new();
+
+    write!(payload, "{{\"name\":")?;
+    write_json_str(&mut payload, entity.name)?;
+    write!(payload, ",\"unique_id\":\"")?;
+    write_json_escaped(&mut payload, CONFIG.device_id)?;
+    write!(payload, "_{}\"", key)?;
+
+    write!(payload, ",\"state_topic\":")?;
+    write_json_str(&mut payload, CONFIG.mqtt_topic)?;
+    write!(
+        payload,
+        ",\"value_template\":\"{{{{ value_json.{} }}}}\"",
+        key
+    )?;
+    if let Some(device_class) = entity.device_class {
+        write!(payload, ",\"device_class\":\"{}\"", device_class)?;
+    }
+    if let Some(unit) = entity.unit {
+        write!(payload, ",\"unit_of_measurement\":\"{}\"", unit)?;
+    }
+    write!(payload, ",\"state_class\":\"measurement\"")?;
+    write!(payload, ",\"availability_topic\":")?;
+    let availability_topic = CONFIG.availability_topic().ok_or(core::fmt::Error)?;
+    write_json_str(&mut payload, &availability_topic)?;
+
+    write!(payload, ",\"device\":{{\"identifiers\":[")?;
+    write_json_str(&mut payload, CONFIG.device_id)?;
+    write!(payload, "],\"name\":")?;
+    write_json_str(&mut payload, CONFIG.device_id)?;
+    write!(
+        payload,
+        ",\"model\":\"{}\",\"sw_version\":\"{}\",\"suggested_area\":",
+        DEVICE_MODEL, VERSION
+    )?;
+    write_json_str(&mut payload, CONFIG.location)?;
+    write!(payload, "}}}}")?;
+
+    Ok(payload)
+}
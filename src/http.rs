@@ -0,0 +1,490 @@
+//! Minimal HTTP/1.1 client, used to download the firmware updates.
+//!
+//! Sends GET requests on an established connection, plain TCP or TLS, and
+//! parses the status line and headers of the response. The body is read as a
+//! stream, delimited by `Content-Length`, `Transfer-Encoding: chunked` or the
+//! connection close. Redirects to another path of the same host are followed
+//! on the same connection, which is kept alive between requests when the
+//! server allows it.
+//!
+//! Only depends on `core`, `heapless` and `embedded-io-async` so it can be
+//! unit tested on the host, see `tools/firmware-core`.
+
+use core::fmt::Write as _;
+
+use embedded_io_async::{Error as _, ErrorKind, Read, Write};
+use heapless::String;
+
+/// Longest request line and headers, a registry bearer token takes up to
+/// 1.5 KiB
+const REQUEST_MAX_LEN: usize = 2048;
+
+/// Longest path a redirect can point to
+pub const PATH_MAX_LEN: usize = 256;
+
+/// Longest `WWW-Authenticate` header kept
+pub const CHALLENGE_MAX_LEN: usize = 256;
+
+/// Redirects followed for a single request
+pub const MAX_REDIRECTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Reading or writing the connection failed
+    Io(ErrorKind),
+    /// The connection closed before the end of the response
+    UnexpectedEof,
+    /// The server closes the connection after the last response, the next
+    /// request needs a new one
+    Closed,
+    /// Response head or chunk size line larger than the buffer
+    HeadTooLarge,
+    /// Malformed status line, header or chunk
+    Malformed,
+    /// Request line and headers longer than `REQUEST_MAX_LEN`
+    RequestTooLarge,
+    /// Redirect to another host, or too many of them
+    Redirect,
+}
+
+/// Status and headers of a response. The body is read with
+/// `Client::read_body`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    /// Body length, `None` when chunked or delimited by the connection close
+    pub content_length: Option<u64>,
+    /// Part of the resource held by the body of a partial response (206),
+    /// `None` when missing or malformed
+    pub content_range: Option<ContentRange>,
+    /// Authentication challenge of a 401 response, `None` when missing or
+    /// too long
+    pub www_authenticate: Option<String<CHALLENGE_MAX_LEN>>,
+}
+
+/// `Content-Range` header of a partial response, the body holds the bytes
+/// `first..=last` of the resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub first: u64,
+    pub last: u64,
+    /// Size of the whole resource, `None` when unknown
+    pub complete_length: Option<u64>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// How the rest of the body is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Bytes left before the end of the body
+    Length(u64),
+    /// Waiting for the size line of the next chunk
+    ChunkSize,
+    /// Bytes left in the current chunk
+    Chunk(u64),
+    /// Waiting for the line break ending the current chunk
+    ChunkEnd,
+    /// Until the connection closes
+    Close,
+    /// Body read entirely, or no response yet
+    Done,
+}
+
+pub struct Client<'a, C> {
+    conn: C,
+    host: &'a str,
+    port: u16,
+    /// Holds the response head, and the body bytes received with it
+    buf: &'a mut [u8],
+    /// Bytes received and not consumed yet, `buf[start..end]`
+    start: usize,
+    end: usize,
+    framing: Framing,
+    /// Whether the connection can take another request after this response
+    keep_alive: bool,
+}
+
+impl<'a, C: Read + Write> Client<'a, C> {
+    /// Client sending its requests to `host` on `conn`, which is connected to
+    /// its `port`. The response head must fit in `buf`.
+    pub fn new(conn: C, host: &'a str, port: u16, buf: &'a mut [u8]) -> Self {
+        Self {
+            conn,
+            host,
+            port,
+            buf,
+            start: 0,
+            end: 0,
+            framing: Framing::Done,
+            keep_alive: true,
+        }
+    }
+
+    pub fn host(&self) -> &str {
+        self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send a GET request for `path` with extra `headers` and read the head of
+    /// the response, following redirects to the same host. The rest of the
+    /// previous response body is discarded first.
+    pub async fn get(&mut self, path: &str, headers: &[(&str, &str)]) -> Result<Response, Error> {
+        let mut location: String<PATH_MAX_LEN> = String::new();
+        for redirects in 0..=MAX_REDIRECTS {
+            let target = if redirects == 0 { path } else { &location };
+            self.discard_body().await?;
+            if !self.keep_alive {
+                return Err(Error::Closed);
+            }
+            self.send(target, headers).await?;
+            let (response, redirect) = self.read_head().await?;
+            if !matches!(response.status, 301 | 302 | 303 | 307 | 308) {
+                return Ok(response);
+            }
+
+            let redirect = redirect.ok_or(Error::Malformed)?;
+            let target = same_host_path(&redirect, self.host, self.port).ok_or(Error::Redirect)?;
+            location = String::try_from(target).map_err(|_| Error::Redirect)?;
+        }
+        Err(Error::Redirect)
+    }
+
+    /// Read the next bytes of the response body into `out`, returns 0 at the
+    /// end of the body.
+    pub async fn read_body(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.framing {
+                Framing::Done => return Ok(0),
+                Framing::Length(remaining) => {
+                    let n = self.read_raw(out, remaining).await?;
+                    if n == 0 {
+                        return Err(Error::UnexpectedEof);
+                    }
+                    self.framing = match remaining - n as u64 {
+                        0 => Framing::Done,
+                        remaining => Framing::Length(remaining),
+                    };
+                    return Ok(n);
+                }
+                Framing::Close => {
+                    let n = self.read_raw(out, u64::MAX).await?;
+                    if n == 0 {
+                        self.framing = Framing::Done;
+                    }
+                    return Ok(n);
+                }
+                Framing::ChunkSize => {
+                    let line = self.read_line().await?;
+                    let size = parse_chunk_size(&self.buf[line]).ok_or(Error::Malformed)?;
+                    if size == 0 {
+                        // Trailer fields, up to the empty line
+                        while !self.read_line().await?.is_empty() {}
+                        self.framing = Framing::Done;
+                        return Ok(0);
+                    }
+                    self.framing = Framing::Chunk(size);
+                }
+                Framing::Chunk(remaining) => {
+                    let n = self.read_raw(out, remaining).await?;
+                    if n == 0 {
+                        return Err(Error::UnexpectedEof);
+                    }
+                    self.framing = match remaining - n as u64 {
+                        0 => Framing::ChunkEnd,
+                        remaining => Framing::Chunk(remaining),
+                    };
+                    return Ok(n);
+                }
+                Framing::ChunkEnd => {
+                    if !self.read_line().await?.is_empty() {
+                        return Err(Error::Malformed);
+                    }
+                    self.framing = Framing::ChunkSize;
+                }
+            }
+        }
+    }
+
+    /// Read and drop the rest of the response body.
+    pub async fn discard_body(&mut self) -> Result<(), Error> {
+        let mut scratch = [0u8; 64];
+        while self.read_body(&mut scratch).await? > 0 {}
+        Ok(())
+    }
+
+    async fn send(&mut self, path: &str, headers: &[(&str, &str)]) -> Result<(), Error> {
+        let mut request: String<REQUEST_MAX_LEN> = String::new();
+        write!(request, "GET {} HTTP/1.1\r\nHost: {}\r\n", path, self.host)
+            .map_err(|_| Error::RequestTooLarge)?;
+        for (name, value) in headers {
+            write!(request, "{}: {}\r\n", name, value).map_err(|_| Error::RequestTooLarge)?;
+        }
+        request
+            .push_str("\r\n")
+            .map_err(|_| Error::RequestTooLarge)?;
+
+        self.conn
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| Error::Io(e.kind()))?;
+        self.conn.flush().await.map_err(|e| Error::Io(e.kind()))
+    }
+
+    /// Read the head of the next final response, skipping the informational
+    /// ones (1xx). Returns the `Location` header along with it.
+    async fn read_head(&mut self) -> Result<(Response, Option<String<PATH_MAX_LEN>>), Error> {
+        loop {
+            let len = self.fill_head().await?;
+            let head = core::str::from_utf8(&self.buf[self.start..self.start + len])
+                .map_err(|_| Error::Malformed)?;
+            let head = parse_head(head)?;
+            self.start += len;
+            if (100..200).contains(&head.status) {
+                continue;
+            }
+
+            self.keep_alive = head.keep_alive;
+            let mut content_length = None;
+            self.framing = if matches!(head.status, 204 | 304) {
+                Framing::Done
+            } else if head.chunked {
+                Framing::ChunkSize
+            } else if let Some(length) = head.content_length.filter(|_| !head.other_encoding) {
+                content_length = Some(length);
+                match length {
+                    0 => Framing::Done,
+                    length => Framing::Length(length),
+                }
+            } else {
+                self.keep_alive = false;
+                Framing::Close
+            };
+            let response = Response {
+                status: head.status,
+                content_length,
+                content_range: head.content_range,
+                www_authenticate: head.www_authenticate,
+            };
+            return Ok((response, head.location));
+        }
+    }
+
+    /// Receive until the buffer holds a whole response head, returns its
+    /// length including the empty line.
+    async fn fill_head(&mut self) -> Result<usize, Error> {
+        let mut searched = 0;
+        loop {
+            let received = &self.buf[self.start..self.end];
+            if let Some(pos) = find(&received[searched..], b"\r\n\r\n") {
+                return Ok(searched + pos + 4);
+            }
+            searched = received.len().saturating_sub(3);
+            self.fill().await?;
+        }
+    }
+
+    /// Receive until the buffer holds a whole line, returns its range in the
+    /// buffer without the line break, and consumes it.
+    async fn read_line(&mut self) -> Result<core::ops::Range<usize>, Error> {
+        let mut searched = 0;
+        loop {
+            let received = &self.buf[self.start..self.end];
+            if let Some(pos) = find(&received[searched..], b"\r\n") {
+                let line = self.start..self.start + searched + pos;
+                self.start = line.end + 2;
+                return Ok(line);
+            }
+            searched = received.len().saturating_sub(1);
+            self.fill().await?;
+        }
+    }
+
+    /// Receive more bytes at the end of the buffer, moving the ones not
+    /// consumed yet to its start.
+    async fn fill(&mut self) -> Result<(), Error> {
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+        if self.end == self.buf.len() {
+            return Err(Error::HeadTooLarge);
+        }
+        match self.conn.read(&mut self.buf[self.end..]).await {
+            Ok(0) => Err(Error::UnexpectedEof),
+            Ok(n) => {
+                self.end += n;
+                Ok(())
+            }
+            Err(e) => Err(Error::Io(e.kind())),
+        }
+    }
+
+    /// Read up to `limit` body bytes, from the buffer first. Returns 0 when
+    /// the connection closed.
+    async fn read_raw(&mut self, out: &mut [u8], limit: u64) -> Result<usize, Error> {
+        let max = out.len().min(usize::try_from(limit).unwrap_or(usize::MAX));
+        if self.start < self.end {
+            let n = max.min(self.end - self.start);
+            out[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+            self.start += n;
+            return Ok(n);
+        }
+        self.conn
+            .read(&mut out[..max])
+            .await
+            .map_err(|e| Error::Io(e.kind()))
+    }
+}
+
+/// Status and headers of a response which matter to the client
+struct Head {
+    status: u16,
+    content_length: Option<u64>,
+    chunked: bool,
+    /// Transfer coding other than chunked, the body then ends with the
+    /// connection
+    other_encoding: bool,
+    keep_alive: bool,
+    /// `None` when missing or too long
+    location: Option<String<PATH_MAX_LEN>>,
+    content_range: Option<ContentRange>,
+    /// `None` when missing or too long
+    www_authenticate: Option<String<CHALLENGE_MAX_LEN>>,
+}
+
+fn parse_head(head: &str) -> Result<Head, Error> {
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().ok_or(Error::Malformed)?;
+    let (version, rest) = status_line.split_once(' ').ok_or(Error::Malformed)?;
+    let minor = version.strip_prefix("HTTP/1.").ok_or(Error::Malformed)?;
+    let code = rest.split_once(' ').map_or(rest, |(code, _reason)| code);
+    if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(Error::Malformed);
+    }
+
+    let mut parsed = Head {
+        status: code.parse().map_err(|_| Error::Malformed)?,
+        content_length: None,
+        chunked: false,
+        other_encoding: false,
+        // HTTP/1.0 closes the connection unless asked otherwise
+        keep_alive: minor != "0",
+        location: None,
+        content_range: None,
+        www_authenticate: None,
+    };
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or(Error::Malformed)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            let length = value.parse().map_err(|_| Error::Malformed)?;
+            if parsed
+                .content_length
+                .is_some_and(|previous| previous != length)
+            {
+                return Err(Error::Malformed);
+            }
+            parsed.content_length = Some(length);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // Chunked is always the last coding applied
+            let last = value.rsplit(',').next().unwrap_or("").trim();
+            parsed.chunked = last.eq_ignore_ascii_case("chunked");
+            parsed.other_encoding = !parsed.chunked;
+        } else if name.eq_ignore_ascii_case("connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    parsed.keep_alive = false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    parsed.keep_alive = true;
+                }
+            }
+        } else if name.eq_ignore_ascii_case("location") {
+            parsed.location = String::try_from(value).ok();
+        } else if name.eq_ignore_ascii_case("content-range") {
+            parsed.content_range = parse_content_range(value);
+        } else if name.eq_ignore_ascii_case("www-authenticate") {
+            parsed.www_authenticate = String::try_from(value).ok();
+        }
+    }
+    Ok(parsed)
+}
+
+/// `Content-Range` value of a byte range: `bytes <first>-<last>/<length>`, the
+/// length being `*` when unknown
+fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let (unit, range) = value.split_once(' ')?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let (range, complete_length) = range.trim().split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let range = ContentRange {
+        first: first.parse().ok()?,
+        last: last.parse().ok()?,
+        complete_length: match complete_length {
+            "*" => None,
+            length => Some(length.parse().ok()?),
+        },
+    };
+    let valid = range.first <= range.last
+        && range
+            .complete_length
+            .is_none_or(|length| range.last < length);
+    valid.then_some(range)
+}
+
+/// Chunk size line: the size in hexadecimal, then optional extensions
+fn parse_chunk_size(line: &[u8]) -> Option<u64> {
+    let line = core::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim();
+    if size.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
+}
+
+/// Path a redirect `location`, or another URL, points to, `None` when it is
+/// on another host than `host:port`. Relative references other than absolute
+/// paths aren't supported.
+pub fn same_host_path<'l>(location: &'l str, host: &str, port: u16) -> Option<&'l str> {
+    let location = location.split('#').next()?;
+    if location.starts_with('/') && !location.starts_with("//") {
+        return Some(location);
+    }
+
+    let (scheme, rest) = location.split_once("://")?;
+    let default_port = if scheme.eq_ignore_ascii_case("https") {
+        443
+    } else if scheme.eq_ignore_ascii_case("http") {
+        80
+    } else {
+        return None;
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/"),
+    };
+    let (location_host, location_port) = match authority.rsplit_once(':') {
+        Some((location_host, location_port)) => (location_host, location_port.parse().ok()?),
+        None => (authority, default_port),
+    };
+    (location_host.eq_ignore_ascii_case(host) && location_port == port).then_some(path)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,33 @@
+//! Blink the on-board LED on request, to find a device among others.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Output;
+
+use crate::constants::*;
+
+static IDENTIFY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Blink the LED for `IDENTIFY_DURATION_SECS`, starting over when already
+/// blinking.
+pub fn start() {
+    IDENTIFY.signal(());
+}
+
+#[embassy_executor::task]
+pub async fn identify_task(mut led: Output<'static>) {
+    loop {
+        IDENTIFY.wait().await;
+        log::info!("Identifying device");
+
+        let mut until = Instant::now() + Duration::from_secs(IDENTIFY_DURATION_SECS);
+        while Instant::now() < until {
+            led.toggle();
+            Timer::after(Duration::from_millis(IDENTIFY_BLINK_PERIOD_MS / 2)).await;
+            if IDENTIFY.try_take().is_some() {
+                until = Instant::now() + Duration::from_secs(IDENTIFY_DURATION_SECS);
+            }
+        }
+        led.set_low();
+    }
+}
@@ -0,0 +1,227 @@
+//! Reader for the JSON objects received by the device, the commands, the OTA
+//! manifest and the OCI registry responses.
+//!
+//! Only depends on `core` and `heapless` so it can be unit tested on the host,
+//! see `tools/firmware-core`.
+
+use core::str::Chars;
+
+use heapless::String;
+
+/// Value of an object member, as found in the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    /// Content of a string, escape sequences are left as is
+    Str(&'a str),
+    Number(&'a str),
+    Bool(bool),
+    Null,
+    /// Nested object as found in the payload, checked once parsed in turn
+    /// with `parse_object`
+    Object(&'a str),
+    /// Nested array as found in the payload, checked once parsed in turn with
+    /// `parse_array`
+    Array(&'a str),
+}
+
+/// Parse a JSON object, calling `f` for each member.
+pub fn parse_object<'a>(s: &'a str, mut f: impl FnMut(&'a str, Value<'a>)) -> Option<()> {
+    let mut p = Parser { s, pos: 0 };
+    p.expect(b'{')?;
+    if !p.eat(b'}') {
+        loop {
+            let key = p.string()?;
+            p.expect(b':')?;
+            f(key, p.value()?);
+            if p.eat(b'}') {
+                break;
+            }
+            p.expect(b',')?;
+        }
+    }
+    p.skip_whitespace();
+    (p.pos == s.len()).then_some(())
+}
+
+/// Parse a JSON array, calling `f` for each element.
+pub fn parse_array<'a>(s: &'a str, mut f: impl FnMut(Value<'a>)) -> Option<()> {
+    let mut p = Parser { s, pos: 0 };
+    p.expect(b'[')?;
+    if !p.eat(b']') {
+        loop {
+            f(p.value()?);
+            if p.eat(b']') {
+                break;
+            }
+            p.expect(b',')?;
+        }
+    }
+    p.skip_whitespace();
+    (p.pos == s.len()).then_some(())
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip whitespace and `c` if it comes next
+    fn eat(&mut self, c: u8) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Option<()> {
+        self.eat(c).then_some(())
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.peek()? {
+                b'"' => break,
+                b'\\' => {
+                    self.pos += 1;
+                    match self.peek()? {
+                        b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't' => {}
+                        b'u' => {
+                            let hex = self.s.as_bytes().get(self.pos + 1..self.pos + 5)?;
+                            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                                return None;
+                            }
+                            self.pos += 4;
+                        }
+                        _ => return None,
+                    }
+                }
+                c if c < 0x20 => return None,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let content = &self.s[start..self.pos];
+        self.pos += 1;
+        Some(content)
+    }
+
+    fn value(&mut self) -> Option<Value<'a>> {
+        self.skip_whitespace();
+        match self.peek()? {
+            b'"' => self.string().map(Value::Str),
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+                while matches!(
+                    self.peek(),
+                    Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+                ) {
+                    self.pos += 1;
+                }
+                Some(Value::Number(&self.s[start..self.pos]))
+            }
+            open @ (b'{' | b'[') => {
+                // Skip to the matching bracket, without recursion so deep
+                // nesting can't overflow the stack
+                let start = self.pos;
+                let mut depth = 0usize;
+                loop {
+                    match self.peek()? {
+                        b'"' => {
+                            self.string()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.pos += 1;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                    self.pos += 1;
+                }
+                let raw = &self.s[start..self.pos];
+                Some(match open {
+                    b'{' => Value::Object(raw),
+                    _ => Value::Array(raw),
+                })
+            }
+            _ => {
+                for (literal, value) in [
+                    ("true", Value::Bool(true)),
+                    ("false", Value::Bool(false)),
+                    ("null", Value::Null),
+                ] {
+                    if self.s[self.pos..].starts_with(literal) {
+                        self.pos += literal.len();
+                        return Some(value);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Content of a string value with its escape sequences decoded, `None` when
+/// longer than `N` bytes or holding an unpaired surrogate.
+pub fn unescape<const N: usize>(s: &str) -> Option<String<N>> {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let mut code = hex4(&mut chars)?;
+                    // Characters outside the BMP are escaped as a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = hex4(&mut chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    char::from_u32(code)?
+                }
+                // `"`, `\` and `/` stand for themselves
+                c => c,
+            },
+            c => c,
+        };
+        out.push(c).ok()?;
+    }
+    Some(out)
+}
+
+/// Code unit of a `\u` escape sequence
+fn hex4(chars: &mut Chars) -> Option<u32> {
+    let code = u32::from_str_radix(chars.as_str().get(..4)?, 16).ok()?;
+    chars.nth(3);
+    Some(code)
+}
@@ -0,0 +1,66 @@
+use embedded_io_async::{ErrorType, Read, Write};
+
+/// Connection which can wait for incoming data without consuming it.
+///
+/// Waiting can then be raced against other events and cancelled, while the
+/// packet that follows is read to completion: cancelling a read halfway
+/// through a packet would lose the bytes already received and desynchronize
+/// the stream.
+pub struct Lookahead<T> {
+    inner: T,
+    /// Byte received while waiting, returned by the next `read`
+    peeked: Option<u8>,
+}
+
+impl<T> Lookahead<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            peeked: None,
+        }
+    }
+}
+
+impl<T: Read> Lookahead<T> {
+    /// Wait until data is received, or the connection closed, which the
+    /// next `read` reports. Cancel safe as long as `read` of the wrapped
+    /// connection is for a single byte.
+    pub async fn wait_readable(&mut self) -> Result<(), T::Error> {
+        if self.peeked.is_none() {
+            let mut byte = [0];
+            if self.inner.read(&mut byte).await? == 1 {
+                self.peeked = Some(byte[0]);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: ErrorType> ErrorType for Lookahead<T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for Lookahead<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, T::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.peeked.take() {
+            Some(byte) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            None => self.inner.read(buf).await,
+        }
+    }
+}
+
+impl<T: Write> Write for Lookahead<T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, T::Error> {
+        self.inner.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), T::Error> {
+        self.inner.flush().await
+    }
+}
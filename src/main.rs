@@ -4,6 +4,7 @@
 #[cfg(any(feature = "bme280", feature = "scd30"))]
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_net::Stack;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Timer};
@@ -13,6 +14,7 @@ use esp_backtrace as _;
 use esp_hal::{
     self as hal,
     clock::CpuClock,
+    gpio::{Level, Output, OutputConfig},
     ram,
     rng::Rng,
     timer::timg::{MwdtStage, TimerGroup, Wdt},
@@ -31,26 +33,62 @@ use static_cell::StaticCell;
 
 extern crate alloc;
 
+mod app_image;
+mod boot;
+mod boot_loop;
+mod clock;
+mod command;
 pub mod config;
+mod config_store;
 pub mod constants;
+mod crc32;
+#[cfg(feature = "deep_sleep")]
+mod deep_sleep;
+mod diagnostics;
+mod diagnostics_format;
+mod download;
+#[cfg(feature = "homeassistant")]
+mod homeassistant;
+mod http;
+mod identify;
+mod json;
+mod lookahead;
+mod manifest;
 mod measurement;
 mod mqtt;
+mod oci;
 mod ota;
+mod outbox;
+mod provisioning;
+mod rollback;
+mod rtc_record;
+mod sample_store;
 mod semver;
 pub mod sensors;
+mod serializer;
+mod sntp;
+#[cfg(feature = "tls-verify")]
+mod tls_verify;
+mod topic;
 pub mod transport;
 mod wifi;
 
+use command::{Command, LogLevel};
 use config::CONFIG;
 use constants::*;
+#[cfg(feature = "deep_sleep")]
+use deep_sleep::DeepSleep;
 use ota::Ota;
+use outbox::Outbox;
 use measurement::Measurement;
+use mqtt::MqttSession;
 use sensors::Sensors;
 use wifi::Wifi;
 
 #[cfg(any(feature = "bme280", feature = "scd30"))]
 static I2C_BUS: StaticCell<Mutex<NoopRawMutex, I2c<'static, Async>>> = StaticCell::new();
 static STACK: StaticCell<Mutex<NoopRawMutex, Stack<'static>>> = StaticCell::new();
+static FLASH: StaticCell<Mutex<NoopRawMutex, esp_storage::FlashStorage<'static>>> = StaticCell::new();
 
 static RX_BUF: StaticCell<Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>> = StaticCell::new();
 static TX_BUF: StaticCell<Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>> = StaticCell::new();
@@ -74,6 +112,7 @@ async fn main(spawner: Spawner) {
 
     let config = esp_hal::Config::default().with_cpu_clock(CpuClock::max());
     let peripherals = esp_hal::init(config);
+    boot::init();
 
     let rng = Rng::new();
 
@@ -97,11 +136,29 @@ async fn main(spawner: Spawner) {
     // https://github.com/esp-rs/esp-hal/issues/1626
     Timer::after(Duration::from_millis(1000)).await;
 
+    // Apply the configuration stored in flash before anything reads CONFIG
+    let mut flash = esp_storage::FlashStorage::new(peripherals.FLASH);
+    if let Err(e) = config::load(&mut flash) {
+        log::error!("Failed to load stored configuration, using compiled defaults: {:?}", e);
+    }
+    // A new firmware which already failed a boot is rolled back right away
+    ota::init(&mut flash);
+
+    // Counters kept in RTC memory across deep sleep cycles
+    #[cfg(feature = "deep_sleep")]
+    let deep_sleep = DeepSleep::new(peripherals.LPWR);
+
     #[cfg_attr(
         not(any(feature = "bme280", feature = "scd30", feature = "sds011")),
         allow(unused_mut)
     )]
     let mut sensors = Sensors::new();
+    // Sensors skipped in safe mode, reported in the fault status
+    #[cfg_attr(
+        not(any(feature = "bme280", feature = "scd30", feature = "sds011")),
+        allow(unused_mut)
+    )]
+    let mut failed_sensors: heapless::Vec<&'static str, 3> = heapless::Vec::new();
 
     #[cfg(any(feature = "bme280", feature = "scd30"))]
     {
@@ -122,14 +179,12 @@ async fn main(spawner: Spawner) {
 
         #[cfg(feature = "bme280")]
         if (sensors.new_bme280(I2cDevice::new(i2c_bus)).await).is_err() {
-            log::error!("Failed initializing BME280. Rebooting...");
-            esp_hal::system::software_reset();
+            sensor_init_failed("bme280", &mut failed_sensors, &mut flash, &mut wdt0).await;
         }
 
         #[cfg(feature = "scd30")]
         if (sensors.new_scd30(I2cDevice::new(i2c_bus)).await).is_err() {
-            log::error!("Failed initializing SCD30. Rebooting...");
-            esp_hal::system::software_reset();
+            sensor_init_failed("scd30", &mut failed_sensors, &mut flash, &mut wdt0).await;
         }
     }
 
@@ -153,13 +208,10 @@ async fn main(spawner: Spawner) {
         uart.set_at_cmd(hal::uart::AtCmdConfig::default().with_cmd_char(UART_AT_CMD));
 
         if (sensors.new_sds011(uart).await).is_err() {
-            log::error!("Failed initializing SDS011. Rebooting...");
-            esp_hal::system::software_reset();
+            sensor_init_failed("sds011", &mut failed_sensors, &mut flash, &mut wdt0).await;
         }
     }
 
-    let flash = esp_storage::FlashStorage::new(peripherals.FLASH);
-
     let esp_radio_ctrl = &*mk_static!(Controller<'static>, esp_radio::init().unwrap());
 
     let wifi = match Wifi::new(
@@ -173,20 +225,60 @@ async fn main(spawner: Spawner) {
         Ok(wifi) => wifi,
         Err(e) => {
             log::error!("WiFi initialization failed: {:?}. Rebooting...", e);
-            esp_hal::system::software_reset();
+            ota::health_check_failed(&mut flash, rollback::Reason::Wifi);
+            boot::fail(&mut wdt0).await;
         }
     };
 
     // Try to connect to WiFi with a timeout. If it fails, reboot.
     // This prevents the device from hanging indefinitely at boot if WiFi is flaky.
-    match embassy_time::with_timeout(Duration::from_secs(WIFI_INITIAL_CONNECT_TIMEOUT_SECS), wifi.connect()).await {
+    // When the network can't be joined, the provisioning portal, if
+    // configured, is started first so the settings can be fixed without
+    // reflashing.
+    let connected = embassy_time::with_timeout(Duration::from_secs(WIFI_INITIAL_CONNECT_TIMEOUT_SECS), wifi.connect()).await;
+    match connected {
         Ok(Ok(_)) => log::info!("Initial WiFi connection successful"),
         Ok(Err(e)) => {
             log::error!("Initial WiFi connection failed: {:?}. Rebooting...", e);
-            esp_hal::system::software_reset();
+            ota::health_check_failed(&mut flash, rollback::Reason::Wifi);
+            boot::fail(&mut wdt0).await;
         }
         Err(_) => {
-            log::error!("Initial WiFi connection timed out. Rebooting...");
+            // A new firmware which can't join is rolled back rather than
+            // waiting in the portal
+            ota::health_check_failed(&mut flash, rollback::Reason::Wifi);
+
+            // On battery, an unreachable network must not keep the radio up
+            // for long: the portal only starts after a power on or reset
+            #[cfg(feature = "deep_sleep")]
+            if deep_sleep.woke_up() {
+                log::error!("Initial WiFi connection timed out. Going back to sleep...");
+                // An unreachable network isn't a boot loop
+                boot::mark_planned();
+                sensors.sleep().await;
+                deep_sleep.enter();
+            }
+
+            // The portal is opt-in
+            if CONFIG.provisioning_psk.is_none() {
+                log::error!("Initial WiFi connection timed out. Rebooting...");
+                boot::mark_planned();
+                esp_hal::system::software_reset();
+            }
+
+            log::error!("Initial WiFi connection timed out. Starting provisioning portal...");
+            // Smart sleep that feeds watchdog while the portal is up
+            let timeout = async {
+                for _ in 0..PROVISIONING_TIMEOUT_SECS {
+                    Timer::after(Duration::from_secs(1)).await;
+                    wdt0.feed();
+                }
+            };
+            match select(wifi.provision(&mut flash), timeout).await {
+                Either::First(()) => log::info!("Provisioning complete. Rebooting..."),
+                Either::Second(()) => log::info!("Provisioning portal timed out. Rebooting..."),
+            }
+            boot::mark_planned();
             esp_hal::system::software_reset();
         }
     }
@@ -199,6 +291,10 @@ async fn main(spawner: Spawner) {
     }
     let chacha_rng = ChaCha20Rng::from_seed(seed);
 
+    spawner
+        .spawn(clock::sntp_task(wifi.stack, rng))
+        .expect("Failed to spawn SNTP task");
+
     let stack_shared = Mutex::new(wifi.stack);
     let stack_shared = STACK.init(stack_shared);
 
@@ -207,6 +303,16 @@ async fn main(spawner: Spawner) {
     let tls_read_buf = TLS_READ_BUF.init(Mutex::new([0; TLS_BUFFER_MAX]));
     let tls_write_buf = TLS_WRITE_BUF.init(Mutex::new([0; TLS_BUFFER_MAX]));
 
+    let flash = FLASH.init(Mutex::new(flash));
+    // Messages left unpublished before a reboot or deep sleep are still there
+    let outbox = match Outbox::mount(flash).await {
+        Ok(outbox) => Some(outbox),
+        Err(e) => {
+            log::error!("Failed to open the outbox, unpublished messages will be lost: {:?}", e);
+            None
+        }
+    };
+
     let ota = Ota::new(
         stack_shared,
         chacha_rng.clone(),
@@ -217,84 +323,176 @@ async fn main(spawner: Spawner) {
         flash,
     )
     .unwrap();
-    let measurement = Measurement::new(
+    let mqtt_session = MqttSession::new(
         stack_shared,
         chacha_rng,
         rx_buf,
         tx_buf,
         tls_read_buf,
         tls_write_buf,
-        sensors,
-    )
-    .unwrap();
+        outbox,
+    );
+    #[cfg(feature = "deep_sleep")]
+    sensors.restore_failures(deep_sleep.state.sensor_failures);
+    let measurement = Measurement::new(sensors);
 
     spawner
-        .spawn(main_task(ota, measurement, wdt0))
+        .spawn(mqtt::mqtt_task(mqtt_session))
+        .expect("Failed to spawn MQTT task");
+
+    if boot::safe_mode() {
+        diagnostics::publish_fault(&failed_sensors);
+    }
+
+    // In deep sleep mode, the main task verifies it before sleeping
+    #[cfg(not(feature = "deep_sleep"))]
+    if ota::pending() {
+        spawner
+            .spawn(ota::verify_task(flash))
+            .expect("Failed to spawn firmware verification task");
+    }
+
+    // On-board LED of most ESP32 DevKit boards
+    let led = Output::new(peripherals.GPIO2, Level::Low, OutputConfig::default());
+    spawner
+        .spawn(identify::identify_task(led))
+        .expect("Failed to spawn identify task");
+
+    #[cfg(not(feature = "deep_sleep"))]
+    spawner
+        .spawn(diagnostics::diagnostics_task(wifi.stack))
+        .expect("Failed to spawn diagnostics task");
+
+    spawner
+        .spawn(main_task(
+            ota,
+            measurement,
+            wdt0,
+            #[cfg(feature = "deep_sleep")]
+            deep_sleep,
+            #[cfg(feature = "deep_sleep")]
+            wifi.stack,
+        ))
         .expect("Failed to spawn main task");
 }
 
 #[embassy_executor::task]
 async fn main_task(
-    #[cfg_attr(not(feature = "ota"), allow(unused))] mut ota: Ota<'static>,
+    #[cfg_attr(not(feature = "ota"), allow(unused))] mut ota: Ota,
     mut measurement: Measurement,
     mut wdt: Wdt<esp_hal::peripherals::TIMG0<'static>>,
+    #[cfg(feature = "deep_sleep")] mut deep_sleep: DeepSleep,
+    #[cfg(feature = "deep_sleep")] stack: Stack<'static>,
 ) {
+    // Changed by the set_interval command until the next reboot, or power
+    // loss in deep sleep mode
+    let mut interval = CONFIG.measurement_interval_seconds;
+    #[cfg(feature = "deep_sleep")]
+    if deep_sleep.state.measurement_interval_seconds != 0 {
+        interval = deep_sleep.state.measurement_interval_seconds;
+    }
+
     // check for firmware update at boot time
-    #[cfg(feature = "ota")]
-    let mut update_counter: u64 =
-        FIRMWARE_CHECK_INTERVAL / CONFIG.measurement_interval_seconds as u64;
+    #[cfg(all(feature = "ota", not(feature = "deep_sleep")))]
+    let mut update_counter: u64 = FIRMWARE_CHECK_INTERVAL / interval as u64;
+    // every boot is a measurement cycle, the count is kept across deep sleep
+    #[cfg(all(feature = "ota", feature = "deep_sleep"))]
+    let mut update_counter: u64 = deep_sleep.state.ota_counter as u64;
+
+    // Give the first measurement a chance to be timestamped
+    if embassy_time::with_timeout(Duration::from_secs(SNTP_INITIAL_SYNC_TIMEOUT_SECS), clock::synced())
+        .await
+        .is_err()
+    {
+        log::warn!("Clock not synchronised yet, publishing readings without timestamp");
+    }
+
+    #[cfg(not(feature = "deep_sleep"))]
+    let mut wake = Wake::Measure;
 
     loop {
         // Feed watchdog at start of loop
         wdt.feed();
 
+        // Safe mode tries the normal mode again after a while, and a boot
+        // running long enough is no longer part of a boot loop. In deep sleep
+        // mode, checked before sleeping instead.
+        #[cfg(not(feature = "deep_sleep"))]
+        {
+            let uptime = embassy_time::Instant::now().as_secs();
+            if boot::safe_mode() && uptime >= boot::retry_delay_secs() {
+                log::info!("Leaving safe mode to try the normal mode again...");
+                reboot().await;
+            } else if !boot::safe_mode() && !boot::is_stable() && uptime >= BOOT_STABLE_SECS {
+                mark_stable();
+            }
+        }
+
+        #[cfg(not(feature = "deep_sleep"))]
+        match wake {
+            Wake::Measure => {}
+            #[cfg(feature = "ota")]
+            Wake::OtaCheck => update_counter = u64::MAX,
+            Wake::Reboot => reboot().await,
+        }
+
         // Only check for firmware updates periodically
         #[cfg(feature = "ota")]
-        if update_counter >= FIRMWARE_CHECK_INTERVAL / CONFIG.measurement_interval_seconds as u64 {
+        if update_counter >= FIRMWARE_CHECK_INTERVAL / interval as u64 {
             update_counter = 0;
-            if let Err(e) = ota.check().await {
+
+            // The OTA check needs the network buffers held by the MQTT session,
+            // it is skipped when they are not released in time
+            let result = match feeding_watchdog(&mut wdt, mqtt::pause()).await {
+                Ok(()) => {
+                    // Downloading and verifying the image outlasts the watchdog
+                    let result = feeding_watchdog(&mut wdt, ota.check()).await;
+                    mqtt::resume();
+                    result
+                }
+                Err(_) => Ok(false),
+            };
+            wdt.feed();
+
+            if let Ok(true) = result {
+                log::info!("Rebooting into the new firmware...");
+                boot::mark_switched();
+                restart().await;
+            }
+
+            if let Err(e) = result {
                 log::error!("Firmware update error: {:?}", e);
                 // If OTA failed due to network issues, reboot to recover network stack
                 // This handles cases where WiFi disconnected during long OTA operations
                 match e {
-                    ota::Error::Connection | ota::Error::Firmware => {
+                    // Safe mode keeps the device reachable rather than rebooting
+                    ota::Error::Connection if !boot::safe_mode() => {
                         log::error!("OTA failed due to network issues, rebooting to recover...");
-                        Timer::after(Duration::from_millis(1000)).await;
-                        esp_hal::system::software_reset();
+                        reboot().await;
                     }
                     _ => {
-                        // For other OTA errors (config, info), just skip this cycle
+                        // For other OTA errors (config, info, checksum, signature), just skip
+                        // this cycle. A rejected image leaves the running partition active.
                         log::info!("OTA failed due to non-network issues, continuing...");
                     }
                 }
-                // Smart sleep that feeds watchdog
-                for _ in 0..CONFIG.measurement_interval_seconds {
-                    Timer::after(Duration::from_secs(1)).await;
-                    wdt.feed();
+                // In deep sleep mode, go on with the measurement rather than
+                // staying awake
+                #[cfg(not(feature = "deep_sleep"))]
+                {
+                    wake = wait_next_cycle(&mut wdt, &mut interval).await;
+                    continue;
                 }
-                continue;
             }
         }
 
         // Feed before potentially long measurement/network operation
         wdt.feed();
 
-        // Take measurements each cycle
+        // Take measurements each cycle. Broker connection failures are handled
+        // by the MQTT task, so just continue to the next cycle on error.
         if let Err(e) = measurement.take().await {
             log::error!("Measurement error: {:?}", e);
-            // If measurement failed due to transport/network issues, reboot to recover
-            // This handles cases where WiFi disconnected between OTA and measurement
-            match e {
-                measurement::Error::Transport => {
-                    log::error!("Measurement failed due to transport issues, rebooting to recover...");
-                    Timer::after(Duration::from_millis(1000)).await;
-                    esp_hal::system::software_reset();
-                }
-                _ => {
-                    // For sensor or format errors, just continue to next cycle
-                    log::info!("Measurement failed due to non-transport issues, continuing...");
-                }
-            }
         }
 
         #[cfg(feature = "ota")]
@@ -302,10 +500,196 @@ async fn main_task(
             update_counter += 1;
         }
 
+        // Power down until the next measurement, everything but the RTC
+        // memory is lost
+        #[cfg(feature = "deep_sleep")]
+        {
+            // Only the commands received while awake are run, the broker
+            // doesn't keep them while the device sleeps
+            while let Some(request) = mqtt::try_next_command() {
+                match run_command(request, &mut interval) {
+                    Some(Wake::Measure) => {
+                        if let Err(e) = measurement.take().await {
+                            log::error!("Measurement error: {:?}", e);
+                        }
+                    }
+                    // Checked at the next boot
+                    #[cfg(feature = "ota")]
+                    Some(Wake::OtaCheck) => update_counter = u64::MAX,
+                    Some(Wake::Reboot) => reboot().await,
+                    None => {}
+                }
+            }
+
+            // Counted in measurement cycles as the firmware checks
+            let diagnostics_interval = CONFIG.diagnostics_interval_seconds / interval;
+            let diagnostics_counter = &mut deep_sleep.state.diagnostics_counter;
+            if CONFIG.diagnostics_interval_seconds != 0
+                && *diagnostics_counter >= diagnostics_interval as u32
+            {
+                diagnostics::publish(stack);
+                *diagnostics_counter = 0;
+            }
+            *diagnostics_counter = diagnostics_counter.saturating_add(1);
+
+            #[cfg(feature = "ota")]
+            {
+                deep_sleep.state.ota_counter = update_counter.try_into().unwrap_or(u32::MAX);
+            }
+            deep_sleep.state.measurement_interval_seconds =
+                if interval == CONFIG.measurement_interval_seconds {
+                    0
+                } else {
+                    interval
+                };
+            deep_sleep.state.sensor_failures = measurement.sensor_failures();
+            // A new firmware is kept once a publication got through, within
+            // its health check delay
+            feeding_watchdog(&mut wdt, ota.verify()).await;
+            // A completed cycle is a successful boot. Waking up from safe mode
+            // tries the normal mode again.
+            if boot::safe_mode() {
+                boot::mark_planned();
+            } else {
+                mark_stable();
+            }
+            measurement.sleep().await;
+            mqtt::sleep().await;
+            deep_sleep.enter();
+        }
+
+        #[cfg(not(feature = "deep_sleep"))]
+        {
+            wake = wait_next_cycle(&mut wdt, &mut interval).await;
+        }
+    }
+}
+
+/// What the main loop does next after running a command
+enum Wake {
+    Measure,
+    #[cfg(feature = "ota")]
+    OtaCheck,
+    Reboot,
+}
+
+/// Run `future` to completion while feeding the watchdog, for the steps which
+/// may outlast its timeout. Only this task feeds it.
+#[cfg(any(feature = "ota", feature = "deep_sleep"))]
+async fn feeding_watchdog<F: core::future::Future>(
+    wdt: &mut Wdt<esp_hal::peripherals::TIMG0<'static>>,
+    future: F,
+) -> F::Output {
+    match select(future, feed_watchdog(wdt)).await {
+        Either::First(output) => output,
+        Either::Second(never) => never,
+    }
+}
+
+#[cfg(any(feature = "ota", feature = "deep_sleep"))]
+async fn feed_watchdog(wdt: &mut Wdt<esp_hal::peripherals::TIMG0<'static>>) -> ! {
+    loop {
+        wdt.feed();
+        Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Wait `interval` seconds for the next measurement. Commands are run
+/// meanwhile, the wait ends early for those returning a `Wake`.
+#[cfg(not(feature = "deep_sleep"))]
+async fn wait_next_cycle(
+    wdt: &mut Wdt<esp_hal::peripherals::TIMG0<'static>>,
+    interval: &mut u16,
+) -> Wake {
+    let next = embassy_time::Instant::now() + Duration::from_secs(*interval as u64);
+    loop {
         // Smart sleep that feeds watchdog instead of single long sleep
-        for _ in 0..CONFIG.measurement_interval_seconds {
-            Timer::after(Duration::from_secs(1)).await;
-            wdt.feed();
+        wdt.feed();
+        let now = embassy_time::Instant::now();
+        if now >= next {
+            return Wake::Measure;
+        }
+        let tick = Timer::after((next - now).min(Duration::from_secs(1)));
+        if let Either::Second(request) = select(tick, mqtt::next_command()).await {
+            if let Some(wake) = run_command(request, interval) {
+                return wake;
+            }
         }
     }
 }
+
+/// Run a command received from the broker and publish its result.
+fn run_command(request: mqtt::CommandRequest, interval: &mut u16) -> Option<Wake> {
+    let (result, wake) = match request.command {
+        Command::Reboot => (Ok(()), Some(Wake::Reboot)),
+        #[cfg(feature = "ota")]
+        Command::OtaCheck => (Ok(()), Some(Wake::OtaCheck)),
+        #[cfg(not(feature = "ota"))]
+        Command::OtaCheck => (Err(command::Error::Unsupported), None),
+        Command::MeasureNow => (Ok(()), Some(Wake::Measure)),
+        Command::SetInterval(secs) => {
+            log::info!("Measurement interval set to {}s", secs);
+            *interval = secs;
+            (Ok(()), None)
+        }
+        Command::SetLogLevel(level) => {
+            log::set_max_level(match level {
+                LogLevel::Off => log::LevelFilter::Off,
+                LogLevel::Error => log::LevelFilter::Error,
+                LogLevel::Warn => log::LevelFilter::Warn,
+                LogLevel::Info => log::LevelFilter::Info,
+                LogLevel::Debug => log::LevelFilter::Debug,
+                LogLevel::Trace => log::LevelFilter::Trace,
+            });
+            (Ok(()), None)
+        }
+        Command::Identify => {
+            identify::start();
+            (Ok(()), None)
+        }
+    };
+    request.reply(result);
+    wake
+}
+
+/// Carry on without a sensor which failed to initialise in safe mode, reboot
+/// otherwise. A new firmware is rolled back instead.
+#[cfg(any(feature = "bme280", feature = "scd30", feature = "sds011"))]
+async fn sensor_init_failed(
+    name: &'static str,
+    failed_sensors: &mut heapless::Vec<&'static str, 3>,
+    flash: &mut esp_storage::FlashStorage<'static>,
+    wdt: &mut Wdt<esp_hal::peripherals::TIMG0<'static>>,
+) {
+    ota::health_check_failed(flash, rollback::Reason::Sensors);
+    if boot::safe_mode() {
+        log::error!("Failed initializing {}, skipped in safe mode", name);
+        let _ = failed_sensors.push(name);
+    } else {
+        log::error!("Failed initializing {}. Rebooting...", name);
+        boot::fail(wdt).await;
+    }
+}
+
+/// Mark the boot as stable, removing the fault status of a past boot loop.
+fn mark_stable() {
+    if boot::failed_boots() > 0 {
+        log::info!("Recovered from {} failed boots", boot::failed_boots());
+        diagnostics::clear_fault();
+    }
+    boot::mark_stable();
+}
+
+/// Planned reboot. Lets the MQTT task announce the device offline first so the
+/// availability topic doesn't keep reporting "online" during the restart.
+async fn reboot() -> ! {
+    boot::mark_planned();
+    restart().await
+}
+
+/// Reboot once the outcome of the boot is marked, see `reboot`.
+async fn restart() -> ! {
+    mqtt::shutdown().await;
+    Timer::after(Duration::from_millis(1000)).await;
+    esp_hal::system::software_reset();
+}
@@ -0,0 +1,313 @@
+//! Firmware manifest served by the OTA server on `/version`.
+//!
+//! The manifest is a JSON object describing the latest firmware for the
+//! device, versioned by `manifest_version`:
+//!
+//! ```text
+//! {
+//!   "manifest_version": 1,
+//!   "version": "0.1.4",
+//!   "size": 1835008,
+//!   "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+//!   "signature": "MEUCIQD...",
+//!   "min_current_version": "0.1.0",
+//!   "current_version_requirement": ">=0.1.3, <0.3.0",
+//!   "allow_downgrade": false,
+//!   "channel": "stable",
+//!   "rollout_percentage": 10,
+//!   "release_notes_url": "https://example.com/releases/0.1.4",
+//!   "download_url": "/firmware/esp32-outdoor/0.1.4.bin"
+//! }
+//! ```
+//!
+//! `rollout_percentage` stages the release: only the devices whose
+//! `rollout_bucket` is below it install it, all of them when missing.
+//!
+//! `current_version_requirement` restricts the running versions upgrading to
+//! the release, e.g. one carrying a migration which can't be skipped. Devices
+//! are only offered versions older than the running one, the rollback
+//! releases, with `allow_downgrade`.
+//!
+//! A server without firmware for the device answers with an `error` member
+//! instead: `{"manifest_version":1,"error":"No firmware for device"}`.
+//!
+//! Servers predating the manifest answer with the version, CRC32, size and
+//! signature, one per line, which is still accepted.
+//!
+//! Only depends on `core` and `heapless` so it can be unit tested on the host,
+//! see `tools/firmware-core`.
+
+use heapless::String;
+
+use crate::crc32::Crc32;
+use crate::http;
+use crate::json::{parse_object, unescape, Value};
+use crate::rtc_record::VERSION_MAX_LEN;
+use crate::semver::VersionReq;
+
+/// Latest `manifest_version` understood
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Longest base64 signature, a DER signature takes up to 96 characters
+pub const SIGNATURE_MAX_LEN: usize = 128;
+
+/// Longest channel name
+pub const CHANNEL_MAX_LEN: usize = 16;
+
+/// Longest URL, the download one must fit in a request path
+pub const URL_MAX_LEN: usize = crate::http::PATH_MAX_LEN;
+
+/// Longest error message kept, longer ones are dropped
+pub const ERROR_MAX_LEN: usize = 96;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Neither a JSON object nor the legacy format
+    Syntax,
+    /// `manifest_version` missing or newer than `MANIFEST_VERSION`
+    UnsupportedVersion,
+    /// The named member is missing, of the wrong type or too long
+    Invalid(&'static str),
+    /// The server has no firmware for the device, with its explanation
+    /// (empty when too long)
+    NoFirmware(String<ERROR_MAX_LEN>),
+    /// `download_url` points to another server
+    ForeignUrl,
+}
+
+/// Checksum of the image, verified over the flash content once downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// Advertised by legacy servers
+    Crc32(u32),
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    /// Short identifier of the image, to tell whether an interrupted download
+    /// is for the same one.
+    pub fn fingerprint(&self) -> u32 {
+        match self {
+            Checksum::Crc32(crc) => *crc,
+            Checksum::Sha256(digest) => {
+                u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+            }
+        }
+    }
+}
+
+/// Latest firmware for the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub version: String<VERSION_MAX_LEN>,
+    pub size: u32,
+    pub checksum: Checksum,
+    /// Base64 ECDSA P-256 signature of the image
+    pub signature: String<SIGNATURE_MAX_LEN>,
+    /// Oldest running version allowed to install it, older ones need an
+    /// intermediate release first
+    pub min_current_version: Option<String<VERSION_MAX_LEN>>,
+    /// Running versions allowed to install it, the others need another
+    /// release first
+    pub current_version_requirement: Option<VersionReq>,
+    /// Whether it is installed over newer versions, to roll a release back
+    pub allow_downgrade: bool,
+    pub channel: Option<String<CHANNEL_MAX_LEN>>,
+    /// Share of the devices the release is rolled out to, 0 to 100
+    pub rollout_percentage: u8,
+    pub release_notes_url: Option<String<URL_MAX_LEN>>,
+    /// Where to download the image, `/firmware?device=<id>` otherwise
+    pub download_url: Option<String<URL_MAX_LEN>>,
+}
+
+impl Manifest {
+    /// Path of `download_url` on the OTA server at `host` and `port`, `None`
+    /// when the manifest has none. An absolute URL must point to the same
+    /// server, the image is downloaded on the connection of the manifest.
+    pub fn download_path(&self, host: &str, port: u16) -> Result<Option<&str>, Error> {
+        self.download_url
+            .as_deref()
+            .map(|url| http::same_host_path(url, host, port).ok_or(Error::ForeignUrl))
+            .transpose()
+    }
+
+    /// Whether the release is rolled out to the device
+    pub fn is_rolled_out_to(&self, device_id: &str) -> bool {
+        rollout_bucket(device_id) < self.rollout_percentage
+    }
+}
+
+/// Rollout bucket of the device, 0 to 99. Derived from the device ID so a
+/// device stays in the same bucket across checks and releases, and a release
+/// rolled out to a larger share still includes the first devices.
+pub fn rollout_bucket(device_id: &str) -> u8 {
+    let mut crc = Crc32::new();
+    crc.update(device_id.as_bytes());
+    (crc.finalize() % 100) as u8
+}
+
+/// Parse the body of a `/version` response, a JSON manifest or the legacy
+/// line format.
+pub fn parse(body: &[u8]) -> Result<Manifest, Error> {
+    let body = core::str::from_utf8(body).map_err(|_| Error::Syntax)?;
+    let body = body.trim();
+    if body.starts_with('{') {
+        parse_json(body)
+    } else {
+        parse_legacy(body)
+    }
+}
+
+fn parse_json(body: &str) -> Result<Manifest, Error> {
+    let mut manifest_version = None;
+    let mut error = None;
+    let mut version = None;
+    let mut size = None;
+    let mut sha256 = None;
+    let mut signature = None;
+    let mut min_current_version = None;
+    let mut current_version_requirement = None;
+    let mut allow_downgrade = None;
+    let mut channel = None;
+    let mut rollout_percentage = None;
+    let mut release_notes_url = None;
+    let mut download_url = None;
+
+    parse_object(body, |key, value| match key {
+        "manifest_version" => manifest_version = Some(value),
+        "error" => error = Some(value),
+        "version" => version = Some(value),
+        "size" => size = Some(value),
+        "sha256" => sha256 = Some(value),
+        "signature" => signature = Some(value),
+        "min_current_version" => min_current_version = Some(value),
+        "current_version_requirement" => current_version_requirement = Some(value),
+        "allow_downgrade" => allow_downgrade = Some(value),
+        "channel" => channel = Some(value),
+        "rollout_percentage" => rollout_percentage = Some(value),
+        "release_notes_url" => release_notes_url = Some(value),
+        "download_url" => download_url = Some(value),
+        _ => {}
+    })
+    .ok_or(Error::Syntax)?;
+
+    match manifest_version {
+        Some(Value::Number(n)) if n.parse::<u32>().is_ok_and(|n| n <= MANIFEST_VERSION) => {}
+        _ => return Err(Error::UnsupportedVersion),
+    }
+    if let Some(error) = error {
+        let message = match error {
+            Value::Str(message) => unescape(message).unwrap_or_default(),
+            _ => String::new(),
+        };
+        return Err(Error::NoFirmware(message));
+    }
+
+    let sha256 = match sha256 {
+        Some(Value::Str(hex)) => parse_hex(hex).ok_or(Error::Invalid("sha256"))?,
+        _ => return Err(Error::Invalid("sha256")),
+    };
+    Ok(Manifest {
+        version: string(version, "version")?,
+        size: match size {
+            Some(Value::Number(n)) => n.parse().map_err(|_| Error::Invalid("size"))?,
+            _ => return Err(Error::Invalid("size")),
+        },
+        checksum: Checksum::Sha256(sha256),
+        signature: string(signature, "signature")?,
+        min_current_version: optional_string(min_current_version, "min_current_version")?,
+        current_version_requirement: match current_version_requirement {
+            None | Some(Value::Null) => None,
+            Some(Value::Str(requirement)) => Some(
+                VersionReq::parse(requirement)
+                    .ok_or(Error::Invalid("current_version_requirement"))?,
+            ),
+            Some(_) => return Err(Error::Invalid("current_version_requirement")),
+        },
+        allow_downgrade: match allow_downgrade {
+            None | Some(Value::Null) => false,
+            Some(Value::Bool(allow)) => allow,
+            Some(_) => return Err(Error::Invalid("allow_downgrade")),
+        },
+        channel: optional_string(channel, "channel")?,
+        rollout_percentage: match rollout_percentage {
+            None | Some(Value::Null) => 100,
+            Some(Value::Number(n)) => n
+                .parse()
+                .ok()
+                .filter(|n| *n <= 100)
+                .ok_or(Error::Invalid("rollout_percentage"))?,
+            Some(_) => return Err(Error::Invalid("rollout_percentage")),
+        },
+        release_notes_url: optional_string(release_notes_url, "release_notes_url")?,
+        download_url: optional_string(download_url, "download_url")?,
+    })
+}
+
+/// Version, CRC32, size and signature, one per line. Anything after them is
+/// ignored.
+fn parse_legacy(body: &str) -> Result<Manifest, Error> {
+    let mut lines = body.lines().map(str::trim);
+    let version = lines.next().filter(|line| !line.is_empty());
+    let version = version
+        .and_then(|line| String::try_from(line).ok())
+        .ok_or(Error::Syntax)?;
+    let crc32 = lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or(Error::Invalid("crc32"))?;
+    let size = lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or(Error::Invalid("size"))?;
+    let signature = lines
+        .next()
+        .filter(|line| !line.is_empty())
+        .and_then(|line| String::try_from(line).ok())
+        .ok_or(Error::Invalid("signature"))?;
+    Ok(Manifest {
+        version,
+        size,
+        checksum: Checksum::Crc32(crc32),
+        signature,
+        min_current_version: None,
+        current_version_requirement: None,
+        allow_downgrade: false,
+        channel: None,
+        rollout_percentage: 100,
+        release_notes_url: None,
+        download_url: None,
+    })
+}
+
+/// Content of the string member `name`
+fn string<const N: usize>(value: Option<Value>, name: &'static str) -> Result<String<N>, Error> {
+    match value {
+        Some(Value::Str(s)) => unescape(s).ok_or(Error::Invalid(name)),
+        _ => Err(Error::Invalid(name)),
+    }
+}
+
+/// Content of the string member `name`, `None` when missing or null
+fn optional_string<const N: usize>(
+    value: Option<Value>,
+    name: &'static str,
+) -> Result<Option<String<N>>, Error> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        value => string(value, name).map(Some),
+    }
+}
+
+/// SHA-256 digest written in hexadecimal
+pub fn parse_hex(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
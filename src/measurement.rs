@@ -1,153 +1,82 @@
-use embassy_net::Stack;
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
 use heapless::String;
-use rand_chacha::ChaCha20Rng;
-use rust_mqtt::buffer::AllocBuffer;
-use static_cell::StaticCell;
 
+use crate::clock;
 use crate::config::CONFIG;
 use crate::constants::*;
-use crate::mqtt::Mqtt;
+use crate::mqtt;
 use crate::sensors::{SensorData, Sensors};
-use crate::transport::Transport;
-
-static MQTT_BUFFER: StaticCell<Mutex<NoopRawMutex, AllocBuffer>> = StaticCell::new();
+use crate::serializer::{self, Timestamp};
 
 #[derive(Debug)]
 pub enum Error {
-    Sensor,
-    Transport,
     Mqtt,
     Format,
 }
 
 pub struct Measurement {
-    stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
-    rng: ChaCha20Rng,
-    rx_buf: &'static Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>,
-    tx_buf: &'static Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>,
-    tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
-    tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
-    mqtt_buffer: &'static Mutex<NoopRawMutex, AllocBuffer>,
     sensors: Sensors,
 }
 
 impl Measurement {
-    pub fn new(
-        stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
-        rng: ChaCha20Rng,
-        rx_buf: &'static Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>,
-        tx_buf: &'static Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>,
-        tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
-        tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
-        sensors: Sensors,
-    ) -> Result<Self, Error> {
-        let mqtt_buffer = MQTT_BUFFER.init(Mutex::new(AllocBuffer));
-
-        Ok(Self {
-            stack,
-            rng,
-            rx_buf,
-            tx_buf,
-            tls_read_buf,
-            tls_write_buf,
-            mqtt_buffer,
-            sensors,
-        })
+    pub fn new(sensors: Sensors) -> Self {
+        Self { sensors }
     }
 
     pub async fn take(&mut self) -> Result<(), Error> {
-        // Measure sensor data first
-        let sensor_data = self.sensors.measure().await.map_err(|_| Error::Sensor)?;
+        // Measure sensor data first. Failing sensors are reported in the
+        // payload, the readings of the others are still published.
+        let sensor_data = self.sensors.measure().await;
         log::debug!("Sensor data received: {:?}", sensor_data);
+        crate::diagnostics::record_sensor_failures(self.sensors.failures());
+        let timestamp = clock::now().map_or(Timestamp::Unsynced, Timestamp::Synced);
+
+        #[cfg(feature = "homeassistant")]
+        crate::homeassistant::update_sensors(&sensor_data);
 
         // Format MQTT message
-        let message = format_mqtt_message(&sensor_data).map_err(|_| Error::Format)?;
+        let message = format_mqtt_message(&sensor_data, timestamp).map_err(|e| {
+            log::error!("Failed to format MQTT message: {:?}", e);
+            Error::Format
+        })?;
         log::debug!("Formatted MQTT message: {}", message);
 
-        // Acquire locks for shared resources only when needed
-        let stack_guard = self.stack.lock().await;
-        let mut rx_buf = self.rx_buf.lock().await;
-        let mut tx_buf = self.tx_buf.lock().await;
-        let mut tls_read_buf = self.tls_read_buf.lock().await;
-        let mut tls_write_buf = self.tls_write_buf.lock().await;
-
-        // Create transport session
-        let transport = Transport::new(
-            *stack_guard,
-            &mut self.rng,
-            &mut *rx_buf,
-            &mut *tx_buf,
-            &mut *tls_read_buf,
-            &mut *tls_write_buf,
-            CONFIG.mqtt_hostname,
-            CONFIG.mqtt_port,
-        )
-        .await
-        .map_err(|e| {
-            log::error!("Transport creation failed: {:?}", e);
-            Error::Transport
+        // Hand the message over to the MQTT task which owns the broker
+        // connection
+        mqtt::publish(CONFIG.mqtt_topic, message.as_bytes(), false).map_err(|e| {
+            log::error!("Failed to queue MQTT message: {:?}", e);
+            Error::Mqtt
         })?;
 
-        // Create MQTT client
-        let mut mqtt_buffer = self.mqtt_buffer.lock().await;
-        let mut mqtt = Mqtt::new(transport, &mut *mqtt_buffer)
-            .await
-            .map_err(|_| Error::Mqtt)?;
-
-        // Publish MQTT message
-        mqtt.send_message(CONFIG.mqtt_topic, message.as_bytes())
-            .await
-            .map_err(|_| Error::Mqtt)?;
-
-        // Explicitly disconnect MQTT
-        mqtt.disconnect().await;
+        Ok(())
+    }
 
-        // Allow network stack time to process socket cleanup.
-        // Without this, rapid reconnections can exhaust sockets before
-        // the previous connection is fully closed.
-        Timer::after(Duration::from_millis(MQTT_DISCONNECT_CLEANUP_DELAY_MS)).await;
+    #[cfg(feature = "deep_sleep")]
+    pub fn sensor_failures(&self) -> crate::sensors::Failures {
+        self.sensors.failures()
+    }
 
-        log::info!("MQTT data published successfully");
-        Ok(())
+    /// Put the sensors in their low-power state before deep sleep.
+    #[cfg(feature = "deep_sleep")]
+    pub async fn sleep(&mut self) {
+        self.sensors.sleep().await;
     }
 }
 
-fn format_mqtt_message(sensor_data: &SensorData) -> Result<String<256>, core::fmt::Error> {
-    use core::fmt::Write;
-    let mut payload: String<256> = String::new();
+/// Format the MQTT payload. JSON takes precedence when both formats are
+/// enabled, since `influx` is part of the default features.
+fn format_mqtt_message(
+    sensor_data: &SensorData,
+    timestamp: Timestamp,
+) -> Result<String<MQTT_PAYLOAD_MAX_LEN>, serializer::Error> {
+    let mut payload: String<MQTT_PAYLOAD_MAX_LEN> = String::new();
+    let tags = [("location", CONFIG.location), ("firmware", VERSION)];
+    let fields = sensor_data.data.iter().map(|(key, value)| (*key, *value));
 
     #[cfg(feature = "json")]
-    {
-        write!(
-            payload,
-            "{{\"location\": \"{}\",\"firmware\": \"{}\"",
-            CONFIG.location, VERSION,
-        )?;
-        for (key, value) in sensor_data.data.iter() {
-            write!(payload, ", \"{}\": \"{:.2}\"", key, value)?;
-        }
-        write!(payload, "}}")?;
-    }
+    serializer::write_json(&mut payload, &tags, fields, timestamp)?;
 
-    #[cfg(feature = "influx")]
-    {
-        write!(
-            payload,
-            "weather,location={},firmware={}",
-            CONFIG.location, VERSION,
-        )?;
-        let mut first = true;
-        for (key, value) in sensor_data.data.iter() {
-            if first {
-                write!(payload, " {}={:.2}", key, value)?;
-                first = false;
-            } else {
-                write!(payload, ",{}={:.2}", key, value)?;
-            }
-        }
-    }
+    #[cfg(all(feature = "influx", not(feature = "json")))]
+    serializer::write_influx(&mut payload, "weather", &tags, fields, timestamp)?;
 
     Ok(payload)
 }
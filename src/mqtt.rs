@@ -1,57 +1,383 @@
-use embedded_io_async::{Read, Write};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
+use embassy_net::Stack;
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    channel::{Channel, TrySendError},
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant, TimeoutError, Timer};
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::{String, Vec};
+use rand_chacha::ChaCha20Rng;
 use rust_mqtt::{
     buffer::AllocBuffer,
     client::{
-        options::{ConnectOptions, DisconnectOptions, PublicationOptions},
+        event::{Event, Publish},
+        options::{
+            ConnectOptions, DisconnectOptions, PublicationOptions, RetainHandling,
+            SubscriptionOptions, WillOptions,
+        },
         Client,
     },
-    config::KeepAlive,
-    types::{MqttBinary, MqttString, QoS, TopicName},
+    config::{KeepAlive, SessionExpiryInterval},
+    types::{MqttBinary, MqttString, QoS, TopicFilter, TopicName},
     Bytes,
 };
+use static_cell::StaticCell;
 
+use crate::boot;
+use crate::command::{self, Command};
 use crate::config::CONFIG;
-use crate::constants::{MQTT_MAX_SUBSCRIBES, MQTT_RECEIVE_MAXIMUM, MQTT_SEND_MAXIMUM};
+use crate::constants::*;
+use crate::lookahead::Lookahead;
+use crate::outbox::Outbox;
+use crate::transport::Transport;
+
+static MQTT_BUFFER: StaticCell<Mutex<NoopRawMutex, AllocBuffer>> = StaticCell::new();
+
+/// Messages queued for the MQTT task
+static PUBLICATIONS: Channel<CriticalSectionRawMutex, Publication, MQTT_PUBLISH_QUEUE_SIZE> =
+    Channel::new();
+
+/// Command results, published as soon as possible while connected. Unlike
+/// the other messages they are never stored in the outbox: a result published
+/// long after its command, possibly after a reboot, would only mislead.
+static RESULTS: Channel<CriticalSectionRawMutex, Publication, MQTT_COMMAND_RESULT_QUEUE_SIZE> =
+    Channel::new();
+
+/// Commands received from the broker, run by the main task
+static COMMANDS: Channel<CriticalSectionRawMutex, CommandRequest, MQTT_COMMAND_QUEUE_SIZE> =
+    Channel::new();
+
+/// Broker connections lost or failed since boot
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the broker acknowledged a publication since boot, raised once
+static ACKNOWLEDGED: AtomicBool = AtomicBool::new(false);
+static FIRST_ACK: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Pause/resume handshake used to hand the shared network buffers over to
+/// another user (OTA) without tearing down the MQTT task.
+static PAUSE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static PAUSED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static RESUME: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Shutdown handshake used to leave the broker cleanly before the device goes
+/// away (planned reboot or deep sleep).
+static SHUTDOWN_REQUEST: Signal<CriticalSectionRawMutex, Shutdown> = Signal::new();
+static SHUTDOWN_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Availability payloads, retained on `CONFIG.availability_topic()`
+const AVAILABILITY_ONLINE: &str = "online";
+const AVAILABILITY_OFFLINE: &str = "offline";
 
 #[derive(Debug)]
 pub enum Error {
     ConnectionFailed,
+    ConnectionLost,
     PublishMessageFailed,
+    SubscribeFailed,
+    QueueFull,
+    MessageTooLarge,
+    Transport,
+}
+
+/// Why the MQTT task stopped serving the broker connection
+enum Control {
+    Pause,
+    Shutdown(Shutdown),
+}
+
+/// How the device leaves the broker
+#[derive(Clone, Copy)]
+enum Shutdown {
+    /// Planned reboot: the broker publishes the "offline" Last Will
+    Reboot,
+    /// Deep sleep: queued messages are published first and the device stays
+    /// "online", it is back for the next measurement. The Last Will is kept
+    /// but delayed, it only fires if the device misses its next wake-up
+    #[cfg(feature = "deep_sleep")]
+    Sleep,
+}
+
+/// A message waiting to be published by the MQTT task
+pub struct Publication {
+    topic: String<MQTT_TOPIC_MAX_LEN>,
+    payload: Vec<u8, MQTT_PAYLOAD_MAX_LEN>,
+    retain: bool,
+}
+
+impl Publication {
+    fn new(topic: &str, payload: &[u8], retain: bool) -> Result<Self, Error> {
+        Ok(Self {
+            topic: String::try_from(topic).map_err(|_| Error::MessageTooLarge)?,
+            payload: Vec::from_slice(payload).map_err(|_| Error::MessageTooLarge)?,
+            retain,
+        })
+    }
+}
+
+/// A command waiting to be run by the main task
+pub struct CommandRequest {
+    /// Correlation ID, JSON escaped
+    id: Option<String<{ command::ID_MAX_LEN }>>,
+    pub command: Command,
+}
+
+impl CommandRequest {
+    /// Publish the result of the command on the result topic.
+    pub fn reply(&self, result: Result<(), command::Error>) {
+        publish_result(self.id.as_deref(), Some(self.command), result);
+    }
+}
+
+/// Wait for the next command received on `CONFIG.command_topic()`.
+pub async fn next_command() -> CommandRequest {
+    COMMANDS.receive().await
+}
+
+/// Command received on `CONFIG.command_topic()`, if any.
+#[cfg(feature = "deep_sleep")]
+pub fn try_next_command() -> Option<CommandRequest> {
+    COMMANDS.try_receive().ok()
+}
+
+/// Queue the result of a command for publication on the `result` subtopic of
+/// the command topic, see `RESULTS`.
+fn publish_result(id: Option<&str>, command: Option<Command>, result: Result<(), command::Error>) {
+    let mut topic: String<MQTT_TOPIC_MAX_LEN> = String::new();
+    let mut payload: String<MQTT_COMMAND_RESULT_MAX_LEN> = String::new();
+    let Some(command_topic) = CONFIG.command_topic() else {
+        log::error!("Command topic too long");
+        return;
+    };
+    if write!(topic, "{}/result", command_topic).is_err()
+        || command::write_result(&mut payload, id, command, result).is_err()
+    {
+        log::error!("Command result too large");
+        return;
+    }
+    let Ok(publication) = Publication::new(&topic, payload.as_bytes(), false) else {
+        log::error!("Command result too large");
+        return;
+    };
+    if RESULTS.try_send(publication).is_err() {
+        log::warn!("Command result queue full, dropping result");
+    }
+}
+
+/// Parse a command and hand it over to the main task, replying right away
+/// when it is invalid.
+fn handle_command(publish: Publish<'_>) {
+    let request = command::parse(&publish.message);
+    let command = match request.command {
+        Ok(command) => command,
+        Err(e) => {
+            log::warn!("Invalid command: {}", e.as_str());
+            publish_result(request.id, None, Err(e));
+            return;
+        }
+    };
+    log::info!("Received command {}", command.name());
+
+    // Fits, `parse` checks the length
+    let id = request.id.and_then(|id| String::try_from(id).ok());
+    if let Err(TrySendError::Full(request)) = COMMANDS.try_send(CommandRequest { id, command }) {
+        log::warn!("Command queue full, dropping {}", command.name());
+        publish_result(request.id.as_deref(), Some(command), Err(command::Error::Busy));
+    }
+}
+
+/// Queue a message for publication. Returns as soon as the message is queued,
+/// the MQTT task publishes it once the broker connection is up.
+pub fn publish(topic: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+    let publication = Publication::new(topic, payload, retain)?;
+
+    PUBLICATIONS.try_send(publication).map_err(|_| {
+        log::warn!("MQTT publish queue full, dropping message for {}", topic);
+        Error::QueueFull
+    })
+}
+
+/// Broker connections lost or failed since boot, reported in the diagnostics.
+pub fn reconnect_count() -> u32 {
+    RECONNECTS.load(Ordering::Relaxed)
+}
+
+/// Wait for the broker to acknowledge a first publication, returns at once
+/// when it already did.
+pub async fn acknowledged() {
+    if !ACKNOWLEDGED.load(Ordering::Acquire) {
+        FIRST_ACK.wait().await;
+    }
+}
+
+/// Ask the MQTT task to disconnect and release the shared network buffers.
+/// Returns once the buffers are free to use, call `resume` when done. Gives
+/// up after `MQTT_PAUSE_TIMEOUT_SECS`, the buffers are then still in use and
+/// `resume` must not be called.
+pub async fn pause() -> Result<(), TimeoutError> {
+    log::info!("Pausing MQTT session...");
+    // Left over by a pause which timed out
+    PAUSED.reset();
+    RESUME.reset();
+    PAUSE_REQUEST.signal(());
+    let paused = with_timeout(Duration::from_secs(MQTT_PAUSE_TIMEOUT_SECS), PAUSED.wait()).await;
+    if paused.is_err() {
+        log::warn!("Timed out pausing the MQTT session");
+        // Withdraw the request, or let the task go on if it paused meanwhile
+        PAUSE_REQUEST.reset();
+        RESUME.signal(());
+    }
+    paused
+}
+
+/// Let the MQTT task reconnect after a `pause`.
+pub fn resume() {
+    log::info!("Resuming MQTT session");
+    RESUME.signal(());
+}
+
+/// Announce a planned reboot: the broker publishes the retained "offline"
+/// availability and the MQTT task stops for good. Gives up after
+/// `MQTT_SHUTDOWN_TIMEOUT_SECS` so a broken network never blocks the reboot.
+pub async fn shutdown() {
+    log::info!("Shutting down MQTT session...");
+    request_shutdown(Shutdown::Reboot).await;
+}
+
+/// Publish the queued messages and disconnect before deep sleep, keeping the
+/// "online" availability. Gives up after `MQTT_SHUTDOWN_TIMEOUT_SECS`, the
+/// messages not published by then are lost.
+#[cfg(feature = "deep_sleep")]
+pub async fn sleep() {
+    log::info!("Flushing MQTT session before deep sleep...");
+    request_shutdown(Shutdown::Sleep).await;
+}
+
+async fn request_shutdown(shutdown: Shutdown) {
+    SHUTDOWN_REQUEST.signal(shutdown);
+    if with_timeout(
+        Duration::from_secs(MQTT_SHUTDOWN_TIMEOUT_SECS),
+        SHUTDOWN_DONE.wait(),
+    )
+    .await
+    .is_err()
+    {
+        log::warn!("Timed out leaving the broker");
+    }
+}
+
+/// Connection used by the client, shared with `Mqtt::wait_readable`
+struct Shared<'a, T>(&'a Mutex<NoopRawMutex, Lookahead<T>>);
+
+impl<T: ErrorType> ErrorType for Shared<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for Shared<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, T::Error> {
+        self.0.lock().await.read(buf).await
+    }
+}
+
+impl<T: Write> Write for Shared<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, T::Error> {
+        self.0.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), T::Error> {
+        self.0.lock().await.flush().await
+    }
 }
 
 pub struct Mqtt<'a, T>
 where
     T: Read + Write,
 {
-    client: Client<'a, T, AllocBuffer, MQTT_MAX_SUBSCRIBES, MQTT_RECEIVE_MAXIMUM, MQTT_SEND_MAXIMUM>,
+    client: Client<
+        'a,
+        Shared<'a, T>,
+        AllocBuffer,
+        MQTT_MAX_SUBSCRIBES,
+        MQTT_RECEIVE_MAXIMUM,
+        MQTT_SEND_MAXIMUM,
+    >,
+    transport: &'a Mutex<NoopRawMutex, Lookahead<T>>,
+    awaiting_pingresp: bool,
 }
 
 impl<'a, T> Mqtt<'a, T>
 where
     T: Read + Write,
 {
-    pub async fn new(transport: T, buffer: &'a mut AllocBuffer) -> Result<Self, Error> {
-        let mut client =
-            Client::<'_, T, AllocBuffer, MQTT_MAX_SUBSCRIBES, MQTT_RECEIVE_MAXIMUM, MQTT_SEND_MAXIMUM>::new(buffer);
+    pub async fn new(
+        transport: &'a Mutex<NoopRawMutex, Lookahead<T>>,
+        buffer: &'a mut AllocBuffer,
+    ) -> Result<Self, Error> {
+        let mut client = Client::<
+            '_,
+            Shared<'a, T>,
+            AllocBuffer,
+            MQTT_MAX_SUBSCRIBES,
+            MQTT_RECEIVE_MAXIMUM,
+            MQTT_SEND_MAXIMUM,
+        >::new(buffer);
+
+        let availability_topic = CONFIG.availability_topic().ok_or_else(|| {
+            log::error!("Availability topic too long");
+            Error::ConnectionFailed
+        })?;
+        // On battery, the device disconnects before each deep sleep: the
+        // session and the Last Will outlive the connection and are picked up
+        // again on wake-up, so the broker only publishes "offline" once the
+        // device is overdue
+        #[cfg(feature = "deep_sleep")]
+        let (clean_start, session_expiry_interval, will_delay_interval) = {
+            let delay = u32::from(CONFIG.measurement_interval_seconds) * MQTT_WILL_DELAY_INTERVALS;
+            (false, SessionExpiryInterval::Seconds(delay), delay)
+        };
+        #[cfg(not(feature = "deep_sleep"))]
+        let (clean_start, session_expiry_interval, will_delay_interval) =
+            (true, SessionExpiryInterval::EndOnDisconnect, 0);
 
         let connect_options = ConnectOptions {
-            clean_start: true,
-            keep_alive: KeepAlive::Seconds(30),
-            session_expiry_interval: Default::default(),
+            clean_start,
+            keep_alive: KeepAlive::Seconds(MQTT_KEEP_ALIVE_SECS),
+            session_expiry_interval,
             user_name: Some(
                 MqttString::try_from(CONFIG.mqtt_username).map_err(|_| Error::ConnectionFailed)?,
             ),
             password: Some(
                 MqttBinary::try_from(CONFIG.mqtt_password).map_err(|_| Error::ConnectionFailed)?,
             ),
-            will: None,
+            // The broker marks the device offline when the connection drops
+            // without a DISCONNECT (power loss, crash, network failure)
+            will: Some(WillOptions {
+                will_qos: QoS::AtLeastOnce,
+                will_retain: true,
+                will_topic: MqttString::try_from(availability_topic.as_str())
+                    .map_err(|_| Error::ConnectionFailed)?,
+                will_payload: MqttBinary::try_from(AVAILABILITY_OFFLINE)
+                    .map_err(|_| Error::ConnectionFailed)?,
+                will_delay_interval,
+                is_payload_utf8: true,
+                message_expiry_interval: None,
+                content_type: None,
+                response_topic: None,
+                correlation_data: None,
+            }),
         };
 
         let client_id =
             MqttString::try_from(CONFIG.device_id).map_err(|_| Error::ConnectionFailed)?;
 
         match client
-            .connect(transport, &connect_options, Some(client_id))
+            .connect(Shared(transport), &connect_options, Some(client_id))
             .await
         {
             Ok(_) => {
@@ -63,49 +389,578 @@ where
             }
         }
 
-        Ok(Self { client })
+        Ok(Self {
+            client,
+            transport,
+            awaiting_pingresp: false,
+        })
     }
 
-    pub async fn send_message(&mut self, topic: &str, message: &[u8]) -> Result<(), Error> {
+    /// Publish a message with QoS 1 and wait for the broker acknowledgment.
+    pub async fn send_message(&mut self, topic: &str, message: &[u8], retain: bool) -> Result<(), Error> {
         let topic_str = MqttString::try_from(topic).map_err(|_| Error::PublishMessageFailed)?;
         // SAFETY: Topic names from config are valid (no wildcards)
         let topic_name = unsafe { TopicName::new_unchecked(topic_str) };
 
         let pub_options = PublicationOptions {
-            retain: false,
+            retain,
             topic: topic_name,
             qos: QoS::AtLeastOnce,
         };
 
-        match self
+        let packet_identifier = match self
             .client
             .publish(&pub_options, Bytes::from(message))
             .await
         {
-            Ok(_) => {
+            Ok(pid) => pid,
+            Err(e) => {
+                log::error!("Failed to publish message: {:?}", e);
+                return Err(Error::PublishMessageFailed);
+            }
+        };
+
+        // Other packets (e.g. PINGRESP) may arrive before the PUBACK. Only
+        // the wait for the next packet times out, not its read.
+        let deadline = Instant::now() + Duration::from_secs(MQTT_ACK_TIMEOUT_SECS);
+        let ack: Result<(), Error> = async {
+            loop {
+                with_deadline(deadline, self.wait_readable())
+                    .await
+                    .map_err(|_| {
+                        log::warn!("Timed out waiting for publish acknowledgment");
+                        Error::ConnectionLost
+                    })??;
                 match self.client.poll().await {
-                    Ok(_) => {
-                        log::debug!("Message published and acknowledged");
+                    Ok(Event::PublishAcknowledged(puback))
+                        if puback.packet_identifier == packet_identifier =>
+                    {
+                        return Ok(());
+                    }
+                    Ok(Event::PublishRejected(pubrej))
+                        if pubrej.packet_identifier == packet_identifier =>
+                    {
+                        log::error!("Publish rejected by broker: {:?}", pubrej.reason_code);
+                        return Err(Error::PublishMessageFailed);
                     }
+                    Ok(event) => self.handle_event(event),
                     Err(e) => {
                         log::warn!("Failed to receive publish acknowledgment: {:?}", e);
+                        return Err(Error::ConnectionLost);
                     }
                 }
             }
-            Err(e) => {
-                log::error!("Failed to publish message: {:?}", e);
-                return Err(Error::PublishMessageFailed);
+        }
+        .await;
+
+        if ack.is_ok() {
+            log::debug!("Message published and acknowledged");
+            if !ACKNOWLEDGED.swap(true, Ordering::AcqRel) {
+                FIRST_ACK.signal(());
             }
         }
+        ack
+    }
+
+    /// Wait until the broker sent data. Cancel safe, unlike reading a packet:
+    /// a packet read cancelled halfway loses its first bytes, the next read
+    /// then starts in the middle of it.
+    async fn wait_readable(&self) -> Result<(), Error> {
+        self.transport
+            .lock()
+            .await
+            .wait_readable()
+            .await
+            .map_err(|e| {
+                log::error!("MQTT connection lost: {:?}", e);
+                Error::ConnectionLost
+            })
+    }
+
+    /// Announce the device online, then publish queued messages and keep the
+    /// connection alive with pings until the connection drops (error) or a
+    /// pause or shutdown is requested (Ok). Queued messages are polled first,
+    /// so they are all published before a shutdown is handled.
+    ///
+    /// A message that could not be published is left in `pending` so it can
+    /// be sent again after reconnecting. The messages stored in `outbox`
+    /// while offline are published next, oldest first, and new messages are
+    /// stored behind them until it is empty to keep the order.
+    async fn serve(
+        &mut self,
+        pending: &mut Option<Publication>,
+        mut outbox: Option<&mut Outbox>,
+    ) -> Result<Control, Error> {
+        // Checked when connecting
+        let availability_topic = CONFIG.availability_topic().unwrap_or_default();
+        self.send_message(&availability_topic, AVAILABILITY_ONLINE.as_bytes(), true)
+            .await?;
+
+        #[cfg(feature = "homeassistant")]
+        crate::homeassistant::announce(self).await?;
+
+        self.subscribe_commands().await?;
+
+        if let Some(publication) = pending.take() {
+            self.send_publication(publication, pending).await?;
+        }
+
+        // Ping at half the negotiated keep alive to stay well within it
+        let keep_alive = match self.client.shared_config().keep_alive {
+            KeepAlive::Seconds(secs) => Duration::from_secs(secs as u64),
+            KeepAlive::Infinite => Duration::from_secs(MQTT_KEEP_ALIVE_SECS as u64),
+        };
+        let mut next_ping = Instant::now() + keep_alive / 2;
+        // Whether the outbox may still hold messages
+        let mut replaying = outbox.is_some();
+        let mut shutdown_deadline = None;
 
+        loop {
+            // One stored message per turn so a pause request is still handled
+            // in between. A shutdown leaves them some time, the rest is
+            // published after the next boot.
+            if SHUTDOWN_REQUEST.signaled() && shutdown_deadline.is_none() {
+                shutdown_deadline =
+                    Some(Instant::now() + Duration::from_secs(MQTT_SHUTDOWN_REPLAY_SECS));
+            }
+            let can_replay = !PAUSE_REQUEST.signaled()
+                && shutdown_deadline.is_none_or(|deadline| Instant::now() < deadline);
+            if let Some(outbox) = outbox.as_deref_mut().filter(|_| replaying && can_replay) {
+                while let Ok(publication) = PUBLICATIONS.try_receive() {
+                    self.store_behind(outbox, publication, pending).await?;
+                }
+                // Results don't wait for the stored messages
+                while let Ok(result) = RESULTS.try_receive() {
+                    self.send_result(result).await?;
+                }
+                replaying = self.replay(outbox).await?;
+                next_ping = Instant::now() + keep_alive / 2;
+                continue;
+            }
+
+            match select4(
+                self.wait_readable(),
+                select(RESULTS.receive(), PUBLICATIONS.receive()),
+                Timer::at(next_ping),
+                select3(
+                    PAUSE_REQUEST.wait(),
+                    SHUTDOWN_REQUEST.wait(),
+                    discovery_changed(),
+                ),
+            )
+            .await
+            {
+                // Read the whole packet, nothing cancels it anymore
+                Either4::First(readable) => {
+                    readable?;
+                    let header = self.client.poll_header().await.map_err(|e| {
+                        log::error!("MQTT connection lost: {:?}", e);
+                        Error::ConnectionLost
+                    })?;
+                    match self.client.poll_body(header).await {
+                        Ok(event) => self.handle_event(event),
+                        Err(e) => {
+                            log::error!("MQTT connection lost: {:?}", e);
+                            return Err(Error::ConnectionLost);
+                        }
+                    }
+                }
+                Either4::Second(Either::First(result)) => {
+                    self.send_result(result).await?;
+                    next_ping = Instant::now() + keep_alive / 2;
+                }
+                Either4::Second(Either::Second(publication)) => match outbox.as_deref_mut() {
+                    Some(outbox) if replaying => {
+                        self.store_behind(outbox, publication, pending).await?
+                    }
+                    _ => {
+                        self.send_publication(publication, pending).await?;
+                        log::info!("MQTT data published successfully");
+                        next_ping = Instant::now() + keep_alive / 2;
+                    }
+                },
+                Either4::Third(()) => {
+                    if self.awaiting_pingresp {
+                        log::error!("No PINGRESP received from broker, connection lost");
+                        return Err(Error::ConnectionLost);
+                    }
+                    self.client.ping().await.map_err(|e| {
+                        log::error!("MQTT ping failed: {:?}", e);
+                        Error::ConnectionLost
+                    })?;
+                    self.awaiting_pingresp = true;
+                    next_ping = Instant::now() + keep_alive / 2;
+                }
+                Either4::Fourth(Either3::First(())) => return Ok(Control::Pause),
+                Either4::Fourth(Either3::Second(shutdown)) => return Ok(Control::Shutdown(shutdown)),
+                Either4::Fourth(Either3::Third(())) => {
+                    #[cfg(feature = "homeassistant")]
+                    crate::homeassistant::announce(self).await?;
+                    next_ping = Instant::now() + keep_alive / 2;
+                }
+            }
+        }
+    }
+
+    /// Publish a command result. It is dropped when the connection is lost
+    /// rather than kept in `pending`.
+    async fn send_result(&mut self, result: Publication) -> Result<(), Error> {
+        self.send_message(&result.topic, &result.payload, result.retain).await
+    }
+
+    /// Subscribe to `CONFIG.command_topic()`. Needed at every connection,
+    /// the session is not kept by the broker. The SUBACK is handled with the
+    /// other events.
+    async fn subscribe_commands(&mut self) -> Result<(), Error> {
+        let command_topic = CONFIG.command_topic().ok_or_else(|| {
+            log::error!("Command topic too long");
+            Error::SubscribeFailed
+        })?;
+        let topic =
+            MqttString::try_from(command_topic.as_str()).map_err(|_| Error::SubscribeFailed)?;
+        // SAFETY: the broker rejects the subscription when the configured
+        // filter is invalid
+        let topic_filter = unsafe { TopicFilter::new_unchecked(topic) };
+
+        let options = SubscriptionOptions {
+            // A retained command would run again at every connection
+            retain_handling: RetainHandling::NeverSend,
+            retain_as_published: false,
+            no_local: true,
+            qos: QoS::AtLeastOnce,
+        };
+        self.client.subscribe(topic_filter, options).await.map_err(|e| {
+            log::error!("Failed to subscribe to commands: {:?}", e);
+            Error::SubscribeFailed
+        })?;
         Ok(())
     }
 
-    pub async fn disconnect(mut self) {
+    /// Handle a packet which isn't the acknowledgment being waited for.
+    fn handle_event(&mut self, event: Event<'_>) {
+        match event {
+            Event::Pingresp => self.awaiting_pingresp = false,
+            Event::Publish(publish)
+                if CONFIG
+                    .command_topic()
+                    .is_some_and(|topic| publish.topic.as_ref() == topic.as_str()) =>
+            {
+                handle_command(publish)
+            }
+            Event::Suback(suback) if !suback.reason_code.is_success() => {
+                log::error!("Command subscription rejected: {:?}", suback.reason_code)
+            }
+            event => log::debug!("Ignoring MQTT event: {:?}", event),
+        }
+    }
+
+    /// Publish a queued message, leaving it in `pending` on failure.
+    async fn send_publication(
+        &mut self,
+        publication: Publication,
+        pending: &mut Option<Publication>,
+    ) -> Result<(), Error> {
+        let result = self
+            .send_message(&publication.topic, &publication.payload, publication.retain)
+            .await;
+        if result.is_err() {
+            *pending = Some(publication);
+        }
+        result
+    }
+
+    /// Store `publication` behind the messages of `outbox` to keep the order,
+    /// or publish it when it can't be stored.
+    async fn store_behind(
+        &mut self,
+        outbox: &mut Outbox,
+        publication: Publication,
+        pending: &mut Option<Publication>,
+    ) -> Result<(), Error> {
+        match outbox
+            .push(&publication.topic, &publication.payload, publication.retain)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::error!("Failed to store message in the outbox: {:?}", e);
+                self.send_publication(publication, pending).await
+            }
+        }
+    }
+
+    /// Publish the oldest message of `outbox` and remove it once
+    /// acknowledged. Returns false when the outbox is empty, or can't be
+    /// read.
+    async fn replay(&mut self, outbox: &mut Outbox) -> Result<bool, Error> {
+        let message = match outbox.peek().await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                log::error!("Failed to read the outbox: {:?}", e);
+                return Ok(false);
+            }
+        };
+        self.send_message(message.topic, message.payload, message.retain)
+            .await?;
+        log::info!("Stored MQTT message published");
+
+        // A failure here publishes the message again next time
+        if let Err(e) = outbox.pop().await {
+            log::error!("Failed to remove published message from the outbox: {:?}", e);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Close the connection. With `publish_will` the broker publishes the
+    /// Last Will ("offline") as for an unexpected disconnection.
+    pub async fn disconnect(mut self, publish_will: bool) {
         let disconnect_options = DisconnectOptions {
-            publish_will: false,
+            publish_will,
             session_expiry_interval: None,
         };
         let _ = self.client.disconnect(&disconnect_options).await;
     }
+
+    /// Drop a connection which can no longer be used.
+    pub async fn abort(mut self) {
+        self.client.abort().await;
+    }
+}
+
+/// Long-lived MQTT session: connects to the broker, publishes queued messages
+/// and reconnects with exponential backoff when the connection drops.
+pub struct MqttSession {
+    stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
+    rng: ChaCha20Rng,
+    rx_buf: &'static Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>,
+    tx_buf: &'static Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>,
+    tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
+    tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
+    mqtt_buffer: &'static Mutex<NoopRawMutex, AllocBuffer>,
+    /// Messages kept in flash while the broker is unreachable, `None` when
+    /// the storage partition can't be used
+    outbox: Option<Outbox>,
+    pending: Option<Publication>,
+    backoff_secs: u64,
+    connect_failures: u32,
+}
+
+impl MqttSession {
+    pub fn new(
+        stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
+        rng: ChaCha20Rng,
+        rx_buf: &'static Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>,
+        tx_buf: &'static Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>,
+        tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
+        tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
+        outbox: Option<Outbox>,
+    ) -> Self {
+        let mqtt_buffer = MQTT_BUFFER.init(Mutex::new(AllocBuffer));
+
+        Self {
+            stack,
+            rng,
+            rx_buf,
+            tx_buf,
+            tls_read_buf,
+            tls_write_buf,
+            mqtt_buffer,
+            outbox,
+            pending: None,
+            backoff_secs: MQTT_RECONNECT_BACKOFF_MIN_SECS,
+            connect_failures: 0,
+        }
+    }
+
+    async fn run(&mut self) -> ! {
+        loop {
+            if PAUSE_REQUEST.try_take().is_some() {
+                wait_resumed().await;
+            }
+
+            match self.connect_and_serve().await {
+                Ok(Control::Pause) => wait_resumed().await,
+                Ok(Control::Shutdown(_)) => {
+                    self.stash().await;
+                    stopped().await
+                }
+                Err(e) => {
+                    log::error!("MQTT session error: {:?}", e);
+                    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                    self.stash().await;
+
+                    self.connect_failures += 1;
+                    // Safe mode keeps trying rather than rebooting
+                    if self.connect_failures >= MQTT_MAX_CONNECT_FAILURES && !boot::safe_mode() {
+                        // Recover the network stack, same as other unrecoverable
+                        // network failures
+                        log::error!(
+                            "MQTT connection failed {} times in a row, rebooting to recover...",
+                            self.connect_failures
+                        );
+                        Timer::after(Duration::from_millis(1000)).await;
+                        esp_hal::system::software_reset();
+                    }
+
+                    log::info!("Reconnecting to MQTT broker in {}s", self.backoff_secs);
+                    let reconnect_at = Instant::now() + Duration::from_secs(self.backoff_secs);
+                    loop {
+                        match select4(
+                            Timer::at(reconnect_at),
+                            self.offline_publication(),
+                            PAUSE_REQUEST.wait(),
+                            SHUTDOWN_REQUEST.wait(),
+                        )
+                        .await
+                        {
+                            Either4::First(()) => break,
+                            Either4::Second(publication) => self.store(publication).await,
+                            Either4::Third(()) => {
+                                wait_resumed().await;
+                                break;
+                            }
+                            // Not connected, the broker publishes the Last Will
+                            // for the dropped connection
+                            Either4::Fourth(_) => stopped().await,
+                        }
+                    }
+                    self.backoff_secs =
+                        core::cmp::min(self.backoff_secs * 2, MQTT_RECONNECT_BACKOFF_MAX_SECS);
+                }
+            }
+        }
+    }
+
+    /// Move the message left unpublished and the queued ones to the outbox,
+    /// where they survive a reboot. Does nothing without an outbox.
+    async fn stash(&mut self) {
+        if self.outbox.is_none() {
+            return;
+        }
+        if let Some(publication) = self.pending.take() {
+            self.store(publication).await;
+        }
+        while let Ok(publication) = PUBLICATIONS.try_receive() {
+            self.store(publication).await;
+        }
+    }
+
+    /// Keep `publication` in the outbox until the broker is back. Falls back
+    /// to `pending` when it can't be stored, the message is lost when that is
+    /// taken already.
+    async fn store(&mut self, publication: Publication) {
+        if let Some(outbox) = &mut self.outbox {
+            match outbox
+                .push(&publication.topic, &publication.payload, publication.retain)
+                .await
+            {
+                Ok(()) => return,
+                Err(e) => log::error!("Failed to store message in the outbox: {:?}", e),
+            }
+        }
+        if self.pending.is_none() {
+            self.pending = Some(publication);
+        } else {
+            log::warn!("Dropping message for {}", publication.topic);
+        }
+    }
+
+    /// Next message queued while disconnected. Never completes without an
+    /// outbox, the messages then wait in the queue.
+    async fn offline_publication(&self) -> Publication {
+        if self.outbox.is_none() {
+            core::future::pending::<()>().await;
+        }
+        PUBLICATIONS.receive().await
+    }
+
+    async fn connect_and_serve(&mut self) -> Result<Control, Error> {
+        // Hold the shared network buffers for the lifetime of the connection
+        let stack_guard = self.stack.lock().await;
+        let mut rx_buf = self.rx_buf.lock().await;
+        let mut tx_buf = self.tx_buf.lock().await;
+        let mut tls_read_buf = self.tls_read_buf.lock().await;
+        let mut tls_write_buf = self.tls_write_buf.lock().await;
+
+        let transport = Transport::new(
+            *stack_guard,
+            &mut self.rng,
+            &mut *rx_buf,
+            &mut *tx_buf,
+            &mut *tls_read_buf,
+            &mut *tls_write_buf,
+            CONFIG.mqtt_hostname,
+            CONFIG.mqtt_port,
+        )
+        .await
+        .map_err(|e| {
+            log::error!("Transport creation failed: {:?}", e);
+            Error::Transport
+        })?;
+
+        let transport = Mutex::new(Lookahead::new(transport));
+        let mut mqtt_buffer = self.mqtt_buffer.lock().await;
+        let mut mqtt = Mqtt::new(&transport, &mut *mqtt_buffer).await?;
+
+        self.connect_failures = 0;
+        self.backoff_secs = MQTT_RECONNECT_BACKOFF_MIN_SECS;
+
+        let result = match SHUTDOWN_REQUEST.try_take() {
+            // Reconnected after a pause only to announce a reboot, skip "online"
+            Some(shutdown) if matches!(shutdown, Shutdown::Reboot) => {
+                Ok(Control::Shutdown(shutdown))
+            }
+            // Queued messages must still be published, serve handles it
+            Some(shutdown) => {
+                SHUTDOWN_REQUEST.signal(shutdown);
+                mqtt.serve(&mut self.pending, self.outbox.as_mut()).await
+            }
+            None => mqtt.serve(&mut self.pending, self.outbox.as_mut()).await,
+        };
+        match result {
+            Ok(Control::Pause) => mqtt.disconnect(false).await,
+            // The Last Will is kept before deep sleep too, the Will Delay
+            // Interval holding it back
+            Ok(Control::Shutdown(_)) => mqtt.disconnect(true).await,
+            Err(_) => mqtt.abort().await,
+        }
+
+        // Allow network stack time to process socket cleanup.
+        // Without this, rapid reconnections can exhaust sockets before
+        // the previous connection is fully closed.
+        Timer::after(Duration::from_millis(MQTT_DISCONNECT_CLEANUP_DELAY_MS)).await;
+
+        result
+    }
+}
+
+/// Wait until the Home Assistant discovery must be published again. Never
+/// completes when discovery is disabled.
+async fn discovery_changed() {
+    #[cfg(feature = "homeassistant")]
+    crate::homeassistant::changed().await;
+    #[cfg(not(feature = "homeassistant"))]
+    core::future::pending::<()>().await;
+}
+
+/// Acknowledge a pause request once the network buffers are released and
+/// wait until allowed to reconnect.
+async fn wait_resumed() {
+    log::info!("MQTT session paused");
+    PAUSED.signal(());
+    RESUME.wait().await;
+}
+
+/// Acknowledge a shutdown request and stop serving until the reboot.
+async fn stopped() -> ! {
+    log::info!("MQTT session stopped");
+    SHUTDOWN_DONE.signal(());
+    core::future::pending().await
+}
+
+#[embassy_executor::task]
+pub async fn mqtt_task(mut session: MqttSession) {
+    session.run().await
 }
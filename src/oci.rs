@@ -0,0 +1,573 @@
+//! Firmware pulled from an OCI registry, e.g. Harbor, over the distribution
+//! API instead of an OTA server.
+//!
+//! Each device has its own repository, `<repository>/<device_id>`, tagged
+//! with the firmware versions. An image holds the firmware as a layer of type
+//! `FIRMWARE_MEDIA_TYPE`, and its signature as a manifest annotation, as
+//! pushed by `oras`:
+//!
+//! ```text
+//! oras push harbor.example.com/firmware/esp32-outdoor:0.1.4 \
+//!     --annotation "vnd.espressif.esp32.firmware.signature=$(cat firmware.bin.sig)" \
+//!     firmware.bin:application/vnd.espressif.esp32.firmware.v1+binary
+//! ```
+//!
+//! The newest tag accepted by the device channel is resolved to a `Manifest`
+//! whose `download_url` is the blob of the layer, its SHA-256 digest being the
+//! checksum, so the image is downloaded and verified as one served by an OTA
+//! server.
+//!
+//! Registries asking for credentials (401) get them with Basic auth, or a
+//! bearer token requested from the realm of the challenge (Docker token
+//! auth). The realm and the blobs must be served by the registry itself, all
+//! the requests share its connection.
+//!
+//! Only depends on `core`, `heapless` and `embedded-io-async` so it can be
+//! unit tested on the host, see `tools/firmware-core`.
+
+use core::fmt::Write as _;
+
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+use crate::http::{self, Client, PATH_MAX_LEN};
+use crate::json::{parse_array, parse_object, unescape, Value};
+use crate::manifest::{self, Checksum, Manifest};
+use crate::rtc_record::VERSION_MAX_LEN;
+use crate::semver::{Channel, SemVer, VersionReq};
+
+/// Media type of the firmware layer
+pub const FIRMWARE_MEDIA_TYPE: &str = "application/vnd.espressif.esp32.firmware.v1+binary";
+
+/// Media type of the image manifests understood
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Manifest annotation holding the base64 signature of the firmware, as
+/// written by `sign-firmware`
+pub const SIGNATURE_ANNOTATION: &str = "vnd.espressif.esp32.firmware.signature";
+
+/// Optional manifest annotation, see `Manifest::min_current_version`
+pub const MIN_CURRENT_VERSION_ANNOTATION: &str = "vnd.espressif.esp32.firmware.min-current-version";
+
+/// Optional manifest annotation, see `Manifest::current_version_requirement`
+pub const CURRENT_VERSION_REQUIREMENT_ANNOTATION: &str =
+    "vnd.espressif.esp32.firmware.current-version-requirement";
+
+/// Optional manifest annotation, "true" or "false", see
+/// `Manifest::allow_downgrade`
+pub const ALLOW_DOWNGRADE_ANNOTATION: &str = "vnd.espressif.esp32.firmware.allow-downgrade";
+
+/// Optional manifest annotation, see `Manifest::rollout_percentage`
+pub const ROLLOUT_PERCENTAGE_ANNOTATION: &str = "vnd.espressif.esp32.firmware.rollout-percentage";
+
+/// Longest `Authorization` header value, registry tokens are JWTs
+pub const AUTHORIZATION_MAX_LEN: usize = 1536;
+
+/// Longest repository name
+pub const NAME_MAX_LEN: usize = 128;
+
+/// Longest bearer token
+const TOKEN_MAX_LEN: usize = AUTHORIZATION_MAX_LEN - "Bearer ".len();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Http(http::Error),
+    /// The registry answered with an error status
+    Status(u16),
+    /// Credentials missing or refused, or the token realm is on another
+    /// server
+    Unauthorized,
+    /// Response larger than the buffer
+    TooLarge,
+    /// Response which isn't a JSON object
+    Syntax,
+    /// The named member of the response is missing, of the wrong type or too
+    /// long
+    Invalid(&'static str),
+    /// No tag of the repository is a version accepted by the channel
+    NoVersion,
+    /// No layer of the image is of type `FIRMWARE_MEDIA_TYPE`
+    NoFirmwareLayer,
+}
+
+/// Account the device pulls with, a Harbor robot account for instance
+#[derive(Debug, Clone, Copy)]
+pub struct Credentials<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+
+/// Repository of a device on a registry, and its authorization
+pub struct Registry<'a> {
+    name: String<NAME_MAX_LEN>,
+    credentials: Option<Credentials<'a>>,
+    /// Sent with the requests once the registry asked for credentials, kept
+    /// across checks until refused
+    authorization: String<AUTHORIZATION_MAX_LEN>,
+}
+
+impl<'a> Registry<'a> {
+    /// Repository `<repository>/<device_id>`, pulled anonymously without
+    /// `credentials`. `None` when the name is too long.
+    pub fn new(
+        repository: &str,
+        device_id: &str,
+        credentials: Option<Credentials<'a>>,
+    ) -> Option<Self> {
+        let mut name = String::new();
+        write!(name, "{}/{}", repository.trim_matches('/'), device_id).ok()?;
+        Some(Self {
+            name,
+            credentials,
+            authorization: String::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Value of the `Authorization` header to send along the blob request,
+    /// `None` when the registry didn't ask for credentials
+    pub fn authorization(&self) -> Option<&str> {
+        (!self.authorization.is_empty()).then_some(self.authorization.as_str())
+    }
+
+    /// Manifest of the newest firmware accepted by `channel`. `buf` holds the
+    /// responses.
+    pub async fn latest<C: Read + Write>(
+        &mut self,
+        client: &mut Client<'_, C>,
+        channel: Channel,
+        buf: &mut [u8],
+    ) -> Result<Manifest, Error> {
+        let mut path: String<PATH_MAX_LEN> = String::new();
+        write!(path, "/v2/{}/tags/list", self.name).map_err(|_| Error::TooLarge)?;
+        let len = self.get(client, &path, None, buf).await?;
+        let tag = newest_tag(&buf[..len], channel)?;
+
+        path.clear();
+        write!(path, "/v2/{}/manifests/{}", self.name, tag).map_err(|_| Error::TooLarge)?;
+        let len = self
+            .get(client, &path, Some(MANIFEST_MEDIA_TYPE), buf)
+            .await?;
+        parse_manifest(&buf[..len], &self.name, &tag)
+    }
+
+    /// Body of `path` read into `buf`, returns its length. Authenticates and
+    /// retries once when the registry asks for credentials.
+    async fn get<C: Read + Write>(
+        &mut self,
+        client: &mut Client<'_, C>,
+        path: &str,
+        accept: Option<&str>,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut authenticated = false;
+        loop {
+            let response = {
+                let mut headers: Vec<(&str, &str), 3> = Vec::new();
+                let _ = headers.push(("Connection", "keep-alive"));
+                if let Some(accept) = accept {
+                    let _ = headers.push(("Accept", accept));
+                }
+                if let Some(authorization) = self.authorization() {
+                    let _ = headers.push(("Authorization", authorization));
+                }
+                client.get(path, &headers).await.map_err(Error::Http)?
+            };
+            match response.status {
+                // A token from a previous check may have expired
+                401 if !authenticated => {
+                    let challenge = response.www_authenticate.ok_or(Error::Unauthorized)?;
+                    self.authenticate(client, &challenge, buf).await?;
+                    authenticated = true;
+                }
+                401 | 403 => return Err(Error::Unauthorized),
+                status if !response.is_success() => return Err(Error::Status(status)),
+                _ => return read_body(client, buf).await,
+            }
+        }
+    }
+
+    /// Answer the `WWW-Authenticate` `challenge`, setting the authorization
+    /// of the next requests.
+    async fn authenticate<C: Read + Write>(
+        &mut self,
+        client: &mut Client<'_, C>,
+        challenge: &str,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.authorization.clear();
+        let challenge = Challenge::parse(challenge).ok_or(Error::Unauthorized)?;
+        let mut basic: String<AUTHORIZATION_MAX_LEN> = String::new();
+        if let Some(credentials) = self.credentials {
+            basic_authorization(&mut basic, credentials).map_err(|_| Error::Unauthorized)?;
+        }
+        if challenge.scheme.eq_ignore_ascii_case("basic") {
+            if basic.is_empty() {
+                return Err(Error::Unauthorized);
+            }
+            self.authorization = basic;
+            return Ok(());
+        }
+        if !challenge.scheme.eq_ignore_ascii_case("bearer") {
+            return Err(Error::Unauthorized);
+        }
+
+        // Anonymous pulls get a token too
+        let realm = challenge.realm.ok_or(Error::Unauthorized)?;
+        let realm =
+            http::same_host_path(realm, client.host(), client.port()).ok_or(Error::Unauthorized)?;
+        let mut path: String<PATH_MAX_LEN> = String::new();
+        path.push_str(realm).map_err(|_| Error::TooLarge)?;
+        let mut separator = if realm.contains('?') { '&' } else { '?' };
+        for (name, value) in [("service", challenge.service), ("scope", challenge.scope)] {
+            if let Some(value) = value {
+                write!(path, "{}{}=", separator, name).map_err(|_| Error::TooLarge)?;
+                push_query_value(&mut path, value).map_err(|_| Error::TooLarge)?;
+                separator = '&';
+            }
+        }
+        let mut headers: Vec<(&str, &str), 2> = Vec::new();
+        let _ = headers.push(("Connection", "keep-alive"));
+        if !basic.is_empty() {
+            let _ = headers.push(("Authorization", &basic));
+        }
+        let response = client.get(&path, &headers).await.map_err(Error::Http)?;
+        match response.status {
+            401 | 403 => return Err(Error::Unauthorized),
+            status if !response.is_success() => return Err(Error::Status(status)),
+            _ => {}
+        }
+
+        let len = read_body(client, buf).await?;
+        let body = core::str::from_utf8(&buf[..len]).map_err(|_| Error::Syntax)?;
+        let mut token = None;
+        parse_object(body, |key, value| match (key, value) {
+            // `access_token` is the OAuth 2 name of the same token
+            ("token" | "access_token", Value::Str(value)) if token.is_none() => token = Some(value),
+            _ => {}
+        })
+        .ok_or(Error::Syntax)?;
+        let token: String<TOKEN_MAX_LEN> =
+            token.and_then(unescape).ok_or(Error::Invalid("token"))?;
+        let _ = write!(self.authorization, "Bearer {}", token);
+        Ok(())
+    }
+}
+
+/// Newest tag of a `/tags/list` response accepted by `channel`. Tags other
+/// than versions, e.g. `latest`, are ignored.
+pub fn newest_tag(body: &[u8], channel: Channel) -> Result<String<VERSION_MAX_LEN>, Error> {
+    let body = core::str::from_utf8(body).map_err(|_| Error::Syntax)?;
+    let mut tags = None;
+    parse_object(body, |key, value| {
+        if key == "tags" {
+            tags = Some(value);
+        }
+    })
+    .ok_or(Error::Syntax)?;
+    let tags = match tags {
+        Some(Value::Array(tags)) => tags,
+        // Repository without tags
+        Some(Value::Null) => return Err(Error::NoVersion),
+        _ => return Err(Error::Invalid("tags")),
+    };
+
+    let mut newest: Option<(SemVer, &str)> = None;
+    parse_array(tags, |value| {
+        // Tags can't hold escape sequences
+        let Value::Str(tag) = value else {
+            return;
+        };
+        let Some(version) = SemVer::parse(tag) else {
+            return;
+        };
+        if channel.accepts(&version) && newest.as_ref().is_none_or(|(newest, _)| version > *newest)
+        {
+            newest = Some((version, tag));
+        }
+    })
+    .ok_or(Error::Invalid("tags"))?;
+    newest
+        .and_then(|(_, tag)| String::try_from(tag).ok())
+        .ok_or(Error::NoVersion)
+}
+
+/// Firmware of the image manifest of the repository `name` tagged `tag`
+pub fn parse_manifest(body: &[u8], name: &str, tag: &str) -> Result<Manifest, Error> {
+    let body = core::str::from_utf8(body).map_err(|_| Error::Syntax)?;
+    let mut schema_version = None;
+    let mut media_type = None;
+    let mut layers = None;
+    let mut annotations = None;
+    parse_object(body, |key, value| match key {
+        "schemaVersion" => schema_version = Some(value),
+        "mediaType" => media_type = Some(value),
+        "layers" => layers = Some(value),
+        "annotations" => annotations = Some(value),
+        _ => {}
+    })
+    .ok_or(Error::Syntax)?;
+
+    if schema_version != Some(Value::Number("2")) {
+        return Err(Error::Invalid("schemaVersion"));
+    }
+    // An image index points to other manifests, e.g. one per platform
+    if media_type.is_some_and(|media_type| media_type != Value::Str(MANIFEST_MEDIA_TYPE)) {
+        return Err(Error::Invalid("mediaType"));
+    }
+    let Some(Value::Array(layers)) = layers else {
+        return Err(Error::Invalid("layers"));
+    };
+    let layer = firmware_layer(layers)?;
+
+    let mut signature = None;
+    let mut min_current_version = None;
+    let mut current_version_requirement = None;
+    let mut allow_downgrade = None;
+    let mut rollout_percentage = None;
+    match annotations {
+        None => {}
+        Some(Value::Object(annotations)) => parse_object(annotations, |key, value| match key {
+            SIGNATURE_ANNOTATION => signature = Some(value),
+            MIN_CURRENT_VERSION_ANNOTATION => min_current_version = Some(value),
+            CURRENT_VERSION_REQUIREMENT_ANNOTATION => current_version_requirement = Some(value),
+            ALLOW_DOWNGRADE_ANNOTATION => allow_downgrade = Some(value),
+            ROLLOUT_PERCENTAGE_ANNOTATION => rollout_percentage = Some(value),
+            _ => {}
+        })
+        .ok_or(Error::Invalid("annotations"))?,
+        Some(_) => return Err(Error::Invalid("annotations")),
+    }
+
+    let version = SemVer::parse(tag).ok_or(Error::Invalid("tag"))?;
+    let mut download_url = String::new();
+    write!(download_url, "/v2/{}/blobs/sha256:", name).map_err(|_| Error::TooLarge)?;
+    for byte in layer.digest {
+        write!(download_url, "{:02x}", byte).map_err(|_| Error::TooLarge)?;
+    }
+    Ok(Manifest {
+        version: String::try_from(tag).map_err(|_| Error::Invalid("tag"))?,
+        size: layer.size,
+        checksum: Checksum::Sha256(layer.digest),
+        signature: annotation(signature, SIGNATURE_ANNOTATION)?
+            .ok_or(Error::Invalid(SIGNATURE_ANNOTATION))?,
+        min_current_version: annotation(min_current_version, MIN_CURRENT_VERSION_ANNOTATION)?,
+        current_version_requirement: match annotation::<64>(
+            current_version_requirement,
+            CURRENT_VERSION_REQUIREMENT_ANNOTATION,
+        )? {
+            None => None,
+            Some(requirement) => Some(
+                VersionReq::parse(&requirement)
+                    .ok_or(Error::Invalid(CURRENT_VERSION_REQUIREMENT_ANNOTATION))?,
+            ),
+        },
+        allow_downgrade: match annotation::<5>(allow_downgrade, ALLOW_DOWNGRADE_ANNOTATION)?
+            .as_deref()
+        {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(_) => return Err(Error::Invalid(ALLOW_DOWNGRADE_ANNOTATION)),
+        },
+        channel: String::try_from(version.channel().as_str()).ok(),
+        rollout_percentage: match annotation::<3>(
+            rollout_percentage,
+            ROLLOUT_PERCENTAGE_ANNOTATION,
+        )? {
+            None => 100,
+            Some(percentage) => percentage
+                .parse()
+                .ok()
+                .filter(|percentage| *percentage <= 100)
+                .ok_or(Error::Invalid(ROLLOUT_PERCENTAGE_ANNOTATION))?,
+        },
+        release_notes_url: None,
+        download_url: Some(download_url),
+    })
+}
+
+/// Layer holding the firmware
+struct Layer {
+    digest: [u8; 32],
+    size: u32,
+}
+
+/// The single layer of type `FIRMWARE_MEDIA_TYPE` of the `layers` array
+fn firmware_layer(layers: &str) -> Result<Layer, Error> {
+    let mut found = None;
+    let mut result = Ok(());
+    parse_array(layers, |layer| {
+        let Value::Object(layer) = layer else {
+            result = Err(Error::Invalid("layers"));
+            return;
+        };
+        let mut media_type = None;
+        let mut digest = None;
+        let mut size = None;
+        let valid = parse_object(layer, |key, value| match key {
+            "mediaType" => media_type = Some(value),
+            "digest" => digest = Some(value),
+            "size" => size = Some(value),
+            _ => {}
+        });
+        if valid.is_none() {
+            result = Err(Error::Invalid("layers"));
+        } else if media_type == Some(Value::Str(FIRMWARE_MEDIA_TYPE)) {
+            if found.is_some() {
+                // Which one to install would be a guess
+                result = Err(Error::Invalid("layers"));
+            }
+            found = Some((digest, size));
+        }
+    })
+    .ok_or(Error::Invalid("layers"))?;
+    result?;
+
+    let (digest, size) = found.ok_or(Error::NoFirmwareLayer)?;
+    Ok(Layer {
+        digest: match digest {
+            Some(Value::Str(digest)) => digest
+                .strip_prefix("sha256:")
+                .and_then(manifest::parse_hex)
+                .ok_or(Error::Invalid("digest"))?,
+            _ => return Err(Error::Invalid("digest")),
+        },
+        size: match size {
+            Some(Value::Number(size)) => size.parse().map_err(|_| Error::Invalid("size"))?,
+            _ => return Err(Error::Invalid("size")),
+        },
+    })
+}
+
+/// Content of the annotation `name`, annotations are strings
+fn annotation<const N: usize>(
+    value: Option<Value>,
+    name: &'static str,
+) -> Result<Option<String<N>>, Error> {
+    match value {
+        None => Ok(None),
+        Some(Value::Str(s)) => unescape(s).map(Some).ok_or(Error::Invalid(name)),
+        Some(_) => Err(Error::Invalid(name)),
+    }
+}
+
+/// Read the response body into `buf`, returns its length.
+async fn read_body<C: Read + Write>(
+    client: &mut Client<'_, C>,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            return Err(Error::TooLarge);
+        }
+        match client
+            .read_body(&mut buf[len..])
+            .await
+            .map_err(Error::Http)?
+        {
+            0 => return Ok(len),
+            n => len += n,
+        }
+    }
+}
+
+/// Parameters of a `WWW-Authenticate` challenge which matter to the client
+struct Challenge<'a> {
+    scheme: &'a str,
+    realm: Option<&'a str>,
+    service: Option<&'a str>,
+    scope: Option<&'a str>,
+}
+
+impl<'a> Challenge<'a> {
+    /// `<scheme> <name>=<value>, ...`, values being quoted or not
+    fn parse(challenge: &'a str) -> Option<Self> {
+        let (scheme, mut params) = challenge
+            .trim()
+            .split_once(' ')
+            .unwrap_or((challenge.trim(), ""));
+        let mut parsed = Challenge {
+            scheme,
+            realm: None,
+            service: None,
+            scope: None,
+        };
+        loop {
+            params = params.trim_start_matches([' ', ',']);
+            if params.is_empty() {
+                return Some(parsed);
+            }
+            let (name, rest) = params.split_once('=')?;
+            // Scopes are comma separated, quoted values can hold commas
+            let (value, rest) = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"')?,
+                None => rest.split_once(',').unwrap_or((rest, "")),
+            };
+            params = rest;
+            match name.trim() {
+                "realm" => parsed.realm = Some(value),
+                "service" => parsed.service = Some(value),
+                "scope" => parsed.scope = Some(value),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Write `value` to a query, percent-encoding the reserved characters
+fn push_query_value<const N: usize>(out: &mut String<N>, value: &str) -> core::fmt::Result {
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~:/@".contains(&byte) {
+            out.push(byte as char).map_err(|_| core::fmt::Error)?;
+        } else {
+            write!(out, "%{:02X}", byte)?;
+        }
+    }
+    Ok(())
+}
+
+/// `Basic <base64 of username:password>`
+fn basic_authorization<const N: usize>(
+    out: &mut String<N>,
+    credentials: Credentials,
+) -> core::fmt::Result {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    out.push_str("Basic ").map_err(|_| core::fmt::Error)?;
+    let mut user_pass = credentials
+        .username
+        .bytes()
+        .chain(core::iter::once(b':'))
+        .chain(credentials.password.bytes());
+    loop {
+        let mut group = [0u8; 3];
+        let mut len = 0;
+        for byte in group.iter_mut() {
+            match user_pass.next() {
+                Some(next) => {
+                    *byte = next;
+                    len += 1;
+                }
+                None => break,
+            }
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let bits = u32::from_be_bytes([0, group[0], group[1], group[2]]);
+        for i in 0..4 {
+            let c = if i <= len {
+                ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3F] as char
+            } else {
+                '='
+            };
+            out.push(c).map_err(|_| core::fmt::Error)?;
+        }
+        if len < 3 {
+            return Ok(());
+        }
+    }
+}
@@ -1,39 +1,262 @@
-use alloc::format;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 use embassy_net::Stack;
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex, once_lock::OnceLock};
+use embassy_time::{with_deadline, Instant};
 use embedded_io_async::{Read, Write};
-use embedded_storage::nor_flash::NorFlash;
 use esp_bootloader_esp_idf::{
-    ota::OtaImageState, ota_updater::OtaUpdater, partitions::PARTITION_TABLE_MAX_LEN,
+    ota::OtaImageState,
+    ota_updater::OtaUpdater,
+    partitions::{AppPartitionSubType, PARTITION_TABLE_MAX_LEN},
 };
+use esp_hal::ram;
 use esp_storage::FlashStorage;
 use rand_chacha::ChaCha20Rng;
 use static_cell::StaticCell;
 use heapless::String;
+use p256::ecdsa::{signature::DigestVerifier, Signature, VerifyingKey};
+use p256::pkcs8::DecodePublicKey;
+use sha2::{Digest, Sha256};
 
+use crate::app_image;
 use crate::config::CONFIG;
 use crate::constants::*;
-use crate::semver::SemVer;
-use crate::transport::Transport;
+use crate::diagnostics_format::AppSlot;
+use crate::download::{self, Progress};
+use crate::rollback::{self, Reason, Report};
+use crate::semver::{Channel, SemVer};
+use crate::transport::{decode_pem, Transport};
+use crate::manifest::{self, Checksum, Manifest};
+use crate::{boot, diagnostics, http, mqtt, oci};
 
 /// Static buffer for OTA partition table operations to avoid heap allocation.
-/// This is shared across all OTA operations since they're serialized through `&mut self`.
+/// This is shared across all OTA data accesses, see `table_buffer`.
 static OTA_TABLE_BUFFER: StaticCell<[u8; PARTITION_TABLE_MAX_LEN]> = StaticCell::new();
 static OTA_TABLE_PTR: AtomicPtr<[u8; PARTITION_TABLE_MAX_LEN]> =
     AtomicPtr::new(core::ptr::null_mut());
 
+/// Partition the firmware was booted from
+static RUNNING_SLOT: OnceLock<AppSlot> = OnceLock::new();
+
+/// Whether the running firmware was just installed and hasn't passed its
+/// health check yet
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Report left by a firmware rolled back to this one
+#[ram(unstable(rtc_fast, persistent))]
+static mut RTC_ROLLBACK: [u32; rollback::WORDS] = [0; rollback::WORDS];
+
+/// Progress of an interrupted firmware download, resumed by the next check
+/// after a reboot. Lost on power loss, the download then starts over.
+#[ram(unstable(rtc_fast, persistent))]
+static mut RTC_DOWNLOAD: [u32; download::WORDS] = [0; download::WORDS];
+
+/// Partition the firmware was booted from, `None` when the OTA data can't be
+/// read.
+pub fn running_slot() -> Option<AppSlot> {
+    RUNNING_SLOT.try_get().copied()
+}
+
+/// Whether the running firmware is a new one still to be confirmed, see
+/// `verify`.
+pub fn pending() -> bool {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// Check the state of the running firmware, before anything can fail at boot.
+///
+/// A new firmware is left pending verification: it must join Wi-Fi,
+/// initialise its sensors and get a publication acknowledged by the broker
+/// within `CONFIG.ota_health_timeout_seconds`, or it is rolled back. One which
+/// already failed a boot is rolled back at once. The report of a firmware
+/// rolled back to this one is queued for publication.
+pub fn init(flash: &mut FlashStorage<'static>) {
+    match load_report() {
+        // Written by the update which installed this firmware, in case it
+        // resets before passing its health check
+        Some(report) if report.version() == VERSION => {}
+        // Kept once published so the same image isn't installed again
+        Some(mut report) if !report.published => {
+            log::warn!(
+                "Firmware {} was rolled back ({})",
+                report.version(),
+                report.reason.as_str()
+            );
+            diagnostics::publish_rollback(&report);
+            report.published = true;
+            save_report(Some(report));
+        }
+        _ => {}
+    }
+
+    let mut ota = match OtaUpdater::new(flash, table_buffer()) {
+        Ok(ota) => ota,
+        Err(e) => {
+            log::warn!("Failed to read the OTA data: {:?}", e);
+            return;
+        }
+    };
+    if let Ok(partition) = ota.selected_partition() {
+        let slot = match partition {
+            AppPartitionSubType::Factory => AppSlot::Factory,
+            ota => AppSlot::Ota(ota as u8 - AppPartitionSubType::Ota0 as u8),
+        };
+        let _ = RUNNING_SLOT.init(slot);
+    }
+
+    // No OTA data after flashing over serial, nothing to verify
+    if !matches!(
+        ota.current_ota_state(),
+        Ok(OtaImageState::New | OtaImageState::PendingVerify)
+    ) {
+        return;
+    }
+    if CONFIG.ota_health_timeout_seconds == 0 {
+        confirm(&mut ota);
+    } else if boot::failed_boots() > 0 {
+        roll_back(&mut ota, Reason::Reset);
+    } else {
+        log::info!("Firmware {} pending verification", VERSION);
+        PENDING.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Roll the running firmware back when it is still pending verification, on
+/// a boot failure `reason`. Returns otherwise, or when it can't be rolled
+/// back.
+pub fn health_check_failed(flash: &mut FlashStorage<'static>, reason: Reason) {
+    if !pending() {
+        return;
+    }
+    match OtaUpdater::new(flash, table_buffer()) {
+        Ok(mut ota) => roll_back(&mut ota, reason),
+        Err(e) => log::error!("Failed to read the OTA data: {:?}", e),
+    }
+}
+
+/// Confirm the running firmware once the broker acknowledged a publication, or
+/// roll it back when none was within `CONFIG.ota_health_timeout_seconds` of
+/// the boot. Returns at once when there is nothing to verify.
+pub async fn verify(flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>) {
+    if !pending() {
+        return;
+    }
+    let deadline = Instant::from_secs(CONFIG.ota_health_timeout_seconds as u64);
+    let acknowledged = with_deadline(deadline, mqtt::acknowledged()).await.is_ok();
+
+    let mut flash = flash.lock().await;
+    let mut ota = match OtaUpdater::new(&mut *flash, table_buffer()) {
+        Ok(ota) => ota,
+        Err(e) => {
+            log::error!("Failed to read the OTA data: {:?}", e);
+            return;
+        }
+    };
+    if acknowledged {
+        confirm(&mut ota);
+    } else {
+        roll_back(&mut ota, Reason::Mqtt);
+    }
+}
+
+#[embassy_executor::task]
+pub async fn verify_task(flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>) {
+    verify(flash).await
+}
+
+fn confirm(ota: &mut OtaUpdater<'_, FlashStorage<'static>>) {
+    match ota.set_current_ota_state(OtaImageState::Valid) {
+        Ok(()) => log::info!("Firmware {} confirmed", VERSION),
+        Err(e) => log::error!("Failed to confirm firmware {}: {:?}", VERSION, e),
+    }
+    save_report(None);
+    PENDING.store(false, Ordering::Relaxed);
+}
+
+/// Mark the running firmware invalid and reboot into the previous one, which
+/// was confirmed before the update. Returns when the previous one can't be
+/// selected, the running firmware is then kept.
+fn roll_back(ota: &mut OtaUpdater<'_, FlashStorage<'static>>, reason: Reason) {
+    log::error!(
+        "Firmware {} failed its health check ({}), rolling back...",
+        VERSION,
+        reason.as_str()
+    );
+    if let Err(e) = ota.set_current_ota_state(OtaImageState::Invalid) {
+        log::error!("Failed to mark the firmware invalid: {:?}", e);
+    }
+    if let Err(e) = ota.activate_next_partition() {
+        log::error!("Failed to select the previous firmware, keeping this one: {:?}", e);
+        confirm(ota);
+        return;
+    }
+    // The slot state is kept from its last update, it may not say so
+    let _ = ota.set_current_ota_state(OtaImageState::Valid);
+
+    // The checksum is only known to the update which installed this firmware
+    let checksum = load_report()
+        .filter(|report| report.version() == VERSION)
+        .and_then(|report| report.checksum);
+    save_report(Some(Report::new(VERSION, checksum, reason)));
+    boot::mark_switched();
+    esp_hal::system::software_reset();
+}
+
+/// Report of the last firmware rolled back to this one, or of the firmware
+/// being installed.
+fn load_report() -> Option<Report> {
+    // SAFETY: only accessed at boot, by the firmware check and right before
+    // rebooting, all on the main task
+    Report::from_words(unsafe { &core::ptr::addr_of!(RTC_ROLLBACK).read_volatile() })
+}
+
+fn save_report(report: Option<Report>) {
+    let words = report.map_or([0; rollback::WORDS], Report::to_words);
+    // SAFETY: see `load_report`
+    unsafe { core::ptr::addr_of_mut!(RTC_ROLLBACK).write_volatile(words) };
+}
+
+/// Progress of the last firmware download, `None` when none was interrupted.
+fn load_progress() -> Option<Progress> {
+    // SAFETY: only accessed by the firmware check, which runs on the main
+    // task
+    Progress::from_words(unsafe { &core::ptr::addr_of!(RTC_DOWNLOAD).read_volatile() })
+}
+
+fn save_progress(progress: Option<Progress>) {
+    let words = progress.map_or([0; download::WORDS], Progress::to_words);
+    // SAFETY: see `load_progress`
+    unsafe { core::ptr::addr_of_mut!(RTC_DOWNLOAD).write_volatile(words) };
+}
+
+/// Partition table buffer shared by all OTA data accesses, which are
+/// serialised by the flash mutex or happen before it exists.
+fn table_buffer() -> &'static mut [u8; PARTITION_TABLE_MAX_LEN] {
+    // Check if already initialized to avoid panic on re-entry
+    let ptr = OTA_TABLE_PTR.load(Ordering::Acquire);
+    if ptr.is_null() {
+        let buf = OTA_TABLE_BUFFER.init([0u8; PARTITION_TABLE_MAX_LEN]);
+        OTA_TABLE_PTR.store(buf as *mut _, Ordering::Release);
+        buf
+    } else {
+        // SAFETY: The pointer is only set once and the buffer is valid for 'static
+        unsafe { &mut *ptr }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Connection, // Network/TLS connection errors
-    Firmware,   // Firmware download or validation errors
     Info,       // Version info parsing errors
     Ota,        // Flash/partition operation errors
     Config,     // Configuration errors (missing OTA settings)
+    Checksum,   // Flashed image does not match the advertised CRC32 or SHA-256
+    Signature,  // Firmware signature missing, malformed or not matching the public key
+    Version,    // Signed image version differs from the one in the manifest
+    Status,     // OTA server answered with an error status, e.g. no firmware for the device
 }
 
-pub struct Ota<'a> {
+pub struct Ota {
     stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
     rng: ChaCha20Rng,
     rx_buf: &'static Mutex<NoopRawMutex, [u8; RX_BUFFER_SIZE]>,
@@ -41,12 +264,17 @@ pub struct Ota<'a> {
     tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
     tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
     device_id: &'static str,
+    channel: Channel,
     ota_hostname: &'static str,
     ota_port: u16,
-    flash: FlashStorage<'a>,
+    /// Repository the firmware is pulled from when `ota_hostname` is an OCI
+    /// registry rather than an OTA server
+    registry: Option<oci::Registry<'static>>,
+    verifying_key: VerifyingKey,
+    flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
 }
 
-impl<'a> Ota<'a> {
+impl Ota {
     pub fn new(
         stack: &'static Mutex<NoopRawMutex, Stack<'static>>,
         rng: ChaCha20Rng,
@@ -54,29 +282,42 @@ impl<'a> Ota<'a> {
         tx_buf: &'static Mutex<NoopRawMutex, [u8; TX_BUFFER_SIZE]>,
         tls_read_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
         tls_write_buf: &'static Mutex<NoopRawMutex, [u8; TLS_BUFFER_MAX]>,
-        mut flash: FlashStorage<'a>,
+        flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
     ) -> Result<Self, Error> {
-        // Initialize static buffer for OTA operations. This buffer persists
-        // for the lifetime of the program and avoids heap allocation.
-        // Check if already initialized to avoid panic on re-entry (e.g. soft reset)
-        let ptr = OTA_TABLE_PTR.load(Ordering::Acquire);
-        let table_buffer = if ptr.is_null() {
-            let buf = OTA_TABLE_BUFFER.init([0u8; PARTITION_TABLE_MAX_LEN]);
-            OTA_TABLE_PTR.store(buf as *mut _, Ordering::Release);
-            buf
-        } else {
-            // SAFETY: The pointer is only set once and the buffer is valid for 'static
-            unsafe { &mut *ptr }
-        };
-
-        // Mark current app as valid on startup. This confirms the boot was
-        // successful after an OTA update.
-        let mut ota = OtaUpdater::new(&mut flash, table_buffer).map_err(|_| Error::Ota)?;
-        ota.set_current_ota_state(OtaImageState::Valid).ok();
-
+        // The running firmware is confirmed by `verify`, after its health
+        // check rather than at startup
         let device_id = CONFIG.device_id;
+        let channel = CONFIG.ota_channel;
         let ota_hostname = CONFIG.ota_hostname.ok_or(Error::Config)?;
         let ota_port = CONFIG.ota_port.ok_or(Error::Config)?;
+        let registry = match CONFIG.ota_registry_repository {
+            None => None,
+            Some(repository) => {
+                let credentials = CONFIG.ota_registry_username.map(|username| oci::Credentials {
+                    username,
+                    password: CONFIG.ota_registry_password.unwrap_or(""),
+                });
+                let registry = oci::Registry::new(repository, device_id, credentials)
+                    .ok_or_else(|| {
+                        log::error!("OCI repository name too long");
+                        Error::Config
+                    })?;
+                log::info!("Pulling firmware from OCI repository {}", registry.name());
+                Some(registry)
+            }
+        };
+
+        // Public key used to verify firmware signatures. Without it, any server
+        // answering on ota_hostname could flash the device, so it is mandatory.
+        let public_key_pem = CONFIG.ota_public_key.ok_or(Error::Config)?;
+        let public_key_der = decode_pem(public_key_pem).map_err(|_| {
+            log::error!("Failed to decode OTA public key PEM");
+            Error::Config
+        })?;
+        let verifying_key = VerifyingKey::from_public_key_der(&public_key_der).map_err(|e| {
+            log::error!("Failed to parse OTA public key as P-256 SPKI: {:?}", e);
+            Error::Config
+        })?;
 
         Ok(Self {
             stack,
@@ -86,464 +327,531 @@ impl<'a> Ota<'a> {
             tls_read_buf,
             tls_write_buf,
             device_id,
+            channel,
             ota_hostname,
             ota_port,
+            registry,
+            verifying_key,
             flash,
         })
     }
 
+    /// Confirm or roll back the running firmware, see `verify`.
+    pub async fn verify(&self) {
+        verify(self.flash).await
+    }
+
     /// Check for and apply firmware updates from the OTA server.
     ///
     /// This method:
     /// 1. Connects to the OTA server over TLS
-    /// 2. Queries the current version available for this device
-    /// 3. If a newer version exists, downloads and flashes it
-    /// 4. Reboots the device to apply the update
+    /// 2. Queries the current version available for this device, or the
+    ///    newest tag of the OCI repository when one is configured
+    /// 3. If a newer version exists, downloads and flashes it, resuming an
+    ///    interrupted download with a range request
+    /// 4. Verifies the CRC32 and the ECDSA P-256 signature of the image
+    /// 5. Activates the new partition
+    ///
+    /// Returns `true` when a new firmware was activated, the caller is
+    /// expected to reboot the device to apply it. It is left pending
+    /// verification, see `init`.
     ///
     /// IMPORTANT: This method ensures the TLS session is properly closed on ALL
     /// code paths (success or error) to prevent orphaned TCP sockets from
     /// corrupting the network stack for subsequent connections.
-    pub async fn check(&mut self) -> Result<(), Error> {
+    pub async fn check(&mut self) -> Result<bool, Error> {
+        // Rolling back must select the firmware which was running before
+        if pending() {
+            log::info!("Firmware pending verification, skipping update check");
+            return Ok(false);
+        }
+
         let stack_guard = self.stack.lock().await;
         let mut rx_buf = self.rx_buf.lock().await;
         let mut tx_buf = self.tx_buf.lock().await;
         let mut tls_read_buf = self.tls_read_buf.lock().await;
         let mut tls_write_buf = self.tls_write_buf.lock().await;
 
-        // Create transport session (stack-allocated, no Box needed)
-        let mut session = Transport::new(
-            *stack_guard,
-            &mut self.rng,
-            &mut *rx_buf,
-            &mut *tx_buf,
-            &mut *tls_read_buf,
-            &mut *tls_write_buf,
-            self.ota_hostname,
-            self.ota_port,
-        )
-        .await
-        .map_err(|e| {
-            log::error!("OTA transport connection failed: {:?}", e);
-            Error::Connection
-        })?;
-
-        // HTTP request templates as static strings to avoid dynamic allocation.
-        // The leading \r\n in INFO_REQ_PREFIX handles potential leftover data.
-        const INFO_REQ_PREFIX: &str = "\r\nGET /version?device=";
-        const REQ_PREFIX: &str = " HTTP/1.1\r\nHost: ";
-        const REQ_SUFFIX_KEEPALIVE: &str = "\r\nConnection: keep-alive\r\n\r\n";
-        const REQ_SUFFIX_CLOSE: &str = "\r\nConnection: close\r\n\r\n";
+        // An interrupted download is resumed on a new connection, see
+        // `download`
+        let mut last_err = Error::Connection;
+        for attempt in 1..=OTA_DOWNLOAD_ATTEMPTS {
+            // Create transport session (stack-allocated, no Box needed)
+            let mut session = Transport::new(
+                *stack_guard,
+                &mut self.rng,
+                &mut *rx_buf,
+                &mut *tx_buf,
+                &mut *tls_read_buf,
+                &mut *tls_write_buf,
+                self.ota_hostname,
+                self.ota_port,
+            )
+            .await
+            .map_err(|e| {
+                log::error!("OTA transport connection failed: {:?}", e);
+                Error::Connection
+            })?;
 
-        // Send version check request. Close session on error to prevent
-        // orphaned sockets.
-        if let Err(e) = self.send_version_request(&mut session, INFO_REQ_PREFIX, REQ_PREFIX, REQ_SUFFIX_KEEPALIVE).await {
+            // Holds the response heads, both requests share the TLS session
+            let mut head_buf = [0u8; 1024];
+            let mut client =
+                http::Client::new(&mut session, self.ota_hostname, self.ota_port, &mut head_buf);
+            let result = update(
+                &mut client,
+                self.device_id,
+                self.channel,
+                self.registry.as_mut(),
+                &self.verifying_key,
+                self.flash,
+            )
+            .await;
+
+            // Close the session whatever the outcome to release the socket
             session.close().await;
-            return Err(e);
-        }
-
-        // Read and parse version info response
-        let mut buf = [0u8; 1024];
-        let (mut total_read, body_start) = match self.read_http_response(&mut session, &mut buf).await {
-            Ok(result) => result,
-            Err(e) => {
-                session.close().await;
-                return Err(e);
-            }
-        };
-
-        // Ensure we have at least 3 lines of body (Version, CRC, Size)
-        // or 2 newlines separating them (Ver\nCRC\nSize...)
-        loop {
-            let body = &buf[body_start..total_read];
-            let newlines = body.iter().filter(|&&b| b == b'\n').count();
-
-            // We need 2 newlines to guarantee we have "Line1\nLine2\n..." which
-            // corresponds to "Version\nCRC\nSize(maybe partial)"
-            if newlines >= 2 {
-                break;
-            }
-
-            if total_read == buf.len() {
-                // Buffer full but still not enough data
-                log::error!("OTA buffer full ({} bytes) while waiting for version info body", total_read);
-                session.close().await;
-                return Err(Error::Info);
-            }
-
-            let n = session.read(&mut buf[total_read..]).await.map_err(|e| {
-                log::error!("Failed to read from session: {:?}", e);
-                Error::Info
-            })?;
-            if n == 0 {
-                // EOF - if we have some data, we try to parse what we have
-                break;
+            match result {
+                Err(e @ Error::Connection) if load_progress().is_some() => {
+                    log::warn!(
+                        "Firmware download interrupted (attempt {}/{})",
+                        attempt,
+                        OTA_DOWNLOAD_ATTEMPTS
+                    );
+                    last_err = e;
+                }
+                result => return result,
             }
-            total_read += n;
         }
+        Err(last_err)
+    }
+}
 
-        let info_body = &buf[body_start..total_read];
-        let mut lines = info_body.split(|&b| b == b'\n');
-
-        // Parse version string from response. Server returns:
-        // Line 1: version (e.g., "1.2.3" or "No firmware for device 'xxx'")
-        // Line 2: CRC32 checksum
-        // Line 3: firmware size in bytes
-        let remote_version_str = match lines.next()
-            .and_then(|line| core::str::from_utf8(line).ok())
-            .map(|s| s.trim())
-        {
-            Some(v) => v,
-            None => {
-                log::error!("Failed to parse version response");
-                session.close().await;
-                return Err(Error::Info);
-            }
-        };
-
-        // Parse current version from Cargo.toml (VERSION constant)
-        let current_version = match SemVer::parse(VERSION) {
-            Some(v) => v,
-            None => {
-                log::error!("Failed to parse current version '{}' as semver", VERSION);
-                session.close().await;
-                return Err(Error::Info);
-            }
-        };
+/// Query the manifest of the firmware available for `device_id` on `channel`,
+/// from the OTA server or the OCI `registry` the client is connected to, and,
+/// when newer and rolled out to the device, download, verify and activate it.
+/// See `Ota::check`.
+async fn update<C: Read + Write>(
+    client: &mut http::Client<'_, C>,
+    device_id: &str,
+    channel: Channel,
+    mut registry: Option<&mut oci::Registry<'_>>,
+    verifying_key: &VerifyingKey,
+    flash: &Mutex<NoopRawMutex, FlashStorage<'static>>,
+) -> Result<bool, Error> {
+    let mut manifest_buf = [0u8; OTA_MANIFEST_MAX_SIZE];
+    let manifest = match registry.as_deref_mut() {
+        Some(registry) => pull_manifest(client, registry, channel, &mut manifest_buf).await?,
+        None => fetch_manifest(client, device_id, channel, &mut manifest_buf).await?,
+    };
+    let remote_version_str = manifest.version.as_str();
+
+    // Parse current version from Cargo.toml (VERSION constant)
+    let current_version = SemVer::parse(VERSION).ok_or_else(|| {
+        log::error!("Failed to parse current version '{}' as semver", VERSION);
+        Error::Info
+    })?;
 
-        // Parse remote version - may fail if server returns error message
-        // instead of a version string
-        let remote_version = match SemVer::parse(remote_version_str) {
-            Some(v) => v,
-            None => {
-                log::error!("Failed to parse remote version '{}' as semver", remote_version_str);
-                session.close().await;
-                return Err(Error::Info);
-            }
-        };
+    // Parse remote version - may fail if a legacy server returns an error
+    // message instead of a version string
+    let remote_version = SemVer::parse(remote_version_str).ok_or_else(|| {
+        log::error!("Failed to parse remote version '{}' as semver", remote_version_str);
+        Error::Info
+    })?;
 
-        // Only update if remote version is strictly greater (handles pre-release correctly)
-        if !remote_version.is_greater_than(&current_version) {
-            if remote_version == current_version {
-                log::info!("Already running latest version {}. Skipping update.", VERSION);
-            } else {
-                log::info!(
-                    "Remote version {} is not newer than current {}. Skipping update.",
-                    remote_version_str,
-                    VERSION
-                );
-            }
-            session.close().await;
-            return Ok(());
+    // Only update if remote version is strictly greater (handles pre-release
+    // correctly), unless it is a release rolling a newer one back
+    if remote_version == current_version {
+        log::info!("Already running latest version {}. Skipping update.", VERSION);
+        return Ok(false);
+    }
+    if remote_version < current_version {
+        if !manifest.allow_downgrade {
+            log::info!(
+                "Remote version {} is not newer than current {}. Skipping update.",
+                remote_version_str,
+                VERSION
+            );
+            return Ok(false);
         }
+        log::warn!("Rolling back from {} to version {}", VERSION, remote_version_str);
+    }
 
-        // Parse CRC32 and size for firmware validation
-        let _crc32 = match lines.next().and_then(|line| parse_number::<u32>(line).ok()) {
-            Some(v) => v,
-            None => {
-                log::error!("Failed to parse CRC32 from version info");
-                session.close().await;
-                return Err(Error::Info);
-            }
-        };
-
-        let size = match lines.next().and_then(|line| parse_number::<usize>(line).ok()) {
-            Some(v) => v,
-            None => {
-                log::error!("Failed to parse size from version info");
-                session.close().await;
-                return Err(Error::Info);
-            }
-        };
-
+    // A server unaware of channels may advertise a pre-release the device
+    // doesn't follow
+    let manifest_channel = manifest.channel.as_deref().and_then(Channel::from_name);
+    if !channel.accepts(&remote_version) || manifest_channel.is_some_and(|c| c < channel) {
         log::info!(
-            "OTA: upgrading from {} to {} (size={} bytes)",
-            VERSION,
+            "Version {} is not released on the {} channel, skipping update",
             remote_version_str,
-            size
+            channel.as_str()
         );
+        return Ok(false);
+    }
 
-        // Reuse the same TLS session for firmware download (keep-alive)
-        const FIRMWARE_REQ_PREFIX: &str = "GET /firmware?device=";
+    // Staged rollout, the release reaches the other devices later
+    if !manifest.is_rolled_out_to(device_id) {
+        log::info!(
+            "Version {} is rolled out to {}% of the devices, not this one (bucket {})",
+            remote_version_str,
+            manifest.rollout_percentage,
+            manifest::rollout_bucket(device_id)
+        );
+        return Ok(false);
+    }
 
-        if let Err(e) = self.send_version_request(&mut session, FIRMWARE_REQ_PREFIX, REQ_PREFIX, REQ_SUFFIX_CLOSE).await {
-            session.close().await;
-            return Err(e);
+    // Releases carrying migrations can't be skipped, older firmware must
+    // install an intermediate release first
+    if let Some(min_version) = &manifest.min_current_version {
+        let min = SemVer::parse(min_version).ok_or_else(|| {
+            log::error!("Failed to parse minimum version '{}' as semver", min_version);
+            Error::Info
+        })?;
+        if min > current_version {
+            log::warn!(
+                "Version {} requires at least {} to be running, skipping update",
+                remote_version_str,
+                min_version
+            );
+            return Ok(false);
         }
-
-        // Read firmware HTTP response headers
-        let mut buf = [0u8; 1024];
-        let (total_read, body_start) = match self.read_http_response(&mut session, &mut buf).await {
-            Ok(result) => result,
-            Err(_) => {
-                session.close().await;
-                return Err(Error::Firmware);
-            }
-        };
-
-        // Initialize OTA using the static table buffer.
-        // SAFETY: OTA_TABLE_PTR is set once in `new()` via OTA_TABLE_BUFFER.init() which returns
-        // a 'static reference. OTA operations are serialized through `&mut self`.
-        let table_buffer = unsafe { &mut *OTA_TABLE_PTR.load(Ordering::Acquire) };
-        let mut ota = match OtaUpdater::new(&mut self.flash, table_buffer) {
-            Ok(o) => o,
-            Err(_) => {
-                session.close().await;
-                return Err(Error::Ota);
-            }
-        };
-
-        let (mut next_app_partition, _part_type) = match ota.next_partition() {
-            Ok(p) => p,
-            Err(_) => {
-                session.close().await;
-                return Err(Error::Ota);
-            }
-        };
-
-        // Erase partition in chunks to avoid watchdog timeout.
-        // Flash erase is a blocking operation that can take several seconds for
-        // large partitions. Erasing in 64KB chunks allows yielding to keep the
-        // watchdog and WiFi tasks running.
-        let erase_len = (size + 4095) & !4095; // Round up to 4KB page boundary
-        log::info!("Erasing OTA partition ({} bytes)...", erase_len);
-
-        const ERASE_CHUNK_SIZE: u32 = 65536; // 64KB chunks
-        let mut erased: u32 = 0;
-        while erased < erase_len as u32 {
-            let chunk = core::cmp::min(ERASE_CHUNK_SIZE, erase_len as u32 - erased);
-            if let Err(e) = next_app_partition.erase(erased, erased + chunk) {
-                log::error!("Flash erase failed at offset {}: {:?}", erased, e);
-                session.close().await;
-                return Err(Error::Ota);
-            }
-            erased += chunk;
-
-            // Yield to let the watchdog and WiFi tasks run
-            Timer::after(embassy_time::Duration::from_millis(10)).await;
-            if erased % (256 * 1024) == 0 {
-                log::info!("Erase progress: {}KB / {}KB", erased / 1024, erase_len / 1024);
-            }
+    }
+    if let Some(requirement) = &manifest.current_version_requirement {
+        if !requirement.matches(&current_version) {
+            log::warn!(
+                "Version {} requires the running version to match {}, skipping update",
+                remote_version_str,
+                requirement
+            );
+            return Ok(false);
         }
+    }
 
-        let mut bytes_written: usize = 0;
-
-        // ESP32 flash requires 4-byte aligned writes. We buffer incoming data
-        // and only write when we have a multiple of 4 bytes. Any remainder is
-        // kept in the buffer for the next iteration.
-        const WRITE_ALIGN: usize = 4;
-        let mut write_buf = [0u8; OTA_CHUNK_BUFFER_SIZE];
-        let mut write_buf_len: usize = 0;
-
-        // Process any leftover bytes from the HTTP header read. These are the
-        // first bytes of the firmware binary that were read along with headers.
-        if total_read > body_start {
-            let leftover = &buf[body_start..total_read];
-            write_buf[..leftover.len()].copy_from_slice(leftover);
-            write_buf_len = leftover.len();
-            log::info!("OTA: buffered {} leftover bytes from header read", leftover.len());
+    // An image which failed its health check on this device is only
+    // installed again once the server offered another one
+    match load_report() {
+        Some(report) if report.rejects(remote_version_str, &manifest.checksum) => {
+            log::warn!(
+                "Version {} was rolled back on this device, skipping update",
+                remote_version_str
+            );
+            return Ok(false);
         }
+        Some(_) => save_report(None),
+        None => {}
+    }
 
-        // Download and flash firmware in chunks
-        let mut chunk_buf = [0u8; OTA_CHUNK_BUFFER_SIZE];
-        loop {
-            // Only read as much as we have space for in write_buf
-            let max_read = OTA_CHUNK_BUFFER_SIZE - write_buf_len;
-            let read_result = session.read(&mut chunk_buf[..max_read]).await;
-
-            match read_result {
-                Ok(0) => {
-                    // EOF reached - flush any remaining buffered data with padding
-                    if write_buf_len > 0 {
-                        let padded_len = (write_buf_len + WRITE_ALIGN - 1) & !(WRITE_ALIGN - 1);
-                        // Pad with 0xFF (erased flash state) for alignment
-                        for i in write_buf_len..padded_len {
-                            write_buf[i] = 0xFF;
-                        }
-                        if let Err(e) = next_app_partition.write(bytes_written as u32, &write_buf[..padded_len]) {
-                            log::error!("Flash write failed at offset {}: {:?}", bytes_written, e);
-                            session.close().await;
-                            return Err(Error::Ota);
-                        }
-                        bytes_written += write_buf_len; // Count actual bytes, not padding
-                    }
-                    break;
-                }
-                Ok(bytes_read) => {
-                    // Add new data to write buffer
-                    write_buf[write_buf_len..write_buf_len + bytes_read]
-                        .copy_from_slice(&chunk_buf[..bytes_read]);
-                    write_buf_len += bytes_read;
-
-                    // Write the aligned portion (multiple of 4 bytes)
-                    let aligned_len = write_buf_len & !(WRITE_ALIGN - 1);
-                    if aligned_len > 0 {
-                        if let Err(e) = next_app_partition.write(bytes_written as u32, &write_buf[..aligned_len]) {
-                            log::error!("Flash write failed at offset {}: {:?}", bytes_written, e);
-                            session.close().await;
-                            return Err(Error::Ota);
-                        }
-                        bytes_written += aligned_len;
-
-                        // Move remaining unaligned bytes to start of buffer
-                        let remaining = write_buf_len - aligned_len;
-                        if remaining > 0 {
-                            write_buf.copy_within(aligned_len..write_buf_len, 0);
-                        }
-                        write_buf_len = remaining;
-                    }
-
-                    // Log progress every ~10%
-                    if size > 0 && bytes_written % (size / 10) < OTA_CHUNK_BUFFER_SIZE {
-                        log::info!("Progress: {}%", (bytes_written * 100) / size);
-                        // Yield to let other tasks (wifi, watchdog) run
-                        Timer::after(embassy_time::Duration::from_millis(10)).await;
-                    }
-                }
-                Err(e) => {
-                    // Some TLS implementations signal EOF via error instead of Ok(0).
-                    // Treat EOF/ConnectionClosed as successful completion.
-                    let error_str = format!("{:?}", e);
-                    if error_str.contains("Eof")
-                        || error_str.contains("EOF")
-                        || error_str.contains("ConnectionClosed")
-                        || error_str.contains("Closed")
-                    {
-                        // EOF via error - flush remaining data with padding
-                        if write_buf_len > 0 {
-                            let padded_len = (write_buf_len + WRITE_ALIGN - 1) & !(WRITE_ALIGN - 1);
-                            for i in write_buf_len..padded_len {
-                                write_buf[i] = 0xFF;
-                            }
-                            if let Err(write_err) = next_app_partition.write(bytes_written as u32, &write_buf[..padded_len]) {
-                                log::error!("Flash write failed at offset {}: {:?}", bytes_written, write_err);
-                                session.close().await;
-                                return Err(Error::Ota);
-                            }
-                            bytes_written += write_buf_len;
-                        }
-                        break;
-                    } else {
-                        log::error!("Error reading firmware chunk: {:?}", e);
-                        session.close().await;
-                        return Err(Error::Firmware);
-                    }
-                }
-            }
-        }
+    let size = manifest.size;
+    let signature = parse_signature(&manifest.signature)?;
+    let download_path = manifest.download_path(client.host(), client.port()).map_err(|_| {
+        log::error!("Firmware download URL points to another server");
+        Error::Info
+    })?;
 
-        // Done reading firmware - close the TLS session to release the socket
-        session.close().await;
+    log::info!(
+        "OTA: upgrading from {} to {} (size={} bytes, channel={})",
+        VERSION,
+        remote_version_str,
+        size,
+        manifest.channel.as_deref().unwrap_or("-")
+    );
+    if let Some(url) = &manifest.release_notes_url {
+        log::info!("Release notes: {}", url);
+    }
 
-        log::info!(
-            "Firmware download complete: {} bytes written (expected {})",
-            bytes_written,
-            size
+    // Resume the download of the same image where it stopped. The image is
+    // told apart by its CRC32, or the start of its SHA-256.
+    let fingerprint = manifest.checksum.fingerprint();
+    let mut progress = match load_progress() {
+        Some(progress) if progress.is_for(remote_version_str, fingerprint, size) => {
+            log::info!("Resuming firmware download at {}/{} bytes", progress.written(), size);
+            progress
+        }
+        _ => Progress::new(remote_version_str, fingerprint, size),
+    };
+
+    // Initialize OTA using the static table buffer.
+    // The flash is shared with the MQTT outbox
+    let mut flash = flash.lock().await;
+    let mut ota = OtaUpdater::new(&mut *flash, table_buffer()).map_err(|_| Error::Ota)?;
+    let (mut next_app_partition, _part_type) = ota.next_partition().map_err(|_| Error::Ota)?;
+
+    // Reuse the same TLS session for firmware download (keep-alive). Each
+    // sector is erased right before being written, so there is no long
+    // blocking erase, and the reads let the WiFi task run.
+    let default_path = request_path("/firmware", device_id)?;
+    let path = download_path.unwrap_or(default_path.as_str());
+    // Registries want the blob request authorized as well
+    let authorization = registry
+        .as_deref()
+        .and_then(|registry| registry.authorization())
+        .map(|authorization| ("Authorization", authorization));
+    let mut buf = [0u8; OTA_CHUNK_BUFFER_SIZE];
+    let result = download::download(
+        client,
+        path,
+        authorization.as_slice(),
+        &mut next_app_partition,
+        &mut progress,
+        &mut buf,
+    )
+    .await;
+    save_progress(Some(progress));
+    if let Err(e) = result {
+        log::error!(
+            "Firmware download failed at {}/{} bytes: {:?}",
+            progress.written(),
+            size,
+            e
         );
-
-        if bytes_written != size {
+        return Err(match e {
+            download::Error::Http(e) => http_error(e),
+            download::Error::Status(_) | download::Error::Range | download::Error::Length => {
+                // Start over next time
+                save_progress(None);
+                Error::Status
+            }
+            download::Error::TooLarge | download::Error::Flash | download::Error::Checksum => {
+                Error::Ota
+            }
+        });
+    }
+    log::info!("Firmware download complete: {} bytes written", size);
+
+    // The image is verified once, a rejected one is downloaded again from the
+    // start
+    save_progress(None);
+
+    // Read the image back from flash to make sure what will be booted is
+    // what was downloaded. Hashing the flash content rather than the bytes
+    // received covers downloads resumed after a reboot.
+    let mut hasher = Sha256::new();
+    let mut header = [0u8; app_image::HEADER_LEN];
+    let mut header_len = 0;
+    let crc32 = match manifest.checksum {
+        Checksum::Crc32(crc32) => Some(crc32),
+        Checksum::Sha256(_) => None,
+    };
+    download::read_back(
+        &mut next_app_partition,
+        size as usize,
+        crc32,
+        &mut buf,
+        |chunk| {
+            hasher.update(chunk);
+            let len = chunk.len().min(header.len() - header_len);
+            header[header_len..header_len + len].copy_from_slice(&chunk[..len]);
+            header_len += len;
+        },
+    )
+    .await
+    .map_err(|e| match e {
+        download::Error::Checksum => {
             log::error!(
-                "Size mismatch: wrote {} bytes, expected {}",
-                bytes_written,
-                size
+                "CRC32 mismatch on flash read-back, expected {:08x}",
+                crc32.unwrap_or_default()
             );
-            return Err(Error::Firmware);
+            Error::Checksum
         }
-
-        // Activate the new partition and mark it for boot
-        ota.activate_next_partition().map_err(|e| {
-            log::error!("Failed to activate next partition: {:?}", e);
+        e => {
+            log::error!("Failed to read back OTA partition: {:?}", e);
             Error::Ota
-        })?;
-        ota.set_current_ota_state(OtaImageState::New).map_err(|e| {
-            log::error!("Failed to set OTA state to New: {:?}", e);
-            Error::Ota
-        })?;
-
-        log::info!("OTA complete. Rebooting...");
-        Timer::after(embassy_time::Duration::from_millis(1_000)).await;
-        esp_hal::system::software_reset();
+        }
+    })?;
+    match manifest.checksum {
+        Checksum::Crc32(crc32) => log::info!("Firmware CRC32 verified: {:08x}", crc32),
+        Checksum::Sha256(digest) => {
+            if hasher.clone().finalize()[..] != digest[..] {
+                log::error!("SHA-256 mismatch on flash read-back");
+                return Err(Error::Checksum);
+            }
+            log::info!("Firmware SHA-256 verified");
+        }
     }
 
-    /// Send an HTTP request to the OTA server.
-    /// Buffers the request to send in a single packet for efficiency.
-    async fn send_version_request<T: Read + Write>(
-        &self,
-        session: &mut T,
-        req_prefix: &str,
-        host_prefix: &str,
-        req_suffix: &str,
-    ) -> Result<(), Error> {
-        // Buffer the request to send in a single packet
-        // Max length: prefix (~20) + device_id (32) + host_prefix (~20) + hostname (~64) + suffix (~25) = ~161
-        // 512 is plenty safe
-        let mut request: String<512> = String::new();
-        use core::fmt::Write as FmtWrite;
-
-        write!(
-            request,
-            "{}{}{}{}{}",
-            req_prefix,
-            self.device_id,
-            host_prefix,
-            self.ota_hostname,
-            req_suffix
-        ).map_err(|_| Error::Connection)?;
-
-        session.write_all(request.as_bytes()).await.map_err(|_| Error::Connection)?;
-        Ok(())
+    verifying_key
+        .verify_digest(hasher, &signature)
+        .map_err(|_| {
+            log::error!("Firmware signature verification failed");
+            Error::Signature
+        })?;
+    log::info!("Firmware signature verified");
+
+    // The manifest isn't signed: the version the update and downgrade checks
+    // relied on must be the one of the signed image
+    let image_version = app_image::version(&header);
+    if image_version != Some(remote_version_str) {
+        log::error!(
+            "Signed image version {:?} differs from the manifest version {}",
+            image_version,
+            remote_version_str
+        );
+        return Err(Error::Version);
     }
 
-    /// Read an HTTP response until the header/body boundary (\r\n\r\n).
-    /// Returns (total_bytes_read, body_start_offset).
-    async fn read_http_response<T: Read>(
-        &self,
-        session: &mut T,
-        buf: &mut [u8],
-    ) -> Result<(usize, usize), Error> {
-        let mut total_read = 0;
-
-        loop {
-            let n = session.read(&mut buf[total_read..]).await.map_err(|e| {
-                log::error!("Failed to read HTTP response: {:?}", e);
-                Error::Info
-            })?;
+    // Activate the new partition and mark it for boot
+    ota.activate_next_partition().map_err(|e| {
+        log::error!("Failed to activate next partition: {:?}", e);
+        Error::Ota
+    })?;
+    ota.set_current_ota_state(OtaImageState::New).map_err(|e| {
+        log::error!("Failed to set OTA state to New: {:?}", e);
+        Error::Ota
+    })?;
 
-            if n == 0 {
-                break;
-            }
+    // Stands for the new firmware if it resets before its health check
+    save_report(Some(Report::new(
+        remote_version_str,
+        Some(manifest.checksum),
+        Reason::Reset,
+    )));
 
-            total_read += n;
-            if let Some(pos) = find_header_end(&buf[..total_read]) {
-                return Ok((total_read, pos));
-            }
+    log::info!("OTA complete, new firmware activated");
+    Ok(true)
+}
+
+/// Manifest of the firmware the OTA server has for `device_id` on `channel`.
+/// `buf` holds the response.
+async fn fetch_manifest<C: Read + Write>(
+    client: &mut http::Client<'_, C>,
+    device_id: &str,
+    channel: Channel,
+    buf: &mut [u8],
+) -> Result<Manifest, Error> {
+    // The server picks the release for the channel, and can tell what the
+    // device runs
+    let mut path = request_path("/version", device_id)?;
+    write!(path, "&channel={}&version={}", channel.as_str(), VERSION).map_err(|_| Error::Config)?;
+    let response = client
+        .get(&path, &[("Connection", "keep-alive")])
+        .await
+        .map_err(|e| {
+            log::error!("OTA request /version failed: {:?}", e);
+            http_error(e)
+        })?;
 
-            // Buffer full but no header end found
-            if total_read == buf.len() {
-                log::error!("OTA buffer full ({} bytes) while reading HTTP headers", total_read);
-                return Err(Error::Info);
+    // The manifest, or the explanation of an error status
+    let len = read_manifest(client, buf).await;
+    if !response.is_success() {
+        match len.map(|len| manifest::parse(&buf[..len])) {
+            Ok(Err(manifest::Error::NoFirmware(message))) => {
+                log::error!("OTA server answered {}: {}", response.status, message)
             }
+            _ => log::error!("OTA server answered {} to /version", response.status),
         }
-
-        Err(Error::Info)
+        return Err(Error::Status);
     }
+    manifest::parse(&buf[..len?]).map_err(|e| match e {
+        manifest::Error::NoFirmware(message) => {
+            log::warn!("No firmware for this device: {}", message);
+            Error::Status
+        }
+        manifest::Error::Invalid("signature") => {
+            log::error!("Missing or malformed firmware signature in version info");
+            Error::Signature
+        }
+        e => {
+            log::error!("Failed to parse version info: {:?}", e);
+            Error::Info
+        }
+    })
 }
 
-/// Parse a number from a byte slice (UTF-8 string).
-fn parse_number<T: core::str::FromStr>(bytes: &[u8]) -> Result<T, Error> {
-    let s = core::str::from_utf8(bytes).map_err(|_| {
-        log::error!("Failed to parse bytes as UTF-8");
-        Error::Info
-    })?;
-    s.trim().parse::<T>().map_err(|_| {
-        log::error!("Failed to parse number from string: '{}'", s.trim());
-        Error::Info
+/// Manifest of the newest firmware on `channel` in the repository of the
+/// device on the OCI registry. `buf` holds the responses.
+async fn pull_manifest<C: Read + Write>(
+    client: &mut http::Client<'_, C>,
+    registry: &mut oci::Registry<'_>,
+    channel: Channel,
+    buf: &mut [u8],
+) -> Result<Manifest, Error> {
+    let result = registry.latest(client, channel, buf).await;
+    result.map_err(|e| match e {
+        oci::Error::Http(e) => {
+            log::error!("OCI registry request failed: {:?}", e);
+            http_error(e)
+        }
+        oci::Error::Status(status) => {
+            log::error!("OCI registry answered {} for {}", status, registry.name());
+            Error::Status
+        }
+        oci::Error::Unauthorized => {
+            log::error!("OCI registry refused to authorize pulling {}", registry.name());
+            Error::Status
+        }
+        oci::Error::NoVersion => {
+            log::warn!(
+                "No firmware for this device on the {} channel in {}",
+                channel.as_str(),
+                registry.name()
+            );
+            Error::Status
+        }
+        oci::Error::Invalid(oci::SIGNATURE_ANNOTATION) => {
+            log::error!("Missing or malformed firmware signature annotation");
+            Error::Signature
+        }
+        e => {
+            log::error!("Failed to resolve the firmware image: {:?}", e);
+            Error::Info
+        }
     })
 }
 
-/// Find the HTTP header/body boundary (\r\n\r\n) in a buffer.
-/// Returns the offset where the body starts (after the boundary).
-fn find_header_end(buf: &[u8]) -> Option<usize> {
-    buf.windows(4)
-        .position(|window| window == b"\r\n\r\n")
-        .map(|pos| pos + 4)
+/// Read the body of the `/version` response into `buf`, returns its length.
+async fn read_manifest<C: Read + Write>(
+    client: &mut http::Client<'_, C>,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let mut len = 0;
+    loop {
+        if len == buf.len() {
+            log::error!("Version info larger than {} bytes", buf.len());
+            return Err(Error::Info);
+        }
+        let n = client.read_body(&mut buf[len..]).await.map_err(|e| {
+            log::error!("Failed to read version info: {:?}", e);
+            Error::Info
+        })?;
+        if n == 0 {
+            return Ok(len);
+        }
+        len += n;
+    }
+}
+
+/// Path of `endpoint` for `device_id`
+fn request_path(endpoint: &str, device_id: &str) -> Result<String<{ http::PATH_MAX_LEN }>, Error> {
+    let mut path = String::new();
+    write!(path, "{}?device={}", endpoint, device_id).map_err(|_| Error::Config)?;
+    Ok(path)
+}
+
+fn http_error(e: http::Error) -> Error {
+    match e {
+        http::Error::Io(_) | http::Error::UnexpectedEof => Error::Connection,
+        // The firmware is downloaded on the connection of the version
+        // request, the server must keep it open
+        http::Error::Closed
+        | http::Error::HeadTooLarge
+        | http::Error::Malformed
+        | http::Error::RequestTooLarge
+        | http::Error::Redirect => Error::Info,
+    }
+}
+
+/// Parse a base64 encoded ECDSA P-256 signature, either in fixed-size (r || s)
+/// or ASN.1 DER form.
+fn parse_signature(s: &str) -> Result<Signature, Error> {
+    use base64::Engine;
+    let raw = base64::engine::general_purpose::STANDARD.decode(s.trim());
+    let signature = raw.ok().and_then(|raw| {
+        if raw.len() == 64 {
+            Signature::from_slice(&raw).ok()
+        } else {
+            Signature::from_der(&raw).ok()
+        }
+    });
+    signature.ok_or_else(|| {
+        log::error!("Malformed firmware signature in version info");
+        Error::Signature
+    })
 }
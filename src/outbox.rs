@@ -0,0 +1,129 @@
+//! MQTT messages kept in the `storage` partition while the broker is
+//! unreachable, so they survive reboots and deep sleep.
+//!
+//! Each message is a `SampleStore` record:
+//!
+//! ```text
+//! retain     u8   1 for a retained message
+//! topic_len  u8
+//! topic      topic_len bytes, UTF-8
+//! payload    up to the end of the record
+//! ```
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use esp_bootloader_esp_idf::partitions::{
+    self, DataPartitionSubType, PartitionType, PARTITION_TABLE_MAX_LEN,
+};
+use esp_storage::FlashStorage;
+
+use crate::constants::*;
+use crate::sample_store::{self, SampleStore};
+
+/// Largest record written, a message of maximum size
+const RECORD_MAX_LEN: usize = 2 + MQTT_TOPIC_MAX_LEN + MQTT_PAYLOAD_MAX_LEN;
+
+#[derive(Debug)]
+pub enum Error {
+    PartitionTable,
+    NoPartition,
+    Store(sample_store::Error),
+}
+
+impl From<sample_store::Error> for Error {
+    fn from(e: sample_store::Error) -> Self {
+        Error::Store(e)
+    }
+}
+
+/// A stored message, borrowed from the outbox until the next call
+pub struct Message<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub retain: bool,
+}
+
+pub struct Outbox {
+    flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
+    store: SampleStore,
+    buf: [u8; RECORD_MAX_LEN],
+}
+
+impl Outbox {
+    /// Open the store in the `storage` partition (data subtype 0x82, named
+    /// SPIFFS by ESP-IDF). Messages stored before a reboot are kept.
+    pub async fn mount(
+        flash: &'static Mutex<NoopRawMutex, FlashStorage<'static>>,
+    ) -> Result<Self, Error> {
+        let mut guard = flash.lock().await;
+        let mut table = [0u8; PARTITION_TABLE_MAX_LEN];
+        let partition_table = partitions::read_partition_table(&mut *guard, &mut table)
+            .map_err(|_| Error::PartitionTable)?;
+        let storage = partition_table
+            .find_partition(PartitionType::Data(DataPartitionSubType::Spiffs))
+            .map_err(|_| Error::PartitionTable)?
+            .ok_or(Error::NoPartition)?;
+
+        let store = SampleStore::mount(&mut *guard, storage.offset(), storage.len())?;
+        drop(guard);
+
+        Ok(Self {
+            flash,
+            store,
+            buf: [0; RECORD_MAX_LEN],
+        })
+    }
+
+    /// Append a message behind the stored ones. The oldest messages are
+    /// dropped when the partition is full.
+    pub async fn push(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), Error> {
+        let topic_len = u8::try_from(topic.len()).map_err(|_| sample_store::Error::TooLarge)?;
+        let len = 2 + topic.len() + payload.len();
+        let record = self
+            .buf
+            .get_mut(..len)
+            .ok_or(sample_store::Error::TooLarge)?;
+        record[0] = retain as u8;
+        record[1] = topic_len;
+        record[2..2 + topic.len()].copy_from_slice(topic.as_bytes());
+        record[2 + topic.len()..].copy_from_slice(payload);
+
+        let mut flash = self.flash.lock().await;
+        self.store.push(&mut *flash, &self.buf[..len])?;
+        Ok(())
+    }
+
+    /// Oldest stored message, which stays in the outbox until `pop`.
+    /// Unreadable records are dropped.
+    pub async fn peek(&mut self) -> Result<Option<Message<'_>>, Error> {
+        let len = loop {
+            let mut flash = self.flash.lock().await;
+            match self.store.peek(&mut *flash, &mut self.buf) {
+                Ok(None) => return Ok(None),
+                Ok(Some(len)) if decode(&self.buf[..len]).is_some() => break len,
+                Ok(Some(_)) | Err(sample_store::Error::TooLarge) => {
+                    log::warn!("Dropping unreadable message from the outbox");
+                    self.store.pop(&mut *flash)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(decode(&self.buf[..len]))
+    }
+
+    /// Remove the message returned by `peek` once published.
+    pub async fn pop(&mut self) -> Result<(), Error> {
+        let mut flash = self.flash.lock().await;
+        self.store.pop(&mut *flash)?;
+        Ok(())
+    }
+}
+
+fn decode(record: &[u8]) -> Option<Message<'_>> {
+    let (&[retain, topic_len], rest) = record.split_first_chunk::<2>()?;
+    let (topic, payload) = rest.split_at_checked(topic_len as usize)?;
+    Some(Message {
+        topic: core::str::from_utf8(topic).ok()?,
+        payload,
+        retain: retain == 1,
+    })
+}
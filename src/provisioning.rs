@@ -0,0 +1,598 @@
+//! Wi-Fi provisioning portal.
+//!
+//! When the device can't join its network, `wifi.rs` starts a WPA2 access
+//! point serving a form to enter the Wi-Fi and MQTT settings, which are saved
+//! as configuration overrides (see `config_store`). This module holds the parts
+//! which don't depend on the radio:
+//!
+//! - HTTP request and `application/x-www-form-urlencoded` form parsing
+//! - the HTML page
+//! - minimal DHCP and DNS servers, handing out addresses to the clients and
+//!   resolving every name to the portal so phones open it on their own
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+
+use crate::config_store::{self, Key, Record, Value};
+
+/// Longest SSID accepted by the radio
+const SSID_MAX_LEN: usize = 32;
+/// WPA passphrases are 8 to 63 characters, or the key as 64 hex digits
+const PSK_MIN_LEN: usize = 8;
+const PSK_MAX_LEN: usize = 63;
+const PSK_HEX_LEN: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Malformed or unsupported HTTP request
+    BadRequest,
+    /// The request doesn't fit in the receive buffer
+    TooLarge,
+    /// A form value isn't valid percent encoded UTF-8
+    InvalidEncoding,
+    /// The SSID is missing or too long
+    InvalidSsid,
+    /// The passphrase is neither empty (open network), 8 to 63 characters
+    /// nor 64 hex digits
+    InvalidPsk,
+    /// The MQTT port isn't a number between 1 and 65535
+    InvalidPort,
+}
+
+impl Error {
+    /// Message shown on the portal page
+    pub fn message(&self) -> &'static str {
+        match self {
+            Error::BadRequest => "Invalid request.",
+            Error::TooLarge => "The form is too large.",
+            Error::InvalidEncoding => "The form contains invalid characters.",
+            Error::InvalidSsid => "The Wi-Fi network name must be 1 to 32 bytes long.",
+            Error::InvalidPsk => {
+                "The Wi-Fi password must be empty (open network), 8 to 63 characters long or 64 hex digits."
+            }
+            Error::InvalidPort => "The MQTT port must be a number between 1 and 65535.",
+        }
+    }
+}
+
+/// An HTTP request, received completely
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    /// Request target without the query string
+    pub path: &'a str,
+    pub body: &'a [u8],
+}
+
+/// Parse an HTTP/1.x request from the bytes received so far. Returns `None`
+/// until the headers and the whole body announced by `Content-Length` are
+/// received.
+pub fn parse_request(buf: &[u8]) -> Result<Option<Request<'_>>, Error> {
+    let Some(header_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = core::str::from_utf8(&buf[..header_end]).map_err(|_| Error::BadRequest)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version), None) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(Error::BadRequest);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(Error::BadRequest);
+    }
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(Error::BadRequest)?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| Error::BadRequest)?;
+        }
+    }
+
+    let body_start = header_end + 4;
+    let Some(body) = buf.get(body_start..body_start + content_length) else {
+        return Ok(None);
+    };
+
+    Ok(Some(Request { method, path, body }))
+}
+
+/// Settings submitted through the portal. Field names match the `cfg.toml`
+/// keys.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Form<'a> {
+    pub wifi_ssid: &'a str,
+    pub wifi_psk: &'a str,
+    pub mqtt_hostname: &'a str,
+    pub mqtt_port: Option<u16>,
+    pub mqtt_username: &'a str,
+    pub mqtt_password: &'a str,
+    pub location: &'a str,
+}
+
+impl<'a> Form<'a> {
+    /// Decode and validate a form body, using `buf` (at least as long as
+    /// `body`) to hold the decoded values. Unknown fields are ignored.
+    pub fn parse(body: &[u8], buf: &'a mut [u8]) -> Result<Self, Error> {
+        let mut form = Form::default();
+        let mut buf = buf;
+
+        for field in body.split(|&b| b == b'&').filter(|f| !f.is_empty()) {
+            let (name, value) = match field.iter().position(|&b| b == b'=') {
+                Some(i) => (&field[..i], &field[i + 1..]),
+                None => (field, &[][..]),
+            };
+            let (value, rest) = url_decode(value, core::mem::take(&mut buf))?;
+            buf = rest;
+
+            match name {
+                b"wifi_ssid" => form.wifi_ssid = value,
+                b"wifi_psk" => form.wifi_psk = value,
+                b"mqtt_hostname" => form.mqtt_hostname = value.trim(),
+                b"mqtt_port" => {
+                    let value = value.trim();
+                    form.mqtt_port = match value {
+                        "" => None,
+                        _ => match value.parse() {
+                            Ok(port) if port > 0 => Some(port),
+                            _ => return Err(Error::InvalidPort),
+                        },
+                    };
+                }
+                b"mqtt_username" => form.mqtt_username = value,
+                b"mqtt_password" => form.mqtt_password = value,
+                b"location" => form.location = value.trim(),
+                _ => {}
+            }
+        }
+
+        if form.wifi_ssid.is_empty() || form.wifi_ssid.len() > SSID_MAX_LEN {
+            return Err(Error::InvalidSsid);
+        }
+        if !valid_psk(form.wifi_psk) {
+            return Err(Error::InvalidPsk);
+        }
+
+        Ok(form)
+    }
+
+    /// Store the settings in `record`. The Wi-Fi credentials are always set,
+    /// an empty passphrase meaning an open network. Other fields left empty
+    /// keep their current value.
+    pub fn apply(&self, record: &mut Record<'a>) -> Result<(), config_store::Error> {
+        record.set(Key::WifiSsid, Value::Str(self.wifi_ssid))?;
+        record.set(Key::WifiPsk, Value::Str(self.wifi_psk))?;
+
+        let strings = [
+            (Key::MqttHostname, self.mqtt_hostname),
+            (Key::MqttUsername, self.mqtt_username),
+            (Key::MqttPassword, self.mqtt_password),
+            (Key::Location, self.location),
+        ];
+        for (key, value) in strings {
+            if !value.is_empty() {
+                record.set(key, Value::Str(value))?;
+            }
+        }
+        if let Some(port) = self.mqtt_port {
+            record.set(Key::MqttPort, Value::U16(port))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `psk` is empty (open network), a passphrase or a hex encoded key
+fn valid_psk(psk: &str) -> bool {
+    match psk.len() {
+        0 | PSK_MIN_LEN..=PSK_MAX_LEN => true,
+        PSK_HEX_LEN => psk.bytes().all(|b| b.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+/// Decode a form value into the start of `out`, returning it along with the
+/// rest of `out`.
+fn url_decode<'a>(input: &[u8], out: &'a mut [u8]) -> Result<(&'a str, &'a mut [u8]), Error> {
+    let mut len = 0;
+    let mut i = 0;
+
+    while i < input.len() {
+        let byte = match input[i] {
+            b'+' => b' ',
+            b'%' => {
+                let hex = input.get(i + 1..i + 3).ok_or(Error::InvalidEncoding)?;
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(Error::InvalidEncoding);
+                }
+                i += 2;
+                (hex_value(hex[0]) << 4) | hex_value(hex[1])
+            }
+            b => b,
+        };
+        *out.get_mut(len).ok_or(Error::TooLarge)? = byte;
+        len += 1;
+        i += 1;
+    }
+
+    let (decoded, rest) = out.split_at_mut(len);
+    let decoded = core::str::from_utf8(decoded).map_err(|_| Error::InvalidEncoding)?;
+    Ok((decoded, rest))
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => (digit | 0x20) - b'a' + 10,
+    }
+}
+
+/// Write the status line and headers of an HTML response.
+pub fn write_response_head<W: Write>(
+    w: &mut W,
+    status: &str,
+    content_length: usize,
+) -> fmt::Result {
+    write!(
+        w,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-store\r\n\
+         Connection: close\r\n\r\n",
+        status, content_length
+    )
+}
+
+/// Write the settings page, pre-filled with `current`. Passwords are never
+/// sent back. `notice` is shown above the form, e.g. a validation error.
+pub fn write_page<W: Write>(
+    w: &mut W,
+    device_id: &str,
+    current: &Form<'_>,
+    notice: Option<&str>,
+) -> fmt::Result {
+    write_page_start(w, device_id)?;
+    if let Some(notice) = notice {
+        w.write_str("<p class=\"notice\">")?;
+        write_html_escaped(w, notice)?;
+        w.write_str("</p>")?;
+    }
+
+    w.write_str("<form method=\"post\" action=\"/\"><fieldset><legend>Wi-Fi</legend>")?;
+    write_input(
+        w,
+        "Network name",
+        "wifi_ssid",
+        "text",
+        current.wifi_ssid,
+        true,
+    )?;
+    write_input(w, "Password", "wifi_psk", "password", "", false)?;
+    w.write_str("</fieldset><fieldset><legend>MQTT</legend>")?;
+    write_input(
+        w,
+        "Broker",
+        "mqtt_hostname",
+        "text",
+        current.mqtt_hostname,
+        false,
+    )?;
+    let mut port: String<5> = String::new();
+    if let Some(value) = current.mqtt_port {
+        write!(port, "{}", value)?;
+    }
+    write_input(w, "Port", "mqtt_port", "number", &port, false)?;
+    write_input(
+        w,
+        "Username",
+        "mqtt_username",
+        "text",
+        current.mqtt_username,
+        false,
+    )?;
+    write_input(
+        w,
+        "Password (empty to keep)",
+        "mqtt_password",
+        "password",
+        "",
+        false,
+    )?;
+    w.write_str("</fieldset><fieldset><legend>Device</legend>")?;
+    write_input(w, "Location", "location", "text", current.location, false)?;
+    w.write_str("</fieldset><button type=\"submit\">Save and restart</button></form>")?;
+
+    write_page_end(w)
+}
+
+/// Write the page confirming the settings were saved.
+pub fn write_saved_page<W: Write>(w: &mut W, device_id: &str, wifi_ssid: &str) -> fmt::Result {
+    write_page_start(w, device_id)?;
+    w.write_str("<p>Settings saved. The device restarts and joins <b>")?;
+    write_html_escaped(w, wifi_ssid)?;
+    w.write_str("</b>, this access point goes away.</p>")?;
+    write_page_end(w)
+}
+
+fn write_page_start<W: Write>(w: &mut W, device_id: &str) -> fmt::Result {
+    w.write_str(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width,initial-scale=1\">\
+         <title>Sensor setup</title><style>\
+         body{font-family:sans-serif;max-width:28em;margin:auto;padding:1em}\
+         label,input,button{display:block;width:100%;box-sizing:border-box}\
+         input{margin:.2em 0 .8em;padding:.4em}button{padding:.6em}\
+         .notice{color:#b00}</style></head><body><h1>",
+    )?;
+    write_html_escaped(w, device_id)?;
+    w.write_str("</h1>")
+}
+
+fn write_page_end<W: Write>(w: &mut W) -> fmt::Result {
+    w.write_str("</body></html>")
+}
+
+fn write_input<W: Write>(
+    w: &mut W,
+    label: &str,
+    name: &str,
+    kind: &str,
+    value: &str,
+    required: bool,
+) -> fmt::Result {
+    write!(
+        w,
+        "<label for=\"{name}\">{label}</label><input id=\"{name}\" name=\"{name}\" type=\"{kind}\" value=\""
+    )?;
+    write_html_escaped(w, value)?;
+    w.write_str(if required { "\" required>" } else { "\">" })
+}
+
+fn write_html_escaped<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '&' => w.write_str("&amp;")?,
+            '<' => w.write_str("&lt;")?,
+            '>' => w.write_str("&gt;")?,
+            '"' => w.write_str("&quot;")?,
+            '\'' => w.write_str("&#39;")?,
+            c => w.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Answer a DNS query with `address` for any name, which makes clients open
+/// the portal whatever URL they try. Queries for other record types than A get
+/// an empty answer. Returns the length of the reply written in `out`, `None`
+/// when `query` isn't a standard query.
+pub fn dns_reply(query: &[u8], address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    const HEADER_LEN: usize = 12;
+    const TYPE_A: u16 = 1;
+    const TYPE_ANY: u16 = 255;
+
+    let header = query.get(..HEADER_LEN)?;
+    // Must be a query (QR = 0) with opcode QUERY and a single question
+    if header[2] & 0xF8 != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+
+    // Question: a sequence of labels ended by the root label, type and class
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers aren't used in questions
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    let question = query.get(HEADER_LEN..end)?;
+
+    let answer = qtype == TYPE_A || qtype == TYPE_ANY;
+    let reply_len = end + if answer { 16 } else { 0 };
+    let out = out.get_mut(..reply_len)?;
+
+    out[0..2].copy_from_slice(&header[0..2]);
+    // QR, authoritative, recursion desired copied from the query, no error
+    out[2] = 0x84 | (header[2] & 0x01);
+    out[3] = 0x00;
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&u16::from(answer).to_be_bytes());
+    out[8..12].fill(0);
+    out[HEADER_LEN..end].copy_from_slice(question);
+
+    if answer {
+        let record = &mut out[end..];
+        // Name as a pointer to the question, class IN, TTL 60s
+        record[0..2].copy_from_slice(&0xC00Cu16.to_be_bytes());
+        record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&1u16.to_be_bytes());
+        record[6..10].copy_from_slice(&60u32.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..16].copy_from_slice(&address);
+    }
+
+    Some(reply_len)
+}
+
+/// Number of clients which can get an address from the portal
+const DHCP_POOL_SIZE: usize = 4;
+/// Lease time handed out to the clients
+const DHCP_LEASE_SECS: u32 = 3600;
+
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// BOOTP replies are padded to this size, some clients drop shorter ones
+const DHCP_MIN_REPLY_LEN: usize = 300;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+/// Minimal DHCP server for the portal network (`/24` around the portal
+/// address). Addresses following the portal are handed out to the clients in
+/// turn, recycling the oldest lease when the pool is full.
+pub struct DhcpServer {
+    address: [u8; 4],
+    clients: [Option<[u8; 6]>; DHCP_POOL_SIZE],
+    next: usize,
+}
+
+impl DhcpServer {
+    pub const fn new(address: [u8; 4]) -> Self {
+        Self {
+            address,
+            clients: [None; DHCP_POOL_SIZE],
+            next: 0,
+        }
+    }
+
+    /// Handle a client message, writing the reply to broadcast in `out`.
+    /// Returns the reply length, `None` when the message needs no answer.
+    pub fn reply(&mut self, request: &[u8], out: &mut [u8]) -> Option<usize> {
+        // BOOTREQUEST over Ethernet
+        if request.len() < DHCP_OPTIONS_OFFSET
+            || request[0] != 1
+            || request[1] != 1
+            || request[2] != 6
+        {
+            return None;
+        }
+        if request[236..240] != DHCP_MAGIC_COOKIE {
+            return None;
+        }
+        let options = &request[DHCP_OPTIONS_OFFSET..];
+        let mac: [u8; 6] = request[28..34].try_into().ok()?;
+
+        let reply_type = match dhcp_option(options, 53)? {
+            [DHCP_DISCOVER] => DHCP_OFFER,
+            [DHCP_REQUEST] => {
+                // Another server was selected by the client
+                if let Some(server) = dhcp_option(options, 54) {
+                    if server != self.address {
+                        return None;
+                    }
+                }
+                let requested = match dhcp_option(options, 50) {
+                    Some(requested) => requested,
+                    // Renewal: the address is in ciaddr
+                    None => &request[12..16],
+                };
+                if requested == self.lease(mac) {
+                    DHCP_ACK
+                } else {
+                    DHCP_NAK
+                }
+            }
+            // Releases and declines need no answer, the pool recycles leases
+            _ => return None,
+        };
+
+        let out = out.get_mut(..DHCP_MIN_REPLY_LEN)?;
+        out.fill(0);
+        // BOOTREPLY, Ethernet, transaction id and flags copied from the request
+        out[0] = 2;
+        out[1] = 1;
+        out[2] = 6;
+        out[4..8].copy_from_slice(&request[4..8]);
+        out[10..12].copy_from_slice(&request[10..12]);
+        if reply_type != DHCP_NAK {
+            out[16..20].copy_from_slice(&self.lease(mac));
+            out[20..24].copy_from_slice(&self.address);
+        }
+        out[28..34].copy_from_slice(&mac);
+        out[236..240].copy_from_slice(&DHCP_MAGIC_COOKIE);
+
+        let mut options = DhcpOptions {
+            out: &mut out[DHCP_OPTIONS_OFFSET..],
+            len: 0,
+        };
+        options.push(53, &[reply_type])?;
+        options.push(54, &self.address)?;
+        if reply_type != DHCP_NAK {
+            options.push(51, &DHCP_LEASE_SECS.to_be_bytes())?;
+            options.push(1, &[255, 255, 255, 0])?;
+            options.push(3, &self.address)?;
+            options.push(6, &self.address)?;
+        }
+        options.push(255, &[])?;
+
+        Some(DHCP_MIN_REPLY_LEN)
+    }
+
+    /// Address of the client with hardware address `mac`, allocating one if
+    /// needed.
+    fn lease(&mut self, mac: [u8; 6]) -> [u8; 4] {
+        let index = match self.clients.iter().position(|c| *c == Some(mac)) {
+            Some(index) => index,
+            None => {
+                let index = self.next;
+                self.clients[index] = Some(mac);
+                self.next = (self.next + 1) % DHCP_POOL_SIZE;
+                index
+            }
+        };
+        let mut address = self.address;
+        address[3] = address[3].wrapping_add(1 + index as u8);
+        address
+    }
+}
+
+/// Value of DHCP option `code`
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match *options.first()? {
+            255 => return None,
+            0 => options = &options[1..],
+            c => {
+                let len = *options.get(1)? as usize;
+                let value = options.get(2..2 + len)?;
+                if c == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
+
+struct DhcpOptions<'a> {
+    out: &'a mut [u8],
+    len: usize,
+}
+
+impl DhcpOptions<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) -> Option<()> {
+        if code == 255 {
+            *self.out.get_mut(self.len)? = code;
+            self.len += 1;
+            return Some(());
+        }
+        let option = self.out.get_mut(self.len..self.len + 2 + value.len())?;
+        option[0] = code;
+        option[1] = value.len() as u8;
+        option[2..].copy_from_slice(value);
+        self.len += option.len();
+        Some(())
+    }
+}
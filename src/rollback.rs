@@ -0,0 +1,141 @@
+//! Firmware rollback report.
+//!
+//! A firmware which fails its health gate switches back to the previous one,
+//! leaving this report in RTC memory, which survives the reset. The previous
+//! firmware publishes it once connected to the broker, and keeps it so that
+//! the same image isn't installed again while the server still offers it.
+//!
+//! The update writes the report before rebooting into the new firmware, with
+//! the checksum of the image from its manifest and `Reason::Reset`: it stands
+//! if the new firmware resets before writing its own, and is cleared once the
+//! new firmware is confirmed.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use crate::manifest::Checksum;
+use crate::rtc_record::{self, Version};
+
+/// Marks an initialised report ("ROLL")
+const MAGIC: u32 = 0x524F_4C4C;
+
+/// Checksum kind, then the CRC32 or the SHA-256
+const CHECKSUM_WORDS: usize = 1 + 32 / 4;
+
+/// RTC memory taken by the report: the reason, whether it was published, the
+/// version and the checksum, see `rtc_record`
+pub const WORDS: usize = rtc_record::len(2 + Version::WORDS + CHECKSUM_WORDS);
+
+/// Health check the rolled back firmware failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// Reset before passing the gate, e.g. a crash or a watchdog
+    Reset,
+    /// Couldn't join the Wi-Fi network
+    Wifi,
+    /// A sensor failed to initialise
+    Sensors,
+    /// No publication acknowledged by the broker in time
+    Mqtt,
+}
+
+impl Reason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::Reset => "reset",
+            Reason::Wifi => "wifi",
+            Reason::Sensors => "sensors",
+            Reason::Mqtt => "mqtt",
+        }
+    }
+
+    fn from_u32(value: u32) -> Option<Self> {
+        Some(match value {
+            0 => Reason::Reset,
+            1 => Reason::Wifi,
+            2 => Reason::Sensors,
+            3 => Reason::Mqtt,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    pub reason: Reason,
+    version: Version,
+    /// Checksum of the rolled back image, `None` when it wasn't installed by
+    /// an update
+    pub checksum: Option<Checksum>,
+    /// Whether the previous firmware published the report already
+    pub published: bool,
+}
+
+impl Report {
+    /// Report for the rolled back firmware `version`.
+    pub fn new(version: &str, checksum: Option<Checksum>, reason: Reason) -> Self {
+        Self {
+            reason,
+            version: Version::new(version),
+            checksum,
+            published: false,
+        }
+    }
+
+    /// Version of the rolled back firmware
+    pub fn version(&self) -> &str {
+        self.version.as_str()
+    }
+
+    /// Whether the image `version` with `checksum` offered by the server is
+    /// the one which was rolled back.
+    pub fn rejects(&self, version: &str, checksum: &Checksum) -> bool {
+        self.checksum.as_ref() == Some(checksum) && self.version == Version::new(version)
+    }
+
+    pub fn to_words(self) -> [u32; WORDS] {
+        let mut fields = [0; 2 + Version::WORDS + CHECKSUM_WORDS];
+        fields[0] = self.reason as u32;
+        fields[1] = self.published as u32;
+        let (version, checksum) = fields[2..].split_at_mut(Version::WORDS);
+        version.copy_from_slice(&self.version.to_words());
+        match self.checksum {
+            None => checksum[0] = 0,
+            Some(Checksum::Crc32(crc32)) => {
+                checksum[0] = 1;
+                checksum[1] = crc32;
+            }
+            Some(Checksum::Sha256(digest)) => {
+                checksum[0] = 2;
+                for (word, chunk) in checksum[1..].iter_mut().zip(digest.chunks(4)) {
+                    *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                }
+            }
+        }
+        rtc_record::encode(MAGIC, &fields)
+    }
+
+    /// Decode a report, `None` when the words hold no valid report.
+    pub fn from_words(words: &[u32; WORDS]) -> Option<Self> {
+        let fields = rtc_record::decode(MAGIC, words)?;
+        let (version, checksum) = fields[2..].split_at(Version::WORDS);
+        let checksum = match checksum[0] {
+            0 => None,
+            1 => Some(Checksum::Crc32(checksum[1])),
+            2 => {
+                let mut digest = [0; 32];
+                for (chunk, word) in digest.chunks_mut(4).zip(&checksum[1..]) {
+                    chunk.copy_from_slice(&word.to_le_bytes());
+                }
+                Some(Checksum::Sha256(digest))
+            }
+            _ => return None,
+        };
+        Some(Self {
+            reason: Reason::from_u32(fields[0])?,
+            version: Version::from_words(version)?,
+            checksum,
+            published: fields[1] != 0,
+        })
+    }
+}
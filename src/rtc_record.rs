@@ -0,0 +1,108 @@
+//! Records kept in RTC memory, which survives resets and deep sleep but not a
+//! power loss.
+//!
+//! RTC memory isn't initialised at boot and holds garbage after a power loss,
+//! so each record starts with a magic telling which record it is and ends
+//! with a checksum:
+//!
+//! ```text
+//! magic   u32  one per record type
+//! fields  u32  as many as the record needs
+//! crc32   u32  CRC32 of the previous words
+//! ```
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use crate::crc32::Crc32;
+
+/// Longest version kept, longer ones are truncated
+pub const VERSION_MAX_LEN: usize = 32;
+
+/// Words taken by a record holding `fields` words
+pub const fn len(fields: usize) -> usize {
+    fields + 2
+}
+
+/// Record holding `fields`, `N` being `len(fields.len())`.
+pub fn encode<const N: usize>(magic: u32, fields: &[u32]) -> [u32; N] {
+    let mut words = [0; N];
+    words[0] = magic;
+    words[1..N - 1].copy_from_slice(fields);
+    words[N - 1] = checksum(&words[..N - 1]);
+    words
+}
+
+/// Fields of the record in `words`, `None` when they hold no valid record
+/// with this `magic`, e.g. after a power loss.
+pub fn decode<const N: usize>(magic: u32, words: &[u32; N]) -> Option<&[u32]> {
+    if words[0] != magic || words[N - 1] != checksum(&words[..N - 1]) {
+        return None;
+    }
+    Some(&words[1..N - 1])
+}
+
+fn checksum(words: &[u32]) -> u32 {
+    let mut crc = Crc32::new();
+    for word in words {
+        crc.update(&word.to_le_bytes());
+    }
+    crc.finalize()
+}
+
+/// Firmware version field, truncated to `VERSION_MAX_LEN` bytes at a char
+/// boundary. Stored as its length followed by the UTF-8 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    bytes: [u8; VERSION_MAX_LEN],
+    len: u8,
+}
+
+impl Version {
+    /// Words taken by the field
+    pub const WORDS: usize = 1 + VERSION_MAX_LEN / 4;
+
+    pub fn new(version: &str) -> Self {
+        let mut len = version.len().min(VERSION_MAX_LEN);
+        while !version.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; VERSION_MAX_LEN];
+        bytes[..len].copy_from_slice(&version.as_bytes()[..len]);
+        Self {
+            bytes,
+            len: len as u8,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+
+    pub fn to_words(self) -> [u32; Self::WORDS] {
+        let mut words = [0; Self::WORDS];
+        words[0] = self.len as u32;
+        for (word, chunk) in words[1..].iter_mut().zip(self.bytes.chunks(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        words
+    }
+
+    /// Decode the field from the first `WORDS` of `words`, `None` when they
+    /// don't hold a valid version.
+    pub fn from_words(words: &[u32]) -> Option<Self> {
+        let (len, words) = words.get(..Self::WORDS)?.split_first()?;
+        if *len as usize > VERSION_MAX_LEN {
+            return None;
+        }
+        let mut bytes = [0; VERSION_MAX_LEN];
+        for (chunk, word) in bytes.chunks_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        core::str::from_utf8(&bytes[..*len as usize]).ok()?;
+        Some(Self {
+            bytes,
+            len: *len as u8,
+        })
+    }
+}
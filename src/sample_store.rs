@@ -0,0 +1,425 @@
+//! Flash ring buffer of samples waiting to be published.
+//!
+//! Samples which can't be published while the broker is unreachable are
+//! appended here and read back in order once it is reachable again. The
+//! region is split in erase sectors used in turn, so every sector is erased
+//! once per turn of the ring. When full, the oldest sector is dropped.
+//!
+//! ```text
+//! sector header (16 bytes, little endian)
+//!   magic        u32  "SMPL"
+//!   sequence     u32  incremented for each new sector, orders the sectors
+//!   erase_count  u32  times the sector was erased
+//!   crc32        u32  CRC32 of the first 12 header bytes
+//! records, word aligned, until the erased space (0xFF)
+//!   length       u16  payload length
+//!   state        u16  0xFFFF while pending, cleared once published
+//!   crc32        u32  CRC32 of the length and the payload
+//!   payload      padded with 0xFF to a whole word
+//! ```
+//!
+//! A record header is written before its payload, so a power loss during a
+//! write leaves a record failing its CRC. Nothing is appended after it: the
+//! rest of that sector is skipped and writing goes on in the next sector.
+//! Published records are marked by clearing their state bits, which needs no
+//! erase. A power loss before the mark makes the sample published again.
+//!
+//! Only depends on `core` and `embedded-storage` so it can be unit tested on
+//! the host, see `tools/firmware-core`.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::crc32::Crc32;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"SMPL");
+const SECTOR_HEADER_SIZE: u32 = 16;
+const RECORD_HEADER_SIZE: u32 = 8;
+
+/// Record state bits as written, cleared once published
+const PENDING: u16 = 0xFFFF;
+/// Length of the erased space after the last record
+const ERASED: u16 = 0xFFFF;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Flash,
+    /// The region holds less than two sectors
+    TooSmall,
+    /// The sample doesn't fit in a sector, or in the read buffer
+    TooLarge,
+}
+
+/// Position in the region, relative to its start
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    sector: u32,
+    offset: u32,
+}
+
+/// Sector being appended to
+#[derive(Debug, Clone, Copy)]
+struct Head {
+    sector: u32,
+    sequence: u32,
+    /// Where the next record goes, `None` once a damaged record was found
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SectorHeader {
+    sequence: u32,
+    erase_count: u32,
+}
+
+enum Record {
+    Pending {
+        len: u16,
+        next: u32,
+    },
+    Published {
+        next: u32,
+    },
+    /// Erased space, damaged record or end of the sector
+    End,
+}
+
+pub struct SampleStore {
+    base: u32,
+    sector_size: u32,
+    sectors: u32,
+    /// `None` until the first sample is written
+    head: Option<Head>,
+    /// Next record to read
+    tail: Position,
+}
+
+impl SampleStore {
+    /// Open the store kept in the `len` bytes at `base`, both multiples of
+    /// the erase size.
+    pub fn mount<F: NorFlash>(flash: &mut F, base: u32, len: u32) -> Result<Self, Error> {
+        let sector_size = F::ERASE_SIZE as u32;
+        let sectors = len / sector_size;
+        if sectors < 2 {
+            return Err(Error::TooSmall);
+        }
+
+        let mut store = Self {
+            base,
+            sector_size,
+            sectors,
+            head: None,
+            tail: Position {
+                sector: 0,
+                offset: SECTOR_HEADER_SIZE,
+            },
+        };
+
+        let mut oldest: Option<(u32, u32)> = None;
+        for sector in 0..sectors {
+            let Some(header) = store.read_sector_header(flash, sector)? else {
+                continue;
+            };
+            if store
+                .head
+                .is_none_or(|head| header.sequence > head.sequence)
+            {
+                store.head = Some(Head {
+                    sector,
+                    sequence: header.sequence,
+                    offset: None,
+                });
+            }
+            if oldest.is_none_or(|(_, sequence)| header.sequence < sequence) {
+                oldest = Some((sector, header.sequence));
+            }
+        }
+
+        if let (Some(head), Some((sector, _))) = (store.head, oldest) {
+            store.tail.sector = sector;
+            let offset = store.append_offset(flash, head.sector)?;
+            store.head = Some(Head { offset, ..head });
+        }
+        Ok(store)
+    }
+
+    /// Append a sample, dropping the oldest sector when the ring is full.
+    pub fn push<F: NorFlash>(&mut self, flash: &mut F, payload: &[u8]) -> Result<(), Error> {
+        let size = record_size(payload.len());
+        if payload.len() >= ERASED as usize || SECTOR_HEADER_SIZE + size > self.sector_size {
+            return Err(Error::TooLarge);
+        }
+
+        let position = match self.head {
+            Some(Head {
+                sector,
+                offset: Some(offset),
+                ..
+            }) if offset + size <= self.sector_size => Position { sector, offset },
+            _ => self.next_sector(flash)?,
+        };
+        if let Some(head) = &mut self.head {
+            head.offset = Some(position.offset + size);
+        }
+        let address = self.address(position);
+
+        let len = (payload.len() as u16).to_le_bytes();
+        let mut crc = Crc32::new();
+        crc.update(&len);
+        crc.update(payload);
+
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        header[0..2].copy_from_slice(&len);
+        header[2..4].copy_from_slice(&PENDING.to_le_bytes());
+        header[4..8].copy_from_slice(&crc.finalize().to_le_bytes());
+        flash.write(address, &header).map_err(|_| Error::Flash)?;
+
+        // Whole words first, then the last one padded with the erased value
+        let aligned = payload.len() - payload.len() % F::WRITE_SIZE;
+        let address = address + RECORD_HEADER_SIZE;
+        if aligned > 0 {
+            flash
+                .write(address, &payload[..aligned])
+                .map_err(|_| Error::Flash)?;
+        }
+        if aligned < payload.len() {
+            let mut word = [0xFFu8; 16];
+            let word = &mut word[..F::WRITE_SIZE];
+            word[..payload.len() - aligned].copy_from_slice(&payload[aligned..]);
+            flash
+                .write(address + aligned as u32, word)
+                .map_err(|_| Error::Flash)?;
+        }
+        Ok(())
+    }
+
+    /// Read the oldest sample not published yet into `buf`, returns its
+    /// length. The sample stays in the store until `pop`.
+    pub fn peek<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let Some(len) = self.seek(flash)? else {
+            return Ok(None);
+        };
+        let buf = buf.get_mut(..len as usize).ok_or(Error::TooLarge)?;
+        let address = self.address(self.tail) + RECORD_HEADER_SIZE;
+
+        let aligned = buf.len() - buf.len() % F::READ_SIZE;
+        flash
+            .read(address, &mut buf[..aligned])
+            .map_err(|_| Error::Flash)?;
+        if aligned < buf.len() {
+            let mut word = [0u8; 16];
+            let word = &mut word[..F::READ_SIZE];
+            flash
+                .read(address + aligned as u32, word)
+                .map_err(|_| Error::Flash)?;
+            let rest = buf.len() - aligned;
+            buf[aligned..].copy_from_slice(&word[..rest]);
+        }
+        Ok(Some(len as usize))
+    }
+
+    /// Mark the sample returned by `peek` as published.
+    pub fn pop<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), Error> {
+        if self.head.is_none() {
+            return Ok(());
+        }
+        if let Record::Pending { len, next } = self.read_record(flash, self.tail)? {
+            let mut word = [0u8; 4];
+            word[0..2].copy_from_slice(&len.to_le_bytes());
+            flash
+                .write(self.address(self.tail), &word)
+                .map_err(|_| Error::Flash)?;
+            self.tail.offset = next;
+        }
+        Ok(())
+    }
+
+    /// Whether samples are waiting to be published.
+    pub fn is_empty<F: ReadNorFlash>(&mut self, flash: &mut F) -> Result<bool, Error> {
+        Ok(self.seek(flash)?.is_none())
+    }
+
+    /// Move the tail to the oldest pending record, returns its length.
+    fn seek<F: ReadNorFlash>(&mut self, flash: &mut F) -> Result<Option<u16>, Error> {
+        let Some(head) = self.head else {
+            return Ok(None);
+        };
+
+        loop {
+            match self.read_record(flash, self.tail)? {
+                Record::Pending { len, .. } => return Ok(Some(len)),
+                Record::Published { next } => self.tail.offset = next,
+                Record::End if self.tail.sector == head.sector => return Ok(None),
+                Record::End => {
+                    self.tail = Position {
+                        sector: (self.tail.sector + 1) % self.sectors,
+                        offset: SECTOR_HEADER_SIZE,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Erase the sector after the head and make it the new head. Returns the
+    /// position of its first record.
+    fn next_sector<F: NorFlash>(&mut self, flash: &mut F) -> Result<Position, Error> {
+        let (sector, sequence) = match self.head {
+            Some(head) => (
+                (head.sector + 1) % self.sectors,
+                head.sequence.wrapping_add(1),
+            ),
+            None => (0, 1),
+        };
+        let erase_count = self
+            .read_sector_header(flash, sector)?
+            .map_or(0, |header| header.erase_count);
+
+        // The ring is full: the samples of the oldest sector are lost
+        if self.head.is_some() && self.tail.sector == sector {
+            self.tail = Position {
+                sector: (sector + 1) % self.sectors,
+                offset: SECTOR_HEADER_SIZE,
+            };
+        }
+
+        let start = self.base + sector * self.sector_size;
+        flash
+            .erase(start, start + self.sector_size)
+            .map_err(|_| Error::Flash)?;
+
+        let mut header = [0u8; SECTOR_HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8..12].copy_from_slice(&erase_count.wrapping_add(1).to_le_bytes());
+        let mut crc = Crc32::new();
+        crc.update(&header[..12]);
+        header[12..16].copy_from_slice(&crc.finalize().to_le_bytes());
+        flash.write(start, &header).map_err(|_| Error::Flash)?;
+
+        if self.head.is_none() {
+            self.tail = Position {
+                sector,
+                offset: SECTOR_HEADER_SIZE,
+            };
+        }
+        self.head = Some(Head {
+            sector,
+            sequence,
+            offset: Some(SECTOR_HEADER_SIZE),
+        });
+        Ok(Position {
+            sector,
+            offset: SECTOR_HEADER_SIZE,
+        })
+    }
+
+    fn read_sector_header<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        sector: u32,
+    ) -> Result<Option<SectorHeader>, Error> {
+        let mut header = [0u8; SECTOR_HEADER_SIZE as usize];
+        flash
+            .read(self.base + sector * self.sector_size, &mut header)
+            .map_err(|_| Error::Flash)?;
+
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let mut crc = Crc32::new();
+        crc.update(&header[..12]);
+        if word(0) != MAGIC || word(12) != crc.finalize() {
+            return Ok(None);
+        }
+        Ok(Some(SectorHeader {
+            sequence: word(4),
+            erase_count: word(8),
+        }))
+    }
+
+    /// Offset after the last record of `sector`, `None` when a damaged record
+    /// was found.
+    fn append_offset<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        sector: u32,
+    ) -> Result<Option<u32>, Error> {
+        let mut position = Position {
+            sector,
+            offset: SECTOR_HEADER_SIZE,
+        };
+        while let Record::Pending { next, .. } | Record::Published { next } =
+            self.read_record(flash, position)?
+        {
+            position.offset = next;
+        }
+
+        // Only erased space can be written
+        if position.offset + RECORD_HEADER_SIZE > self.sector_size {
+            return Ok(Some(position.offset));
+        }
+        let mut word = [0u8; 4];
+        flash
+            .read(self.address(position), &mut word)
+            .map_err(|_| Error::Flash)?;
+        Ok((u16::from_le_bytes([word[0], word[1]]) == ERASED).then_some(position.offset))
+    }
+
+    /// Read and check the record at `position`.
+    fn read_record<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        position: Position,
+    ) -> Result<Record, Error> {
+        if position.offset + RECORD_HEADER_SIZE > self.sector_size {
+            return Ok(Record::End);
+        }
+        let address = self.address(position);
+        let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+        flash.read(address, &mut header).map_err(|_| Error::Flash)?;
+
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let state = u16::from_le_bytes([header[2], header[3]]);
+        let expected = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let next = position.offset + record_size(len as usize);
+        if len == ERASED || next > self.sector_size {
+            return Ok(Record::End);
+        }
+
+        // The payload is read in chunks, it can be larger than the buffers
+        let mut crc = Crc32::new();
+        crc.update(&len.to_le_bytes());
+        let mut chunk = [0u8; 64];
+        let mut read = 0;
+        while read < len as usize {
+            let n = core::cmp::min(chunk.len(), len as usize - read);
+            let n_aligned = n.next_multiple_of(F::READ_SIZE);
+            flash
+                .read(
+                    address + RECORD_HEADER_SIZE + read as u32,
+                    &mut chunk[..n_aligned],
+                )
+                .map_err(|_| Error::Flash)?;
+            crc.update(&chunk[..n]);
+            read += n;
+        }
+        if crc.finalize() != expected {
+            return Ok(Record::End);
+        }
+
+        if state != PENDING {
+            return Ok(Record::Published { next });
+        }
+        Ok(Record::Pending { len, next })
+    }
+
+    fn address(&self, position: Position) -> u32 {
+        self.base + position.sector * self.sector_size + position.offset
+    }
+}
+
+/// Space taken by a record, including its header and padding
+fn record_size(len: usize) -> u32 {
+    RECORD_HEADER_SIZE + len.next_multiple_of(4) as u32
+}
@@ -1,83 +1,91 @@
-/// Semantic version representation for comparing firmware versions.
-/// Supports standard semver format: major.minor.patch[-prerelease]
-/// Examples: "1.2.3", "v1.2.3", "1.2.3-beta", "v1.2.3-rc.1"
-#[derive(Debug, PartialEq, Eq)]
+use core::cmp::Ordering;
+use core::fmt;
+
+use heapless::{String, Vec};
+
+/// Longest pre-release, and longest build metadata
+pub const IDENTIFIERS_MAX_LEN: usize = 32;
+
+/// Most comparisons in a `VersionReq`
+pub const COMPARATORS_MAX: usize = 4;
+
+/// Semantic version representation for comparing firmware versions, following
+/// Semantic Versioning 2.0: major.minor.patch[-prerelease][+build]
+/// Examples: "1.2.3", "v1.2.3", "1.2.3-beta", "v1.2.3-rc.1", "1.2.3+20261016"
+///
+/// Versions are ordered by precedence, build metadata being ignored by the
+/// comparisons: "1.2.3+a" == "1.2.3+b".
+#[derive(Debug, Clone)]
 pub struct SemVer {
     major: u32,
     minor: u32,
     patch: u32,
-    /// Pre-release identifier (e.g., "beta.0", "rc.1", "alpha")
-    /// None means it's a stable release (stable > pre-release)
-    pre_release: Option<PreRelease>,
-}
-
-/// Pre-release version component (e.g., "beta.1", "rc.2", "alpha")
-#[derive(Debug, PartialEq, Eq)]
-struct PreRelease {
-    /// The type of pre-release (alpha < beta < rc < other)
-    kind: PreReleaseKind,
-    /// Optional numeric suffix (e.g., the "1" in "beta.1")
-    number: Option<u32>,
+    /// Dot-separated pre-release identifiers (e.g., "beta.0", "rc.1", "alpha")
+    /// Empty means it's a stable release (stable > pre-release)
+    pre_release: String<IDENTIFIERS_MAX_LEN>,
+    /// Dot-separated build metadata identifiers, only kept for display
+    build: String<IDENTIFIERS_MAX_LEN>,
 }
 
-/// Pre-release type ordering: alpha < beta < rc < other < stable
+/// Release channel a device follows, from the least to the most stable. Each
+/// channel accepts the releases of the more stable ones.
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
-enum PreReleaseKind {
-    Alpha,
+pub enum Channel {
+    /// Any pre-release, e.g. "alpha" or "nightly"
+    Dev,
+    /// "beta" and "rc" pre-releases
     Beta,
-    Rc,
-    Other, // Unknown pre-release types sort after rc but before stable
-}
-
-impl PreRelease {
-    /// Parse a pre-release string like "beta", "beta.0", "rc1", "alpha"
-    fn parse(s: &str) -> Option<Self> {
-        let s = s.to_ascii_lowercase();
-
-        // Try to find a known pre-release kind
-        let (kind, remainder) = if s.starts_with("alpha") {
-            (PreReleaseKind::Alpha, &s[5..])
-        } else if s.starts_with("beta") {
-            (PreReleaseKind::Beta, &s[4..])
-        } else if s.starts_with("rc") {
-            (PreReleaseKind::Rc, &s[2..])
-        } else {
-            (PreReleaseKind::Other, s.as_str())
-        };
-
-        // Parse optional numeric suffix (handles ".0", "0", ".1", "1", etc.)
-        let number = if remainder.is_empty() {
-            None
-        } else {
-            let num_str = remainder.trim_start_matches('.');
-            num_str.parse().ok()
-        };
+    /// Releases without pre-release identifier
+    Stable,
+}
 
-        Some(PreRelease { kind, number })
+impl Channel {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "dev" => Channel::Dev,
+            "beta" => Channel::Beta,
+            "stable" => Channel::Stable,
+            _ => return None,
+        })
     }
 
-    /// Compare pre-releases by kind first, then by number.
-    fn cmp(&self, other: &PreRelease) -> core::cmp::Ordering {
-        use core::cmp::Ordering;
-
-        // First compare by kind (alpha < beta < rc < other)
-        match self.kind.cmp(&other.kind) {
-            Ordering::Equal => {}
-            ord => return ord,
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Dev => "dev",
+            Channel::Beta => "beta",
+            Channel::Stable => "stable",
         }
+    }
 
-        // Then by number (None < Some(0) < Some(1) < ...)
-        match (&self.number, &other.number) {
-            (None, None) => Ordering::Equal,
-            (None, Some(_)) => Ordering::Less,
-            (Some(_), None) => Ordering::Greater,
-            (Some(a), Some(b)) => a.cmp(b),
-        }
+    /// Whether a device following this channel installs `version`
+    pub fn accepts(self, version: &SemVer) -> bool {
+        version.channel() >= self
     }
 }
 
 impl SemVer {
+    /// Most stable channel the version is released on, from its first
+    /// pre-release identifier: "beta" and "rc", with an optional number
+    /// ("beta2", "rc1"), are on the beta channel
+    pub fn channel(&self) -> Channel {
+        let Some(first) = self.pre_release().next() else {
+            return Channel::Stable;
+        };
+        let first = first.as_bytes();
+        let starts_with = |prefix: &str| {
+            first.len() >= prefix.len()
+                && first[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+        };
+        if starts_with("beta") || starts_with("rc") {
+            Channel::Beta
+        } else {
+            Channel::Dev
+        }
+    }
+
     /// Parse a semver string like "1.2.3", "v1.2.3", "1.2.3-beta", "v1.2.3-beta.0"
+    /// or "1.2.3-rc.1+build.5". Leading zeros in numbers, empty identifiers and
+    /// characters other than [0-9A-Za-z-] in identifiers are rejected.
     pub fn parse(version: &str) -> Option<Self> {
         let version = version.trim();
 
@@ -87,55 +95,224 @@ impl SemVer {
             .or_else(|| version.strip_prefix('V'))
             .unwrap_or(version);
 
-        // Split on '-' to separate version from pre-release
-        let (version_part, pre_release_part) = match version.split_once('-') {
-            Some((v, p)) => (v, Some(p)),
-            None => (version, None),
+        // Build metadata comes last, the pre-release may itself contain '-'.
+        // Numeric pre-release identifiers have no leading zeros, unlike build
+        // metadata ones.
+        let (version, build) = match version.split_once('+') {
+            Some((v, b)) if identifiers_valid(b, |_| true) => (v, b),
+            Some(_) => return None,
+            None => (version, ""),
+        };
+        let (version_part, pre_release) = match version.split_once('-') {
+            Some((v, p)) if identifiers_valid(p, |id| !is_numeric(id) || !has_leading_zero(id)) => {
+                (v, p)
+            }
+            Some(_) => return None,
+            None => (version, ""),
         };
 
         let mut parts = version_part.split('.');
-        let major = parts.next()?.parse().ok()?;
-        let minor = parts.next()?.parse().ok()?;
-        let patch = parts.next()?.parse().ok()?;
+        let major = parse_number(parts.next()?)?;
+        let minor = parse_number(parts.next()?)?;
+        let patch = parse_number(parts.next()?)?;
 
         // Ensure no extra parts in version (e.g., reject "1.2.3.4")
         if parts.next().is_some() {
             return None;
         }
 
-        let pre_release = pre_release_part.and_then(PreRelease::parse);
-
         Some(SemVer {
             major,
             minor,
             patch,
-            pre_release,
+            pre_release: String::try_from(pre_release).ok()?,
+            build: String::try_from(build).ok()?,
         })
     }
 
-    /// Returns true if self is strictly greater than other.
-    /// Stable releases are greater than pre-releases with same version.
-    pub fn is_greater_than(&self, other: &SemVer) -> bool {
-        use core::cmp::Ordering;
+    /// Whether the version has pre-release identifiers
+    pub fn is_pre_release(&self) -> bool {
+        !self.pre_release.is_empty()
+    }
+
+    /// Pre-release identifiers, none for a release
+    pub fn pre_release(&self) -> impl Iterator<Item = &str> {
+        self.pre_release.split('.').filter(|id| !id.is_empty())
+    }
+
+    /// Build metadata, empty when there is none
+    pub fn build(&self) -> &str {
+        &self.build
+    }
+}
+
+impl Ord for SemVer {
+    /// Precedence: major, minor and patch numerically, then a release above
+    /// its pre-releases, whose identifiers are compared one by one
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (self.is_pre_release(), other.is_pre_release()) {
+                (false, false) => Ordering::Equal,
+                (false, true) => Ordering::Greater,
+                (true, false) => Ordering::Less,
+                // A larger set of identifiers has a higher precedence when
+                // all the preceding ones are equal
+                (true, true) => self
+                    .pre_release()
+                    .map(Identifier)
+                    .cmp(other.pre_release().map(Identifier)),
+            })
+    }
+}
 
-        // Compare major.minor.patch first
-        if self.major != other.major {
-            return self.major > other.major;
+impl PartialOrd for SemVer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SemVer {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SemVer {}
+
+impl fmt::Display for SemVer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if self.is_pre_release() {
+            write!(f, "-{}", self.pre_release)?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build)?;
+        }
+        Ok(())
+    }
+}
+
+/// Requirement on a version, comma-separated comparisons which must all hold,
+/// e.g. ">=0.1.3, <0.3.0". A version without operator must be equal.
+///
+/// Versions compare by precedence, so "<0.3.0" also matches "0.3.0-beta.1".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReq {
+    comparators: Vec<(Op, SemVer), COMPARATORS_MAX>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+}
+
+impl Op {
+    fn as_str(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Greater => ">",
+            Op::GreaterEq => ">=",
+            Op::Less => "<",
+            Op::LessEq => "<=",
         }
-        if self.minor != other.minor {
-            return self.minor > other.minor;
+    }
+}
+
+impl VersionReq {
+    /// Parse a requirement like ">=0.1.3, <0.3.0", "<= 1.0.0" or "=0.2.0",
+    /// `None` when empty or holding more than `COMPARATORS_MAX` comparisons
+    pub fn parse(requirement: &str) -> Option<Self> {
+        let mut comparators = Vec::new();
+        for comparator in requirement.split(',') {
+            let comparator = comparator.trim();
+            // Longest operators first
+            let (op, version) = [Op::GreaterEq, Op::LessEq, Op::Eq, Op::Greater, Op::Less]
+                .into_iter()
+                .find_map(|op| Some((op, comparator.strip_prefix(op.as_str())?)))
+                .unwrap_or((Op::Eq, comparator));
+            let version = SemVer::parse(version)?;
+            comparators.push((op, version)).ok()?;
         }
-        if self.patch != other.patch {
-            return self.patch > other.patch;
+        Some(VersionReq { comparators })
+    }
+
+    /// Whether `version` satisfies all the comparisons
+    pub fn matches(&self, version: &SemVer) -> bool {
+        self.comparators.iter().all(|(op, bound)| match op {
+            Op::Eq => version == bound,
+            Op::Greater => version > bound,
+            Op::GreaterEq => version >= bound,
+            Op::Less => version < bound,
+            Op::LessEq => version <= bound,
+        })
+    }
+}
+
+impl fmt::Display for VersionReq {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (op, version)) in self.comparators.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}{}", op.as_str(), version)?;
         }
+        Ok(())
+    }
+}
 
-        // Same major.minor.patch - compare pre-release
-        // A stable release (no pre-release) is greater than any pre-release
-        match (&self.pre_release, &other.pre_release) {
-            (None, None) => false,    // Equal versions
-            (None, Some(_)) => true,  // Stable > pre-release
-            (Some(_), None) => false, // Pre-release < stable
-            (Some(a), Some(b)) => a.cmp(b) == Ordering::Greater,
+/// Pre-release identifier, numeric ones compare numerically and lower than
+/// alphanumeric ones, compared in ASCII order
+#[derive(PartialEq, Eq)]
+struct Identifier<'a>(&'a str);
+
+impl Ord for Identifier<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (is_numeric(self.0), is_numeric(other.0)) {
+            // Without leading zeros, the longer number is the larger, this
+            // also holds for numbers overflowing u64
+            (true, true) => self
+                .0
+                .len()
+                .cmp(&other.0.len())
+                .then_with(|| self.0.cmp(other.0)),
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self.0.cmp(other.0),
         }
     }
 }
+
+impl PartialOrd for Identifier<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Major, minor or patch number, without sign nor leading zero
+fn parse_number(s: &str) -> Option<u32> {
+    if !is_numeric(s) || has_leading_zero(s) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Whether `identifiers` are non-empty dot-separated [0-9A-Za-z-] identifiers
+/// all passing `valid`
+fn identifiers_valid(identifiers: &str, valid: impl Fn(&str) -> bool) -> bool {
+    identifiers.split('.').all(|id| {
+        !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') && valid(id)
+    })
+}
+
+fn is_numeric(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn has_leading_zero(s: &str) -> bool {
+    s.len() > 1 && s.starts_with('0')
+}
@@ -12,18 +12,7 @@ impl<I2C: embedded_hal_async::i2c::I2c> Bme280<I2C> {
     pub async fn new(i2c: I2C) -> Result<Self, SensorError> {
         info!("Initialising BME280...");
         let mut sensor = AsyncBme280::new(i2c, Delay);
-        sensor.init().await.map_err(|_| SensorError::InitFailure)?;
-
-        sensor
-            .set_sampling_configuration(
-                bme280_rs::Configuration::default()
-                    .with_temperature_oversampling(Oversampling::Oversample1)
-                    .with_pressure_oversampling(Oversampling::Oversample1)
-                    .with_humidity_oversampling(Oversampling::Oversample1)
-                    .with_sensor_mode(SensorMode::Normal),
-            )
-            .await
-            .map_err(|_| SensorError::InitFailure)?;
+        configure(&mut sensor).await?;
 
         info!("Initialised BME280");
 
@@ -31,8 +20,38 @@ impl<I2C: embedded_hal_async::i2c::I2c> Bme280<I2C> {
     }
 }
 
+/// In deep sleep mode the sensor takes a single measurement when asked and
+/// sleeps in between, instead of measuring continuously
+#[cfg(feature = "deep_sleep")]
+const SENSOR_MODE: SensorMode = SensorMode::Forced;
+#[cfg(not(feature = "deep_sleep"))]
+const SENSOR_MODE: SensorMode = SensorMode::Normal;
+
+async fn configure<I2C: embedded_hal_async::i2c::I2c>(
+    sensor: &mut AsyncBme280<I2C, Delay>,
+) -> Result<(), SensorError> {
+    sensor.init().await.map_err(|_| SensorError::InitFailure)?;
+
+    sensor
+        .set_sampling_configuration(
+            bme280_rs::Configuration::default()
+                .with_temperature_oversampling(Oversampling::Oversample1)
+                .with_pressure_oversampling(Oversampling::Oversample1)
+                .with_humidity_oversampling(Oversampling::Oversample1)
+                .with_sensor_mode(SENSOR_MODE),
+        )
+        .await
+        .map_err(|_| SensorError::InitFailure)
+}
+
 impl<I2C: embedded_hal_async::i2c::I2c> Sensor for Bme280<I2C> {
     async fn measure(&mut self, data: &mut SensorData) -> Result<(), SensorError> {
+        #[cfg(feature = "deep_sleep")]
+        self.sensor
+            .take_forced_measurement()
+            .await
+            .map_err(|_| SensorError::MeasurementFailure)?;
+
         match self.sensor.read_sample().await {
             Ok(sample) => {
                 data.add_measurement(
@@ -54,4 +73,11 @@ impl<I2C: embedded_hal_async::i2c::I2c> Sensor for Bme280<I2C> {
             Err(_) => Err(SensorError::MeasurementFailure),
         }
     }
+
+    async fn reinit(&mut self) -> Result<(), SensorError> {
+        info!("Re-initialising BME280...");
+        configure(&mut self.sensor).await?;
+        info!("Re-initialised BME280");
+        Ok(())
+    }
 }
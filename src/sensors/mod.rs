@@ -2,8 +2,9 @@
 
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use heapless::FnvIndexMap;
+use heapless::{FnvIndexMap, Vec};
 
+use crate::constants::*;
 use crate::hal::{i2c::master::I2c, uart::Uart, Async};
 
 pub mod bme280;
@@ -24,24 +25,50 @@ pub enum SensorError {
 #[derive(Default, Debug)]
 pub struct SensorData {
     pub data: FnvIndexMap<&'static str, f32, 16>,
+    /// Sensors which failed to produce a reading for this sample
+    pub errors: Vec<(&'static str, SensorError), 3>,
 }
 
 impl SensorData {
     pub fn add_measurement(&mut self, key: &'static str, value: f32) {
+        // NaN/Inf can't be serialized, drop the reading rather than the sample
+        if !value.is_finite() {
+            log::warn!("Invalid {} reading ({}), dropping measurement", key, value);
+            return;
+        }
         if self.data.insert(key, value).is_err() {
             log::warn!("SensorData map full, dropping measurement: {}", key);
         }
     }
+
+    pub fn add_error(&mut self, sensor: &'static str, error: SensorError) {
+        if self.errors.push((sensor, error)).is_err() {
+            log::warn!("SensorData errors full, dropping error for {}", sensor);
+        }
+    }
 }
 
 pub trait Sensor {
     async fn measure(&mut self, data: &mut SensorData) -> Result<(), SensorError>;
+
+    /// Bring the sensor back to a working state after repeated measurement
+    /// failures.
+    async fn reinit(&mut self) -> Result<(), SensorError>;
+}
+
+/// Consecutive measurement failures of each sensor
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Failures {
+    pub bme280: u32,
+    pub scd30: u32,
+    pub sds011: u32,
 }
 
 pub struct Sensors {
     pub bme280: Option<Bme280<I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>>>,
     pub sds011: Option<Sds011<Uart<'static, Async>>>,
     pub scd30: Option<Scd30<I2cDevice<'static, NoopRawMutex, I2c<'static, Async>>>>,
+    failures: Failures,
 }
 
 impl Default for Sensors {
@@ -56,6 +83,7 @@ impl Sensors {
             bme280: None,
             sds011: None,
             scd30: None,
+            failures: Failures::default(),
         }
     }
 
@@ -80,21 +108,95 @@ impl Sensors {
         Ok(())
     }
 
-    pub async fn measure(&mut self) -> Result<SensorData, SensorError> {
+    /// Measure all sensors. A failing sensor doesn't prevent the others from
+    /// being measured: its error is recorded in `SensorData::errors` and the
+    /// `sensor_errors` reading counts the failed sensors.
+    pub async fn measure(&mut self) -> SensorData {
         let mut sensor_data = SensorData::default();
 
         if let Some(ref mut bme280) = self.bme280 {
-            bme280.measure(&mut sensor_data).await?;
+            measure_sensor(
+                "bme280",
+                bme280,
+                &mut self.failures.bme280,
+                &mut sensor_data,
+            )
+            .await;
         }
 
         if let Some(ref mut scd30) = self.scd30 {
-            scd30.measure(&mut sensor_data).await?;
+            measure_sensor("scd30", scd30, &mut self.failures.scd30, &mut sensor_data).await;
+        }
+
+        if let Some(ref mut sds011) = self.sds011 {
+            measure_sensor(
+                "sds011",
+                sds011,
+                &mut self.failures.sds011,
+                &mut sensor_data,
+            )
+            .await;
         }
 
+        sensor_data.add_measurement("sensor_errors", sensor_data.errors.len() as f32);
+        sensor_data
+    }
+
+    /// Failure counters, kept across deep sleep cycles and reported in the
+    /// diagnostics
+    pub fn failures(&self) -> Failures {
+        self.failures
+    }
+
+    #[cfg(feature = "deep_sleep")]
+    pub fn restore_failures(&mut self, failures: Failures) {
+        self.failures = failures;
+    }
+
+    /// Put the sensors in their low-power state before deep sleep.
+    #[cfg(feature = "deep_sleep")]
+    pub async fn sleep(&mut self) {
         if let Some(ref mut sds011) = self.sds011 {
-            sds011.measure(&mut sensor_data).await?;
+            if let Err(e) = sds011.sleep().await {
+                log::error!("Failed to put SDS011 to sleep: {:?}", e);
+            }
         }
+    }
+}
 
-        Ok(sensor_data)
+/// Measure a single sensor, merging its readings into `sensor_data` only when
+/// the whole measurement succeeded. The sensor is re-initialised after
+/// `SENSOR_MAX_CONSECUTIVE_FAILURES` failures in a row.
+async fn measure_sensor<S: Sensor>(
+    name: &'static str,
+    sensor: &mut S,
+    failures: &mut u32,
+    sensor_data: &mut SensorData,
+) {
+    let mut readings = SensorData::default();
+    match sensor.measure(&mut readings).await {
+        Ok(()) => {
+            *failures = 0;
+            for (key, value) in readings.data.iter() {
+                sensor_data.add_measurement(key, *value);
+            }
+        }
+        Err(e) => {
+            *failures += 1;
+            log::error!(
+                "{} measurement failed ({} in a row): {:?}",
+                name,
+                failures,
+                e
+            );
+            sensor_data.add_error(name, e);
+
+            if *failures >= SENSOR_MAX_CONSECUTIVE_FAILURES {
+                *failures = 0;
+                if let Err(e) = sensor.reinit().await {
+                    log::error!("{} re-initialisation failed: {:?}", name, e);
+                }
+            }
+        }
     }
 }
@@ -10,6 +10,9 @@ use crate::config::CONFIG;
 const AMBIENT_PRESSURE: u16 = 1013;
 /// Maximum number of retries for SCD30 initialization
 const MAX_INIT_RETRIES: u8 = 5;
+/// Retries when re-initialising after measurement failures, kept low so the
+/// measurement cycle stays within the watchdog timeout
+const MAX_REINIT_RETRIES: u8 = 1;
 /// Maximum time to wait for sensor data to be ready (in milliseconds)
 const DATA_READY_TIMEOUT_MS: u64 = 30_000;
 
@@ -22,47 +25,58 @@ impl<I2C: I2c> Scd30<I2C> {
         info!("Initialising Scd30...");
         let mut sensor = Scd30Sensor::new(i2c, Delay);
 
-        Timer::after(Duration::from_millis(1000)).await;
+        configure(&mut sensor, MAX_INIT_RETRIES).await?;
 
-        info!("Stopping continuous measurement...");
-        let mut retries = 0;
-        loop {
-            match sensor.stop_continuous_measurement().await {
-                Ok(_) => break,
-                Err(e) => {
-                    retries += 1;
-                    if retries >= MAX_INIT_RETRIES {
-                        error!("SCD30: Failed to stop continuous measurement after {} retries: {:?}", MAX_INIT_RETRIES, e);
-                        return Err(SensorError::InitFailure);
-                    }
-                    info!("Error occurred: {:?}. Retry {}/{} in 5 seconds...", e, retries, MAX_INIT_RETRIES);
-                    Timer::after(Duration::from_millis(5000)).await;
+        info!("Initialised Scd30");
+
+        Ok(Self { sensor })
+    }
+}
+
+/// Stop any running measurement, then start continuous measurement at the
+/// configured interval.
+async fn configure<I2C: I2c>(
+    sensor: &mut Scd30Sensor<I2C, Delay>,
+    max_retries: u8,
+) -> Result<(), SensorError> {
+    Timer::after(Duration::from_millis(1000)).await;
+
+    info!("Stopping continuous measurement...");
+    let mut retries = 0;
+    loop {
+        match sensor.stop_continuous_measurement().await {
+            Ok(_) => break,
+            Err(e) => {
+                retries += 1;
+                if retries >= max_retries {
+                    error!("SCD30: Failed to stop continuous measurement after {} retries: {:?}", max_retries, e);
+                    return Err(SensorError::InitFailure);
                 }
+                info!("Error occurred: {:?}. Retry {}/{} in 5 seconds...", e, retries, max_retries);
+                Timer::after(Duration::from_millis(5000)).await;
             }
         }
+    }
 
-        Timer::after(Duration::from_millis(1000)).await;
-        sensor
-            .set_measurement_interval(CONFIG.measurement_interval_seconds)
-            .await
-            .map_err(|e| {
-                error!("SCD30: Failed to set measurement interval: {:?}", e);
-                SensorError::InitFailure
-            })?;
-
-        Timer::after(Duration::from_millis(100)).await;
-        sensor
-            .start_continuous_measurement(AMBIENT_PRESSURE)
-            .await
-            .map_err(|e| {
-                error!("SCD30: Failed to start continuous measurement: {:?}", e);
-                SensorError::InitFailure
-            })?;
+    Timer::after(Duration::from_millis(1000)).await;
+    sensor
+        .set_measurement_interval(CONFIG.measurement_interval_seconds)
+        .await
+        .map_err(|e| {
+            error!("SCD30: Failed to set measurement interval: {:?}", e);
+            SensorError::InitFailure
+        })?;
 
-        info!("Initialised Scd30");
+    Timer::after(Duration::from_millis(100)).await;
+    sensor
+        .start_continuous_measurement(AMBIENT_PRESSURE)
+        .await
+        .map_err(|e| {
+            error!("SCD30: Failed to start continuous measurement: {:?}", e);
+            SensorError::InitFailure
+        })?;
 
-        Ok(Self { sensor })
-    }
+    Ok(())
 }
 
 impl<I2C: I2c> Sensor for Scd30<I2C> {
@@ -100,4 +114,14 @@ impl<I2C: I2c> Sensor for Scd30<I2C> {
             }
         }
     }
+
+    async fn reinit(&mut self) -> Result<(), SensorError> {
+        info!("Re-initialising Scd30...");
+        if let Err(e) = self.sensor.soft_reset().await {
+            error!("SCD30: Soft reset failed: {:?}", e);
+        }
+        configure(&mut self.sensor, MAX_REINIT_RETRIES).await?;
+        info!("Re-initialised Scd30");
+        Ok(())
+    }
 }
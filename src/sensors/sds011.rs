@@ -8,14 +8,34 @@ use sds011_nostd_rs::{
 use super::{Sensor, SensorData, SensorError};
 
 pub struct Sds011<S> {
-    sensor: Sds011Sensor<S>,
+    serial: S,
 }
 
 impl<S: Read + Write> Sds011<S> {
     pub async fn new(serial: S) -> Result<Self, SensorError> {
         info!("Initialising SDS011...");
-        let mut sensor = Sds011Sensor::new(
-            serial,
+        let mut sds011 = Self { serial };
+
+        // Put to sleep at the end of the previous cycle
+        #[cfg(feature = "deep_sleep")]
+        sds011.wake().await?;
+
+        sds011
+            .sensor()
+            .init()
+            .await
+            .map_err(|_| SensorError::InitFailure)?;
+
+        info!("Initialised SDS011");
+
+        Ok(sds011)
+    }
+
+    /// Driver borrowing the serial port, so the port stays available to send
+    /// the sleep commands the driver doesn't implement.
+    fn sensor(&mut self) -> Sds011Sensor<&mut S> {
+        Sds011Sensor::new(
+            &mut self.serial,
             Sds011Config {
                 id: Sds011DeviceID {
                     id1: 0xFF,
@@ -23,22 +43,59 @@ impl<S: Read + Write> Sds011<S> {
                 },
                 mode: Sds011DeviceMode::Active,
             },
-        );
-
-        sensor
-            .init()
-            .await
-            .map_err(|_| SensorError::InitFailure)?;
+        )
+    }
 
-        info!("Initialised SDS011");
+    /// Stop the fan and the laser until `wake`.
+    #[cfg(feature = "deep_sleep")]
+    pub async fn sleep(&mut self) -> Result<(), SensorError> {
+        info!("Putting SDS011 to sleep");
+        set_working(&mut self.serial, false).await
+    }
 
-        Ok(Self { sensor })
+    /// Start the fan and the laser, then wait for the readings to settle.
+    #[cfg(feature = "deep_sleep")]
+    async fn wake(&mut self) -> Result<(), SensorError> {
+        set_working(&mut self.serial, true).await?;
+        info!(
+            "SDS011 woken up, waiting {}s for readings to settle",
+            crate::constants::SDS011_WARMUP_SECS
+        );
+        embassy_time::Timer::after_secs(crate::constants::SDS011_WARMUP_SECS).await;
+        Ok(())
     }
 }
 
+/// Send the "set sleep and work" command (0xB4, data byte 6) to all devices,
+/// without waiting for the reply.
+#[cfg(feature = "deep_sleep")]
+async fn set_working<S: Write>(serial: &mut S, working: bool) -> Result<(), SensorError> {
+    let mut frame = [0u8; 19];
+    frame[0] = 0xAA;
+    frame[1] = 0xB4;
+    frame[2] = 6;
+    // Set mode, then 0 for sleep or 1 for work
+    frame[3] = 1;
+    frame[4] = working as u8;
+    // Device ID: all devices
+    frame[15] = 0xFF;
+    frame[16] = 0xFF;
+    frame[17] = frame[2..17].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    frame[18] = 0xAB;
+
+    serial
+        .write_all(&frame)
+        .await
+        .map_err(|_| SensorError::MeasurementFailure)?;
+    serial
+        .flush()
+        .await
+        .map_err(|_| SensorError::MeasurementFailure)
+}
+
 impl<S: Read + Write> Sensor for Sds011<S> {
     async fn measure(&mut self, data: &mut SensorData) -> Result<(), SensorError> {
-        match self.sensor.read_sample().await {
+        match self.sensor().read_sample().await {
             Ok(sample) => {
                 data.add_measurement("air_quality_pm2_5", sample.pm2_5);
                 data.add_measurement("air_quality_pm10", sample.pm10);
@@ -47,4 +104,14 @@ impl<S: Read + Write> Sensor for Sds011<S> {
             Err(_) => Err(SensorError::MeasurementFailure),
         }
     }
+
+    async fn reinit(&mut self) -> Result<(), SensorError> {
+        info!("Re-initialising SDS011...");
+        self.sensor()
+            .init()
+            .await
+            .map_err(|_| SensorError::InitFailure)?;
+        info!("Re-initialised SDS011");
+        Ok(())
+    }
 }
@@ -0,0 +1,178 @@
+//! Serialization of sensor readings into the MQTT payload formats.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+//!
+//! - JSON: an object with the tags as strings, the readings as numbers and the
+//!   Unix time in milliseconds, e.g.
+//!   `{"location":"outdoor","firmware":"0.1.3","temperature":21.50,"ts":1700000000000}`
+//! - Influx line protocol, with the Unix time in nanoseconds:
+//!   `weather,location=outdoor,firmware=0.1.3 temperature=21.50 1700000000000000000`
+//!
+//! Readings taken before the clock was synchronised have no timestamp: `ts` is
+//! `null` in JSON and the line protocol timestamp is left out, in which case
+//! the server stamps the point at arrival.
+//!
+//! The JSON escaping helpers are shared with the other JSON payloads, see
+//! `diagnostics_format` and `homeassistant`.
+
+use core::fmt::{self, Write};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer is too small
+    Format,
+    /// A reading is NaN or infinite, which none of the formats can represent
+    NonFinite(&'static str),
+    /// Line protocol requires at least one field
+    NoFields,
+    /// Names and tag values can't contain line breaks in line protocol
+    InvalidName,
+}
+
+impl From<fmt::Error> for Error {
+    fn from(_: fmt::Error) -> Self {
+        Error::Format
+    }
+}
+
+/// Time a reading was taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Microseconds since the Unix epoch
+    Synced(u64),
+    /// The clock was never synchronised, the time is unknown
+    Unsynced,
+}
+
+/// Write a JSON object holding `tags` as strings followed by `fields` as
+/// numbers with two decimals and `ts`. The time is in milliseconds, which
+/// JSON parsers read as float64 without losing precision.
+pub fn write_json<W: Write>(
+    w: &mut W,
+    tags: &[(&str, &str)],
+    fields: impl IntoIterator<Item = (&'static str, f32)>,
+    timestamp: Timestamp,
+) -> Result<(), Error> {
+    w.write_char('{')?;
+    let mut first = true;
+
+    for (key, value) in tags {
+        if !first {
+            w.write_char(',')?;
+        }
+        first = false;
+        write_json_str(w, key)?;
+        w.write_char(':')?;
+        write_json_str(w, value)?;
+    }
+
+    for (key, value) in fields {
+        check_finite(key, value)?;
+        if !first {
+            w.write_char(',')?;
+        }
+        first = false;
+        write_json_str(w, key)?;
+        write!(w, ":{:.2}", value)?;
+    }
+
+    if !first {
+        w.write_char(',')?;
+    }
+    match timestamp {
+        Timestamp::Synced(micros) => write!(w, "\"ts\":{}", micros / 1000)?,
+        Timestamp::Unsynced => w.write_str("\"ts\":null")?,
+    }
+
+    w.write_char('}')?;
+    Ok(())
+}
+
+/// Write a single Influx line protocol point, with a nanosecond timestamp
+/// when synced. Tags with an empty value are omitted, as required by the
+/// protocol.
+pub fn write_influx<W: Write>(
+    w: &mut W,
+    measurement: &str,
+    tags: &[(&str, &str)],
+    fields: impl IntoIterator<Item = (&'static str, f32)>,
+    timestamp: Timestamp,
+) -> Result<(), Error> {
+    write_influx_escaped(w, measurement, &[',', ' '])?;
+
+    for (key, value) in tags {
+        if value.is_empty() {
+            continue;
+        }
+        w.write_char(',')?;
+        write_influx_escaped(w, key, &[',', '=', ' '])?;
+        w.write_char('=')?;
+        write_influx_escaped(w, value, &[',', '=', ' '])?;
+    }
+
+    let mut first = true;
+    for (key, value) in fields {
+        check_finite(key, value)?;
+        w.write_char(if first { ' ' } else { ',' })?;
+        first = false;
+        write_influx_escaped(w, key, &[',', '=', ' '])?;
+        write!(w, "={:.2}", value)?;
+    }
+
+    if first {
+        return Err(Error::NoFields);
+    }
+    if let Timestamp::Synced(micros) = timestamp {
+        write!(w, " {}000", micros)?;
+    }
+    Ok(())
+}
+
+/// Write a JSON string literal.
+pub fn write_json_str<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    w.write_char('"')?;
+    write_json_escaped(w, s)?;
+    w.write_char('"')
+}
+
+/// Write the content of a JSON string, escaping quotes, backslashes and
+/// control characters.
+pub fn write_json_escaped<W: Write>(w: &mut W, s: &str) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            '\n' => w.write_str("\\n")?,
+            '\r' => w.write_str("\\r")?,
+            '\t' => w.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+/// Write a line protocol name or tag value, backslash escaping `special`
+/// characters. Backslashes themselves are written as is, as done by the
+/// Influx client libraries.
+fn write_influx_escaped<W: Write>(w: &mut W, s: &str, special: &[char]) -> Result<(), Error> {
+    for c in s.chars() {
+        if c == '\n' || c == '\r' {
+            return Err(Error::InvalidName);
+        }
+        if special.contains(&c) {
+            w.write_char('\\')?;
+        }
+        w.write_char(c)?;
+    }
+    Ok(())
+}
+
+fn check_finite(key: &'static str, value: f32) -> Result<(), Error> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(Error::NonFinite(key))
+    }
+}
@@ -0,0 +1,113 @@
+//! SNTP (RFC 4330) client packets.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`. The socket side lives in `clock`.
+
+/// UDP port of NTP servers
+pub const PORT: u16 = 123;
+
+/// Size of an NTP packet without extension fields
+pub const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP prime epoch (1900-01-01) to the Unix epoch
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+/// NTP timestamps only hold 32 bits of seconds, the counter wraps in 2036.
+/// Timestamps below this pivot are in the next era, which keeps the device
+/// working until 2104.
+const ERA_PIVOT: u64 = 0x8000_0000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Shorter than an NTP packet
+    Truncated,
+    /// Not a reply from a server (mode 4)
+    NotServer,
+    /// The server isn't synchronised itself (leap indicator 3 or stratum 0)
+    Unsynchronized,
+    /// The server asks us to stop querying it, with the reason code
+    KissOfDeath([u8; 4]),
+    /// The reply doesn't echo the timestamp of our request
+    OriginMismatch,
+    /// Zero timestamp
+    InvalidTimestamp,
+}
+
+/// Write a client request into `out`. `transmit` is echoed by the server in
+/// the origin timestamp of the reply: any value unpredictable to third
+/// parties works, the server doesn't use it.
+pub fn write_request(transmit: u64, out: &mut [u8; PACKET_LEN]) {
+    *out = [0; PACKET_LEN];
+    // Leap indicator 0, version 4, mode 3 (client)
+    out[0] = (4 << 3) | 3;
+    out[40..48].copy_from_slice(&transmit.to_be_bytes());
+}
+
+/// Server reply, times in microseconds since the Unix epoch
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub stratum: u8,
+    /// Time the request arrived at the server
+    pub receive: u64,
+    /// Time the reply left the server
+    pub transmit: u64,
+}
+
+impl Response {
+    /// Current time when the reply arrived, `elapsed_us` after the request
+    /// was sent. Half of the network delay is added to the server transmit
+    /// time, assuming symmetric paths.
+    pub fn time_at_arrival(&self, elapsed_us: u64) -> u64 {
+        let server_time = self.transmit.saturating_sub(self.receive);
+        let delay = elapsed_us.saturating_sub(server_time);
+        self.transmit + delay / 2
+    }
+}
+
+/// Parse the reply to the request sent with `transmit`.
+pub fn parse_response(packet: &[u8], transmit: u64) -> Result<Response, Error> {
+    if packet.len() < PACKET_LEN {
+        return Err(Error::Truncated);
+    }
+    let leap = packet[0] >> 6;
+    let mode = packet[0] & 0x07;
+    let stratum = packet[1];
+
+    if mode != 4 {
+        return Err(Error::NotServer);
+    }
+    if stratum == 0 {
+        // The reference id holds the ASCII reason, e.g. "RATE" or "DENY"
+        return Err(Error::KissOfDeath(packet[12..16].try_into().unwrap()));
+    }
+    if leap == 3 || stratum > 15 {
+        return Err(Error::Unsynchronized);
+    }
+    if read_u64(packet, 24) != transmit {
+        return Err(Error::OriginMismatch);
+    }
+
+    Ok(Response {
+        stratum,
+        receive: unix_micros(read_u64(packet, 32))?,
+        transmit: unix_micros(read_u64(packet, 40))?,
+    })
+}
+
+fn read_u64(packet: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+}
+
+/// Convert an NTP timestamp (32 bits of seconds, 32 bits of fraction) to
+/// microseconds since the Unix epoch.
+fn unix_micros(timestamp: u64) -> Result<u64, Error> {
+    if timestamp == 0 {
+        return Err(Error::InvalidTimestamp);
+    }
+    let mut seconds = timestamp >> 32;
+    if seconds < ERA_PIVOT {
+        seconds += 1 << 32;
+    }
+    let fraction = timestamp & 0xFFFF_FFFF;
+    Ok((seconds - UNIX_EPOCH) * 1_000_000 + ((fraction * 1_000_000) >> 32))
+}
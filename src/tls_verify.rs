@@ -0,0 +1,71 @@
+//! Server certificate verification for the `tls-verify` feature.
+//!
+//! Only depends on embedded-tls and the crypto crates, so the handshake can
+//! be tested on the host against a real TLS server.
+
+use embedded_tls::{
+    pki::CertVerifier, Aes128GcmSha256, CryptoProvider, CryptoRngCore, SignatureScheme, TlsClock,
+    TlsError, TlsVerifier,
+};
+use rand_core::{CryptoRng, RngCore};
+
+/// Crypto provider validating the server certificate chain against the
+/// configured CA and the certificate CommonName against the server name.
+/// The validity dates are checked when `CLOCK` knows the current time.
+/// Also signs the CertificateVerify message with the client key for mTLS.
+pub struct VerifyingProvider<RNG, CLOCK: TlsClock, const CERT_SIZE: usize> {
+    rng: RNG,
+    verifier: CertVerifier<Aes128GcmSha256, CLOCK, CERT_SIZE>,
+}
+
+impl<RNG: CryptoRng + RngCore, CLOCK: TlsClock, const CERT_SIZE: usize>
+    VerifyingProvider<RNG, CLOCK, CERT_SIZE>
+{
+    pub fn new(rng: RNG) -> Self {
+        Self {
+            rng,
+            verifier: CertVerifier::new(),
+        }
+    }
+}
+
+impl<RNG: CryptoRng + RngCore, CLOCK: TlsClock, const CERT_SIZE: usize> CryptoProvider
+    for VerifyingProvider<RNG, CLOCK, CERT_SIZE>
+{
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = p256::ecdsa::DerSignature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+        key_der: &[u8],
+    ) -> Result<
+        (
+            impl p256::ecdsa::signature::SignerMut<Self::Signature>,
+            SignatureScheme,
+        ),
+        TlsError,
+    > {
+        let secret_key =
+            p256::SecretKey::from_sec1_der(key_der).map_err(|_| TlsError::InvalidPrivateKey)?;
+
+        Ok((
+            p256::ecdsa::SigningKey::from(&secret_key),
+            SignatureScheme::EcdsaSecp256r1Sha256,
+        ))
+    }
+}
+
+/// Whether a handshake failed because the server certificate was rejected:
+/// untrusted chain, CommonName not matching, outside its validity dates, or
+/// CertificateVerify signature not matching. Malformed messages are not.
+pub fn is_verification_error(e: &TlsError) -> bool {
+    matches!(e, TlsError::InvalidCertificate | TlsError::InvalidSignature)
+}
@@ -0,0 +1,26 @@
+//! MQTT topics derived from the device identity.
+//!
+//! Only depends on `core` so it can be unit tested on the host, see
+//! `tools/firmware-core`.
+
+use core::fmt::Write as _;
+
+use heapless::String;
+
+/// MQTT topic of the device: `explicit` when configured, otherwise
+/// `<mqtt_topic>/<device_id>/<leaf>` built from the effective values, so that
+/// devices flashed from one image and told apart by their stored `DeviceId`
+/// get their own topics. `None` when longer than `N`.
+pub fn device_topic<const N: usize>(
+    explicit: Option<&str>,
+    mqtt_topic: &str,
+    device_id: &str,
+    leaf: &str,
+) -> Option<String<N>> {
+    let mut topic = String::new();
+    match explicit {
+        Some(explicit) => topic.push_str(explicit).ok()?,
+        None => write!(topic, "{}/{}/{}", mqtt_topic, device_id, leaf).ok()?,
+    }
+    Some(topic)
+}
@@ -2,7 +2,7 @@ use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::marker::PhantomData;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
 use embassy_net::tcp::ConnectError;
 use embassy_net::{
     dns::{DnsQueryType, Error as DNSError},
@@ -10,15 +10,27 @@ use embassy_net::{
     Stack,
 };
 use embassy_time::Duration;
+#[cfg(feature = "tls")]
+use embassy_time::Instant;
 use embedded_io_async::{ErrorType, Read, ReadExactError, Write};
-use embedded_tls::{Aes128GcmSha256, TlsConfig, TlsConnection, TlsContext, UnsecureProvider};
+#[cfg(feature = "tls-verify")]
+use embedded_tls::TlsClock;
+#[cfg(not(feature = "tls-verify"))]
+use embedded_tls::UnsecureProvider;
+use embedded_tls::{Aes128GcmSha256, TlsConfig, TlsConnection, TlsContext};
 #[cfg(feature = "mtls")]
 use p256::elliptic_curve::SecretKey;
 use rand_core::{CryptoRng, RngCore};
 use static_cell::StaticCell;
 
+#[cfg(feature = "tls-verify")]
+use crate::clock;
 use crate::config::CONFIG;
 use crate::constants::TCP_SOCKET_TIMEOUT_SECS;
+#[cfg(feature = "tls-verify")]
+use crate::constants::TLS_CERT_MAX_SIZE;
+#[cfg(feature = "tls-verify")]
+use crate::tls_verify::{self, VerifyingProvider};
 
 const MAX_RETRIES: usize = 3;
 
@@ -38,6 +50,18 @@ static CACHED_CERTS: StaticCell<CachedCerts> = StaticCell::new();
 #[cfg(feature = "tls")]
 static CERTS_PTR: AtomicPtr<CachedCerts> = AtomicPtr::new(core::ptr::null_mut());
 
+/// Duration of the last successful TLS handshake in milliseconds, 0 before
+/// the first one
+static HANDSHAKE_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Duration of the last successful TLS handshake, reported in the diagnostics.
+pub fn last_handshake_ms() -> Option<u32> {
+    match HANDSHAKE_MS.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(ms),
+    }
+}
+
 /// Initialize and cache TLS certificates. Called once, results are reused.
 #[cfg(feature = "tls")]
 fn get_or_init_cached_certs() -> Result<&'static CachedCerts, Error> {
@@ -89,6 +113,18 @@ fn get_or_init_cached_certs() -> Result<&'static CachedCerts, Error> {
     Ok(cached)
 }
 
+/// Time for the certificate validity checks, unknown until the clock is
+/// synchronised over SNTP: the dates are not checked before.
+#[cfg(feature = "tls-verify")]
+struct SntpClock;
+
+#[cfg(feature = "tls-verify")]
+impl TlsClock for SntpClock {
+    fn now() -> Option<u64> {
+        clock::now().map(|micros| micros / 1_000_000)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     CACertificateMissing,
@@ -98,6 +134,7 @@ pub enum Error {
     DNSLookupFailed,
     SocketConnectionError(ConnectError),
     TLSHandshakeFailed,
+    CertificateVerificationFailed,
     PEMParseError,
 }
 
@@ -168,19 +205,38 @@ impl<'a> Transport<'a, TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>> {
             hostname
         );
 
-        let crypto_provider = UnsecureProvider::new::<Aes128GcmSha256>(rng);
+        #[cfg(feature = "tls-verify")]
+        let crypto_provider = VerifyingProvider::<_, SntpClock, TLS_CERT_MAX_SIZE>::new(rng);
+        #[cfg(not(feature = "tls-verify"))]
+        let crypto_provider = {
+            log::warn!("Server certificate verification disabled (tls-verify feature off)");
+            UnsecureProvider::new::<Aes128GcmSha256>(rng)
+        };
+
+        let started = Instant::now();
         tls.open(TlsContext::new(&config, crypto_provider))
             .await
             .map_err(|e| {
+                #[cfg(feature = "tls-verify")]
+                if tls_verify::is_verification_error(&e) {
+                    log::error!("Server certificate verification failed: {:?}", e);
+                    log::error!("Check that the server certificate chains to tls_ca, that");
+                    log::error!(
+                        "its CommonName matches {} and that it is not expired",
+                        hostname
+                    );
+                    return Error::CertificateVerificationFailed;
+                }
                 log::error!("TLS handshake failed: {:?}", e);
                 log::error!("This could be due to:");
-                log::error!("  - Server certificate verification failure");
                 log::error!("  - Cipher suite mismatch");
                 log::error!("  - Protocol version mismatch (ensure server supports TLS 1.3)");
                 log::error!("  - Buffer size too small (minimum 16384 bytes required)");
                 Error::TLSHandshakeFailed
             })?;
-        log::info!("TLS handshake complete");
+        let elapsed = started.elapsed().as_millis();
+        HANDSHAKE_MS.store(elapsed.clamp(1, u32::MAX as u64) as u32, Ordering::Relaxed);
+        log::info!("TLS handshake complete in {}ms", elapsed);
 
         Ok(Self {
             session: tls,
@@ -206,7 +262,8 @@ impl<'a> Transport<'a, TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>> {
     }
 }
 
-fn decode_pem(pem: &str) -> Result<Vec<u8>, Error> {
+/// Decode the first PEM block of `pem` into DER bytes.
+pub fn decode_pem(pem: &str) -> Result<Vec<u8>, Error> {
     use base64::Engine;
     let start_marker = "-----BEGIN";
     let end_marker = "-----END";
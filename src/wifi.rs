@@ -1,30 +1,63 @@
+use core::cell::Cell;
+
 use embassy_executor::Spawner;
-use embassy_net::{Runner, Stack, StackResources};
+use embassy_futures::select::{select, select3, Either};
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    Ipv4Address, Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4,
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
 use embassy_time::{Duration, Timer, with_timeout};
+use embedded_io_async::{Read, Write};
 
 use esp_hal::rng::Rng;
 use esp_radio::{
-    wifi::{ClientConfig, Config, ModeConfig, WifiController, WifiDevice, WifiEvent, WifiStaState},
+    wifi::{
+        event::{EventExt, StaConnected},
+        AccessPointConfig, AuthMethod, ClientConfig, Config, ModeConfig, WifiController, WifiDevice,
+        WifiEvent, WifiStaState,
+    },
     Controller,
 };
+use esp_storage::FlashStorage;
 
 use heapless::String;
 use log::info;
 use static_cell::StaticCell;
 
-use crate::config::CONFIG;
-use crate::constants::{WIFI_CONNECT_TIMEOUT_SECS, WIFI_RECONNECT_DELAY_MS};
+use crate::config::{self, CONFIG};
+use crate::constants::*;
+use crate::diagnostics_format::WifiLink;
+use crate::provisioning::{self, DhcpServer, Form};
 
 static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+/// Provisioning portal sockets: DHCP, DNS and HTTP
+static AP_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+
+/// Asks the connection task to stop joining `CONFIG.wifi_ssid` and start the
+/// provisioning access point instead
+static START_ACCESS_POINT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Access point the station is associated with, `None` while disconnected
+static LINK: Mutex<CriticalSectionRawMutex, Cell<Option<WifiLink>>> = Mutex::new(Cell::new(None));
 
 pub struct Wifi {
     pub stack: Stack<'static>,
+    ap: WifiDevice<'static>,
+    rng: Rng,
 }
 
 #[derive(Debug)]
 pub enum Error {
     WifiInitFailed,
     HostnameTooLong,
+    Socket,
+    Request(provisioning::Error),
+    Config(config::Error),
 }
 
 impl Wifi {
@@ -37,6 +70,18 @@ impl Wifi {
         let (controller, interfaces) = esp_radio::wifi::new(init, wifi, Config::default())
             .map_err(|_| Error::WifiInitFailed)?;
 
+        // The BSSID and channel are only reported by the connection event,
+        // the signal strength is sampled by the connection task
+        StaConnected::update_handler(|event| {
+            let bssid = event.bssid().try_into().unwrap_or_default();
+            let link = WifiLink {
+                rssi: 0,
+                channel: event.channel(),
+                bssid,
+            };
+            LINK.lock(|cell| cell.set(Some(link)));
+        });
+
         let mut dhcp_config = embassy_net::DhcpConfig::default();
         dhcp_config.hostname = Some(
             String::<32>::try_from(CONFIG.device_id).map_err(|_| Error::HostnameTooLong)?,
@@ -51,7 +96,11 @@ impl Wifi {
         spawner.spawn(connection(controller)).expect("Failed to spawn WiFi connection task");
         spawner.spawn(net_task(runner)).expect("Failed to spawn network task");
 
-        Ok(Self { stack })
+        Ok(Self {
+            stack,
+            ap: interfaces.ap,
+            rng,
+        })
     }
 
     pub async fn connect(&self) -> Result<(), Error> {
@@ -74,6 +123,28 @@ impl Wifi {
 
         Ok(())
     }
+
+    /// Fallback when the configured network can't be joined: start an access
+    /// point named after the device, protected by `CONFIG.provisioning_psk`,
+    /// serving a form to enter the Wi-Fi and MQTT settings. Returns once settings were saved, they are
+    /// applied on the next boot.
+    pub async fn provision(self, flash: &mut FlashStorage<'_>) {
+        START_ACCESS_POINT.signal(());
+
+        let address = Ipv4Address::from(PORTAL_ADDRESS);
+        let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+            address: Ipv4Cidr::new(address, 24),
+            gateway: Some(address),
+            dns_servers: heapless::Vec::new(),
+        });
+        let seed = (self.rng.random() as u64) << 32 | self.rng.random() as u64;
+        let resources = AP_RESOURCES.init(StackResources::new());
+        let (stack, mut runner) = embassy_net::new(self.ap, config, resources, seed);
+
+        info!("Provisioning portal listening on http://{}/", address);
+        let servers = select3(dhcp_server(stack), dns_server(stack), http_server(stack, flash));
+        select(runner.run(), servers).await;
+    }
 }
 
 #[embassy_executor::task]
@@ -82,10 +153,33 @@ async fn connection(mut controller: WifiController<'static>) {
         "Start connection task, device capabilities: {:?}",
         controller.capabilities()
     );
+
+    select(station(&mut controller), START_ACCESS_POINT.wait()).await;
+    access_point(&mut controller).await;
+}
+
+/// Access point the station is associated with, `None` while disconnected.
+pub fn link() -> Option<WifiLink> {
+    LINK.lock(|cell| cell.get())
+}
+
+/// Join `CONFIG.wifi_ssid`, reconnecting whenever the connection drops.
+async fn station(controller: &mut WifiController<'static>) {
     loop {
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            // wait until we're no longer connected, sampling the signal
+            // strength meanwhile
+            while esp_radio::wifi::sta_state() == WifiStaState::Connected {
+                if let Ok(rssi) = controller.rssi() {
+                    LINK.lock(|cell| cell.set(cell.get().map(|link| WifiLink { rssi, ..link })));
+                }
+                let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+                let sample = Timer::after(Duration::from_secs(WIFI_RSSI_INTERVAL_SECS));
+                if let Either::First(()) = select(disconnected, sample).await {
+                    break;
+                }
+            }
+            LINK.lock(|cell| cell.set(None));
             Timer::after(Duration::from_millis(WIFI_RECONNECT_DELAY_MS)).await
         }
 
@@ -124,6 +218,210 @@ async fn connection(mut controller: WifiController<'static>) {
     }
 }
 
+/// Switch the radio to the provisioning access point and keep it up.
+async fn access_point(controller: &mut WifiController<'static>) {
+    if let Err(e) = controller.stop_async().await {
+        log::warn!("Failed to stop WiFi station: {:?}", e);
+    }
+
+    // Named after the device so it can be told apart from its neighbours
+    let ssid = CONFIG.device_id.get(..32).unwrap_or(CONFIG.device_id);
+    let Some(psk) = CONFIG.provisioning_psk else {
+        log::error!("No provisioning passphrase configured, not starting the access point");
+        return core::future::pending().await;
+    };
+    info!("Starting provisioning access point with SSID: {:?}", ssid);
+    let ap_config = AccessPointConfig::default()
+        .with_ssid(ssid.into())
+        .with_auth_method(AuthMethod::Wpa2Personal)
+        .with_password(psk.into());
+    let config = ModeConfig::AccessPoint(ap_config);
+    if let Err(e) = controller.set_config(&config) {
+        log::error!("Failed to set access point config: {:?}", e);
+    } else if let Err(e) = controller.start_async().await {
+        log::error!("Failed to start access point: {:?}", e);
+    }
+
+    // The controller must be kept alive for the access point to stay up
+    core::future::pending::<()>().await;
+}
+
+/// Hand out addresses to the clients of the provisioning access point.
+async fn dhcp_server(stack: Stack<'_>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(67) {
+        log::error!("Failed to bind DHCP server socket: {:?}", e);
+        return core::future::pending().await;
+    }
+
+    let mut server = DhcpServer::new(PORTAL_ADDRESS);
+    let mut request = [0u8; 576];
+    let mut reply = [0u8; 576];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        if let Some(len) = server.reply(&request[..len], &mut reply) {
+            // Clients have no address yet, replies are broadcast
+            if let Err(e) = socket.send_to(&reply[..len], (Ipv4Address::BROADCAST, 68)).await {
+                log::warn!("Failed to send DHCP reply: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Resolve every name to the portal, so clients open it as a captive portal.
+async fn dns_server(stack: Stack<'_>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(53) {
+        log::error!("Failed to bind DNS server socket: {:?}", e);
+        return core::future::pending().await;
+    }
+
+    let mut query = [0u8; 512];
+    let mut reply = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = provisioning::dns_reply(&query[..len], PORTAL_ADDRESS, &mut reply) {
+            if let Err(e) = socket.send_to(&reply[..len], meta.endpoint).await {
+                log::warn!("Failed to send DNS reply: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Serve the settings form until valid settings were saved.
+async fn http_server(stack: Stack<'_>, flash: &mut FlashStorage<'_>) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut request = [0u8; PORTAL_REQUEST_MAX_LEN];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(TCP_SOCKET_TIMEOUT_SECS)));
+        if let Err(e) = socket.accept(80).await {
+            log::warn!("Failed to accept portal connection: {:?}", e);
+            continue;
+        }
+
+        let saved = match handle_request(&mut socket, &mut request, flash).await {
+            Ok(saved) => saved,
+            Err(e) => {
+                log::warn!("Provisioning portal request failed: {:?}", e);
+                false
+            }
+        };
+        let _ = socket.flush().await;
+        socket.close();
+        // Let the connection close before the socket is dropped
+        Timer::after(Duration::from_millis(500)).await;
+
+        if saved {
+            return;
+        }
+    }
+}
+
+/// Answer a single portal request. Returns `true` once settings were saved.
+async fn handle_request(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8],
+    flash: &mut FlashStorage<'_>,
+) -> Result<bool, Error> {
+    let mut len = 0;
+    loop {
+        match provisioning::parse_request(&buf[..len]) {
+            Ok(Some(_)) => break,
+            Ok(None) if len < buf.len() => {}
+            Ok(None) => return respond_form(socket, "413 Content Too Large", provisioning::Error::TooLarge).await,
+            Err(e) => return respond_form(socket, "400 Bad Request", e).await,
+        }
+        let n = socket.read(&mut buf[len..]).await.map_err(|_| Error::Socket)?;
+        if n == 0 {
+            return Err(Error::Socket);
+        }
+        len += n;
+    }
+    let request = provisioning::parse_request(&buf[..len])
+        .map_err(Error::Request)?
+        .ok_or(Error::Socket)?;
+
+    // Captive portal checks probe various paths, they all get the form
+    if request.method != "POST" {
+        respond(socket, "200 OK", |page| {
+            provisioning::write_page(page, CONFIG.device_id, &current_settings(), None)
+        })
+        .await?;
+        return Ok(false);
+    }
+
+    // Declared first, the record borrows the decoded values
+    let mut decoded = [0u8; PORTAL_REQUEST_MAX_LEN];
+    let mut record = config::overrides();
+    let form = match Form::parse(request.body, &mut decoded) {
+        Ok(form) => form,
+        Err(e) => return respond_form(socket, "400 Bad Request", e).await,
+    };
+    form.apply(&mut record)
+        .map_err(|e| Error::Config(config::Error::Store(e)))?;
+    config::save(flash, &record).map_err(Error::Config)?;
+
+    info!("Provisioning settings saved for SSID: {:?}", form.wifi_ssid);
+    respond(socket, "200 OK", |page| {
+        provisioning::write_saved_page(page, CONFIG.device_id, form.wifi_ssid)
+    })
+    .await?;
+    Ok(true)
+}
+
+/// Answer with the form showing why the submission was rejected.
+async fn respond_form(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    error: provisioning::Error,
+) -> Result<bool, Error> {
+    respond(socket, status, |page| {
+        provisioning::write_page(page, CONFIG.device_id, &current_settings(), Some(error.message()))
+    })
+    .await?;
+    Err(Error::Request(error))
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    write_page: impl FnOnce(&mut String<PORTAL_PAGE_MAX_LEN>) -> core::fmt::Result,
+) -> Result<(), Error> {
+    let mut page: String<PORTAL_PAGE_MAX_LEN> = String::new();
+    write_page(&mut page).map_err(|_| Error::Request(provisioning::Error::TooLarge))?;
+    let mut head: String<160> = String::new();
+    provisioning::write_response_head(&mut head, status, page.len())
+        .map_err(|_| Error::Request(provisioning::Error::TooLarge))?;
+
+    socket.write_all(head.as_bytes()).await.map_err(|_| Error::Socket)?;
+    socket.write_all(page.as_bytes()).await.map_err(|_| Error::Socket)
+}
+
+/// Settings pre-filled in the form. The network and broker account aren't
+/// disclosed to whoever joins the access point.
+fn current_settings() -> Form<'static> {
+    Form {
+        mqtt_port: Some(CONFIG.mqtt_port),
+        location: CONFIG.location,
+        ..Default::default()
+    }
+}
+
 #[embassy_executor::task]
 async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
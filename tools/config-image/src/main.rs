@@ -0,0 +1,157 @@
+//! Build the runtime configuration image flashed in the `nvs` partition, so a
+//! single firmware image can be used for every device.
+//!
+//! Usage: config-image <device.toml> <output.bin>
+//!
+//! The input uses the same keys as `cfg.toml`, only the keys present override
+//! the values compiled in the firmware. Flash the output at the offset of the
+//! `nvs` partition:
+//!
+//!     espflash write-bin 0x9000 output.bin
+
+use std::{env, error::Error, fs, process::ExitCode};
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use firmware_core::config_store::{self, Key, Record, Value, SLOT_SIZE, STORE_SIZE};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <device.toml> <output.bin>", args[0]);
+        return ExitCode::FAILURE;
+    }
+
+    match build(&args[1], &args[2]) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn build(input_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let table: toml::Table = fs::read_to_string(input_path)?.parse()?;
+
+    let mut record = Record::new();
+    for (name, value) in table.iter() {
+        let key = key(name).ok_or_else(|| format!("unsupported key `{name}`"))?;
+        let value = match value {
+            toml::Value::String(s) => Value::Str(s.as_str()),
+            toml::Value::Integer(i) => {
+                Value::U16(u16::try_from(*i).map_err(|_| format!("`{name}` out of range"))?)
+            }
+            _ => return Err(format!("`{name}` must be a string or an integer").into()),
+        };
+        record
+            .set(key, value)
+            .map_err(|_| format!("invalid type for `{name}`"))?;
+    }
+
+    let mut flash = ImageFlash {
+        data: vec![0xFF; STORE_SIZE],
+    };
+    let mut scratch = vec![0u8; SLOT_SIZE];
+    config_store::save(&mut flash, &record, &mut scratch)
+        .map_err(|e| format!("failed to encode configuration: {e:?}"))?;
+
+    fs::write(output_path, &flash.data)?;
+    eprintln!(
+        "Wrote {} overrides to {} ({} bytes)",
+        record.iter().count(),
+        output_path,
+        flash.data.len()
+    );
+    Ok(())
+}
+
+fn key(name: &str) -> Option<Key> {
+    Some(match name {
+        "device_id" => Key::DeviceId,
+        "diagnostics_interval_seconds" => Key::DiagnosticsIntervalSeconds,
+        "location" => Key::Location,
+        "measurement_interval_seconds" => Key::MeasurementIntervalSeconds,
+        "mqtt_hostname" => Key::MqttHostname,
+        "mqtt_password" => Key::MqttPassword,
+        "mqtt_port" => Key::MqttPort,
+        "mqtt_topic" => Key::MqttTopic,
+        "mqtt_username" => Key::MqttUsername,
+        "mqtt_availability_topic" => Key::MqttAvailabilityTopic,
+        "mqtt_command_topic" => Key::MqttCommandTopic,
+        "mqtt_diagnostics_topic" => Key::MqttDiagnosticsTopic,
+        "ntp_hostname" => Key::NtpHostname,
+        "ota_channel" => Key::OtaChannel,
+        "ota_health_timeout_seconds" => Key::OtaHealthTimeoutSeconds,
+        "ota_hostname" => Key::OtaHostname,
+        "ota_port" => Key::OtaPort,
+        "ota_registry_password" => Key::OtaRegistryPassword,
+        "ota_registry_repository" => Key::OtaRegistryRepository,
+        "ota_registry_username" => Key::OtaRegistryUsername,
+        "tls_ca" => Key::TlsCa,
+        "tls_cert" => Key::TlsCert,
+        "tls_key" => Key::TlsPrivateKey,
+        "wifi_psk" => Key::WifiPsk,
+        "wifi_ssid" => Key::WifiSsid,
+        _ => return None,
+    })
+}
+
+/// Erased flash image the record is saved into
+struct ImageFlash {
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct OutOfBounds;
+
+impl NorFlashError for OutOfBounds {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::OutOfBounds
+    }
+}
+
+impl ErrorType for ImageFlash {
+    type Error = OutOfBounds;
+}
+
+impl ReadNorFlash for ImageFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        let src = self
+            .data
+            .get(offset..offset + bytes.len())
+            .ok_or(OutOfBounds)?;
+        bytes.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for ImageFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.data
+            .get_mut(from as usize..to as usize)
+            .ok_or(OutOfBounds)?
+            .fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        self.data
+            .get_mut(offset..offset + bytes.len())
+            .ok_or(OutOfBounds)?
+            .copy_from_slice(bytes);
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! Firmware modules which only depend on `core`, built for the host so they
+//! can be unit tested with `cargo test` and shared with the host tools.
+
+#[path = "../../../src/app_image.rs"]
+pub mod app_image;
+#[path = "../../../src/boot_loop.rs"]
+pub mod boot_loop;
+#[path = "../../../src/command.rs"]
+pub mod command;
+#[path = "../../../src/config_store.rs"]
+pub mod config_store;
+#[path = "../../../src/crc32.rs"]
+pub mod crc32;
+#[path = "../../../src/diagnostics_format.rs"]
+pub mod diagnostics_format;
+#[path = "../../../src/download.rs"]
+pub mod download;
+#[path = "../../../src/http.rs"]
+pub mod http;
+#[path = "../../../src/json.rs"]
+pub mod json;
+#[path = "../../../src/lookahead.rs"]
+pub mod lookahead;
+#[path = "../../../src/manifest.rs"]
+pub mod manifest;
+#[path = "../../../src/oci.rs"]
+pub mod oci;
+#[path = "../../../src/provisioning.rs"]
+pub mod provisioning;
+#[path = "../../../src/rollback.rs"]
+pub mod rollback;
+#[path = "../../../src/rtc_record.rs"]
+pub mod rtc_record;
+#[path = "../../../src/sample_store.rs"]
+pub mod sample_store;
+#[path = "../../../src/semver.rs"]
+pub mod semver;
+#[path = "../../../src/serializer.rs"]
+pub mod serializer;
+#[path = "../../../src/sntp.rs"]
+pub mod sntp;
+#[path = "../../../src/topic.rs"]
+pub mod topic;
@@ -0,0 +1,39 @@
+use firmware_core::app_image::{version, HEADER_LEN};
+
+/// Start of an image built with `esp_app_desc!()` for `version`
+fn image(version: &[u8]) -> Vec<u8> {
+    let mut image = vec![0u8; 256];
+    image[0] = 0xE9;
+    image[32..36].copy_from_slice(&0xABCD_5432u32.to_le_bytes());
+    image[48..48 + version.len()].copy_from_slice(version);
+    image[80..87].copy_from_slice(b"sensors");
+    image
+}
+
+#[test]
+fn reads_version() {
+    assert_eq!(version(&image(b"0.1.4")), Some("0.1.4"));
+    assert_eq!(
+        version(&image(b"1.0.0-rc.1+build.7")),
+        Some("1.0.0-rc.1+build.7")
+    );
+    // Not NUL terminated when it fills the field
+    let long = [b'1'; 32];
+    assert_eq!(version(&image(&long)), Some("1".repeat(32).as_str()));
+    assert_eq!(version(&image(b"0.1.4")[..HEADER_LEN]), Some("0.1.4"));
+}
+
+#[test]
+fn invalid_header() {
+    assert_eq!(version(&image(b"0.1.4")[..HEADER_LEN - 1]), None);
+
+    let mut not_an_image = image(b"0.1.4");
+    not_an_image[0] = 0;
+    assert_eq!(version(&not_an_image), None);
+
+    let mut no_descriptor = image(b"0.1.4");
+    no_descriptor[32] = 0;
+    assert_eq!(version(&no_descriptor), None);
+
+    assert_eq!(version(&image(&[0xFF, 0xFE])), None);
+}
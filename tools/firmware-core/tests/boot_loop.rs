@@ -0,0 +1,139 @@
+use firmware_core::boot_loop::{BootRecord, Outcome, WORDS};
+
+const THRESHOLD: u32 = 3;
+
+/// Boot after `previous` ended with `outcome`
+fn reboot(previous: BootRecord, outcome: Outcome) -> BootRecord {
+    BootRecord::next(
+        Some(BootRecord {
+            outcome,
+            ..previous
+        }),
+        THRESHOLD,
+    )
+}
+
+#[test]
+fn power_on() {
+    assert_eq!(
+        BootRecord::next(None, THRESHOLD),
+        BootRecord {
+            boot_count: 1,
+            failed_boots: 0,
+            safe_mode: false,
+            outcome: Outcome::Running,
+        }
+    );
+}
+
+#[test]
+fn safe_mode_after_failed_boots() {
+    let mut boot = BootRecord::next(None, THRESHOLD);
+    for failed_boots in 1..THRESHOLD {
+        boot = reboot(boot, Outcome::Running);
+        assert_eq!(boot.failed_boots, failed_boots);
+        assert!(!boot.safe_mode);
+    }
+
+    boot = reboot(boot, Outcome::Running);
+    assert_eq!(boot.failed_boots, THRESHOLD);
+    assert!(boot.safe_mode);
+    assert_eq!(boot.boot_count, THRESHOLD + 1);
+
+    // Failing in safe mode keeps the device in safe mode
+    boot = reboot(boot, Outcome::Running);
+    assert_eq!(boot.failed_boots, THRESHOLD + 1);
+    assert!(boot.safe_mode);
+}
+
+#[test]
+fn planned_reboot_tries_normal_mode() {
+    let mut boot = BootRecord::next(None, THRESHOLD);
+    for _ in 0..THRESHOLD {
+        boot = reboot(boot, Outcome::Running);
+    }
+    assert!(boot.safe_mode);
+
+    // Retry from safe mode, the failures are still counted
+    boot = reboot(boot, Outcome::Planned);
+    assert_eq!(boot.failed_boots, THRESHOLD);
+    assert!(!boot.safe_mode);
+
+    // Failing again goes straight back to safe mode
+    let failed = reboot(boot, Outcome::Running);
+    assert_eq!(failed.failed_boots, THRESHOLD + 1);
+    assert!(failed.safe_mode);
+
+    // Becoming stable clears the failures
+    let recovered = reboot(boot, Outcome::Stable);
+    assert_eq!(recovered.failed_boots, 0);
+    assert!(!recovered.safe_mode);
+}
+
+#[test]
+fn stable_boot_resets_failures() {
+    let mut boot = BootRecord::next(None, THRESHOLD);
+    boot = reboot(boot, Outcome::Running);
+    boot = reboot(boot, Outcome::Running);
+    boot = reboot(boot, Outcome::Stable);
+    assert_eq!(boot.failed_boots, 0);
+
+    // A crash after running for long isn't a boot loop
+    boot = reboot(boot, Outcome::Stable);
+    boot = reboot(boot, Outcome::Running);
+    assert_eq!(boot.failed_boots, 1);
+    assert!(!boot.safe_mode);
+}
+
+#[test]
+fn firmware_switch_resets_failures() {
+    let mut boot = BootRecord::next(None, THRESHOLD);
+    for _ in 0..THRESHOLD {
+        boot = reboot(boot, Outcome::Running);
+    }
+    assert!(boot.safe_mode);
+
+    // An update installed from safe mode gets a clean record
+    boot = reboot(boot, Outcome::Switched);
+    assert_eq!(boot.failed_boots, 0);
+    assert!(!boot.safe_mode);
+
+    boot = reboot(boot, Outcome::Running);
+    assert_eq!(boot.failed_boots, 1);
+}
+
+#[test]
+fn retry_delay_backs_off() {
+    let delay = |failed_boots| {
+        BootRecord {
+            boot_count: 10,
+            failed_boots,
+            safe_mode: true,
+            outcome: Outcome::Running,
+        }
+        .retry_delay_secs(THRESHOLD, 600, 21_600)
+    };
+    assert_eq!(delay(THRESHOLD), 600);
+    assert_eq!(delay(THRESHOLD + 1), 1200);
+    assert_eq!(delay(THRESHOLD + 2), 2400);
+    assert_eq!(delay(THRESHOLD + 6), 21_600);
+    assert_eq!(delay(u32::MAX), 21_600);
+}
+
+#[test]
+fn words_round_trip() {
+    let record = BootRecord {
+        boot_count: 42,
+        failed_boots: 5,
+        safe_mode: true,
+        outcome: Outcome::Planned,
+    };
+    let words = record.to_words();
+    assert_eq!(BootRecord::from_words(&words), Some(record));
+
+    // Garbage left in RTC memory after a power loss
+    assert_eq!(BootRecord::from_words(&[0; WORDS]), None);
+    let mut corrupt = words;
+    corrupt[2] ^= 1;
+    assert_eq!(BootRecord::from_words(&corrupt), None);
+}
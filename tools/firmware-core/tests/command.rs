@@ -0,0 +1,138 @@
+use firmware_core::command::{parse, write_result, Command, Error, LogLevel, Request};
+
+fn command(payload: &str) -> Result<Command, Error> {
+    parse(payload.as_bytes()).command
+}
+
+#[test]
+fn commands_without_value() {
+    assert_eq!(command(r#"{"cmd":"reboot"}"#), Ok(Command::Reboot));
+    assert_eq!(command(r#"{"cmd":"ota_check"}"#), Ok(Command::OtaCheck));
+    assert_eq!(command(r#"{"cmd":"measure_now"}"#), Ok(Command::MeasureNow));
+    assert_eq!(command(r#"{"cmd":"identify"}"#), Ok(Command::Identify));
+}
+
+#[test]
+fn set_interval() {
+    assert_eq!(
+        command(r#"{"cmd":"set_interval","value":300}"#),
+        Ok(Command::SetInterval(300))
+    );
+    // Members can come in any order
+    assert_eq!(
+        command(r#" { "value" : 60 , "cmd" : "set_interval" } "#),
+        Ok(Command::SetInterval(60))
+    );
+
+    for value in ["0", "65536", "-5", "1.5", "\"300\""] {
+        let payload = format!(r#"{{"cmd":"set_interval","value":{value}}}"#);
+        assert_eq!(command(&payload), Err(Error::InvalidValue), "{payload}");
+    }
+    assert_eq!(
+        command(r#"{"cmd":"set_interval"}"#),
+        Err(Error::InvalidValue)
+    );
+}
+
+#[test]
+fn set_log_level() {
+    assert_eq!(
+        command(r#"{"cmd":"set_log_level","value":"debug"}"#),
+        Ok(Command::SetLogLevel(LogLevel::Debug))
+    );
+    assert_eq!(
+        command(r#"{"cmd":"set_log_level","value":"off"}"#),
+        Ok(Command::SetLogLevel(LogLevel::Off))
+    );
+    assert_eq!(
+        command(r#"{"cmd":"set_log_level","value":"verbose"}"#),
+        Err(Error::InvalidValue)
+    );
+}
+
+#[test]
+fn correlation_id() {
+    assert_eq!(
+        parse(br#"{"id":"a1b2","cmd":"reboot","source":"dashboard","dry":false}"#),
+        Request {
+            id: Some("a1b2"),
+            command: Ok(Command::Reboot),
+        }
+    );
+    // Escape sequences are kept, the ID is echoed as received
+    assert_eq!(
+        parse(r#"{"id":"say \"hi\" é","cmd":"unknown"}"#.as_bytes()),
+        Request {
+            id: Some(r#"say \"hi\" é"#),
+            command: Err(Error::UnknownCommand),
+        }
+    );
+
+    let long = format!(r#"{{"id":"{}","cmd":"reboot"}}"#, "x".repeat(65));
+    assert_eq!(parse(long.as_bytes()).command, Err(Error::InvalidId));
+    assert_eq!(
+        command(r#"{"id":42,"cmd":"reboot"}"#),
+        Err(Error::InvalidId)
+    );
+}
+
+#[test]
+fn invalid_payloads() {
+    for payload in [
+        "reboot",
+        "",
+        "{",
+        r#"{"cmd":"reboot"} trailing"#,
+        r#"{"cmd":"reboot",}"#,
+        r#"{"cmd":"reb\qoot"}"#,
+        r#"{"cmd":["reboot"]}"#,
+        "{\"cmd\":\"re\nboot\"}",
+    ] {
+        assert_eq!(
+            parse(payload.as_bytes()),
+            Request {
+                id: None,
+                command: Err(Error::Syntax),
+            },
+            "{payload}"
+        );
+    }
+    assert_eq!(parse(b"{\"cmd\":\"\xff\"}").command, Err(Error::Syntax));
+
+    assert_eq!(command("{}"), Err(Error::MissingCommand));
+    assert_eq!(
+        command(r#"{"cmd":"format_disk"}"#),
+        Err(Error::UnknownCommand)
+    );
+    assert_eq!(command(r#"{"cmd":null}"#), Err(Error::UnknownCommand));
+}
+
+#[test]
+fn result_payload() {
+    let mut out = String::new();
+    write_result(
+        &mut out,
+        Some("a1"),
+        Some(Command::SetInterval(300)),
+        Ok(()),
+    )
+    .unwrap();
+    assert_eq!(out, r#"{"id":"a1","cmd":"set_interval","status":"ok"}"#);
+
+    let mut out = String::new();
+    write_result(&mut out, None, None, Err(Error::Syntax)).unwrap();
+    assert_eq!(out, r#"{"status":"error","error":"syntax"}"#);
+
+    let mut out = String::new();
+    write_result(
+        &mut out,
+        Some(r#"say \"hi\""#),
+        Some(Command::OtaCheck),
+        Err(Error::Unsupported),
+    )
+    .unwrap();
+    assert_eq!(
+        out,
+        r#"{"id":"say \"hi\"","cmd":"ota_check","status":"error","error":"unsupported"}"#
+    );
+}
@@ -0,0 +1,140 @@
+//! Fakes shared by the integration tests, included with `mod common;`
+// Each test crate uses only some of them
+#![allow(dead_code)]
+
+use std::io::{Read as _, Write as _};
+use std::net::TcpStream;
+
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use embedded_storage::nor_flash::{
+    ErrorType as FlashErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+pub const SECTOR_SIZE: usize = 4096;
+
+/// In memory NOR flash with the ESP32 word and sector sizes, counting the
+/// erases of each sector
+pub struct RamFlash {
+    pub data: Vec<u8>,
+    pub erases: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct RamFlashError;
+
+impl NorFlashError for RamFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::Other
+    }
+}
+
+impl RamFlash {
+    pub fn new(sectors: usize) -> Self {
+        Self {
+            data: vec![0xFF; sectors * SECTOR_SIZE],
+            erases: vec![0; sectors],
+        }
+    }
+}
+
+impl FlashErrorType for RamFlash {
+    type Error = RamFlashError;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 4;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        assert_eq!(offset % Self::READ_SIZE, 0, "unaligned read");
+        assert_eq!(bytes.len() % Self::READ_SIZE, 0, "unaligned read length");
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        assert_eq!(from as usize % SECTOR_SIZE, 0, "unaligned erase");
+        assert_eq!(to as usize % SECTOR_SIZE, 0, "unaligned erase");
+        for sector in from as usize / SECTOR_SIZE..to as usize / SECTOR_SIZE {
+            self.erases[sector] += 1;
+        }
+        self.data[from as usize..to as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        assert_eq!(offset % Self::WRITE_SIZE, 0, "unaligned write");
+        assert_eq!(bytes.len() % Self::WRITE_SIZE, 0, "unaligned write length");
+        for (dst, src) in self.data[offset..offset + bytes.len()]
+            .iter_mut()
+            .zip(bytes)
+        {
+            // NOR flash can only clear bits
+            *dst &= *src;
+        }
+        Ok(())
+    }
+}
+
+/// Blocking TCP connection for the async client, which drops after `budget`
+/// received bytes
+pub struct Conn {
+    stream: TcpStream,
+    budget: usize,
+}
+
+impl Conn {
+    /// Connect to a local server on `port`
+    pub fn connect(port: u16) -> Self {
+        Self::with_budget(port, usize::MAX)
+    }
+
+    /// Connect to a local server on `port`, dropping after `budget` bytes
+    pub fn with_budget(port: u16, budget: usize) -> Self {
+        Self {
+            stream: TcpStream::connect(("127.0.0.1", port)).unwrap(),
+            budget,
+        }
+    }
+}
+
+impl ErrorType for Conn {
+    type Error = ErrorKind;
+}
+
+impl Read for Conn {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        if self.budget == 0 {
+            return Err(ErrorKind::ConnectionReset);
+        }
+        let len = buf.len().min(self.budget);
+        let n = self
+            .stream
+            .read(&mut buf[..len])
+            .map_err(|_| ErrorKind::ConnectionReset)?;
+        self.budget -= n;
+        Ok(n)
+    }
+}
+
+impl Write for Conn {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.stream
+            .write(buf)
+            .map_err(|_| ErrorKind::ConnectionReset)
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+mod common;
+
+use firmware_core::config_store::{self, Error, Key, Record, Value, SLOT_SIZE, STORE_SIZE};
+use firmware_core::topic::device_topic;
+
+use common::{RamFlash, SECTOR_SIZE};
+
+fn load(flash: &mut RamFlash) -> Option<Vec<u8>> {
+    let slot = config_store::find(flash).unwrap()?;
+    let mut buf = vec![0u8; slot.payload_len()];
+    config_store::read(flash, &slot, &mut buf).unwrap();
+    Some(buf)
+}
+
+fn save(flash: &mut RamFlash, record: &Record) {
+    let mut scratch = vec![0u8; SLOT_SIZE];
+    config_store::save(flash, record, &mut scratch).unwrap();
+}
+
+fn get<'a>(record: &Record<'a>, key: Key) -> Option<Value<'a>> {
+    record
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, value)| *value)
+}
+
+fn sample() -> Record<'static> {
+    let mut record = Record::new();
+    record.set(Key::WifiSsid, Value::Str("home")).unwrap();
+    record.set(Key::MqttPort, Value::U16(8883)).unwrap();
+    record
+        .set(Key::Location, Value::Str("salle à manger"))
+        .unwrap();
+    record
+}
+
+#[test]
+fn empty_store() {
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    assert_eq!(config_store::find(&mut flash).unwrap(), None);
+}
+
+#[test]
+fn encode_reference() {
+    let mut record = Record::new();
+    record.set(Key::WifiSsid, Value::Str("ab")).unwrap();
+    record.set(Key::MqttPort, Value::U16(1883)).unwrap();
+
+    let mut out = [0u8; 32];
+    let len = record.encode(&mut out).unwrap();
+    assert_eq!(&out[..len], &[16, 2, 0, b'a', b'b', 6, 2, 0, 0x5B, 0x07]);
+}
+
+#[test]
+fn save_and_load() {
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    let record = sample();
+    save(&mut flash, &record);
+
+    let payload = load(&mut flash).unwrap();
+    let loaded = Record::decode(&payload).unwrap();
+    assert_eq!(loaded, record);
+    assert_eq!(get(&loaded, Key::WifiSsid), Some(Value::Str("home")));
+    assert_eq!(get(&loaded, Key::MqttPort), Some(Value::U16(8883)));
+    assert_eq!(get(&loaded, Key::MqttHostname), None);
+}
+
+#[test]
+fn latest_save_wins() {
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    let mut record = sample();
+    for ssid in ["one", "two", "three"] {
+        record.set(Key::WifiSsid, Value::Str(ssid)).unwrap();
+        save(&mut flash, &record);
+    }
+
+    let payload = load(&mut flash).unwrap();
+    let loaded = Record::decode(&payload).unwrap();
+    assert_eq!(get(&loaded, Key::WifiSsid), Some(Value::Str("three")));
+}
+
+#[test]
+fn corrupt_slot_falls_back_to_previous() {
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    let mut record = sample();
+    save(&mut flash, &record);
+    record.set(Key::WifiSsid, Value::Str("newer")).unwrap();
+    save(&mut flash, &record);
+
+    // Flip a bit in the payload of the newest record (second slot)
+    flash.data[SLOT_SIZE + 24] ^= 0x01;
+
+    let payload = load(&mut flash).unwrap();
+    let loaded = Record::decode(&payload).unwrap();
+    assert_eq!(get(&loaded, Key::WifiSsid), Some(Value::Str("home")));
+}
+
+#[test]
+fn all_slots_corrupt() {
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    save(&mut flash, &sample());
+    flash.data[30] ^= 0x80;
+    assert_eq!(config_store::find(&mut flash).unwrap(), None);
+}
+
+#[test]
+fn unknown_keys_are_skipped() {
+    let payload = [200, 3, 0, 1, 2, 3, 16, 2, 0, b'o', b'k'];
+    let record = Record::decode(&payload).unwrap();
+    assert_eq!(record.iter().count(), 1);
+    assert_eq!(get(&record, Key::WifiSsid), Some(Value::Str("ok")));
+}
+
+#[test]
+fn invalid_payloads() {
+    // Entry longer than the payload
+    assert_eq!(Record::decode(&[16, 5, 0, b'a']), Err(Error::Truncated));
+    // Number with the wrong size
+    assert_eq!(
+        Record::decode(&[6, 1, 0, 1]),
+        Err(Error::InvalidValue(Key::MqttPort))
+    );
+    // Invalid UTF-8
+    assert_eq!(
+        Record::decode(&[16, 1, 0, 0xFF]),
+        Err(Error::InvalidValue(Key::WifiSsid))
+    );
+}
+
+#[test]
+fn value_type_is_checked() {
+    let mut record = Record::new();
+    assert_eq!(
+        record.set(Key::MqttPort, Value::Str("1883")),
+        Err(Error::InvalidValue(Key::MqttPort))
+    );
+    assert_eq!(
+        record.set(Key::WifiSsid, Value::U16(1)),
+        Err(Error::InvalidValue(Key::WifiSsid))
+    );
+}
+
+#[test]
+fn set_replaces() {
+    let mut record = sample();
+    record.set(Key::WifiSsid, Value::Str("other")).unwrap();
+    assert_eq!(record.iter().count(), 3);
+    assert_eq!(get(&record, Key::WifiSsid), Some(Value::Str("other")));
+}
+
+#[test]
+fn record_too_large() {
+    let big = "x".repeat(SLOT_SIZE);
+    let mut record = Record::new();
+    record.set(Key::TlsCa, Value::Str(&big)).unwrap();
+
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    let mut scratch = vec![0u8; SLOT_SIZE];
+    assert_eq!(
+        config_store::save(&mut flash, &record, &mut scratch),
+        Err(Error::TooLarge)
+    );
+}
+
+#[test]
+fn topics_follow_device_id() {
+    // Compiled defaults shared by the fleet image
+    let (default_topic, default_device_id) = ("sensors", "esp32-default");
+    let mut flash = RamFlash::new(STORE_SIZE / SECTOR_SIZE);
+    let mut record = Record::new();
+    record
+        .set(Key::DeviceId, Value::Str("esp32-kitchen"))
+        .unwrap();
+    save(&mut flash, &record);
+
+    let buf = load(&mut flash).unwrap();
+    let record = Record::decode(&buf).unwrap();
+    let str_value = |key| match get(&record, key) {
+        Some(Value::Str(s)) => Some(s),
+        _ => None,
+    };
+    let device_id = str_value(Key::DeviceId).unwrap_or(default_device_id);
+    let mqtt_topic = str_value(Key::MqttTopic).unwrap_or(default_topic);
+    let topic = |explicit, leaf| device_topic::<64>(explicit, mqtt_topic, device_id, leaf);
+    assert_eq!(
+        topic(str_value(Key::MqttCommandTopic), "cmd").as_deref(),
+        Some("sensors/esp32-kitchen/cmd")
+    );
+    assert_eq!(
+        topic(str_value(Key::MqttAvailabilityTopic), "status").as_deref(),
+        Some("sensors/esp32-kitchen/status")
+    );
+    assert_eq!(
+        topic(str_value(Key::MqttDiagnosticsTopic), "diagnostics").as_deref(),
+        Some("sensors/esp32-kitchen/diagnostics")
+    );
+}
@@ -0,0 +1,29 @@
+use firmware_core::crc32::Crc32;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finalize()
+}
+
+#[test]
+fn known_vectors() {
+    // CRC-32/ISO-HDLC check value
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"a"), 0xE8B7_BE43);
+    assert_eq!(
+        crc32(b"The quick brown fox jumps over the lazy dog"),
+        0x414F_A339
+    );
+}
+
+#[test]
+fn incremental() {
+    let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    let mut crc = Crc32::new();
+    for chunk in data.chunks(333) {
+        crc.update(chunk);
+    }
+    assert_eq!(crc.finalize(), crc32(&data));
+}
@@ -0,0 +1,103 @@
+use firmware_core::diagnostics_format::{
+    write_diagnostics, write_fault, write_rollback, AppSlot, Diagnostics, ResetReason, WifiLink,
+};
+use firmware_core::serializer::Timestamp;
+
+#[test]
+fn diagnostics_json() {
+    let diagnostics = Diagnostics {
+        firmware: "0.1.3",
+        uptime_secs: 3600,
+        reset_reason: ResetReason::Watchdog,
+        boot_count: 4,
+        ota_slot: Some(AppSlot::Ota(1)),
+        wifi: Some(WifiLink {
+            rssi: -67,
+            channel: 6,
+            bssid: [0xaa, 0xbb, 0xcc, 0x0d, 0x0e, 0x0f],
+        }),
+        ip: Some([192, 168, 1, 20]),
+        heap_free: 41234,
+        heap_min_free: 30512,
+        mqtt_reconnects: 2,
+        tls_handshake_ms: Some(4210),
+        sensor_failures: &[("bme280", 0), ("scd30", 3)],
+    };
+    let mut out = String::new();
+    write_diagnostics(
+        &mut out,
+        &diagnostics,
+        Timestamp::Synced(1_700_000_000_123_456),
+    )
+    .unwrap();
+    assert_eq!(
+        out,
+        concat!(
+            r#"{"firmware":"0.1.3","uptime":3600,"reset_reason":"watchdog","boot_count":4,"#,
+            r#""ota_slot":"ota_1","rssi":-67,"channel":6,"bssid":"aa:bb:cc:0d:0e:0f","#,
+            r#""ip":"192.168.1.20","heap_free":41234,"heap_min_free":30512,"#,
+            r#""mqtt_reconnects":2,"tls_handshake_ms":4210,"#,
+            r#""sensor_failures":{"bme280":0,"scd30":3},"ts":1700000000123}"#
+        )
+    );
+}
+
+#[test]
+fn diagnostics_unknown_values() {
+    let diagnostics = Diagnostics {
+        firmware: "0.1.3",
+        uptime_secs: 12,
+        reset_reason: ResetReason::PowerOn,
+        boot_count: 1,
+        ota_slot: None,
+        wifi: None,
+        ip: None,
+        heap_free: 1024,
+        heap_min_free: 512,
+        mqtt_reconnects: 0,
+        tls_handshake_ms: None,
+        sensor_failures: &[],
+    };
+    let mut out = String::new();
+    write_diagnostics(&mut out, &diagnostics, Timestamp::Unsynced).unwrap();
+    assert_eq!(
+        out,
+        concat!(
+            r#"{"firmware":"0.1.3","uptime":12,"reset_reason":"power_on","boot_count":1,"#,
+            r#""ota_slot":null,"rssi":null,"channel":null,"bssid":null,"ip":null,"#,
+            r#""heap_free":1024,"heap_min_free":512,"mqtt_reconnects":0,"#,
+            r#""tls_handshake_ms":null,"sensor_failures":{},"ts":null}"#
+        )
+    );
+}
+
+#[test]
+fn fault_json() {
+    let mut out = String::new();
+    write_fault(
+        &mut out,
+        4,
+        ResetReason::Software,
+        1200,
+        &["scd30", "sds011"],
+    )
+    .unwrap();
+    assert_eq!(
+        out,
+        r#"{"safe_mode":true,"failed_boots":4,"reset_reason":"software","retry_in":1200,"failed_sensors":["scd30","sds011"]}"#
+    );
+
+    let mut out = String::new();
+    write_fault(&mut out, 3, ResetReason::Watchdog, 600, &[]).unwrap();
+    assert_eq!(
+        out,
+        r#"{"safe_mode":true,"failed_boots":3,"reset_reason":"watchdog","retry_in":600,"failed_sensors":[]}"#
+    );
+}
+
+#[test]
+fn rollback_json() {
+    let mut out = String::new();
+    write_rollback(&mut out, "0.1.4-rc.1", "0.1.3", "mqtt").unwrap();
+    assert_eq!(out, r#"{"from":"0.1.4-rc.1","to":"0.1.3","reason":"mqtt"}"#);
+}
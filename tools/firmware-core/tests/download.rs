@@ -0,0 +1,332 @@
+mod common;
+
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use firmware_core::crc32::Crc32;
+use firmware_core::download::{download, read_back, Error, Progress, WORDS};
+use firmware_core::http::{self, Client};
+
+use common::{RamFlash, SECTOR_SIZE};
+
+const SECTORS: usize = 6;
+
+/// Xorshift generator, reproducible "random" offsets and firmware bytes
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, max: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % max as u64) as usize
+    }
+
+    fn firmware(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next(256) as u8).collect()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Partial responses to range requests
+    Range,
+    /// Always the whole firmware
+    IgnoreRange,
+    /// Partial responses starting 4 bytes before the requested range
+    WrongRange,
+    NotFound,
+}
+
+/// Stand-in for the OTA server, serving `firmware` on a connection which
+/// drops after `cut` response bytes
+struct FlakyServer<'a> {
+    firmware: &'a [u8],
+    mode: Mode,
+    cut: usize,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    sent: usize,
+    /// First byte requested by each request
+    starts: Vec<usize>,
+}
+
+impl<'a> FlakyServer<'a> {
+    fn new(firmware: &'a [u8], mode: Mode, cut: usize) -> Self {
+        Self {
+            firmware,
+            mode,
+            cut,
+            request: Vec::new(),
+            response: Vec::new(),
+            sent: 0,
+            starts: Vec::new(),
+        }
+    }
+
+    fn respond(&mut self) {
+        let request = String::from_utf8(std::mem::take(&mut self.request)).unwrap();
+        assert!(request.starts_with("GET /firmware?device=esp32 HTTP/1.1\r\n"));
+        let start = request
+            .lines()
+            .find_map(|line| line.strip_prefix("Range: bytes="))
+            .map_or(0, |range| range.trim_end_matches('-').parse().unwrap());
+        self.starts.push(start);
+
+        let len = self.firmware.len();
+        let (head, body) = match self.mode {
+            Mode::Range | Mode::WrongRange if start > 0 => {
+                let first = match self.mode {
+                    Mode::WrongRange => start - 4,
+                    _ => start,
+                };
+                let head = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {first}-{}/{len}\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    len - 1,
+                    len - first
+                );
+                (head, &self.firmware[first..])
+            }
+            Mode::NotFound => (
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+                &[][..],
+            ),
+            _ => (
+                format!("HTTP/1.1 200 OK\r\nContent-Length: {len}\r\n\r\n"),
+                self.firmware,
+            ),
+        };
+        self.response.extend_from_slice(head.as_bytes());
+        self.response.extend_from_slice(body);
+    }
+}
+
+impl ErrorType for FlakyServer<'_> {
+    type Error = ErrorKind;
+}
+
+impl Read for FlakyServer<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        if self.sent == self.cut {
+            return Err(ErrorKind::ConnectionReset);
+        }
+        // Small reads, as from TLS records
+        let n = buf
+            .len()
+            .min(700)
+            .min(self.cut - self.sent)
+            .min(self.response.len() - self.sent);
+        buf[..n].copy_from_slice(&self.response[self.sent..self.sent + n]);
+        self.sent += n;
+        Ok(n)
+    }
+}
+
+impl Write for FlakyServer<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.request.extend_from_slice(buf);
+        if self.request.ends_with(b"\r\n\r\n") {
+            self.respond();
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+}
+
+/// Download attempt on a new connection, which drops after `cut` bytes.
+/// Returns the first byte requested, if any.
+fn attempt(
+    firmware: &[u8],
+    mode: Mode,
+    cut: usize,
+    flash: &mut RamFlash,
+    progress: &mut Progress,
+) -> (Result<(), Error>, Option<usize>) {
+    let mut server = FlakyServer::new(firmware, mode, cut);
+    let mut head = [0; 256];
+    let mut client = Client::new(&mut server, "ota.example.com", 443, &mut head);
+    let mut buf = [0; 1024];
+    let result = block_on(download(
+        &mut client,
+        "/firmware?device=esp32",
+        &[],
+        flash,
+        progress,
+        &mut buf,
+    ));
+    (result, server.starts.first().copied())
+}
+
+/// Download `firmware` until it is complete, over connections dropping at
+/// random offsets, the progress being kept as across reboots. Returns the
+/// number of attempts.
+fn download_flaky(firmware: &[u8], mode: Mode, rng: &mut Rng, flash: &mut RamFlash) -> usize {
+    let mut rtc = Progress::new("1.2.3", 0xCAFE, firmware.len() as u32).to_words();
+    for attempts in 1..1000 {
+        let mut progress = Progress::from_words(&rtc).unwrap();
+        let written = progress.written();
+        let cut = rng.next(firmware.len() + 200);
+        let (result, start) = attempt(firmware, mode, cut, flash, &mut progress);
+
+        // Resumes from the last byte written
+        assert_eq!(start, Some(written as usize));
+        assert!(progress.written() >= written);
+        rtc = progress.to_words();
+        match result {
+            Ok(()) => {
+                assert!(progress.is_complete());
+                return attempts;
+            }
+            Err(Error::Http(http::Error::Io(ErrorKind::ConnectionReset))) => {}
+            Err(e) => panic!("unexpected error {e:?}"),
+        }
+    }
+    panic!("download never completed");
+}
+
+#[test]
+fn resumes_after_drops() {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+    let mut attempts = 0;
+    for len in [
+        4 * SECTOR_SIZE + 1234,
+        3 * SECTOR_SIZE,
+        1,
+        4 * SECTOR_SIZE - 3,
+    ] {
+        for mode in [Mode::Range, Mode::IgnoreRange] {
+            let firmware = rng.firmware(len);
+            let mut flash = RamFlash::new(SECTORS);
+            attempts += download_flaky(&firmware, mode, &mut rng, &mut flash);
+
+            assert_eq!(&flash.data[..len], &firmware[..]);
+            // Padding and the rest of the partition are left erased
+            assert!(flash.data[len..].iter().all(|&b| b == 0xFF));
+            // Sectors already written aren't erased again
+            let sectors = len.div_ceil(SECTOR_SIZE);
+            assert!(flash.erases[..sectors].iter().all(|&n| n == 1));
+            assert!(flash.erases[sectors..].iter().all(|&n| n == 0));
+        }
+    }
+    // Most downloads were interrupted
+    assert!(attempts > 20, "{attempts} attempts");
+}
+
+#[test]
+fn lagging_progress() {
+    let mut rng = Rng(42);
+    let firmware = rng.firmware(2 * SECTOR_SIZE + 10);
+    let mut flash = RamFlash::new(SECTORS);
+    let mut progress = Progress::new("1.2.3", 0xCAFE, firmware.len() as u32);
+    let rtc = progress.to_words();
+    let (result, _) = attempt(&firmware, Mode::Range, 6000, &mut flash, &mut progress);
+    assert!(result.is_err());
+    assert!(progress.written() > 0);
+
+    // Crashed before the progress was kept, the same bytes are written again
+    let mut progress = Progress::from_words(&rtc).unwrap();
+    let (result, start) = attempt(
+        &firmware,
+        Mode::Range,
+        usize::MAX,
+        &mut flash,
+        &mut progress,
+    );
+    assert_eq!(result, Ok(()));
+    assert_eq!(start, Some(0));
+    assert_eq!(&flash.data[..firmware.len()], &firmware[..]);
+}
+
+#[test]
+fn rejected_responses() {
+    let firmware = Rng(7).firmware(SECTOR_SIZE + 100);
+    let mut flash = RamFlash::new(SECTORS);
+    let mut progress = Progress::new("1.2.3", 0xCAFE, firmware.len() as u32);
+    let (result, _) = attempt(&firmware, Mode::Range, 2000, &mut flash, &mut progress);
+    assert!(result.is_err());
+    let written = progress.written();
+
+    let (result, _) = attempt(&firmware, Mode::WrongRange, 5000, &mut flash, &mut progress);
+    assert_eq!(result, Err(Error::Range));
+    let (result, _) = attempt(&firmware, Mode::NotFound, 5000, &mut flash, &mut progress);
+    assert_eq!(result, Err(Error::Status(404)));
+    assert_eq!(progress.written(), written);
+
+    // Larger than the partition
+    let mut progress = Progress::new("1.2.3", 0xCAFE, (SECTORS * SECTOR_SIZE + 1) as u32);
+    let (result, start) = attempt(&firmware, Mode::Range, 5000, &mut flash, &mut progress);
+    assert_eq!(result, Err(Error::TooLarge));
+    assert_eq!(start, None);
+}
+
+#[test]
+fn read_back_checks_crc32() {
+    let firmware = Rng(3).firmware(3 * SECTOR_SIZE + 5);
+    let mut crc = Crc32::new();
+    crc.update(&firmware);
+    let crc32 = crc.finalize();
+    let mut flash = RamFlash::new(SECTORS);
+    let mut progress = Progress::new("1.2.3", crc32, firmware.len() as u32);
+    let (result, _) = attempt(
+        &firmware,
+        Mode::Range,
+        usize::MAX,
+        &mut flash,
+        &mut progress,
+    );
+    assert_eq!(result, Ok(()));
+
+    let mut buf = [0; 1024];
+    let mut read = Vec::new();
+    let result = block_on(read_back(
+        &mut flash,
+        firmware.len(),
+        Some(crc32),
+        &mut buf,
+        |chunk| read.extend_from_slice(chunk),
+    ));
+    assert_eq!(result, Ok(()));
+    assert_eq!(read, firmware);
+
+    // A single bit flipped in flash
+    flash.data[SECTOR_SIZE + 17] ^= 0x10;
+    let result = block_on(read_back(
+        &mut flash,
+        firmware.len(),
+        Some(crc32),
+        &mut buf,
+        |_| {},
+    ));
+    assert_eq!(result, Err(Error::Checksum));
+    // Checked by the caller, e.g. against a SHA-256
+    let result = block_on(read_back(
+        &mut flash,
+        firmware.len(),
+        None,
+        &mut buf,
+        |_| {},
+    ));
+    assert_eq!(result, Ok(()));
+}
+
+#[test]
+fn progress_words() {
+    let progress = Progress::new("1.4.0-rc.1", 0xDEAD_BEEF, 1_835_008);
+    let decoded = Progress::from_words(&progress.to_words()).unwrap();
+    assert_eq!(decoded, progress);
+    assert_eq!(decoded.version(), "1.4.0-rc.1");
+    assert_eq!(decoded.written(), 0);
+    assert!(decoded.is_for("1.4.0-rc.1", 0xDEAD_BEEF, 1_835_008));
+    assert!(!decoded.is_for("1.4.0", 0xDEAD_BEEF, 1_835_008));
+    assert!(!decoded.is_for("1.4.0-rc.1", 0xDEAD_BEEF, 1_835_009));
+    assert!(!decoded.is_for("1.4.0-rc.1", 0xDEAD_BEEE, 1_835_008));
+
+    // Garbage left in RTC memory after a power loss
+    assert_eq!(Progress::from_words(&[0; WORDS]), None);
+    let mut words = progress.to_words();
+    words[3] ^= 1;
+    assert_eq!(Progress::from_words(&words), None);
+}
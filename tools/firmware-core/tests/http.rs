@@ -0,0 +1,309 @@
+use std::convert::Infallible;
+
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorType, Read, Write};
+use firmware_core::http::{Client, ContentRange, Error, Response};
+
+/// Connection replaying a canned response, `split` bytes at most per read
+struct Canned {
+    response: Vec<u8>,
+    pos: usize,
+    split: usize,
+    sent: Vec<u8>,
+}
+
+impl Canned {
+    fn new(response: &[u8], split: usize) -> Self {
+        Self {
+            response: response.to_vec(),
+            pos: 0,
+            split,
+            sent: Vec::new(),
+        }
+    }
+}
+
+impl ErrorType for Canned {
+    type Error = Infallible;
+}
+
+impl Read for Canned {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let n = buf
+            .len()
+            .min(self.split)
+            .min(self.response.len() - self.pos);
+        buf[..n].copy_from_slice(&self.response[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Canned {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        self.sent.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Body of `client`'s response, read `chunk` bytes at a time
+fn read_body(client: &mut Client<'_, &mut Canned>, chunk: usize) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    let mut out = vec![0; chunk];
+    loop {
+        match block_on(client.read_body(&mut out))? {
+            0 => return Ok(body),
+            n => body.extend_from_slice(&out[..n]),
+        }
+    }
+}
+
+/// Response and body of a GET request for `/firmware`, for every read size
+fn get_all_splits(response: &[u8]) -> Vec<Result<(Response, Vec<u8>), Error>> {
+    (1..=response.len())
+        .map(|split| {
+            let mut conn = Canned::new(response, split);
+            let mut buf = [0; 256];
+            let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+            let response = block_on(client.get("/firmware", &[]))?;
+            let body = read_body(&mut client, 3)?;
+            Ok((response, body))
+        })
+        .collect()
+}
+
+#[test]
+fn request() {
+    let mut conn = Canned::new(b"HTTP/1.1 204 No Content\r\n\r\n", 64);
+    let mut buf = [0; 256];
+    let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+    block_on(client.get("/version?device=esp32", &[("Range", "bytes=10-")])).unwrap();
+    assert_eq!(
+        String::from_utf8(conn.sent).unwrap(),
+        "GET /version?device=esp32 HTTP/1.1\r\nHost: ota.example.com\r\nRange: bytes=10-\r\n\r\n"
+    );
+}
+
+#[test]
+fn content_length() {
+    let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+        content-length: 11\r\n\r\nhello world";
+    for result in get_all_splits(response) {
+        let (response, body) = result.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.content_length, Some(11));
+        assert_eq!(body, b"hello world");
+    }
+}
+
+#[test]
+fn chunked() {
+    let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5\r\nhello\r\n1;ext=1\r\n \r\nA \r\nchunked!!!\r\n0\r\nExpires: never\r\n\r\n";
+    for result in get_all_splits(response) {
+        let (response, body) = result.unwrap();
+        assert_eq!(response.content_length, None);
+        assert_eq!(body, b"hello chunked!!!");
+    }
+}
+
+#[test]
+fn until_close() {
+    let response = b"HTTP/1.0 200 OK\r\n\r\nuntil the end";
+    for result in get_all_splits(response) {
+        let (response, body) = result.unwrap();
+        assert_eq!(response.content_length, None);
+        assert_eq!(body, b"until the end");
+    }
+}
+
+#[test]
+fn error_status() {
+    let response = b"HTTP/1.1 404 Not Found\r\nContent-Length: 9\r\n\r\nnot found";
+    for result in get_all_splits(response) {
+        let (response, _) = result.unwrap();
+        assert_eq!(response.status, 404);
+        assert!(!response.is_success());
+        assert_eq!(response.www_authenticate, None);
+    }
+
+    let response = b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\
+        WWW-Authenticate: Bearer realm=\"https://registry/token\",service=\"registry\"\r\n\r\n";
+    for result in get_all_splits(response) {
+        let (response, _) = result.unwrap();
+        assert_eq!(response.status, 401);
+        assert_eq!(
+            response.www_authenticate.as_deref(),
+            Some(r#"Bearer realm="https://registry/token",service="registry""#)
+        );
+    }
+}
+
+#[test]
+fn content_range() {
+    let response = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 6-10/11\r\n\
+        Content-Length: 5\r\n\r\nworld";
+    for result in get_all_splits(response) {
+        let (response, body) = result.unwrap();
+        assert_eq!(response.status, 206);
+        assert_eq!(
+            response.content_range,
+            Some(ContentRange {
+                first: 6,
+                last: 10,
+                complete_length: Some(11)
+            })
+        );
+        assert_eq!(body, b"world");
+    }
+
+    for (value, expected) in [
+        ("bytes 0-0/*", Some((0, 0, None))),
+        ("bytes 10-5/20", None),
+        ("bytes 5-20/20", None),
+        ("bytes */20", None),
+        ("pages 0-1/2", None),
+    ] {
+        let response = format!("HTTP/1.1 206 Partial Content\r\nContent-Range: {value}\r\n\r\n");
+        let mut conn = Canned::new(response.as_bytes(), 64);
+        let mut buf = [0; 256];
+        let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+        let response = block_on(client.get("/firmware", &[])).unwrap();
+        let expected = expected.map(|(first, last, complete_length)| ContentRange {
+            first,
+            last,
+            complete_length,
+        });
+        assert_eq!(response.content_range, expected, "{value}");
+    }
+}
+
+#[test]
+fn informational_skipped() {
+    let response = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+    for result in get_all_splits(response) {
+        let (response, body) = result.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(body, b"ok");
+    }
+}
+
+#[test]
+fn truncated() {
+    let length = b"HTTP/1.1 200 OK\r\nContent-Length: 20\r\n\r\nshort";
+    let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel";
+    let head = b"HTTP/1.1 200 OK\r\nContent-Len";
+    for response in [&length[..], &chunked[..], &head[..]] {
+        for result in get_all_splits(response) {
+            assert_eq!(result.unwrap_err(), Error::UnexpectedEof);
+        }
+    }
+}
+
+#[test]
+fn malformed() {
+    for response in [
+        &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n"[..],
+        &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabcd\r\n"[..],
+        &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nab"[..],
+        &b"<html>\r\n\r\n"[..],
+        &b"HTTP/1.1 2000 OK\r\n\r\n"[..],
+        &b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n"[..],
+    ] {
+        for result in get_all_splits(response) {
+            assert_eq!(result.unwrap_err(), Error::Malformed);
+        }
+    }
+}
+
+#[test]
+fn head_too_large() {
+    let response = format!("HTTP/1.1 200 OK\r\nX-Padding: {}\r\n\r\n", "a".repeat(300));
+    for result in get_all_splits(response.as_bytes()) {
+        assert_eq!(result.unwrap_err(), Error::HeadTooLarge);
+    }
+}
+
+#[test]
+fn keep_alive() {
+    let responses = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none\
+        HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n3\r\ntwo\r\n0\r\n\r\n";
+    for split in 1..=responses.len() {
+        let mut conn = Canned::new(responses, split);
+        let mut buf = [0; 256];
+        let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+
+        // The first body is left unread, it is skipped
+        block_on(client.get("/version", &[])).unwrap();
+        block_on(client.get("/firmware", &[])).unwrap();
+        assert_eq!(read_body(&mut client, 2).unwrap(), b"two");
+
+        assert_eq!(block_on(client.get("/again", &[])), Err(Error::Closed));
+    }
+}
+
+#[test]
+fn redirect_same_host() {
+    let responses =
+        b"HTTP/1.1 302 Found\r\nLocation: /v2/firmware\r\nContent-Length: 5\r\n\r\nmoved\
+        HTTP/1.1 301 Moved Permanently\r\nLocation: https://OTA.example.com/v3/firmware#top\r\n\
+        Content-Length: 0\r\n\r\n\
+        HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nfirmware";
+    for split in 1..=responses.len() {
+        let mut conn = Canned::new(responses, split);
+        let mut buf = [0; 256];
+        let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+        let response = block_on(client.get("/firmware", &[("Range", "bytes=0-")])).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(read_body(&mut client, 4).unwrap(), b"firmware");
+
+        let sent = String::from_utf8(conn.sent).unwrap();
+        let requests: Vec<_> = sent
+            .split("\r\n\r\n")
+            .filter(|r| !r.is_empty())
+            .map(|r| r.lines().next().unwrap())
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                "GET /firmware HTTP/1.1",
+                "GET /v2/firmware HTTP/1.1",
+                "GET /v3/firmware HTTP/1.1"
+            ]
+        );
+        // Extra headers are sent again
+        assert_eq!(sent.matches("Range: bytes=0-").count(), 3);
+    }
+}
+
+#[test]
+fn redirect_rejected() {
+    for location in [
+        "https://evil.example.com/firmware",
+        "https://ota.example.com:8443/firmware",
+        "http://ota.example.com/firmware",
+        "//ota.example.com/firmware",
+        "firmware",
+    ] {
+        let response = format!("HTTP/1.1 307 Temporary Redirect\r\nLocation: {location}\r\n\r\n");
+        let mut conn = Canned::new(response.as_bytes(), 64);
+        let mut buf = [0; 256];
+        let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+        assert_eq!(
+            block_on(client.get("/firmware", &[])),
+            Err(Error::Redirect),
+            "{location}"
+        );
+    }
+
+    // Redirect loop
+    let redirect = b"HTTP/1.1 302 Found\r\nLocation: /firmware\r\nContent-Length: 0\r\n\r\n";
+    let mut conn = Canned::new(&redirect.repeat(5), 64);
+    let mut buf = [0; 256];
+    let mut client = Client::new(&mut conn, "ota.example.com", 443, &mut buf);
+    assert_eq!(block_on(client.get("/firmware", &[])), Err(Error::Redirect));
+}
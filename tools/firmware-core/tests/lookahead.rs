@@ -0,0 +1,87 @@
+use std::convert::Infallible;
+
+use embassy_futures::select::{select, Either};
+use embassy_futures::{block_on, yield_now};
+use embedded_io_async::{ErrorType, Read, Write};
+use firmware_core::lookahead::Lookahead;
+
+/// Connection receiving `incoming` in chunks of at most `chunk` bytes, each
+/// one after the reader yielded once
+struct Conn {
+    incoming: Vec<u8>,
+    chunk: usize,
+}
+
+impl ErrorType for Conn {
+    type Error = Infallible;
+}
+
+impl Read for Conn {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        yield_now().await;
+        let n = buf.len().min(self.chunk).min(self.incoming.len());
+        buf[..n].copy_from_slice(&self.incoming[..n]);
+        self.incoming.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Conn {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+fn lookahead(incoming: &[u8], chunk: usize) -> Lookahead<Conn> {
+    Lookahead::new(Conn {
+        incoming: incoming.to_vec(),
+        chunk,
+    })
+}
+
+#[test]
+fn peeked_byte_is_read_first() {
+    let mut conn = lookahead(b"\x30\x05hello", 4);
+    block_on(async {
+        conn.wait_readable().await.unwrap();
+        // Waiting again keeps the same byte
+        conn.wait_readable().await.unwrap();
+
+        let mut packet = [0u8; 7];
+        conn.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet, b"\x30\x05hello");
+
+        // Writes are passed through
+        conn.write_all(b"\xc0\x00").await.unwrap();
+    });
+}
+
+#[test]
+fn cancelled_wait_loses_nothing() {
+    let mut conn = lookahead(b"\xd0\x00", 1);
+    block_on(async {
+        // The connection yields before receiving, the other branch wins
+        match select(conn.wait_readable(), async {}).await {
+            Either::First(_) => panic!("nothing received yet"),
+            Either::Second(()) => {}
+        }
+        conn.wait_readable().await.unwrap();
+
+        let mut packet = [0u8; 2];
+        conn.read_exact(&mut packet).await.unwrap();
+        assert_eq!(&packet, b"\xd0\x00");
+    });
+}
+
+#[test]
+fn closed_connection_is_readable() {
+    let mut conn = lookahead(b"", 4);
+    block_on(async {
+        conn.wait_readable().await.unwrap();
+        assert_eq!(conn.read(&mut [0u8; 4]).await.unwrap(), 0);
+    });
+}
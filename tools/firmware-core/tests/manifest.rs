@@ -0,0 +1,257 @@
+use firmware_core::json::unescape;
+use firmware_core::manifest::{parse, rollout_bucket, Checksum, Error};
+use firmware_core::semver::VersionReq;
+use heapless::String;
+
+const SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+fn sha256_bytes() -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&SHA256[2 * i..2 * i + 2], 16).unwrap();
+    }
+    bytes
+}
+
+#[test]
+fn json_manifest() {
+    let body = format!(
+        r#"{{
+            "manifest_version": 1,
+            "version": "0.1.4",
+            "size": 1835008,
+            "sha256": "{SHA256}",
+            "signature": "U+quTw2A8KG5X0w\/nFxW==",
+            "min_current_version": "0.1.0",
+            "current_version_requirement": ">=0.1.3, <0.3.0",
+            "allow_downgrade": true,
+            "channel": "beta",
+            "release_notes_url": "https:\/\/example.com\/releases\/0.1.4",
+            "download_url": "/firmware/esp32-outdoor/0.1.4.bin",
+            "published_at": "2026-10-01T12:00:00Z"
+        }}"#
+    );
+    let manifest = parse(body.as_bytes()).unwrap();
+    assert_eq!(manifest.version, "0.1.4");
+    assert_eq!(manifest.size, 1_835_008);
+    assert_eq!(manifest.checksum, Checksum::Sha256(sha256_bytes()));
+    assert_eq!(manifest.checksum.fingerprint(), 0x9f86_d081);
+    assert_eq!(manifest.signature, "U+quTw2A8KG5X0w/nFxW==");
+    assert_eq!(manifest.min_current_version.as_deref(), Some("0.1.0"));
+    assert_eq!(
+        manifest.current_version_requirement,
+        VersionReq::parse(">=0.1.3, <0.3.0")
+    );
+    assert!(manifest.allow_downgrade);
+    assert_eq!(manifest.channel.as_deref(), Some("beta"));
+    assert_eq!(manifest.rollout_percentage, 100);
+    assert_eq!(
+        manifest.release_notes_url.as_deref(),
+        Some("https://example.com/releases/0.1.4")
+    );
+    assert_eq!(
+        manifest.download_path("ota.example.com", 443),
+        Ok(Some("/firmware/esp32-outdoor/0.1.4.bin"))
+    );
+
+    // Optional members can be missing or null
+    let body = format!(
+        r#"{{"manifest_version":1,"version":"0.1.4","size":10,"sha256":"{SHA256}",
+            "signature":"sig","channel":null,"allow_downgrade":false}}"#
+    );
+    let manifest = parse(body.as_bytes()).unwrap();
+    assert_eq!(manifest.min_current_version, None);
+    assert_eq!(manifest.current_version_requirement, None);
+    assert!(!manifest.allow_downgrade);
+    assert_eq!(manifest.channel, None);
+    assert_eq!(manifest.release_notes_url, None);
+    assert_eq!(manifest.download_path("ota.example.com", 443), Ok(None));
+}
+
+#[test]
+fn json_errors() {
+    let no_firmware = parse(br#"{"manifest_version":1,"error":"No firmware for device 'esp32'"}"#);
+    assert_eq!(
+        no_firmware,
+        Err(Error::NoFirmware(
+            String::try_from("No firmware for device 'esp32'").unwrap()
+        ))
+    );
+
+    let valid = format!(r#""version":"0.1.4","size":10,"sha256":"{SHA256}","signature":"sig""#);
+    for (body, expected) in [
+        (format!("{{{valid}}}"), Error::UnsupportedVersion),
+        (
+            format!(r#"{{"manifest_version":2,{valid}}}"#),
+            Error::UnsupportedVersion,
+        ),
+        (format!(r#"{{"manifest_version":1,{valid}"#), Error::Syntax),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"size":-1}}"#),
+            Error::Invalid("size"),
+        ),
+        (
+            format!(
+                r#"{{"manifest_version":1,{valid},"sha256":"{}"}}"#,
+                &SHA256[1..]
+            ),
+            Error::Invalid("sha256"),
+        ),
+        (
+            format!(
+                r#"{{"manifest_version":1,{valid},"sha256":"+{}"}}"#,
+                &SHA256[1..]
+            ),
+            Error::Invalid("sha256"),
+        ),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"version":1}}"#),
+            Error::Invalid("version"),
+        ),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"rollout_percentage":101}}"#),
+            Error::Invalid("rollout_percentage"),
+        ),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"rollout_percentage":"10"}}"#),
+            Error::Invalid("rollout_percentage"),
+        ),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"current_version_requirement":"~0.1"}}"#),
+            Error::Invalid("current_version_requirement"),
+        ),
+        (
+            format!(r#"{{"manifest_version":1,{valid},"allow_downgrade":"true"}}"#),
+            Error::Invalid("allow_downgrade"),
+        ),
+        (
+            format!(
+                r#"{{"manifest_version":1,{valid},"channel":"{}"}}"#,
+                "x".repeat(17)
+            ),
+            Error::Invalid("channel"),
+        ),
+    ] {
+        assert_eq!(parse(body.as_bytes()), Err(expected), "{body}");
+    }
+}
+
+#[test]
+fn legacy_format() {
+    let manifest = parse(b"0.1.3\n2868401426\n1835008\nU+quTw2A8KG5X0w+nFxW==\n").unwrap();
+    assert_eq!(manifest.version, "0.1.3");
+    assert_eq!(manifest.size, 1_835_008);
+    assert_eq!(manifest.checksum, Checksum::Crc32(2_868_401_426));
+    assert_eq!(manifest.signature, "U+quTw2A8KG5X0w+nFxW==");
+    assert_eq!(manifest.min_current_version, None);
+    assert_eq!(manifest.download_url, None);
+
+    // CRLF line endings and trailing lines
+    let manifest = parse(b"0.1.3\r\n2868401426\r\n1835008\r\nsig\r\nextra\r\n").unwrap();
+    assert_eq!(manifest.signature, "sig");
+
+    assert_eq!(
+        parse(b"0.1.3\nabc\n10\nsig\n"),
+        Err(Error::Invalid("crc32"))
+    );
+    assert_eq!(parse(b"0.1.3\n1\n10\n"), Err(Error::Invalid("signature")));
+    assert_eq!(parse(b""), Err(Error::Syntax));
+}
+
+#[test]
+fn download_url() {
+    let with_url = |url: &str| {
+        let body = format!(
+            r#"{{"manifest_version":1,"version":"0.1.4","size":10,"sha256":"{SHA256}",
+                "signature":"sig","download_url":"{url}"}}"#
+        );
+        parse(body.as_bytes()).unwrap()
+    };
+    for (url, host, port, expected) in [
+        (
+            "https://ota.example.com/fw.bin",
+            "ota.example.com",
+            443,
+            Ok(Some("/fw.bin")),
+        ),
+        (
+            "https://OTA.example.com:8443/a/b?c=d",
+            "ota.example.com",
+            8443,
+            Ok(Some("/a/b?c=d")),
+        ),
+        (
+            "http://ota.example.com",
+            "ota.example.com",
+            80,
+            Ok(Some("/")),
+        ),
+        (
+            "https://ota.example.com/fw.bin",
+            "ota.example.com",
+            8443,
+            Err(Error::ForeignUrl),
+        ),
+        (
+            "https://cdn.example.com/fw.bin",
+            "ota.example.com",
+            443,
+            Err(Error::ForeignUrl),
+        ),
+        (
+            "ftp://ota.example.com/fw.bin",
+            "ota.example.com",
+            443,
+            Err(Error::ForeignUrl),
+        ),
+        ("fw.bin", "ota.example.com", 443, Err(Error::ForeignUrl)),
+    ] {
+        let manifest = with_url(url);
+        assert_eq!(manifest.download_path(host, port), expected, "{url}");
+    }
+}
+
+#[test]
+fn staged_rollout() {
+    let with_percentage = |percentage: u8| {
+        let body = format!(
+            r#"{{"manifest_version":1,"version":"0.1.4","size":10,"sha256":"{SHA256}",
+                "signature":"sig","rollout_percentage":{percentage}}}"#
+        );
+        parse(body.as_bytes()).unwrap()
+    };
+    let devices: Vec<_> = (0..1000).map(|i| format!("esp32-{i}")).collect();
+    let rolled_out = |percentage: u8| {
+        let manifest = with_percentage(percentage);
+        devices
+            .iter()
+            .filter(|device| manifest.is_rolled_out_to(device))
+            .collect::<Vec<_>>()
+    };
+
+    // Buckets spread the devices evenly
+    assert!(devices.iter().all(|device| rollout_bucket(device) < 100));
+    assert!(rolled_out(0).is_empty());
+    let canary = rolled_out(10);
+    assert!((50..150).contains(&canary.len()), "{}", canary.len());
+    assert_eq!(rolled_out(100).len(), devices.len());
+
+    // Growing the rollout keeps the devices which already updated
+    let half = rolled_out(50);
+    assert!(canary.iter().all(|device| half.contains(device)));
+}
+
+#[test]
+fn unescape_strings() {
+    let unescaped = |s: &str| unescape::<32>(s).map(|s| s.to_string());
+    assert_eq!(unescaped(r#"a\"b\\c\/d"#).as_deref(), Some(r#"a"b\c/d"#));
+    assert_eq!(unescaped(r"tab\tline\n").as_deref(), Some("tab\tline\n"));
+    assert_eq!(unescaped(r"\u00e9t\u00E9").as_deref(), Some("été"));
+    assert_eq!(unescaped(r"\ud83d\ude00").as_deref(), Some("😀"));
+    // Unpaired surrogates
+    assert_eq!(unescaped(r"\ud83d"), None);
+    assert_eq!(unescaped(r"\ude00"), None);
+    assert_eq!(unescaped(r"\ud83dA"), None);
+    // Too long
+    assert_eq!(unescaped(&"x".repeat(33)), None);
+}
@@ -0,0 +1,462 @@
+//! Pull firmware from a stand-in registry speaking the parts of the OCI
+//! distribution API and Docker token auth the device uses, over a real TCP
+//! connection.
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Write as _},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use embassy_futures::block_on;
+use firmware_core::download::{download, Progress};
+use firmware_core::http::Client;
+use firmware_core::manifest::Checksum;
+use firmware_core::oci::{
+    newest_tag, parse_manifest, Credentials, Error, Registry, FIRMWARE_MEDIA_TYPE,
+    MANIFEST_MEDIA_TYPE,
+};
+use firmware_core::semver::{Channel, VersionReq};
+use sha2::{Digest, Sha256};
+
+use common::{Conn, RamFlash};
+
+const NAME: &str = "firmware/esp32-outdoor";
+const SIGNATURE: &str = "U+quTw2A8KG5X0w+nFxW==";
+const CREDENTIALS: Credentials = Credentials {
+    username: "robot$ota",
+    password: "s3cret",
+};
+/// `robot$ota:s3cret` in base64
+const BASIC: &str = "Basic cm9ib3Qkb3RhOnMzY3JldA==";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Image manifest as pushed by `oras push`
+fn oras_manifest(layers: &str, annotations: &str) -> String {
+    format!(
+        r#"{{
+  "schemaVersion": 2,
+  "mediaType": "{MANIFEST_MEDIA_TYPE}",
+  "artifactType": "application/vnd.unknown.artifact.v1",
+  "config": {{
+    "mediaType": "application/vnd.oci.empty.v1+json",
+    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+    "size": 2,
+    "data": "e30="
+  }},
+  "layers": [{layers}],
+  "annotations": {{
+    "org.opencontainers.image.created": "2026-10-01T12:00:00Z"{annotations}
+  }}
+}}"#
+    )
+}
+
+fn firmware_layer(image: &[u8]) -> String {
+    format!(
+        r#"{{"mediaType":"{FIRMWARE_MEDIA_TYPE}","digest":"sha256:{}","size":{},
+            "annotations":{{"org.opencontainers.image.title":"firmware.bin"}}}}"#,
+        hex(&Sha256::digest(image)),
+        image.len()
+    )
+}
+
+fn signature_annotation() -> String {
+    format!(r#","vnd.espressif.esp32.firmware.signature":"{SIGNATURE}""#)
+}
+
+#[test]
+fn newest_tag_per_channel() {
+    let tags = br#"{"name":"firmware/esp32-outdoor","tags":
+        ["0.1.3","latest","0.1.10","0.2.0-alpha.1","0.2.0-rc.1","0.1.9","sha256-abc.sig"]}"#;
+    for (channel, expected) in [
+        (Channel::Stable, "0.1.10"),
+        (Channel::Beta, "0.2.0-rc.1"),
+        (Channel::Dev, "0.2.0-rc.1"),
+    ] {
+        assert_eq!(newest_tag(tags, channel).unwrap(), expected, "{channel:?}");
+    }
+
+    let pre_releases = br#"{"name":"firmware/esp32-outdoor","tags":["0.2.0-alpha.1"]}"#;
+    assert_eq!(
+        newest_tag(pre_releases, Channel::Beta),
+        Err(Error::NoVersion)
+    );
+    // Registries answer null for a repository without tags
+    let untagged = br#"{"name":"firmware/esp32-outdoor","tags":null}"#;
+    assert_eq!(newest_tag(untagged, Channel::Dev), Err(Error::NoVersion));
+    assert_eq!(newest_tag(b"{}", Channel::Dev), Err(Error::Invalid("tags")));
+    assert_eq!(newest_tag(b"<html>", Channel::Dev), Err(Error::Syntax));
+}
+
+#[test]
+fn image_manifest() {
+    let image = b"firmware image";
+    let digest: [u8; 32] = Sha256::digest(image).into();
+    let layers = format!(
+        r#"{{"mediaType":"text/markdown","digest":"sha256:{}","size":5}},{}"#,
+        hex(&[0; 32]),
+        firmware_layer(image)
+    );
+    let annotations = format!(
+        r#"{},"vnd.espressif.esp32.firmware.min-current-version":"0.1.0",
+            "vnd.espressif.esp32.firmware.rollout-percentage":"25",
+            "vnd.espressif.esp32.firmware.current-version-requirement":">=0.1.3, <0.3.0",
+            "vnd.espressif.esp32.firmware.allow-downgrade":"true""#,
+        signature_annotation()
+    );
+    let body = oras_manifest(&layers, &annotations);
+    let manifest = parse_manifest(body.as_bytes(), NAME, "0.2.0-rc.1").unwrap();
+    assert_eq!(manifest.version, "0.2.0-rc.1");
+    assert_eq!(manifest.size, image.len() as u32);
+    assert_eq!(manifest.checksum, Checksum::Sha256(digest));
+    assert_eq!(manifest.signature, SIGNATURE);
+    assert_eq!(manifest.min_current_version.as_deref(), Some("0.1.0"));
+    assert_eq!(manifest.channel.as_deref(), Some("beta"));
+    assert_eq!(manifest.rollout_percentage, 25);
+    assert_eq!(
+        manifest.current_version_requirement,
+        VersionReq::parse(">=0.1.3, <0.3.0")
+    );
+    assert!(manifest.allow_downgrade);
+    let blob = format!("/v2/{NAME}/blobs/sha256:{}", hex(&digest));
+    assert_eq!(
+        manifest.download_path("registry.example.com", 443),
+        Ok(Some(blob.as_str()))
+    );
+
+    let layer = firmware_layer(image);
+    let signature = signature_annotation();
+    for (body, expected) in [
+        (
+            oras_manifest(
+                r#"{"mediaType":"text/plain","digest":"sha256:00","size":1}"#,
+                &signature,
+            ),
+            Error::NoFirmwareLayer,
+        ),
+        (
+            oras_manifest(&layer, ""),
+            Error::Invalid("vnd.espressif.esp32.firmware.signature"),
+        ),
+        (
+            oras_manifest(&format!("{layer},{layer}"), &signature),
+            Error::Invalid("layers"),
+        ),
+        (
+            oras_manifest(&layer.replace("sha256:", "sha512:"), &signature),
+            Error::Invalid("digest"),
+        ),
+        (
+            oras_manifest(
+                &layer,
+                &format!(r#"{signature},"vnd.espressif.esp32.firmware.rollout-percentage":"200""#),
+            ),
+            Error::Invalid("vnd.espressif.esp32.firmware.rollout-percentage"),
+        ),
+        (
+            oras_manifest(
+                &layer,
+                &format!(r#"{signature},"vnd.espressif.esp32.firmware.allow-downgrade":"yes""#),
+            ),
+            Error::Invalid("vnd.espressif.esp32.firmware.allow-downgrade"),
+        ),
+        (
+            oras_manifest(&layer, &signature).replace(
+                MANIFEST_MEDIA_TYPE,
+                "application/vnd.oci.image.index.v1+json",
+            ),
+            Error::Invalid("mediaType"),
+        ),
+        (
+            oras_manifest(&layer, &signature)
+                .replace(r#""schemaVersion": 2"#, r#""schemaVersion": 1"#),
+            Error::Invalid("schemaVersion"),
+        ),
+    ] {
+        assert_eq!(
+            parse_manifest(body.as_bytes(), NAME, "0.2.0"),
+            Err(expected),
+            "{body}"
+        );
+    }
+}
+
+/// How the stand-in registry wants the clients to authenticate
+#[derive(Clone, Copy, PartialEq)]
+enum Auth {
+    Anonymous,
+    Basic,
+    /// Tokens from `/token`, given for `CREDENTIALS` only
+    Bearer,
+}
+
+/// Stand-in registry serving the `images` of `NAME`, tagged with their
+/// versions
+struct StandIn {
+    port: u16,
+    /// Tokens handed out, each one being valid until the next
+    tokens: Arc<AtomicU32>,
+}
+
+impl StandIn {
+    fn start(auth: Auth, images: Vec<(&'static str, Vec<u8>)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tokens = Arc::new(AtomicU32::new(0));
+        let images = Arc::new(images);
+        let server_tokens = Arc::clone(&tokens);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let images = Arc::clone(&images);
+                let tokens = Arc::clone(&server_tokens);
+                thread::spawn(move || serve(stream, port, auth, &images, &tokens));
+            }
+        });
+        Self { port, tokens }
+    }
+
+    /// Invalidate the token handed out, as when it expires
+    fn expire_token(&self) {
+        self.tokens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connect(&self) -> Conn {
+        Conn::connect(self.port)
+    }
+}
+
+fn serve(stream: TcpStream, port: u16, auth: Auth, images: &[(&str, Vec<u8>)], tokens: &AtomicU32) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let path = request_line.split(' ').nth(1).unwrap().to_string();
+        let mut authorization = None;
+        let mut range = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "authorization" => authorization = Some(value.trim().to_string()),
+                "range" => range = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+
+        let token = format!("Bearer token-{}", tokens.load(Ordering::Relaxed));
+        let authorized = match auth {
+            Auth::Anonymous => true,
+            Auth::Basic => authorization.as_deref() == Some(BASIC),
+            Auth::Bearer => authorization.as_deref() == Some(&token),
+        };
+        let (status, extra, body) = if path.starts_with("/token?") {
+            let expected = format!("/token?service=stand-in&scope=repository:{NAME}:pull");
+            if auth != Auth::Bearer || path != expected {
+                (400, String::new(), b"bad token request".to_vec())
+            } else if authorization.as_deref() != Some(BASIC) {
+                (401, String::new(), b"bad credentials".to_vec())
+            } else {
+                let body = format!(r#"{{"token":"{}","expires_in":300}}"#, &token[7..]);
+                (200, String::new(), body.into_bytes())
+            }
+        } else if !authorized {
+            let challenge = match auth {
+                Auth::Basic => r#"Basic realm="stand-in""#.to_string(),
+                _ => format!(
+                    r#"Bearer realm="http://127.0.0.1:{port}/token",service="stand-in",scope="repository:{NAME}:pull""#
+                ),
+            };
+            (
+                401,
+                format!("WWW-Authenticate: {challenge}\r\n"),
+                Vec::new(),
+            )
+        } else if path == format!("/v2/{NAME}/tags/list") {
+            let tags: Vec<_> = images.iter().map(|(tag, _)| format!("\"{tag}\"")).collect();
+            let body = format!(r#"{{"name":"{NAME}","tags":[{}]}}"#, tags.join(","));
+            (200, String::new(), body.into_bytes())
+        } else if let Some(tag) = path.strip_prefix(&format!("/v2/{NAME}/manifests/")) {
+            match images.iter().find(|(version, _)| *version == tag) {
+                Some((_, image)) => {
+                    let body = oras_manifest(&firmware_layer(image), &signature_annotation());
+                    (200, String::new(), body.into_bytes())
+                }
+                None => (404, String::new(), Vec::new()),
+            }
+        } else if let Some(digest) = path.strip_prefix(&format!("/v2/{NAME}/blobs/sha256:")) {
+            match images
+                .iter()
+                .find(|(_, image)| hex(&Sha256::digest(image)) == digest)
+            {
+                Some((_, image)) => {
+                    let first: usize = range
+                        .and_then(|range| {
+                            range
+                                .strip_prefix("bytes=")?
+                                .strip_suffix('-')?
+                                .parse()
+                                .ok()
+                        })
+                        .unwrap_or(0);
+                    let extra = format!(
+                        "Content-Range: bytes {first}-{}/{}\r\n",
+                        image.len() - 1,
+                        image.len()
+                    );
+                    match first {
+                        0 => (200, String::new(), image.clone()),
+                        _ => (206, extra, image[first..].to_vec()),
+                    }
+                }
+                None => (404, String::new(), Vec::new()),
+            }
+        } else {
+            (404, String::new(), Vec::new())
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {status} Stand-in\r\n{extra}Content-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(&body).unwrap();
+    }
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 + i / 509) as u8).collect()
+}
+
+/// Resolve the newest image on `channel` and download it as `Ota::check`
+/// does, on a single connection. Returns the flash holding it.
+fn pull(
+    registry: &mut Registry,
+    stand_in: &StandIn,
+    channel: Channel,
+) -> Result<(firmware_core::manifest::Manifest, RamFlash), Error> {
+    let mut head = [0; 1024];
+    let mut client = Client::new(stand_in.connect(), "127.0.0.1", stand_in.port, &mut head);
+    let mut buf = [0; 2048];
+    let mut flash = RamFlash::new(8);
+    block_on(async {
+        let manifest = registry.latest(&mut client, channel, &mut buf).await?;
+        let path = manifest
+            .download_path("127.0.0.1", stand_in.port)
+            .unwrap()
+            .unwrap();
+        let mut progress = Progress::new(
+            &manifest.version,
+            manifest.checksum.fingerprint(),
+            manifest.size,
+        );
+        let authorization = registry
+            .authorization()
+            .map(|value| ("Authorization", value));
+        let headers: Vec<_> = authorization.into_iter().collect();
+        download(
+            &mut client,
+            path,
+            &headers,
+            &mut flash,
+            &mut progress,
+            &mut buf,
+        )
+        .await
+        .unwrap();
+        Ok((manifest, flash))
+    })
+}
+
+#[test]
+fn pull_with_bearer_token() {
+    let stable = firmware(2 * 4096 + 321);
+    let beta = firmware(5000);
+    let stand_in = StandIn::start(
+        Auth::Bearer,
+        vec![
+            ("0.1.3", firmware(100)),
+            ("0.1.4", stable.clone()),
+            ("0.2.0-beta.1", beta.clone()),
+        ],
+    );
+    let mut registry = Registry::new("firmware", "esp32-outdoor", Some(CREDENTIALS)).unwrap();
+    assert_eq!(registry.name(), NAME);
+
+    let (manifest, flash) = pull(&mut registry, &stand_in, Channel::Stable).unwrap();
+    assert_eq!(manifest.version, "0.1.4");
+    assert_eq!(manifest.signature, SIGNATURE);
+    let expected: [u8; 32] = Sha256::digest(&stable).into();
+    assert_eq!(manifest.checksum, Checksum::Sha256(expected));
+    // The checksum is verified over the flash content
+    let flashed: [u8; 32] = Sha256::digest(&flash.data[..manifest.size as usize]).into();
+    assert_eq!(flashed, expected);
+    assert_eq!(registry.authorization(), Some("Bearer token-0"));
+
+    // The token is kept for the next check, and renewed once expired
+    stand_in.expire_token();
+    let (manifest, flash) = pull(&mut registry, &stand_in, Channel::Beta).unwrap();
+    assert_eq!(manifest.version, "0.2.0-beta.1");
+    assert_eq!(&flash.data[..beta.len()], &beta[..]);
+    assert_eq!(registry.authorization(), Some("Bearer token-1"));
+
+    // No credentials, or the wrong ones
+    let mut anonymous = Registry::new("firmware", "esp32-outdoor", None).unwrap();
+    assert_eq!(
+        pull(&mut anonymous, &stand_in, Channel::Stable).err(),
+        Some(Error::Unauthorized)
+    );
+    let wrong = Credentials {
+        username: "robot$ota",
+        password: "guess",
+    };
+    let mut wrong = Registry::new("firmware", "esp32-outdoor", Some(wrong)).unwrap();
+    assert_eq!(
+        pull(&mut wrong, &stand_in, Channel::Stable).err(),
+        Some(Error::Unauthorized)
+    );
+}
+
+#[test]
+fn pull_with_basic_auth() {
+    let image = firmware(3000);
+    let stand_in = StandIn::start(Auth::Basic, vec![("1.0.0", image.clone())]);
+    let mut registry = Registry::new("/firmware/", "esp32-outdoor", Some(CREDENTIALS)).unwrap();
+    let (manifest, flash) = pull(&mut registry, &stand_in, Channel::Stable).unwrap();
+    assert_eq!(manifest.version, "1.0.0");
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+    assert_eq!(registry.authorization(), Some(BASIC));
+}
+
+#[test]
+fn pull_anonymously() {
+    let image = firmware(10);
+    let stand_in = StandIn::start(Auth::Anonymous, vec![("1.0.0-alpha.1", image.clone())]);
+    let mut registry = Registry::new("firmware", "esp32-outdoor", None).unwrap();
+    assert_eq!(
+        pull(&mut registry, &stand_in, Channel::Beta).err(),
+        Some(Error::NoVersion)
+    );
+    let (manifest, flash) = pull(&mut registry, &stand_in, Channel::Dev).unwrap();
+    assert_eq!(manifest.version, "1.0.0-alpha.1");
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+    assert_eq!(registry.authorization(), None);
+}
@@ -0,0 +1,303 @@
+use firmware_core::config_store::{Key, Record, Value};
+use firmware_core::provisioning::{
+    dns_reply, parse_request, write_page, DhcpServer, Error, Form, Request,
+};
+
+const PORTAL: [u8; 4] = [192, 168, 4, 1];
+
+/// Validate a form body, discarding the decoded values
+fn parse_form(body: &str) -> Result<(), Error> {
+    let mut buf = vec![0u8; body.len()];
+    Form::parse(body.as_bytes(), &mut buf).map(|_| ())
+}
+
+fn value<'a>(record: &'a Record<'a>, key: Key) -> Option<Value<'a>> {
+    record.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+#[test]
+fn request_waits_for_headers_and_body() {
+    let raw = b"POST /?x=1 HTTP/1.1\r\nHost: 192.168.4.1\r\ncontent-length: 11\r\n\r\nwifi_ssid=a";
+
+    assert_eq!(parse_request(&raw[..20]), Ok(None));
+    assert_eq!(parse_request(&raw[..raw.len() - 1]), Ok(None));
+    assert_eq!(
+        parse_request(raw),
+        Ok(Some(Request {
+            method: "POST",
+            path: "/",
+            body: b"wifi_ssid=a",
+        }))
+    );
+}
+
+#[test]
+fn request_without_body() {
+    let request = parse_request(b"GET /generate_204 HTTP/1.1\r\nHost: x\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path, "/generate_204");
+    assert!(request.body.is_empty());
+}
+
+#[test]
+fn request_malformed() {
+    assert_eq!(parse_request(b"GET /\r\n\r\n"), Err(Error::BadRequest));
+    assert_eq!(
+        parse_request(b"GET / SPDY/3\r\n\r\n"),
+        Err(Error::BadRequest)
+    );
+    assert_eq!(
+        parse_request(b"POST / HTTP/1.1\r\nContent-Length: x\r\n\r\n"),
+        Err(Error::BadRequest)
+    );
+}
+
+#[test]
+fn form_decodes_values() {
+    let body = b"wifi_ssid=My+Wi-Fi%21&wifi_psk=p%C3%A4ss%26word&mqtt_hostname=+broker.local+\
+                 &mqtt_port=8883&mqtt_username=sensor&mqtt_password=&location=living+room&extra=1";
+    let mut buf = [0u8; 256];
+    let form = Form::parse(body, &mut buf).unwrap();
+
+    assert_eq!(
+        form,
+        Form {
+            wifi_ssid: "My Wi-Fi!",
+            wifi_psk: "päss&word",
+            mqtt_hostname: "broker.local",
+            mqtt_port: Some(8883),
+            mqtt_username: "sensor",
+            mqtt_password: "",
+            location: "living room",
+        }
+    );
+}
+
+#[test]
+fn form_validation() {
+    assert_eq!(parse_form("wifi_psk=password"), Err(Error::InvalidSsid));
+    assert_eq!(
+        parse_form(&format!("wifi_ssid={}", "x".repeat(33))),
+        Err(Error::InvalidSsid)
+    );
+    assert_eq!(
+        parse_form("wifi_ssid=home&wifi_psk=short"),
+        Err(Error::InvalidPsk)
+    );
+    assert_eq!(
+        parse_form("wifi_ssid=home&mqtt_port=0"),
+        Err(Error::InvalidPort)
+    );
+    assert_eq!(
+        parse_form("wifi_ssid=home&mqtt_port=65536"),
+        Err(Error::InvalidPort)
+    );
+    assert_eq!(parse_form("wifi_ssid=home%2"), Err(Error::InvalidEncoding));
+    assert_eq!(parse_form("wifi_ssid=home%+1"), Err(Error::InvalidEncoding));
+    assert_eq!(parse_form("wifi_ssid=%FF"), Err(Error::InvalidEncoding));
+    // Open network
+    assert_eq!(parse_form("wifi_ssid=home&wifi_psk="), Ok(()));
+}
+
+#[test]
+fn form_psk_length() {
+    let psk = |psk: &str| parse_form(&format!("wifi_ssid=home&wifi_psk={psk}"));
+    assert_eq!(psk(&"p".repeat(8)), Ok(()));
+    assert_eq!(psk(&"p".repeat(63)), Ok(()));
+    // 64 characters are the key itself, in hex
+    assert_eq!(psk(&"0123456789abcdef".repeat(4)), Ok(()));
+    assert_eq!(psk(&"0123456789ABCDEF".repeat(4)), Ok(()));
+    assert_eq!(psk(&"p".repeat(64)), Err(Error::InvalidPsk));
+    assert_eq!(psk(&"0".repeat(65)), Err(Error::InvalidPsk));
+}
+
+#[test]
+fn form_buffer_too_small() {
+    let mut buf = [0u8; 4];
+    assert_eq!(
+        Form::parse(b"wifi_ssid=home-network", &mut buf),
+        Err(Error::TooLarge)
+    );
+}
+
+#[test]
+fn form_applies_to_record() {
+    let mut buf = [0u8; 128];
+    let mut record = Record::new();
+    record.set(Key::MqttPassword, Value::Str("secret")).unwrap();
+    record.set(Key::MqttPort, Value::U16(1883)).unwrap();
+    record
+        .set(Key::DeviceId, Value::Str("esp32-outdoor"))
+        .unwrap();
+
+    let form = Form::parse(b"wifi_ssid=home&wifi_psk=&mqtt_hostname=broker", &mut buf).unwrap();
+    form.apply(&mut record).unwrap();
+
+    assert_eq!(value(&record, Key::WifiSsid), Some(Value::Str("home")));
+    assert_eq!(value(&record, Key::WifiPsk), Some(Value::Str("")));
+    assert_eq!(
+        value(&record, Key::MqttHostname),
+        Some(Value::Str("broker"))
+    );
+    // Empty fields keep the stored values
+    assert_eq!(
+        value(&record, Key::MqttPassword),
+        Some(Value::Str("secret"))
+    );
+    assert_eq!(value(&record, Key::MqttPort), Some(Value::U16(1883)));
+    assert_eq!(
+        value(&record, Key::DeviceId),
+        Some(Value::Str("esp32-outdoor"))
+    );
+    assert_eq!(value(&record, Key::Location), None);
+}
+
+#[test]
+fn page_escapes_values_and_hides_passwords() {
+    let current = Form {
+        wifi_ssid: "\"><script>",
+        wifi_psk: "wifi-secret",
+        mqtt_port: Some(1883),
+        mqtt_password: "mqtt-secret",
+        ..Default::default()
+    };
+    let mut page = String::new();
+    write_page(&mut page, "esp32-outdoor", &current, Some("Bad <input>")).unwrap();
+
+    assert!(page.contains("value=\"&quot;&gt;&lt;script&gt;\""));
+    assert!(page.contains("Bad &lt;input&gt;"));
+    assert!(page.contains("name=\"mqtt_port\" type=\"number\" value=\"1883\""));
+    assert!(!page.contains("secret"));
+}
+
+/// Query for `name` with the given record type, id 0x1234, recursion desired
+fn dns_query(name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query
+}
+
+#[test]
+fn dns_resolves_everything_to_portal() {
+    let query = dns_query("connectivitycheck.gstatic.com", 1);
+    let mut out = [0u8; 512];
+    let len = dns_reply(&query, PORTAL, &mut out).unwrap();
+    let reply = &out[..len];
+
+    assert_eq!(len, query.len() + 16);
+    assert_eq!(&reply[0..2], &[0x12, 0x34]);
+    assert_eq!(reply[2] & 0x80, 0x80, "response flag");
+    assert_eq!(reply[3] & 0x0F, 0, "no error");
+    assert_eq!(&reply[6..8], &[0, 1], "one answer");
+    assert_eq!(&reply[12..query.len()], &query[12..]);
+    assert_eq!(&reply[len - 4..], &PORTAL);
+}
+
+#[test]
+fn dns_empty_answer_for_other_types() {
+    let query = dns_query("example.com", 28);
+    let mut out = [0u8; 512];
+    let len = dns_reply(&query, PORTAL, &mut out).unwrap();
+
+    assert_eq!(len, query.len());
+    assert_eq!(&out[6..8], &[0, 0]);
+}
+
+#[test]
+fn dns_ignores_non_queries() {
+    let mut out = [0u8; 512];
+    let mut response = dns_query("example.com", 1);
+    response[2] |= 0x80;
+
+    assert_eq!(dns_reply(&response, PORTAL, &mut out), None);
+    assert_eq!(dns_reply(&[0x12, 0x34], PORTAL, &mut out), None);
+    // Truncated question
+    let query = dns_query("example.com", 1);
+    assert_eq!(dns_reply(&query[..query.len() - 3], PORTAL, &mut out), None);
+}
+
+fn dhcp_message(mac: [u8; 6], message_type: u8, requested: Option<[u8; 4]>) -> Vec<u8> {
+    let mut message = vec![0u8; 240];
+    message[0] = 1;
+    message[1] = 1;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    message[28..34].copy_from_slice(&mac);
+    message[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    message.extend_from_slice(&[53, 1, message_type]);
+    if let Some(requested) = requested {
+        message.extend_from_slice(&[50, 4]);
+        message.extend_from_slice(&requested);
+    }
+    message.push(255);
+    message
+}
+
+/// Message type and offered address of a reply
+fn dhcp_result(reply: &[u8]) -> (u8, [u8; 4]) {
+    assert_eq!(reply[0], 2);
+    assert_eq!(&reply[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(&reply[240..243], &[53, 1, reply[242]]);
+    (reply[242], reply[16..20].try_into().unwrap())
+}
+
+#[test]
+fn dhcp_discover_request() {
+    let mut server = DhcpServer::new(PORTAL);
+    let mut out = [0u8; 576];
+    let phone = [2, 0, 0, 0, 0, 1];
+
+    let len = server
+        .reply(&dhcp_message(phone, 1, None), &mut out)
+        .unwrap();
+    assert_eq!(dhcp_result(&out[..len]), (2, [192, 168, 4, 2]));
+
+    let len = server
+        .reply(&dhcp_message(phone, 3, Some([192, 168, 4, 2])), &mut out)
+        .unwrap();
+    assert_eq!(dhcp_result(&out[..len]), (5, [192, 168, 4, 2]));
+
+    // A second client gets the next address
+    let laptop = [2, 0, 0, 0, 0, 2];
+    let len = server
+        .reply(&dhcp_message(laptop, 1, None), &mut out)
+        .unwrap();
+    assert_eq!(dhcp_result(&out[..len]), (2, [192, 168, 4, 3]));
+}
+
+#[test]
+fn dhcp_nak_for_unknown_address() {
+    let mut server = DhcpServer::new(PORTAL);
+    let mut out = [0u8; 576];
+
+    let len = server
+        .reply(
+            &dhcp_message([2, 0, 0, 0, 0, 1], 3, Some([10, 0, 0, 42])),
+            &mut out,
+        )
+        .unwrap();
+    assert_eq!(dhcp_result(&out[..len]), (6, [0, 0, 0, 0]));
+}
+
+#[test]
+fn dhcp_ignores_other_messages() {
+    let mut server = DhcpServer::new(PORTAL);
+    let mut out = [0u8; 576];
+    let mac = [2, 0, 0, 0, 0, 1];
+
+    // Release
+    assert_eq!(server.reply(&dhcp_message(mac, 7, None), &mut out), None);
+    // Reply from another server
+    let mut offer = dhcp_message(mac, 1, None);
+    offer[0] = 2;
+    assert_eq!(server.reply(&offer, &mut out), None);
+    assert_eq!(server.reply(&[1, 1, 6], &mut out), None);
+}
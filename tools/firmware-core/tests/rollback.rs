@@ -0,0 +1,53 @@
+use firmware_core::manifest::Checksum;
+use firmware_core::rollback::{Reason, Report, WORDS};
+
+const SHA256: Checksum = Checksum::Sha256([0xab; 32]);
+
+#[test]
+fn words_round_trip() {
+    for reason in [Reason::Reset, Reason::Wifi, Reason::Sensors, Reason::Mqtt] {
+        let report = Report::new("1.3.0-rc.1", Some(SHA256), reason);
+        let decoded = Report::from_words(&report.to_words()).unwrap();
+        assert_eq!(decoded, report);
+        assert_eq!(decoded.version(), "1.3.0-rc.1");
+        assert_eq!(decoded.reason, reason);
+    }
+
+    for checksum in [None, Some(Checksum::Crc32(0xDEAD_BEEF))] {
+        let mut report = Report::new("1.3.0", checksum, Reason::Mqtt);
+        report.published = true;
+        assert_eq!(Report::from_words(&report.to_words()), Some(report));
+    }
+}
+
+#[test]
+fn invalid_words() {
+    // Garbage left in RTC memory after a power loss, or a cleared report
+    assert_eq!(Report::from_words(&[0; WORDS]), None);
+
+    let mut corrupt = Report::new("1.3.0", None, Reason::Mqtt).to_words();
+    corrupt[2] ^= 1;
+    assert_eq!(Report::from_words(&corrupt), None);
+}
+
+#[test]
+fn same_manifest_after_rollback() {
+    // Written by the update, then by the new firmware failing its health check
+    let report = Report::new("1.3.0", Some(SHA256), Reason::Mqtt);
+    let report = Report::from_words(&report.to_words()).unwrap();
+
+    // The server still offers the image which was rolled back
+    assert!(report.rejects("1.3.0", &SHA256));
+
+    // A fixed image, even under the same version, or another release
+    assert!(!report.rejects("1.3.0", &Checksum::Sha256([0xcd; 32])));
+    assert!(!report.rejects("1.3.1", &SHA256));
+    assert!(!report.rejects("1.3.0", &Checksum::Crc32(0xabab_abab)));
+}
+
+#[test]
+fn unknown_image_never_rejected() {
+    // Rolled back from a firmware flashed over serial
+    let report = Report::new("1.3.0", None, Reason::Reset);
+    assert!(!report.rejects("1.3.0", &SHA256));
+}
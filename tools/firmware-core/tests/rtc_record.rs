@@ -0,0 +1,67 @@
+use firmware_core::rtc_record::{self, Version, VERSION_MAX_LEN};
+
+const MAGIC: u32 = 0x5445_5354;
+
+#[test]
+fn round_trip() {
+    let words: [u32; rtc_record::len(3)] = rtc_record::encode(MAGIC, &[1, 0, u32::MAX]);
+    assert_eq!(words.len(), 5);
+    assert_eq!(words[0], MAGIC);
+    assert_eq!(
+        rtc_record::decode(MAGIC, &words),
+        Some(&[1, 0, u32::MAX][..])
+    );
+}
+
+#[test]
+fn invalid_words() {
+    // Garbage left in RTC memory after a power loss, or a cleared record
+    assert_eq!(rtc_record::decode(MAGIC, &[0; 5]), None);
+
+    let words: [u32; 5] = rtc_record::encode(MAGIC, &[1, 2, 3]);
+    for i in 0..words.len() {
+        let mut corrupt = words;
+        corrupt[i] ^= 1;
+        assert_eq!(rtc_record::decode(MAGIC, &corrupt), None);
+    }
+
+    // Another record type
+    assert_eq!(rtc_record::decode(MAGIC + 1, &words), None);
+}
+
+#[test]
+fn version_round_trip() {
+    for version in ["", "1.3.0", "1.3.0-rc.1+build.5"] {
+        let field = Version::new(version);
+        assert_eq!(field.as_str(), version);
+        assert_eq!(Version::from_words(&field.to_words()), Some(field));
+    }
+}
+
+#[test]
+fn version_truncated() {
+    let version = "1.0.0-".to_string() + &"a".repeat(VERSION_MAX_LEN);
+    let field = Version::new(&version);
+    assert_eq!(field.as_str(), &version[..VERSION_MAX_LEN]);
+    assert_eq!(Version::from_words(&field.to_words()), Some(field));
+
+    // Never cut through a character
+    let version = "1.0.0+b".to_string() + &"é".repeat(VERSION_MAX_LEN);
+    let field = Version::new(&version);
+    assert_eq!(field.as_str().len(), VERSION_MAX_LEN - 1);
+    assert!(version.starts_with(field.as_str()));
+}
+
+#[test]
+fn invalid_version() {
+    let mut words = Version::new("1.3.0").to_words();
+    assert_eq!(Version::from_words(&words[..Version::WORDS - 1]), None);
+
+    words[0] = VERSION_MAX_LEN as u32 + 1;
+    assert_eq!(Version::from_words(&words), None);
+
+    // Not UTF-8
+    words[0] = 1;
+    words[1] = 0xFF;
+    assert_eq!(Version::from_words(&words), None);
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use firmware_core::sample_store::{Error, SampleStore};
+
+use common::{RamFlash, SECTOR_SIZE};
+
+const SECTORS: usize = 4;
+
+fn mount(flash: &mut RamFlash) -> SampleStore {
+    let len = flash.data.len() as u32;
+    SampleStore::mount(flash, 0, len).unwrap()
+}
+
+fn sample(i: usize) -> Vec<u8> {
+    format!("weather,location=outdoor temperature={i}.5 {i}000").into_bytes()
+}
+
+/// Publish every pending sample
+fn drain(store: &mut SampleStore, flash: &mut RamFlash) -> Vec<Vec<u8>> {
+    let mut samples = Vec::new();
+    let mut buf = [0u8; 512];
+    while let Some(len) = store.peek(flash, &mut buf).unwrap() {
+        samples.push(buf[..len].to_vec());
+        store.pop(flash).unwrap();
+    }
+    samples
+}
+
+#[test]
+fn empty_store() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    let mut buf = [0u8; 64];
+
+    assert!(store.is_empty(&mut flash).unwrap());
+    assert_eq!(store.peek(&mut flash, &mut buf).unwrap(), None);
+    store.pop(&mut flash).unwrap();
+    assert_eq!(flash.erases, vec![0; SECTORS]);
+}
+
+#[test]
+fn samples_read_in_order() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    for i in 0..3 {
+        store.push(&mut flash, &sample(i)).unwrap();
+    }
+
+    let mut buf = [0u8; 64];
+    // Peeking twice returns the same sample until it is popped
+    let len = store.peek(&mut flash, &mut buf).unwrap().unwrap();
+    assert_eq!(&buf[..len], &sample(0)[..]);
+    let len = store.peek(&mut flash, &mut buf).unwrap().unwrap();
+    assert_eq!(&buf[..len], &sample(0)[..]);
+
+    assert_eq!(
+        drain(&mut store, &mut flash),
+        vec![sample(0), sample(1), sample(2)]
+    );
+    assert!(store.is_empty(&mut flash).unwrap());
+}
+
+#[test]
+fn pending_samples_survive_remount() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    for i in 0..4 {
+        store.push(&mut flash, &sample(i)).unwrap();
+    }
+    let mut buf = [0u8; 64];
+    store.peek(&mut flash, &mut buf).unwrap();
+    store.pop(&mut flash).unwrap();
+
+    let mut store = mount(&mut flash);
+    store.push(&mut flash, &sample(4)).unwrap();
+    assert_eq!(
+        drain(&mut store, &mut flash),
+        (1..5).map(sample).collect::<Vec<_>>()
+    );
+
+    let mut store = mount(&mut flash);
+    assert!(store.is_empty(&mut flash).unwrap());
+}
+
+#[test]
+fn samples_span_sectors() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    // About 60 samples per sector
+    for i in 0..150 {
+        store.push(&mut flash, &sample(i)).unwrap();
+    }
+    assert_eq!(flash.erases, vec![1, 1, 1, 0]);
+
+    let mut store = mount(&mut flash);
+    assert_eq!(
+        drain(&mut store, &mut flash),
+        (0..150).map(sample).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn full_ring_drops_oldest_sector() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    for i in 0..1000 {
+        store.push(&mut flash, &sample(i)).unwrap();
+    }
+
+    let samples = drain(&mut store, &mut flash);
+    // Only the newest samples are left, in order and without gaps
+    assert!(samples.len() > 150 && samples.len() < 250);
+    let first = 1000 - samples.len();
+    assert_eq!(samples, (first..1000).map(sample).collect::<Vec<_>>());
+}
+
+#[test]
+fn sectors_are_erased_in_turn() {
+    let mut flash = RamFlash::new(SECTORS);
+    for round in 0..50 {
+        // Remount regularly, as after a reboot
+        let mut store = mount(&mut flash);
+        for i in 0..40 {
+            store.push(&mut flash, &sample(round * 40 + i)).unwrap();
+        }
+        drain(&mut store, &mut flash);
+    }
+
+    let min = *flash.erases.iter().min().unwrap();
+    let max = *flash.erases.iter().max().unwrap();
+    assert!(min > 0);
+    assert!(max - min <= 1, "uneven wear: {:?}", flash.erases);
+}
+
+#[test]
+fn torn_write_is_skipped() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    store.push(&mut flash, &sample(0)).unwrap();
+    let before = flash.data.clone();
+    store.push(&mut flash, &sample(1)).unwrap();
+
+    // Power loss after the record header was written, before the payload
+    let header = 16 + 8 + sample(0).len().next_multiple_of(4);
+    flash.data[header + 8..SECTOR_SIZE].copy_from_slice(&before[header + 8..SECTOR_SIZE]);
+
+    let mut store = mount(&mut flash);
+    store.push(&mut flash, &sample(2)).unwrap();
+    // Nothing is written after the damaged record, the next sector is used
+    assert_eq!(flash.erases, vec![1, 1, 0, 0]);
+    assert_eq!(drain(&mut store, &mut flash), vec![sample(0), sample(2)]);
+}
+
+#[test]
+fn torn_publish_mark() {
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    store.push(&mut flash, &sample(0)).unwrap();
+    store.push(&mut flash, &sample(1)).unwrap();
+
+    // Only part of the state bits of the first record were cleared
+    flash.data[16 + 2] = 0x00;
+
+    let mut store = mount(&mut flash);
+    assert_eq!(drain(&mut store, &mut flash), vec![sample(1)]);
+}
+
+#[test]
+fn region_offset() {
+    let mut flash = RamFlash::new(SECTORS + 1);
+    flash.data[..SECTOR_SIZE].fill(0xA5);
+
+    let base = SECTOR_SIZE as u32;
+    let len = (SECTORS * SECTOR_SIZE) as u32;
+    let mut store = SampleStore::mount(&mut flash, base, len).unwrap();
+    store.push(&mut flash, &sample(0)).unwrap();
+
+    assert!(flash.data[..SECTOR_SIZE].iter().all(|b| *b == 0xA5));
+    let mut store = SampleStore::mount(&mut flash, base, len).unwrap();
+    assert_eq!(drain(&mut store, &mut flash), vec![sample(0)]);
+}
+
+#[test]
+fn invalid_sizes() {
+    let mut flash = RamFlash::new(1);
+    assert!(matches!(
+        SampleStore::mount(&mut flash, 0, SECTOR_SIZE as u32),
+        Err(Error::TooSmall)
+    ));
+
+    let mut flash = RamFlash::new(SECTORS);
+    let mut store = mount(&mut flash);
+    assert_eq!(
+        store.push(&mut flash, &vec![b'x'; SECTOR_SIZE]),
+        Err(Error::TooLarge)
+    );
+
+    store.push(&mut flash, &sample(0)).unwrap();
+    let mut buf = [0u8; 8];
+    assert_eq!(store.peek(&mut flash, &mut buf), Err(Error::TooLarge));
+}
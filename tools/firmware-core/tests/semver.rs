@@ -0,0 +1,174 @@
+use firmware_core::semver::{Channel, SemVer, VersionReq};
+
+fn semver(s: &str) -> SemVer {
+    SemVer::parse(s).unwrap()
+}
+
+#[test]
+fn release_channels() {
+    for (version, channel) in [
+        ("1.2.3", Channel::Stable),
+        ("1.2.3-rc.1", Channel::Beta),
+        ("1.2.3-beta", Channel::Beta),
+        ("1.2.3-BETA.2", Channel::Beta),
+        ("1.2.3-rc1+build.5", Channel::Beta),
+        ("1.2.3-alpha.1", Channel::Dev),
+        ("1.2.3-nightly", Channel::Dev),
+    ] {
+        assert_eq!(semver(version).channel(), channel, "{version}");
+    }
+
+    let accepted = |channel: Channel| {
+        ["1.2.3", "1.2.3-rc.1", "1.2.3-alpha.1"]
+            .into_iter()
+            .filter(|version| channel.accepts(&semver(version)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(accepted(Channel::Stable), ["1.2.3"]);
+    assert_eq!(accepted(Channel::Beta), ["1.2.3", "1.2.3-rc.1"]);
+    assert_eq!(
+        accepted(Channel::Dev),
+        ["1.2.3", "1.2.3-rc.1", "1.2.3-alpha.1"]
+    );
+}
+
+#[test]
+fn channel_names() {
+    for channel in [Channel::Dev, Channel::Beta, Channel::Stable] {
+        assert_eq!(Channel::from_name(channel.as_str()), Some(channel));
+    }
+    assert_eq!(Channel::from_name("Stable"), None);
+    assert_eq!(Channel::from_name("nightly"), None);
+}
+
+#[test]
+fn precedence() {
+    // Examples of the specification, semver.org
+    let ordered = [
+        "1.0.0-alpha",
+        "1.0.0-alpha.1",
+        "1.0.0-alpha.beta",
+        "1.0.0-beta",
+        "1.0.0-beta.2",
+        "1.0.0-beta.11",
+        "1.0.0-rc.1",
+        "1.0.0",
+        "2.0.0",
+        "2.1.0",
+        "2.1.1",
+        "10.0.0",
+    ];
+    for (i, a) in ordered.iter().enumerate() {
+        for (j, b) in ordered.iter().enumerate() {
+            assert_eq!(semver(a).cmp(&semver(b)), i.cmp(&j), "{a} {b}");
+        }
+    }
+
+    // Numeric identifiers are lower than alphanumeric ones, and compare as
+    // numbers whatever their length
+    assert!(semver("1.0.0-1") < semver("1.0.0-a"));
+    assert!(semver("1.0.0-9") < semver("1.0.0-10"));
+    assert!(semver("1.0.0-99999999999999999999") < semver("1.0.0-100000000000000000000"));
+    // Alphanumeric ones compare in ASCII order
+    assert!(semver("1.0.0-BETA") < semver("1.0.0-alpha"));
+    assert!(semver("1.0.0-a-b") < semver("1.0.0-ab"));
+
+    // Build metadata is ignored
+    assert_eq!(semver("1.0.0+20130313144700"), semver("1.0.0"));
+    assert_eq!(
+        semver("1.0.0-beta+exp.sha.5114f85"),
+        semver("1.0.0-beta+001")
+    );
+    assert!(semver("1.0.0-alpha+001") < semver("1.0.0+21AF26D3----117B344092BD"));
+    assert_eq!(semver("v1.2.3"), semver("1.2.3"));
+}
+
+#[test]
+fn parse_and_display() {
+    for (version, displayed) in [
+        ("1.2.3", "1.2.3"),
+        ("v1.2.3", "1.2.3"),
+        (" V0.0.0-0 ", "0.0.0-0"),
+        ("1.2.3-rc.1", "1.2.3-rc.1"),
+        ("1.2.3-x-y.--", "1.2.3-x-y.--"),
+        ("1.0.0+20130313144700", "1.0.0+20130313144700"),
+        ("1.0.0-beta+exp.sha.5114f85", "1.0.0-beta+exp.sha.5114f85"),
+        ("1.0.0+001", "1.0.0+001"),
+        ("1.0.0-alpha+-", "1.0.0-alpha+-"),
+    ] {
+        let version = SemVer::parse(version).unwrap_or_else(|| panic!("{version}"));
+        assert_eq!(version.to_string(), displayed);
+    }
+
+    let version = semver("1.0.0-alpha.1+build.5");
+    assert!(version.is_pre_release());
+    assert_eq!(version.pre_release().collect::<Vec<_>>(), ["alpha", "1"]);
+    assert_eq!(version.build(), "build.5");
+    assert!(!semver("1.0.0+alpha").is_pre_release());
+
+    for version in [
+        "",
+        "1.2",
+        "1.2.3.4",
+        "1.2.x",
+        "01.2.3",
+        "1.02.3",
+        "+1.2.3",
+        "1.2.-3",
+        "1.2.3-",
+        "1.2.3+",
+        "1.2.3-01",
+        "1.2.3-alpha..1",
+        "1.2.3-alpha.",
+        "1.2.3-beta_1",
+        "1.2.3+build+5",
+        "1.2.3+a.",
+        "1.2.3-é",
+        "4294967296.0.0",
+        &format!("1.2.3-{}", "x".repeat(33)),
+    ] {
+        assert_eq!(SemVer::parse(version), None, "{version}");
+    }
+}
+
+#[test]
+fn version_requirements() {
+    let matching = |requirement: &str| {
+        let requirement = VersionReq::parse(requirement).unwrap();
+        ["0.1.2", "0.1.3", "0.2.0", "0.3.0-beta.1", "0.3.0", "1.0.0"]
+            .into_iter()
+            .filter(|version| requirement.matches(&semver(version)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        matching(">=0.1.3, <0.3.0"),
+        ["0.1.3", "0.2.0", "0.3.0-beta.1"]
+    );
+    assert_eq!(
+        matching(">0.1.3,<=0.3.0"),
+        ["0.2.0", "0.3.0-beta.1", "0.3.0"]
+    );
+    assert_eq!(matching("< 0.2.0"), ["0.1.2", "0.1.3"]);
+    assert_eq!(matching("=0.2.0"), ["0.2.0"]);
+    assert_eq!(matching("v0.2.0+build.5"), ["0.2.0"]);
+    assert_eq!(matching(">=0.1.0, <1.0.0, >=0.3.0-0").len(), 2);
+
+    assert_eq!(
+        VersionReq::parse(" >=0.1.3 ,<0.3.0 ").unwrap().to_string(),
+        ">=0.1.3, <0.3.0"
+    );
+    for requirement in [
+        "",
+        ",",
+        ">=0.1.3,",
+        "=>0.1.3",
+        "==0.1.3",
+        "~0.1.3",
+        "^0.1.3",
+        ">=0.1",
+        "*",
+        ">0.1.0, >0.1.1, >0.1.2, >0.1.3, >0.1.4",
+    ] {
+        assert_eq!(VersionReq::parse(requirement), None, "{requirement}");
+    }
+}
@@ -0,0 +1,190 @@
+use firmware_core::serializer::{write_influx, write_json, Error, Timestamp};
+
+const TAGS: [(&str, &str); 2] = [("location", "outdoor"), ("firmware", "0.1.3")];
+
+fn readings() -> Vec<(&'static str, f32)> {
+    vec![
+        ("temperature", 21.5),
+        ("humidity", 48.25),
+        ("pressure", 101325.0),
+    ]
+}
+
+fn json(tags: &[(&str, &str)], fields: Vec<(&'static str, f32)>) -> Result<String, Error> {
+    let mut out = String::new();
+    write_json(&mut out, tags, fields, Timestamp::Unsynced)?;
+    Ok(out)
+}
+
+fn influx(tags: &[(&str, &str)], fields: Vec<(&'static str, f32)>) -> Result<String, Error> {
+    let mut out = String::new();
+    write_influx(&mut out, "weather", tags, fields, Timestamp::Unsynced)?;
+    Ok(out)
+}
+
+#[test]
+fn json_numeric_values() {
+    assert_eq!(
+        json(&TAGS, readings()).unwrap(),
+        r#"{"location":"outdoor","firmware":"0.1.3","temperature":21.50,"humidity":48.25,"pressure":101325.00,"ts":null}"#
+    );
+}
+
+#[test]
+fn json_negative_value() {
+    assert_eq!(
+        json(&TAGS, vec![("temperature", -3.456)]).unwrap(),
+        r#"{"location":"outdoor","firmware":"0.1.3","temperature":-3.46,"ts":null}"#
+    );
+}
+
+#[test]
+fn json_without_fields() {
+    assert_eq!(
+        json(&TAGS, vec![]).unwrap(),
+        r#"{"location":"outdoor","firmware":"0.1.3","ts":null}"#
+    );
+}
+
+#[test]
+fn json_timestamp_in_milliseconds() {
+    let mut out = String::new();
+    write_json(
+        &mut out,
+        &[],
+        vec![("co2", 415.0)],
+        Timestamp::Synced(1_700_000_000_123_456),
+    )
+    .unwrap();
+    assert_eq!(out, r#"{"co2":415.00,"ts":1700000000123}"#);
+
+    out.clear();
+    write_json(&mut out, &[], vec![], Timestamp::Unsynced).unwrap();
+    assert_eq!(out, r#"{"ts":null}"#);
+}
+
+#[test]
+fn json_escapes_strings() {
+    let tags = [("location", "living \"room\"\\\n\u{1}")];
+    assert_eq!(
+        json(&tags, vec![("co2", 415.0)]).unwrap(),
+        r#"{"location":"living \"room\"\\\n\u0001","co2":415.00,"ts":null}"#
+    );
+}
+
+#[test]
+fn json_rejects_non_finite() {
+    assert_eq!(
+        json(&TAGS, vec![("temperature", 21.5), ("humidity", f32::NAN)]),
+        Err(Error::NonFinite("humidity"))
+    );
+    assert_eq!(
+        json(&TAGS, vec![("co2", f32::INFINITY)]),
+        Err(Error::NonFinite("co2"))
+    );
+}
+
+#[test]
+fn influx_line() {
+    assert_eq!(
+        influx(&TAGS, readings()).unwrap(),
+        "weather,location=outdoor,firmware=0.1.3 temperature=21.50,humidity=48.25,pressure=101325.00"
+    );
+}
+
+#[test]
+fn influx_timestamp_in_nanoseconds() {
+    let mut out = String::new();
+    write_influx(
+        &mut out,
+        "weather",
+        &TAGS,
+        vec![("co2", 415.0)],
+        Timestamp::Synced(1_700_000_000_123_456),
+    )
+    .unwrap();
+    assert_eq!(
+        out,
+        "weather,location=outdoor,firmware=0.1.3 co2=415.00 1700000000123456000"
+    );
+}
+
+#[test]
+fn influx_escapes_tags() {
+    let tags = [("location", "living room, north=1")];
+    assert_eq!(
+        influx(&tags, vec![("co2", 415.0)]).unwrap(),
+        r"weather,location=living\ room\,\ north\=1 co2=415.00"
+    );
+}
+
+#[test]
+fn influx_escapes_measurement_and_field_keys() {
+    let mut out = String::new();
+    write_influx(
+        &mut out,
+        "my weather,v2",
+        &[],
+        vec![("pm 2,5=", 1.0)],
+        Timestamp::Unsynced,
+    )
+    .unwrap();
+    assert_eq!(out, r"my\ weather\,v2 pm\ 2\,5\==1.00");
+}
+
+#[test]
+fn influx_omits_empty_tags() {
+    let tags = [("location", ""), ("firmware", "0.1.3")];
+    assert_eq!(
+        influx(&tags, vec![("co2", 415.0)]).unwrap(),
+        "weather,firmware=0.1.3 co2=415.00"
+    );
+}
+
+#[test]
+fn influx_rejects_line_breaks() {
+    let tags = [("location", "out\ndoor")];
+    assert_eq!(influx(&tags, readings()), Err(Error::InvalidName));
+}
+
+#[test]
+fn influx_requires_fields() {
+    assert_eq!(influx(&TAGS, vec![]), Err(Error::NoFields));
+}
+
+#[test]
+fn influx_rejects_non_finite() {
+    assert_eq!(
+        influx(&TAGS, vec![("pressure", f32::NEG_INFINITY)]),
+        Err(Error::NonFinite("pressure"))
+    );
+}
+
+#[test]
+fn buffer_overflow() {
+    let mut out = Capped {
+        len: 0,
+        capacity: 16,
+    };
+    assert_eq!(
+        write_json(&mut out, &TAGS, readings(), Timestamp::Unsynced),
+        Err(Error::Format)
+    );
+}
+
+/// Fixed capacity writer, like the heapless strings used on the device
+struct Capped {
+    len: usize,
+    capacity: usize,
+}
+
+impl std::fmt::Write for Capped {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.len += s.len();
+        if self.len > self.capacity {
+            Err(std::fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use firmware_core::sntp::{parse_response, write_request, Error, Response, PACKET_LEN};
+
+const NONCE: u64 = 0x0123_4567_89ab_cdef;
+
+/// 2023-11-14T22:13:20Z as NTP seconds
+const NTP_SECONDS: u64 = 1_700_000_000 + 2_208_988_800;
+
+/// Reply from a stratum 2 server echoing `NONCE`, received at `NTP_SECONDS`
+/// and sent half a second later
+fn reply() -> [u8; PACKET_LEN] {
+    let mut packet = [0u8; PACKET_LEN];
+    // Leap indicator 0, version 4, mode 4 (server)
+    packet[0] = (4 << 3) | 4;
+    packet[1] = 2;
+    packet[24..32].copy_from_slice(&NONCE.to_be_bytes());
+    packet[32..40].copy_from_slice(&(NTP_SECONDS << 32).to_be_bytes());
+    packet[40..48].copy_from_slice(&(NTP_SECONDS << 32 | 0x8000_0000).to_be_bytes());
+    packet
+}
+
+#[test]
+fn request_packet() {
+    let mut packet = [0xFFu8; PACKET_LEN];
+    write_request(NONCE, &mut packet);
+
+    assert_eq!(packet[0], 0x23, "version 4, client mode");
+    assert!(packet[1..40].iter().all(|b| *b == 0));
+    assert_eq!(&packet[40..48], &NONCE.to_be_bytes());
+}
+
+#[test]
+fn response_converted_to_unix_micros() {
+    assert_eq!(
+        parse_response(&reply(), NONCE),
+        Ok(Response {
+            stratum: 2,
+            receive: 1_700_000_000_000_000,
+            transmit: 1_700_000_000_500_000,
+        })
+    );
+}
+
+#[test]
+fn response_after_2036_rollover() {
+    let mut packet = reply();
+    packet[40..48].copy_from_slice(&(100u64 << 32).to_be_bytes());
+
+    let response = parse_response(&packet, NONCE).unwrap();
+    assert_eq!(
+        response.transmit,
+        (100 + (1 << 32) - 2_208_988_800) * 1_000_000
+    );
+}
+
+#[test]
+fn time_at_arrival_adds_half_the_network_delay() {
+    let response = parse_response(&reply(), NONCE).unwrap();
+
+    // 500ms spent in the server, 40ms on the network
+    assert_eq!(response.time_at_arrival(540_000), 1_700_000_000_520_000);
+    // Local clock faster than the server's
+    assert_eq!(response.time_at_arrival(100_000), 1_700_000_000_500_000);
+}
+
+#[test]
+fn response_rejected() {
+    assert_eq!(
+        parse_response(&reply()[..PACKET_LEN - 1], NONCE),
+        Err(Error::Truncated)
+    );
+    assert_eq!(
+        parse_response(&reply(), NONCE + 1),
+        Err(Error::OriginMismatch)
+    );
+
+    let mut packet = reply();
+    packet[0] = (4 << 3) | 3;
+    assert_eq!(parse_response(&packet, NONCE), Err(Error::NotServer));
+
+    let mut packet = reply();
+    packet[0] |= 3 << 6;
+    assert_eq!(parse_response(&packet, NONCE), Err(Error::Unsynchronized));
+
+    let mut packet = reply();
+    packet[1] = 16;
+    assert_eq!(parse_response(&packet, NONCE), Err(Error::Unsynchronized));
+
+    let mut packet = reply();
+    packet[32..40].fill(0);
+    assert_eq!(parse_response(&packet, NONCE), Err(Error::InvalidTimestamp));
+}
+
+#[test]
+fn kiss_of_death() {
+    let mut packet = reply();
+    packet[1] = 0;
+    packet[12..16].copy_from_slice(b"RATE");
+
+    assert_eq!(
+        parse_response(&packet, NONCE),
+        Err(Error::KissOfDeath(*b"RATE"))
+    );
+}
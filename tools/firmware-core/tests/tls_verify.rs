@@ -0,0 +1,184 @@
+//! Handshakes of the firmware TLS client with a rustls server, checking what
+//! `VerifyingProvider` accepts.
+
+#[path = "../../../src/tls_verify.rs"]
+mod tls_verify;
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use embassy_futures::block_on;
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, NoClock, TlsClock, TlsConfig, TlsConnection, TlsContext, TlsError,
+};
+use rand_core::OsRng;
+use rcgen::{
+    date_time_ymd, BasicConstraints, CertificateParams, DnType, IsCa, KeyPair,
+    PKCS_ECDSA_P256_SHA256,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection};
+use tls_verify::{is_verification_error, VerifyingProvider};
+
+const TLS_BUFFER_MAX: usize = 16640;
+const TLS_CERT_MAX_SIZE: usize = 4096;
+const HOSTNAME: &str = "broker.example";
+
+struct Ca {
+    cert: rcgen::Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(name: &str) -> Self {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key).unwrap();
+        Self { cert, key }
+    }
+
+    /// Server certificate for `common_name`, valid until `not_after`
+    /// (year, month, day), rcgen's far future default without it
+    fn issue(
+        &self,
+        common_name: &str,
+        not_after: Option<(i32, u8, u8)>,
+    ) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = CertificateParams::new(vec![common_name.to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        if let Some((year, month, day)) = not_after {
+            params.not_before = date_time_ymd(year - 1, month, day);
+            params.not_after = date_time_ymd(year, month, day);
+        }
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+        (cert.der().clone(), key.into())
+    }
+}
+
+/// In memory connection to a rustls server presenting `cert`
+struct Server {
+    conn: ServerConnection,
+}
+
+impl Server {
+    fn new(cert: CertificateDer<'static>, key: PrivateKeyDer<'static>) -> Self {
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)
+                .unwrap();
+        Self {
+            conn: ServerConnection::new(Arc::new(config)).unwrap(),
+        }
+    }
+}
+
+impl ErrorType for Server {
+    type Error = ErrorKind;
+}
+
+impl Read for Server {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        self.conn
+            .write_tls(&mut &mut buf[..])
+            .map_err(|_| ErrorKind::Other)
+    }
+}
+
+impl Write for Server {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        let n = self
+            .conn
+            .read_tls(&mut &buf[..])
+            .map_err(|_| ErrorKind::Other)?;
+        // The client aborts with an alert when it rejects the certificate,
+        // which the server reports as an error
+        let _ = self.conn.process_new_packets();
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+}
+
+/// Current time, as the SNTP clock of the firmware once synchronised
+struct SystemClock;
+
+impl TlsClock for SystemClock {
+    fn now() -> Option<u64> {
+        Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        )
+    }
+}
+
+/// Open a connection to `server` as `Transport::new` does, trusting `ca`
+fn handshake<CLOCK: TlsClock>(server: Server, ca: &Ca, hostname: &str) -> Result<(), TlsError> {
+    let mut read_buffer = vec![0u8; TLS_BUFFER_MAX];
+    let mut write_buffer = vec![0u8; TLS_BUFFER_MAX];
+    let config = TlsConfig::new()
+        .with_server_name(hostname)
+        .with_ca(Certificate::X509(ca.cert.der()));
+    let mut tls: TlsConnection<Server, Aes128GcmSha256> =
+        TlsConnection::new(server, &mut read_buffer, &mut write_buffer);
+    let provider = VerifyingProvider::<_, CLOCK, TLS_CERT_MAX_SIZE>::new(OsRng);
+    block_on(tls.open(TlsContext::new(&config, provider)))
+}
+
+#[test]
+fn valid_chain() {
+    let ca = Ca::new("Test CA");
+    let (cert, key) = ca.issue(HOSTNAME, None);
+
+    handshake::<SystemClock>(Server::new(cert, key), &ca, HOSTNAME).unwrap();
+}
+
+#[test]
+fn wrong_ca() {
+    let ca = Ca::new("Test CA");
+    let other = Ca::new("Other CA");
+    let (cert, key) = ca.issue(HOSTNAME, None);
+
+    let e = handshake::<SystemClock>(Server::new(cert, key), &other, HOSTNAME).unwrap_err();
+    assert!(is_verification_error(&e), "{e:?}");
+}
+
+#[test]
+fn wrong_hostname() {
+    let ca = Ca::new("Test CA");
+    let (cert, key) = ca.issue("other.example", None);
+
+    let e = handshake::<SystemClock>(Server::new(cert, key), &ca, HOSTNAME).unwrap_err();
+    assert!(is_verification_error(&e), "{e:?}");
+}
+
+#[test]
+fn expired_certificate() {
+    let ca = Ca::new("Test CA");
+    let (cert, key) = ca.issue(HOSTNAME, Some((2021, 1, 1)));
+
+    let e = handshake::<SystemClock>(Server::new(cert.clone(), key.clone_key()), &ca, HOSTNAME)
+        .unwrap_err();
+    assert!(is_verification_error(&e), "{e:?}");
+
+    // Before the first SNTP sync the dates can't be checked
+    handshake::<NoClock>(Server::new(cert, key), &ca, HOSTNAME).unwrap();
+}
+
+#[test]
+fn malformed_message_is_not_a_verification_error() {
+    assert!(!is_verification_error(&TlsError::DecodeError));
+}
@@ -0,0 +1,38 @@
+use firmware_core::topic::device_topic;
+
+#[test]
+fn derived_from_device_id() {
+    assert_eq!(
+        device_topic::<64>(None, "sensors", "esp32-kitchen", "cmd").as_deref(),
+        Some("sensors/esp32-kitchen/cmd")
+    );
+    assert_eq!(
+        device_topic::<64>(None, "home/sensors", "esp32-kitchen", "status").as_deref(),
+        Some("home/sensors/esp32-kitchen/status")
+    );
+}
+
+#[test]
+fn explicit_topic_kept() {
+    assert_eq!(
+        device_topic::<64>(Some("home/kitchen/cmd"), "sensors", "esp32-kitchen", "cmd").as_deref(),
+        Some("home/kitchen/cmd")
+    );
+}
+
+#[test]
+fn too_long() {
+    assert_eq!(
+        device_topic::<16>(None, "sensors", "esp32-kitchen", "cmd"),
+        None
+    );
+    assert_eq!(
+        device_topic::<16>(Some("home/kitchen/commands"), "sensors", "esp32", "cmd"),
+        None
+    );
+    // Exactly fits
+    assert_eq!(
+        device_topic::<20>(None, "sensors", "esp32", "status").as_deref(),
+        Some("sensors/esp32/status")
+    );
+}
@@ -0,0 +1,645 @@
+//! Reference OTA server, speaking the protocol of `src/ota.rs` described in
+//! `docs/ota-firmware-upgrade.md`.
+//!
+//! Images are read from a local directory, one subdirectory per device ID,
+//! each image named after its version with the signature written by
+//! `sign-firmware` next to it:
+//!
+//! ```text
+//! firmware/
+//!   esp32-outdoor/
+//!     0.1.3.bin
+//!     0.1.3.bin.sig
+//!     0.1.4.bin
+//!     0.1.4.bin.sig
+//!     0.1.5-beta.1.bin
+//!     0.1.5-beta.1.bin.sig
+//!     0.1.5-beta.1.bin.rollout
+//!     0.2.0.bin
+//!     0.2.0.bin.sig
+//!     0.2.0.bin.requires
+//! ```
+//!
+//! Devices get the newest version released on their channel, pre-releases
+//! only reaching the beta and dev ones, see `Channel`. An optional `.rollout`
+//! file holds the percentage of the devices the release is rolled out to. An
+//! optional `.requires` file holds the requirement on the running version,
+//! e.g. `>=0.1.4`: devices running another version get the newest release they
+//! can install instead, an intermediate one. An empty `.downgrade` file marks
+//! a release rolling newer ones back.
+//!
+//! The newest version is served, the directory being read again on every
+//! request so a release only needs copying the files over. Connections are
+//! plain TCP or TLS, optionally requiring a client certificate (mTLS).
+
+use std::{
+    error::Error,
+    fmt::Write as _,
+    fs, io,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use firmware_core::{
+    crc32::Crc32,
+    semver::{Channel, SemVer, VersionReq},
+};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+/// Longest request line or header
+const LINE_MAX_LEN: u64 = 8192;
+
+/// Idle connections are closed after this long
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A signed firmware image
+#[derive(Debug)]
+pub struct Firmware {
+    pub version: String,
+    pub image: Vec<u8>,
+    /// Base64 signature, as written by `sign-firmware`
+    pub signature: String,
+    /// Most stable channel the version is released on
+    pub channel: Channel,
+    /// Share of the devices the release is rolled out to, all when `None`
+    pub rollout_percentage: Option<u8>,
+    /// Running versions allowed to install it, all when `None`
+    pub current_version_requirement: Option<VersionReq>,
+    /// Whether devices running newer versions install it
+    pub allow_downgrade: bool,
+}
+
+impl Firmware {
+    pub fn crc32(&self) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(&self.image);
+        crc.finalize()
+    }
+
+    pub fn sha256(&self) -> String {
+        Sha256::digest(&self.image)
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            })
+    }
+}
+
+/// Firmware images of a directory, see the module documentation
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Newest image for `device_id` released on `channel`, or the given
+    /// `version` of it, which a device `running` a version can install. `None`
+    /// when the device has no such image.
+    pub fn find(
+        &self,
+        device_id: &str,
+        version: Option<&str>,
+        channel: Channel,
+        running: Option<&SemVer>,
+    ) -> io::Result<Option<Firmware>> {
+        if !is_valid_name(device_id) {
+            return Ok(None);
+        }
+        let entries = match fs::read_dir(self.dir.join(device_id)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut candidates = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "bin") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let Some(semver) = SemVer::parse(name) else {
+                eprintln!(
+                    "warning: ignoring {}, not named after its version",
+                    path.display()
+                );
+                continue;
+            };
+            if version.is_some_and(|version| version != name) || !channel.accepts(&semver) {
+                continue;
+            }
+            candidates.push((semver, name.to_string(), path));
+        }
+
+        // Newest first, down to one the running version can upgrade to
+        candidates.sort_by(|(a, _, _), (b, _, _)| b.cmp(a));
+        for (semver, version, path) in candidates {
+            let current_version_requirement = read_requirement(&path)?;
+            if let (Some(requirement), Some(running)) = (&current_version_requirement, running) {
+                if !requirement.matches(running) {
+                    continue;
+                }
+            }
+            return Ok(Some(Firmware {
+                signature: read_signature(&path)?,
+                rollout_percentage: read_rollout_percentage(&path)?,
+                current_version_requirement,
+                allow_downgrade: sidecar(&path, "downgrade").exists(),
+                image: fs::read(&path)?,
+                channel: semver.channel(),
+                version,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// File next to the image `path` with the `extension` appended
+fn sidecar(path: &Path, extension: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{extension}", path.display()))
+}
+
+fn read_signature(path: &Path) -> io::Result<String> {
+    let signature_path = sidecar(path, "sig");
+    let signature = fs::read_to_string(&signature_path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "{}: {e}, sign the image with sign-firmware",
+                signature_path.display()
+            ),
+        )
+    })?;
+    Ok(signature.trim().to_string())
+}
+
+fn read_rollout_percentage(path: &Path) -> io::Result<Option<u8>> {
+    let rollout_path = sidecar(path, "rollout");
+    match fs::read_to_string(&rollout_path) {
+        Ok(rollout) => Ok(Some(
+            rollout
+                .trim()
+                .trim_end_matches('%')
+                .parse()
+                .ok()
+                .filter(|percentage| *percentage <= 100)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: not a percentage between 0 and 100",
+                            rollout_path.display()
+                        ),
+                    )
+                })?,
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_requirement(path: &Path) -> io::Result<Option<VersionReq>> {
+    let requires_path = sidecar(path, "requires");
+    match fs::read_to_string(&requires_path) {
+        Ok(requirement) => Ok(Some(VersionReq::parse(&requirement).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: not a version requirement like \">=0.1.3, <0.3.0\"",
+                    requires_path.display()
+                ),
+            )
+        })?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Device IDs and versions name files, they can't point elsewhere
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'+'))
+}
+
+pub struct Server {
+    store: Store,
+    tls: Option<Arc<ServerConfig>>,
+    /// Answer `/version` with the line format of servers predating the JSON
+    /// manifest
+    legacy: bool,
+}
+
+impl Server {
+    /// Server of the images in `store`, over TLS when `tls` is set.
+    pub fn new(store: Store, tls: Option<ServerConfig>, legacy: bool) -> Self {
+        Self {
+            store,
+            tls: tls.map(Arc::new),
+            legacy,
+        }
+    }
+
+    /// Accept connections on `listener`, each served by its own thread.
+    pub fn serve(self, listener: TcpListener) -> io::Result<()> {
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("warning: failed to accept a connection: {e}");
+                    continue;
+                }
+            };
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle(stream) {
+                    if let Some(peer) = peer {
+                        eprintln!("{peer}: {e}");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let peer = stream.peer_addr()?;
+        match &self.tls {
+            Some(tls) => {
+                let connection =
+                    ServerConnection::new(Arc::clone(tls)).map_err(io::Error::other)?;
+                self.handle_requests(StreamOwned::new(connection, stream), peer)
+            }
+            None => self.handle_requests(stream, peer),
+        }
+    }
+
+    /// Answer the requests received on `stream` until the client closes it.
+    fn handle_requests<S: Read + Write>(&self, stream: S, peer: SocketAddr) -> io::Result<()> {
+        let mut stream = BufReader::new(stream);
+        while let Some(request) = read_request(&mut stream)? {
+            let response = self.respond(&request, peer);
+            let stream = stream.get_mut();
+            response.write_to(stream, request.keep_alive)?;
+            stream.flush()?;
+            if !request.keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: &Request, peer: SocketAddr) -> Response {
+        if request.method != "GET" {
+            return Response::text(405, "Method Not Allowed");
+        }
+        let device_id = request.query("device").unwrap_or_default();
+        let version = request.query("version");
+        // Devices predating channels only install releases
+        let channel = match request.query("channel") {
+            None => Channel::Stable,
+            Some(name) => match Channel::from_name(name) {
+                Some(channel) => channel,
+                None => return Response::text(400, "Unknown channel"),
+            },
+        };
+        match request.path.as_str() {
+            "/version" => self.version(device_id, channel, version, peer),
+            "/firmware" => {
+                // Any channel once the manifest picked the version
+                let channel = if version.is_some() {
+                    Channel::Dev
+                } else {
+                    channel
+                };
+                self.firmware(device_id, version, channel, request.range, peer)
+            }
+            _ => Response::text(404, "Not Found"),
+        }
+    }
+
+    /// Manifest of the newest image for `device_id` on `channel`, which
+    /// reported running `running` if it did, see `Store::find`.
+    fn version(
+        &self,
+        device_id: &str,
+        channel: Channel,
+        running: Option<&str>,
+        peer: SocketAddr,
+    ) -> Response {
+        let running_version = running.and_then(SemVer::parse);
+        let running = running.map(|v| format!(" running {v}")).unwrap_or_default();
+        let channel_name = channel.as_str();
+        let firmware = match self
+            .store
+            .find(device_id, None, channel, running_version.as_ref())
+        {
+            Ok(Some(firmware)) => firmware,
+            Ok(None) => {
+                eprintln!(
+                    "{peer}: device '{device_id}'{running} checked in on {channel_name}, no firmware"
+                );
+                let message = format!("No firmware for device '{device_id}'");
+                return if self.legacy {
+                    Response::text(404, &message)
+                } else {
+                    // Valid names need no JSON escaping
+                    let message = if is_valid_name(device_id) {
+                        message
+                    } else {
+                        "Invalid device ID".to_string()
+                    };
+                    Response::json(
+                        404,
+                        format!(r#"{{"manifest_version":1,"error":"{message}"}}"#),
+                    )
+                };
+            }
+            Err(e) => return Response::server_error(peer, e),
+        };
+        eprintln!(
+            "{peer}: device '{device_id}'{running} checked in on {channel_name}, latest {}",
+            firmware.version
+        );
+
+        if self.legacy {
+            let body = format!(
+                "{}\n{}\n{}\n{}\n",
+                firmware.version,
+                firmware.crc32(),
+                firmware.image.len(),
+                firmware.signature
+            );
+            return Response::text(200, &body);
+        }
+        let rollout = firmware
+            .rollout_percentage
+            .map(|percentage| format!(r#","rollout_percentage":{percentage}"#))
+            .unwrap_or_default();
+        // Requirements are made of versions and operators, no JSON escaping
+        let requirement = firmware
+            .current_version_requirement
+            .as_ref()
+            .map(|requirement| format!(r#","current_version_requirement":"{requirement}""#))
+            .unwrap_or_default();
+        let downgrade = if firmware.allow_downgrade {
+            r#","allow_downgrade":true"#
+        } else {
+            ""
+        };
+        // The download URL pins the version, the image can't change between
+        // the two requests
+        Response::json(
+            200,
+            format!(
+                concat!(
+                    r#"{{"manifest_version":1,"version":"{}","size":{},"sha256":"{}","#,
+                    r#""signature":"{}","channel":"{}"{}{}{},"#,
+                    r#""download_url":"/firmware?device={}&version={}"}}"#
+                ),
+                firmware.version,
+                firmware.image.len(),
+                firmware.sha256(),
+                firmware.signature,
+                firmware.channel.as_str(),
+                rollout,
+                requirement,
+                downgrade,
+                device_id,
+                firmware.version,
+            ),
+        )
+    }
+
+    /// Image for `device_id`, from byte `range` when set.
+    fn firmware(
+        &self,
+        device_id: &str,
+        version: Option<&str>,
+        channel: Channel,
+        range: Option<u64>,
+        peer: SocketAddr,
+    ) -> Response {
+        let firmware = match self.store.find(device_id, version, channel, None) {
+            Ok(Some(firmware)) => firmware,
+            Ok(None) => return Response::text(404, "No such firmware"),
+            Err(e) => return Response::server_error(peer, e),
+        };
+        let len = firmware.image.len() as u64;
+        eprintln!(
+            "{peer}: sending firmware {} to '{device_id}' from byte {}",
+            firmware.version,
+            range.unwrap_or(0)
+        );
+        match range {
+            None => Response::new(200, "application/octet-stream", firmware.image),
+            Some(first) if first >= len => {
+                let mut response = Response::text(416, "Range Not Satisfiable");
+                response.content_range = Some(format!("*/{len}"));
+                response
+            }
+            Some(first) => {
+                let mut response = Response::new(
+                    206,
+                    "application/octet-stream",
+                    firmware.image[first as usize..].to_vec(),
+                );
+                response.content_range = Some(format!("{first}-{}/{len}", len - 1));
+                response
+            }
+        }
+    }
+}
+
+/// TLS configuration presenting the certificate chain of `cert_path` with the
+/// private key of `key_path`, PEM encoded. Clients must present a certificate
+/// issued by one of the CAs of `client_ca_path` when set.
+pub fn tls_config(
+    cert_path: &Path,
+    key_path: &Path,
+    client_ca_path: Option<&Path>,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| format!("{}: {e}", cert_path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {e}", cert_path.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("{}: {e}", key_path.display()))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(path)
+                .map_err(|e| format!("{}: {e}", path.display()))?
+            {
+                roots.add(cert.map_err(|e| format!("{}: {e}", path.display()))?)?;
+            }
+            builder
+                .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    /// First byte of a `Range: bytes=<first>-` request
+    range: Option<u64>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Read the next request head, `None` when the client closed the connection.
+/// Request bodies aren't supported, GET requests have none.
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+    let Some(request_line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed request");
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(http_version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect(),
+        range: None,
+        // HTTP/1.0 closes the connection unless asked otherwise
+        keep_alive: http_version == "HTTP/1.1",
+    };
+
+    loop {
+        let line = read_line(reader)?.ok_or_else(invalid)?;
+        if line.is_empty() {
+            return Ok(Some(request));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                request.keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                request.keep_alive = true;
+            }
+        } else if name.eq_ignore_ascii_case("range") {
+            // Only open ranges are asked for, other ones get the whole image
+            request.range = value
+                .strip_prefix("bytes=")
+                .and_then(|range| range.strip_suffix('-'))
+                .and_then(|first| first.parse().ok());
+        }
+    }
+}
+
+/// Next line without its line break, `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if reader.take(LINE_MAX_LEN).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    /// `Content-Range` value, without the unit
+    content_range: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            content_type,
+            content_range: None,
+            body,
+        }
+    }
+
+    fn text(status: u16, body: &str) -> Self {
+        Self::new(status, "text/plain", body.as_bytes().to_vec())
+    }
+
+    fn json(status: u16, body: String) -> Self {
+        Self::new(status, "application/json", body.into_bytes())
+    }
+
+    fn server_error(peer: SocketAddr, e: io::Error) -> Self {
+        eprintln!("{peer}: error: {e}");
+        Self::text(500, "Internal Server Error")
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W, keep_alive: bool) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            206 => "Partial Content",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            416 => "Range Not Satisfiable",
+            _ => "Internal Server Error",
+        };
+        let mut head = format!(
+            "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        if let Some(range) = &self.content_range {
+            head.push_str(&format!("Content-Range: bytes {range}\r\n"));
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        w.write_all(head.as_bytes())?;
+        w.write_all(&self.body)
+    }
+}
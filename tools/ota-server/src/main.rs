@@ -0,0 +1,95 @@
+//! Serve firmware images to the devices over the OTA protocol, see the
+//! `ota_server` crate documentation for the directory layout.
+//!
+//! Usage: ota-server [--listen <address>] [--cert <cert.pem> --key <key.pem>
+//!        [--client-ca <ca.pem>]] [--legacy] <firmware-dir>
+//!
+//! Without `--cert`, requests are served over plain TCP. `--client-ca`
+//! requires the devices to present a certificate issued by that CA (mTLS), as
+//! with the MQTT broker, see `docs/tls-mtls.md`. `--legacy` answers with the
+//! line format of servers predating the JSON manifest.
+
+use std::{env, error::Error, net::TcpListener, path::PathBuf, process::ExitCode};
+
+use ota_server::{tls_config, Server, Store};
+
+const USAGE: &str = "[--listen <address>] [--cert <cert.pem> --key <key.pem> \
+                     [--client-ca <ca.pem>]] [--legacy] <firmware-dir>";
+
+#[derive(Default)]
+struct Args {
+    listen: Option<String>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+    legacy: bool,
+    dir: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_else(|| "ota-server".to_string());
+    let Some(args) = parse_args(args) else {
+        eprintln!("Usage: {program} {USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Args> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => parsed.listen = Some(args.next()?),
+            "--cert" => parsed.cert = Some(args.next()?.into()),
+            "--key" => parsed.key = Some(args.next()?.into()),
+            "--client-ca" => parsed.client_ca = Some(args.next()?.into()),
+            "--legacy" => parsed.legacy = true,
+            _ if arg.starts_with("--") || parsed.dir.is_some() => return None,
+            _ => parsed.dir = Some(arg.into()),
+        }
+    }
+    // A client CA needs a server certificate
+    let tls_complete = match (&parsed.cert, &parsed.key) {
+        (Some(_), Some(_)) => true,
+        (None, None) => parsed.client_ca.is_none(),
+        _ => false,
+    };
+    (parsed.dir.is_some() && tls_complete).then_some(parsed)
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let tls = match (&args.cert, &args.key) {
+        (Some(cert), Some(key)) => Some(tls_config(cert, key, args.client_ca.as_deref())?),
+        _ => None,
+    };
+    let listen = args.listen.unwrap_or_else(|| {
+        match tls {
+            Some(_) => "0.0.0.0:8443",
+            None => "0.0.0.0:8080",
+        }
+        .to_string()
+    });
+    let dir = args.dir.expect("checked by parse_args");
+
+    let listener = TcpListener::bind(&listen)?;
+    eprintln!(
+        "Serving {} on {} ({})",
+        dir.display(),
+        listener.local_addr()?,
+        match (&tls, &args.client_ca) {
+            (Some(_), Some(_)) => "mTLS",
+            (Some(_), None) => "TLS",
+            _ => "plain TCP",
+        }
+    );
+    Server::new(Store::new(dir), tls, args.legacy).serve(listener)?;
+    Ok(())
+}
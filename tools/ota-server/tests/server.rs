@@ -0,0 +1,324 @@
+//! Drive the server with the firmware modules `Ota::check` is built on, over a
+//! real TCP connection.
+
+#[path = "../../firmware-core/tests/common/mod.rs"]
+mod common;
+
+use std::{
+    fs,
+    io::{Read as _, Write as _},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+};
+
+use embassy_futures::block_on;
+use firmware_core::download::{download, Progress};
+use firmware_core::http::Client;
+use firmware_core::manifest::{self, Checksum};
+use ota_server::{Server, Store};
+
+use common::{Conn, RamFlash};
+
+const DEVICE: &str = "esp32-outdoor";
+const SIGNATURE: &str = "U+quTw2A8KG5X0w+nFxW==";
+
+/// Firmware directory holding `images` of `DEVICE`, removed when dropped
+struct FirmwareDir(PathBuf);
+
+impl FirmwareDir {
+    fn new(images: &[(&str, &[u8])]) -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "ota-server-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let device_dir = dir.join(DEVICE);
+        fs::create_dir_all(&device_dir).unwrap();
+        for (version, image) in images {
+            fs::write(device_dir.join(format!("{version}.bin")), image).unwrap();
+            fs::write(
+                device_dir.join(format!("{version}.bin.sig")),
+                format!("{SIGNATURE}\n"),
+            )
+            .unwrap();
+        }
+        Self(dir)
+    }
+}
+
+impl Drop for FirmwareDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Start a plain TCP server on a free port, returns its port
+fn start(dir: &FirmwareDir, legacy: bool) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = Server::new(Store::new(&dir.0), None, legacy);
+    thread::spawn(move || server.serve(listener));
+    port
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+/// Body of `path` on a new connection, with its status
+fn get(port: u16, path: &str) -> (u16, Vec<u8>) {
+    let mut head = [0; 1024];
+    let mut client = Client::new(Conn::connect(port), "127.0.0.1", port, &mut head);
+    block_on(async {
+        let response = client.get(path, &[]).await.unwrap();
+        let mut body = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            match client.read_body(&mut buf).await.unwrap() {
+                0 => return (response.status, body),
+                n => body.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+}
+
+#[test]
+fn manifest_then_download() {
+    let old = firmware(5000);
+    let new = firmware(3 * 4096 + 123);
+    let dir = FirmwareDir::new(&[("0.1.3", &old), ("0.1.10", &new), ("0.1.4-rc.1", &old)]);
+    let port = start(&dir, false);
+
+    // Both requests on the same connection, as `Ota::check` does
+    let mut head = [0; 1024];
+    let mut client = Client::new(Conn::connect(port), "127.0.0.1", port, &mut head);
+    let mut flash = RamFlash::new(8);
+    let manifest = block_on(async {
+        let path = format!("/version?device={DEVICE}");
+        let response = client
+            .get(&path, &[("Connection", "keep-alive")])
+            .await
+            .unwrap();
+        assert_eq!(response.status, 200);
+        let mut body = [0; 2048];
+        let mut len = 0;
+        loop {
+            match client.read_body(&mut body[len..]).await.unwrap() {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let manifest = manifest::parse(&body[..len]).unwrap();
+
+        let path = manifest.download_path("127.0.0.1", port).unwrap().unwrap();
+        let mut progress = Progress::new(
+            &manifest.version,
+            manifest.checksum.fingerprint(),
+            manifest.size,
+        );
+        let mut buf = [0; 2048];
+        download(&mut client, path, &[], &mut flash, &mut progress, &mut buf)
+            .await
+            .unwrap();
+        manifest
+    });
+
+    assert_eq!(manifest.version, "0.1.10");
+    assert_eq!(manifest.size, new.len() as u32);
+    assert_eq!(manifest.signature, SIGNATURE);
+    let Checksum::Sha256(sha256) = manifest.checksum else {
+        panic!("no SHA-256 in {manifest:?}");
+    };
+    let expected: [u8; 32] = <sha2::Sha256 as sha2::Digest>::digest(&new).into();
+    assert_eq!(sha256, expected);
+    assert_eq!(&flash.data[..new.len()], &new[..]);
+}
+
+#[test]
+fn resumes_download() {
+    let image = firmware(2 * 4096 + 77);
+    let dir = FirmwareDir::new(&[("1.0.0", &image)]);
+    let port = start(&dir, false);
+    let mut flash = RamFlash::new(4);
+    let mut progress = Progress::new("1.0.0", 0, image.len() as u32);
+    let path = format!("/firmware?device={DEVICE}&version=1.0.0");
+
+    // Connection dropped in the middle of the image, then resumed with a
+    // range request on a new one
+    for budget in [5000, usize::MAX] {
+        let mut head = [0; 1024];
+        let mut client = Client::new(
+            Conn::with_budget(port, budget),
+            "127.0.0.1",
+            port,
+            &mut head,
+        );
+        let mut buf = [0; 1000];
+        let result = block_on(download(
+            &mut client,
+            &path,
+            &[],
+            &mut flash,
+            &mut progress,
+            &mut buf,
+        ));
+        assert_eq!(result.is_ok(), budget == usize::MAX);
+        assert!(progress.written() > 0);
+    }
+    assert!(progress.is_complete());
+    assert_eq!(&flash.data[..image.len()], &image[..]);
+
+    // Partial response from the requested byte
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: 127.0.0.1\r\nRange: bytes=8000-\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let head_len = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&response[..head_len]);
+    assert!(
+        head.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+        "{head}"
+    );
+    assert!(head.contains(&format!(
+        "Content-Range: bytes 8000-{}/{}\r\n",
+        image.len() - 1,
+        image.len()
+    )));
+    assert_eq!(&response[head_len..], &image[8000..]);
+}
+
+#[test]
+fn unknown_device() {
+    let dir = FirmwareDir::new(&[]);
+    let port = start(&dir, false);
+
+    let (status, body) = get(port, "/version?device=esp32-kitchen");
+    assert_eq!(status, 404);
+    assert_eq!(
+        manifest::parse(&body),
+        Err(manifest::Error::NoFirmware(
+            "No firmware for device 'esp32-kitchen'".try_into().unwrap()
+        ))
+    );
+
+    // Device IDs can't escape the firmware directory
+    let (status, _) = get(port, "/firmware?device=..");
+    assert_eq!(status, 404);
+    let (status, _) = get(port, "/version?device=%2e%2e%2fetc");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn release_channels() {
+    let image = firmware(100);
+    let dir = FirmwareDir::new(&[
+        ("0.2.0", &image),
+        ("0.3.0-beta.1", &image),
+        ("0.3.0-alpha.2", &image),
+    ]);
+    fs::write(dir.0.join(DEVICE).join("0.3.0-beta.1.bin.rollout"), "10\n").unwrap();
+    let port = start(&dir, false);
+
+    for (query, version, channel, rollout_percentage) in [
+        ("", "0.2.0", "stable", 100),
+        ("&channel=stable&version=0.1.3", "0.2.0", "stable", 100),
+        ("&channel=beta", "0.3.0-beta.1", "beta", 10),
+        ("&channel=dev", "0.3.0-beta.1", "beta", 10),
+    ] {
+        let (status, body) = get(port, &format!("/version?device={DEVICE}{query}"));
+        assert_eq!(status, 200, "{query}");
+        let manifest = manifest::parse(&body).unwrap();
+        assert_eq!(manifest.version, version, "{query}");
+        assert_eq!(manifest.channel.as_deref(), Some(channel), "{query}");
+        assert_eq!(manifest.rollout_percentage, rollout_percentage, "{query}");
+    }
+
+    // The version picked on the beta channel downloads without it
+    let (status, _) = get(
+        port,
+        &format!("/firmware?device={DEVICE}&version=0.3.0-beta.1"),
+    );
+    assert_eq!(status, 200);
+
+    let (status, _) = get(port, &format!("/version?device={DEVICE}&channel=nightly"));
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn upgrade_paths() {
+    let image = firmware(100);
+    let dir = FirmwareDir::new(&[("0.1.4", &image), ("0.2.0", &image), ("0.3.0", &image)]);
+    let device_dir = dir.0.join(DEVICE);
+    // 0.2.0 migrates the configuration, later releases expect it done
+    fs::write(device_dir.join("0.2.0.bin.requires"), ">=0.1.4\n").unwrap();
+    fs::write(device_dir.join("0.3.0.bin.requires"), ">=0.2.0").unwrap();
+    let port = start(&dir, false);
+
+    for (running, version) in [
+        ("0.1.3", "0.1.4"),
+        ("0.1.4", "0.2.0"),
+        ("0.2.0", "0.3.0"),
+        ("0.3.0", "0.3.0"),
+    ] {
+        let (status, body) = get(port, &format!("/version?device={DEVICE}&version={running}"));
+        assert_eq!(status, 200, "{running}");
+        let manifest = manifest::parse(&body).unwrap();
+        assert_eq!(manifest.version, version, "{running}");
+        assert!(!manifest.allow_downgrade);
+    }
+
+    // Devices not telling their version get the newest one and its
+    // requirement to check
+    let (_, body) = get(port, &format!("/version?device={DEVICE}"));
+    let manifest = manifest::parse(&body).unwrap();
+    assert_eq!(manifest.version, "0.3.0");
+    assert_eq!(
+        manifest.current_version_requirement.unwrap().to_string(),
+        ">=0.2.0"
+    );
+
+    // 0.3.0 is withdrawn, 0.2.0 rolls it back
+    fs::remove_file(device_dir.join("0.3.0.bin")).unwrap();
+    fs::write(device_dir.join("0.2.0.bin.downgrade"), "").unwrap();
+    let (_, body) = get(port, &format!("/version?device={DEVICE}&version=0.3.0"));
+    let manifest = manifest::parse(&body).unwrap();
+    assert_eq!(manifest.version, "0.2.0");
+    assert!(manifest.allow_downgrade);
+}
+
+#[test]
+fn legacy_format() {
+    let image = firmware(1000);
+    let dir = FirmwareDir::new(&[("0.2.0", &image)]);
+    let port = start(&dir, true);
+
+    let (status, body) = get(port, &format!("/version?device={DEVICE}"));
+    assert_eq!(status, 200);
+    let manifest = manifest::parse(&body).unwrap();
+    assert_eq!(manifest.version, "0.2.0");
+    assert_eq!(manifest.size, 1000);
+    let mut crc = firmware_core::crc32::Crc32::new();
+    crc.update(&image);
+    assert_eq!(manifest.checksum, Checksum::Crc32(crc.finalize()));
+    assert_eq!(manifest.download_url, None);
+
+    let (status, body) = get(port, &format!("/firmware?device={DEVICE}"));
+    assert_eq!(status, 200);
+    assert_eq!(body, image);
+}
+
+#[test]
+fn missing_signature() {
+    let dir = FirmwareDir::new(&[("0.2.0", &firmware(10))]);
+    fs::remove_file(dir.0.join(DEVICE).join("0.2.0.bin.sig")).unwrap();
+    let port = start(&dir, false);
+    let (status, _) = get(port, &format!("/version?device={DEVICE}"));
+    assert_eq!(status, 500);
+}
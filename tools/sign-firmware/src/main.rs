@@ -0,0 +1,78 @@
+//! Sign a firmware image produced by `espflash save-image` so it can be
+//! verified by the device before activating the new OTA partition.
+//!
+//! Usage: sign-firmware <private-key.pem> <firmware.bin> [output.sig]
+//!
+//! The signature is ECDSA P-256 over the SHA-256 of the image, written as a
+//! single base64 line of the fixed-size (r || s) encoding. This is what the OTA
+//! server returns as the fourth line of the `/version` response.
+
+use std::{env, error::Error, fs, process::ExitCode};
+
+use base64::Engine;
+use p256::{
+    ecdsa::{signature::Signer, signature::Verifier, Signature, SigningKey, VerifyingKey},
+    pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding},
+    SecretKey,
+};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!(
+            "Usage: {} <private-key.pem> <firmware.bin> [output.sig]",
+            args[0]
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let output = args
+        .get(3)
+        .cloned()
+        .unwrap_or_else(|| format!("{}.sig", args[2]));
+
+    match sign(&args[1], &args[2], &output) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn sign(key_path: &str, firmware_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
+    let signing_key = load_signing_key(&fs::read_to_string(key_path)?)?;
+    let firmware = fs::read(firmware_path)?;
+
+    let signature: Signature = signing_key.sign(&firmware);
+
+    // Sanity check before handing the signature over to the release flow
+    let verifying_key = VerifyingKey::from(&signing_key);
+    verifying_key.verify(&firmware, &signature)?;
+
+    let encoded = base64::engine::general_purpose::STANDARD.encode(signature.to_bytes());
+    fs::write(output_path, format!("{encoded}\n"))?;
+
+    eprintln!(
+        "Signed {} ({} bytes) -> {}",
+        firmware_path,
+        firmware.len(),
+        output_path
+    );
+    eprintln!(
+        "Public key (ota_public_key in cfg.toml):\n{}",
+        verifying_key.to_public_key_pem(LineEnding::LF)?
+    );
+    println!("{encoded}");
+    Ok(())
+}
+
+/// Accept both SEC1 (`BEGIN EC PRIVATE KEY`, as produced by
+/// `openssl ecparam -genkey`) and PKCS#8 (`BEGIN PRIVATE KEY`) PEM keys.
+fn load_signing_key(pem: &str) -> Result<SigningKey, Box<dyn Error>> {
+    if pem.contains("BEGIN EC PRIVATE KEY") {
+        Ok(SecretKey::from_sec1_pem(pem)?.into())
+    } else {
+        Ok(SigningKey::from_pkcs8_pem(pem)?)
+    }
+}